@@ -56,7 +56,7 @@ impl Parse for APIEndpoint {
 /// # Example
 /// ```no_run
 /// // Generates a function named `get` that sends a GET request v2/bubble.info when passed
-/// // a Pronto base URL, a reqwest client, and a GetBubbleInfoRequest.
+/// // a Pronto base URL, an HttpClient, and a GetBubbleInfoRequest.
 /// // client_macros::api!(get, "v2/bubble.info", GetBubbleInfoResponse, GetBubbleInfoRequest);
 /// ```
 #[proc_macro]
@@ -111,26 +111,14 @@ pub fn api(input: TokenStream) -> TokenStream {
 
     let parse = if let Some(parse_expr) = extra_args.get("parse") {
         quote! {
+            #process
             return #parse_expr;
         }
     } else {
         quote! {
-            let json = serde_json::from_str(&text);
-            match json {
-                Ok(json) => {
-                    return Ok(json);
-                }
-                Err(e) => {
-                    let json = serde_json::from_str::<#response_name>(&text);
-                    let e = json.unwrap_err();
-                    log::error!("Error parsing json response: {:?}.", e);
-                    let json = serde_json::from_str::<serde_json::Value>(&text);
-                    if json.is_err() {
-                        return Err(crate::ResponseError::NotJson(text));
-                    }
-                    return Err(crate::ResponseError::from(e));
-                }
-            }
+            let status = r.status();
+            let text = r.text().await?;
+            crate::api_error::parse_response::<#response, #response_name>(status, text)
         }
     };
 
@@ -172,13 +160,13 @@ pub fn api(input: TokenStream) -> TokenStream {
     let types = if has_request {
         quote! {
             pronto_base_url: &str,
-            client: &reqwest::Client,
+            client: &crate::HttpClient,
             request: #request,
         }
     } else {
         quote! {
             pronto_base_url: &str,
-            client: &reqwest::Client,
+            client: &crate::HttpClient,
         }
     };
 
@@ -192,7 +180,6 @@ pub fn api(input: TokenStream) -> TokenStream {
             let r = #send_request;
             let elapsed = initial_time.elapsed();
             log::debug!(target: "request_perf", "Network: {} ms", elapsed.as_millis());
-            #process
             #parse
         }
     };
@@ -1,15 +1,124 @@
+use crate::ResponseError;
+use reqwest::StatusCode;
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct APIError {
     pub(crate) ok: bool,
     pub error: String,
+    /// The HTTP status the error came with, `None` if pronto reported it with a success status
+    #[serde(skip)]
+    pub status: Option<StatusCode>,
 }
 
 impl std::fmt::Display for APIError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}", self.error)
+        match self.status {
+            Some(status) => write!(f, "{} ({status})", self.error),
+            None => write!(f, "{}", self.error),
+        }
     }
 }
 
 impl std::error::Error for APIError {}
+
+/// What went wrong with a request, for callers that need to react to it
+/// rather than just show it.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ErrorKind {
+    /// The token is missing, expired or was revoked, the user has to log in again
+    Unauthorized,
+    Forbidden,
+    NotFound,
+    /// Throttled by the server even after the retries of the [`crate::RequestPolicy`]
+    RateLimited,
+    /// The server rejected the request itself
+    Validation,
+    Server,
+    /// The request didn't complete, e.g. no connection or a timeout
+    Transport,
+    /// The response didn't have the shape we expected
+    Decode,
+}
+
+impl ErrorKind {
+    pub fn from_status(status: StatusCode) -> Self {
+        match status {
+            StatusCode::UNAUTHORIZED => ErrorKind::Unauthorized,
+            StatusCode::FORBIDDEN => ErrorKind::Forbidden,
+            StatusCode::NOT_FOUND => ErrorKind::NotFound,
+            StatusCode::TOO_MANY_REQUESTS => ErrorKind::RateLimited,
+            status if status.is_server_error() => ErrorKind::Server,
+            _ => ErrorKind::Validation,
+        }
+    }
+}
+
+impl ResponseError {
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            ResponseError::ReqwestError(e) if e.is_decode() => ErrorKind::Decode,
+            ResponseError::ReqwestError(e) => e
+                .status()
+                .map_or(ErrorKind::Transport, ErrorKind::from_status),
+            ResponseError::SerdeJsonError(_)
+            | ResponseError::DetailedSerdeJsonError(_)
+            | ResponseError::NotJson(_) => ErrorKind::Decode,
+            ResponseError::HttpError { status, .. } => ErrorKind::from_status(*status),
+            // Pronto also reports failures with `"ok": false` and a success status
+            ResponseError::ApiError(e) => e
+                .status
+                .map_or(ErrorKind::Validation, ErrorKind::from_status),
+        }
+    }
+
+    /// The HTTP status of the response, if one was received
+    pub fn status(&self) -> Option<StatusCode> {
+        match self {
+            ResponseError::ReqwestError(e) => e.status(),
+            ResponseError::HttpError { status, .. } => Some(*status),
+            ResponseError::ApiError(e) => e.status,
+            _ => None,
+        }
+    }
+
+    /// The error pronto returned, if the response was one
+    pub fn api_error(&self) -> Option<&APIError> {
+        match self {
+            ResponseError::ApiError(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// Parse a response body as `R`, turning error responses into a [`ResponseError`] that keeps the status.
+/// `D` is the success type, only parsed again to log a detailed error when `R` doesn't fit.
+pub(crate) fn parse_response<R, D>(status: StatusCode, text: String) -> Result<R, ResponseError>
+where
+    R: DeserializeOwned,
+    D: DeserializeOwned,
+{
+    log::trace!("Response: {}", text);
+    if let Ok(error) = serde_json::from_str::<APIError>(&text)
+        && !error.ok
+    {
+        return Err(ResponseError::ApiError(APIError {
+            status: Some(status),
+            ..error
+        }));
+    }
+    let e = match serde_json::from_str::<R>(&text) {
+        Ok(json) => return Ok(json),
+        Err(e) => e,
+    };
+    if !status.is_success() {
+        return Err(ResponseError::HttpError { status, text });
+    }
+    let e = serde_json::from_str::<D>(&text).err().unwrap_or(e);
+    log::error!("Error parsing json response: {:?}.", e);
+    if serde_json::from_str::<serde_json::Value>(&text).is_err() {
+        return Err(ResponseError::NotJson(text));
+    }
+    Err(ResponseError::from(e))
+}
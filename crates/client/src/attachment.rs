@@ -0,0 +1,126 @@
+use futures::{StreamExt, TryStreamExt, stream};
+use reqwest::Body;
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+use tokio::fs::File;
+use tokio::io::AsyncReadExt;
+
+/// Uploads are streamed in chunks of this size, which is also how often progress is reported
+const CHUNK_SIZE: usize = 64 * 1024;
+
+type Progress = Arc<dyn Fn(u64, u64) + Send + Sync>;
+
+enum Source {
+    Bytes(Vec<u8>),
+    File(File),
+}
+
+/// A file to upload with [`crate::ProntoClient::upload_attachment`]
+/// or send with [`crate::ProntoClient::send_attachments`].
+pub struct Attachment {
+    pub name: String,
+    pub mime_type: String,
+    pub size: u64,
+    source: Source,
+    progress: Option<Progress>,
+}
+
+impl Attachment {
+    /// The MIME type is guessed from the extension of `name`.
+    pub fn from_bytes(name: impl Into<String>, data: Vec<u8>) -> Self {
+        let name = name.into();
+        Self {
+            mime_type: guess_mime_type(&name),
+            size: data.len() as u64,
+            name,
+            source: Source::Bytes(data),
+            progress: None,
+        }
+    }
+
+    /// Open a file to stream it from disk, named and typed after its file name.
+    pub async fn from_path(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref();
+        let name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "not a file path"))?;
+        let file = File::open(path).await?;
+        Ok(Self {
+            mime_type: guess_mime_type(&name),
+            size: file.metadata().await?.len(),
+            name,
+            source: Source::File(file),
+            progress: None,
+        })
+    }
+
+    pub fn with_name(mut self, name: impl Into<String>) -> Self {
+        self.name = name.into();
+        self
+    }
+
+    pub fn with_mime_type(mut self, mime_type: impl Into<String>) -> Self {
+        self.mime_type = mime_type.into();
+        self
+    }
+
+    /// Call `progress` with the bytes sent so far and the total size as the upload goes.
+    pub fn on_progress(mut self, progress: impl Fn(u64, u64) + Send + Sync + 'static) -> Self {
+        self.progress = Some(Arc::new(progress));
+        self
+    }
+
+    /// `PHOTO`, `VIDEO` or `FILE`, how pronto shows the attachment
+    pub fn media_type(&self) -> &'static str {
+        media_type(&self.mime_type)
+    }
+
+    pub(crate) fn into_body(self) -> Body {
+        let chunks = match self.source {
+            // Without progress to report the body can be sent, and retried, in one piece
+            Source::Bytes(data) if self.progress.is_none() => return Body::from(data),
+            Source::Bytes(data) => stream::iter(
+                data.chunks(CHUNK_SIZE)
+                    .map(|chunk| Ok(chunk.to_vec()))
+                    .collect::<Vec<_>>(),
+            )
+            .boxed(),
+            Source::File(file) => stream::try_unfold(file, |mut file| async move {
+                let mut chunk = vec![0; CHUNK_SIZE];
+                let read = file.read(&mut chunk).await?;
+                if read == 0 {
+                    return Ok::<_, io::Error>(None);
+                }
+                chunk.truncate(read);
+                Ok(Some((chunk, file)))
+            })
+            .boxed(),
+        };
+        let Some(progress) = self.progress else {
+            return Body::wrap_stream(chunks);
+        };
+        let total = self.size;
+        let mut sent = 0;
+        Body::wrap_stream(chunks.inspect_ok(move |chunk| {
+            sent += chunk.len() as u64;
+            progress(sent, total);
+        }))
+    }
+}
+
+fn guess_mime_type(name: &str) -> String {
+    mime_guess::from_path(name)
+        .first_or_octet_stream()
+        .essence_str()
+        .to_string()
+}
+
+pub(crate) fn media_type(mime_type: &str) -> &'static str {
+    match mime_type.split_once('/').map(|(kind, _)| kind) {
+        Some("image") => "PHOTO",
+        Some("video") => "VIDEO",
+        _ => "FILE",
+    }
+}
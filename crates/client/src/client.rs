@@ -1,7 +1,9 @@
 pub mod announcement;
+pub mod attachment;
 pub mod auth;
 pub mod bubble;
 pub mod message;
+pub mod pagination;
 pub mod reaction;
 pub mod task;
 pub mod user;
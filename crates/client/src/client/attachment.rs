@@ -0,0 +1,99 @@
+use crate::attachment::media_type;
+use crate::files::PutFileResponse;
+use crate::files_normalized::GetFileNormalizedResponse;
+use crate::message_create::NewMessageMedia;
+use crate::{
+    Attachment, MessageModifyResponse, ProntoClient, ResponseError, files, files_normalized,
+    message_create,
+};
+use chrono::Utc;
+use uuid::Uuid;
+
+impl ProntoClient {
+    /// Upload a file, streaming it if it was opened from a path.
+    pub async fn upload_attachment(
+        &self,
+        attachment: Attachment,
+    ) -> Result<PutFileResponse, ResponseError> {
+        Ok(files::put(
+            &self.api_base_url,
+            &self.http_client,
+            files::PutFileRequest {
+                file_name: attachment.name.clone(),
+                content_type: Some(attachment.mime_type.clone()),
+                file_data: attachment.into_body(),
+            },
+        )
+        .await?
+        .to_result()?)
+    }
+
+    pub async fn normalize_file(
+        &self,
+        key: &str,
+        preset: &str,
+    ) -> Result<GetFileNormalizedResponse, ResponseError> {
+        Ok(
+            files_normalized::get(&self.api_base_url, &self.http_client, key, preset)
+                .await?
+                .to_result()?,
+        )
+    }
+
+    /// Upload files and send them as the media of one message, `message` can be empty.
+    /// Images are normalized first like the web client does.
+    pub async fn send_attachments(
+        &self,
+        user_id: u64,
+        bubble_id: u64,
+        message: String,
+        parent_message_id: Option<u64>,
+        attachments: Vec<Attachment>,
+    ) -> Result<MessageModifyResponse, ResponseError> {
+        let mut media = Vec::with_capacity(attachments.len());
+        for attachment in attachments {
+            let title = attachment.name.clone();
+            let uploaded = self.upload_attachment(attachment).await?.data;
+            let media_type = media_type(&uploaded.r#type);
+            media.push(if media_type == "PHOTO" {
+                let normalized = self
+                    .normalize_file(&uploaded.key, media_type)
+                    .await?
+                    .data
+                    .normalized;
+                NewMessageMedia {
+                    uuid: normalized.key,
+                    title,
+                    media_type: media_type.to_string(),
+                    mime_type: normalized.mime_type,
+                    file_size: normalized.file_size,
+                    width: Some(normalized.width),
+                    height: Some(normalized.height),
+                }
+            } else {
+                NewMessageMedia {
+                    uuid: uploaded.key,
+                    title,
+                    media_type: media_type.to_string(),
+                    mime_type: uploaded.r#type,
+                    file_size: uploaded.size,
+                    width: None,
+                    height: None,
+                }
+            });
+        }
+        Ok(message_create::post(
+            &self.api_base_url,
+            &self.http_client,
+            bubble_id,
+            message,
+            user_id,
+            Utc::now(),
+            parent_message_id,
+            &media,
+            &Uuid::new_v4().to_string(),
+        )
+        .await?
+        .to_result()?)
+    }
+}
@@ -1,9 +1,10 @@
 use crate::message_edit::MessageEditRequest;
 use crate::{
     MessageModifyResponse, ProntoClient, ResponseError, message_create, message_delete,
-    message_edit,
+    message_edit, message_search,
 };
 use chrono::Utc;
+use uuid::Uuid;
 
 impl ProntoClient {
     pub async fn send_message(
@@ -12,6 +13,21 @@ impl ProntoClient {
         bubble_id: u64,
         message: String,
         parent_message_id: Option<u64>,
+    ) -> Result<MessageModifyResponse, ResponseError> {
+        let uuid = Uuid::new_v4().to_string();
+        self.send_message_with_uuid(user_id, bubble_id, message, parent_message_id, &uuid)
+            .await
+    }
+
+    /// Send a message with a client generated `uuid`, pronto echoes it back on the message
+    /// so a retried send can be matched with what the server ended up creating.
+    pub async fn send_message_with_uuid(
+        &self,
+        user_id: u64,
+        bubble_id: u64,
+        message: String,
+        parent_message_id: Option<u64>,
+        uuid: &str,
     ) -> Result<MessageModifyResponse, ResponseError> {
         Ok(message_create::post(
             &self.api_base_url,
@@ -21,6 +37,8 @@ impl ProntoClient {
             user_id,
             Utc::now(),
             parent_message_id,
+            &[],
+            uuid,
         )
         .await?
         .to_result()?)
@@ -53,4 +71,15 @@ impl ProntoClient {
                 .to_result()?,
         )
     }
+
+    pub async fn message_search(
+        &self,
+        request: message_search::PostMessageSearchRequest,
+    ) -> Result<message_search::PostMessageSearchResponse, ResponseError> {
+        Ok(
+            message_search::post(&self.api_base_url, &self.http_client, request)
+                .await?
+                .to_result()?,
+        )
+    }
 }
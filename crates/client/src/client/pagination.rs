@@ -0,0 +1,109 @@
+use crate::message_search::{MessageSearchResult, PostMessageSearchRequest};
+use crate::user_search::GetUserSearchRequest;
+use crate::{
+    Member, Message, PostBubbleMembershipSearchRequest, ProntoClient, ResponseError, UserInfo,
+};
+use futures::{Stream, TryStreamExt, stream};
+
+/// Turn a page fetching function into a stream of items.
+/// `fetch` gets the cursor of a page and returns its items and the cursor of the next page,
+/// `None` once the last page was fetched.
+/// Pages are only requested when the previous one has been consumed.
+fn paginate<'a, C, T, F, Fut>(
+    first: C,
+    mut fetch: F,
+) -> impl Stream<Item = Result<T, ResponseError>> + 'a
+where
+    C: 'a,
+    T: 'a,
+    F: FnMut(C) -> Fut + 'a,
+    Fut: Future<Output = Result<(Vec<T>, Option<C>), ResponseError>> + 'a,
+{
+    stream::try_unfold(Some(first), move |cursor| {
+        let page = cursor.map(&mut fetch);
+        async move {
+            let Some(page) = page else {
+                return Ok::<_, ResponseError>(None);
+            };
+            let (items, next) = page.await?;
+            Ok(Some((stream::iter(items.into_iter().map(Ok)), next)))
+        }
+    })
+    .try_flatten()
+}
+
+impl ProntoClient {
+    /// Messages of a bubble from newest to oldest, without the parent messages.
+    /// Starts below `from` (or at the newest message) and stops once `to` is reached,
+    /// both exclusive.
+    pub fn bubble_history_stream(
+        &self,
+        bubble_id: u64,
+        from: Option<u64>,
+        to: Option<u64>,
+    ) -> impl Stream<Item = Result<Message, ResponseError>> + '_ {
+        paginate(from, move |latest| async move {
+            let mut messages = self.bubble_history(bubble_id, latest).await?.messages;
+            let oldest = messages.iter().map(|m| m.id).min();
+            let reached_end = to.is_some_and(|to| messages.iter().any(|m| m.id <= to));
+            messages.retain(|m| to.is_none_or(|to| m.id > to));
+            // Also stop if the server didn't go any further back, rather than looping forever
+            let next = oldest
+                .filter(|oldest| !reached_end && latest.is_none_or(|latest| *oldest < latest))
+                .map(Some);
+            Ok((messages, next))
+        })
+    }
+
+    /// All results of a message search, following `from` from the first page on.
+    pub fn message_search_stream(
+        &self,
+        request: PostMessageSearchRequest,
+    ) -> impl Stream<Item = Result<MessageSearchResult, ResponseError>> + '_ {
+        paginate(request, move |request| async move {
+            let response = self.message_search(request.clone()).await?;
+            let from = request.from + response.results.len() as u64;
+            let next = (!response.results.is_empty()
+                && response.total_results.is_none_or(|total| from < total))
+            .then_some(PostMessageSearchRequest { from, ..request });
+            Ok((response.results, next))
+        })
+    }
+
+    /// All users matching a search, following the cursors from the first page on.
+    pub fn user_search_stream(
+        &self,
+        request: GetUserSearchRequest,
+    ) -> impl Stream<Item = Result<UserInfo, ResponseError>> + '_ {
+        paginate(request, move |request| async move {
+            let response = self.user_search(request.clone()).await?;
+            let next = response
+                .cursors
+                .next
+                .filter(|_| !response.data.is_empty())
+                .map(|cursor| GetUserSearchRequest {
+                    cursor: Some(cursor),
+                    ..request
+                });
+            Ok((response.data, next))
+        })
+    }
+
+    /// All members of a bubble, following the pages from `request.page` on.
+    pub fn bubble_membership_stream(
+        &self,
+        request: PostBubbleMembershipSearchRequest,
+    ) -> impl Stream<Item = Result<Member, ResponseError>> + '_ {
+        paginate(request, move |request| async move {
+            let response = self.bubble_membership(request.clone()).await?;
+            // A short page is the last one
+            let next = (!response.membership.is_empty()
+                && response.membership.len() as u64 >= response.page_size)
+                .then(|| PostBubbleMembershipSearchRequest {
+                    page: request.page + 1,
+                    ..request
+                });
+            Ok((response.membership, next))
+        })
+    }
+}
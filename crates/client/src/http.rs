@@ -0,0 +1,276 @@
+use log::warn;
+use rand::Rng;
+use reqwest::header::{HeaderName, HeaderValue, RETRY_AFTER};
+use reqwest::{Body, IntoUrl, Method, Response, StatusCode};
+use serde::Serialize;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::{Instant, sleep, sleep_until};
+
+/// How requests made through a [`crate::ProntoClient`] are timed out, retried and rate limited.
+#[derive(Clone, Debug)]
+pub struct RequestPolicy {
+    /// Timeout of a single attempt, `None` waits forever
+    pub timeout: Option<Duration>,
+    /// How many times a failed request is retried.
+    /// Only idempotent requests are retried after a server error or a timeout,
+    /// anything is retried after a 429 or when the connection could not be made.
+    pub max_retries: u32,
+    /// First retry delay, doubled on every further attempt
+    pub backoff_base: Duration,
+    /// Longest delay between attempts. A `Retry-After` asking for longer isn't waited for,
+    /// the response is returned instead.
+    pub backoff_max: Duration,
+    /// Client side limit so we get throttled less in the first place
+    pub rate_limit: Option<RateLimit>,
+}
+
+impl Default for RequestPolicy {
+    fn default() -> Self {
+        Self {
+            timeout: Some(Duration::from_secs(30)),
+            max_retries: 3,
+            backoff_base: Duration::from_millis(500),
+            backoff_max: Duration::from_secs(30),
+            rate_limit: None,
+        }
+    }
+}
+
+/// A token bucket refilled at `per_second` tokens a second, holding at most `burst` tokens.
+#[derive(Copy, Clone, Debug)]
+pub struct RateLimit {
+    pub per_second: f64,
+    pub burst: u32,
+}
+
+impl RateLimit {
+    /// Whether requests get through at all, at a rate a [`Duration`] can hold
+    pub fn is_valid(&self) -> bool {
+        self.per_second.is_finite() && self.per_second > 0.0 && self.burst >= 1
+    }
+}
+
+impl RequestPolicy {
+    /// Exponential backoff with jitter
+    fn backoff(&self, attempt: u32) -> Duration {
+        let delay = self
+            .backoff_base
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(self.backoff_max);
+        let half = delay / 2;
+        half + rand::thread_rng().gen_range(Duration::ZERO..=half)
+    }
+}
+
+struct Limiter {
+    /// Available tokens and when they were last refilled
+    bucket: Option<(RateLimit, Mutex<(f64, Instant)>)>,
+    /// Set from `Retry-After`, every request waits until then
+    throttled_until: Mutex<Option<Instant>>,
+}
+
+impl Limiter {
+    fn new(rate_limit: Option<RateLimit>) -> Self {
+        Self {
+            bucket: rate_limit
+                .map(|limit| (limit, Mutex::new((limit.burst as f64, Instant::now())))),
+            throttled_until: Mutex::new(None),
+        }
+    }
+
+    async fn acquire(&self) {
+        let throttled_until = *self.throttled_until.lock().unwrap();
+        if let Some(until) = throttled_until {
+            sleep_until(until).await;
+        }
+        let Some((limit, bucket)) = &self.bucket else {
+            return;
+        };
+        loop {
+            let wait = {
+                let mut bucket = bucket.lock().unwrap();
+                let (tokens, refilled) = &mut *bucket;
+                let now = Instant::now();
+                *tokens = (*tokens + (now - *refilled).as_secs_f64() * limit.per_second)
+                    .min(limit.burst as f64);
+                *refilled = now;
+                if *tokens >= 1.0 {
+                    *tokens -= 1.0;
+                    return;
+                }
+                Duration::from_secs_f64((1.0 - *tokens) / limit.per_second)
+            };
+            sleep(wait).await;
+        }
+    }
+
+    fn throttle(&self, delay: Duration) {
+        let until = Instant::now() + delay;
+        let mut throttled_until = self.throttled_until.lock().unwrap();
+        if throttled_until.is_none_or(|current| current < until) {
+            *throttled_until = Some(until);
+        }
+    }
+}
+
+/// Wraps the reqwest client so every route, generated or not, goes through the [`RequestPolicy`].
+#[derive(Clone)]
+pub struct HttpClient {
+    inner: reqwest::Client,
+    policy: Arc<RequestPolicy>,
+    limiter: Arc<Limiter>,
+}
+
+impl HttpClient {
+    pub(crate) fn new(inner: reqwest::Client, policy: RequestPolicy) -> Self {
+        Self {
+            inner,
+            limiter: Arc::new(Limiter::new(policy.rate_limit)),
+            policy: Arc::new(policy),
+        }
+    }
+
+    pub fn policy(&self) -> &RequestPolicy {
+        &self.policy
+    }
+
+    pub fn request(&self, method: Method, url: impl IntoUrl) -> RequestBuilder {
+        RequestBuilder {
+            client: self.clone(),
+            inner: self.inner.request(method, url),
+        }
+    }
+
+    pub fn get(&self, url: impl IntoUrl) -> RequestBuilder {
+        self.request(Method::GET, url)
+    }
+
+    pub fn post(&self, url: impl IntoUrl) -> RequestBuilder {
+        self.request(Method::POST, url)
+    }
+
+    pub fn put(&self, url: impl IntoUrl) -> RequestBuilder {
+        self.request(Method::PUT, url)
+    }
+
+    pub fn patch(&self, url: impl IntoUrl) -> RequestBuilder {
+        self.request(Method::PATCH, url)
+    }
+
+    pub fn delete(&self, url: impl IntoUrl) -> RequestBuilder {
+        self.request(Method::DELETE, url)
+    }
+}
+
+/// The subset of [`reqwest::RequestBuilder`] the routes need.
+pub struct RequestBuilder {
+    client: HttpClient,
+    inner: reqwest::RequestBuilder,
+}
+
+impl RequestBuilder {
+    pub fn query<T: Serialize + ?Sized>(self, query: &T) -> Self {
+        Self {
+            inner: self.inner.query(query),
+            ..self
+        }
+    }
+
+    pub fn header(self, key: HeaderName, value: HeaderValue) -> Self {
+        Self {
+            inner: self.inner.header(key, value),
+            ..self
+        }
+    }
+
+    pub fn json<T: Serialize + ?Sized>(self, json: &T) -> Self {
+        Self {
+            inner: self.inner.json(json),
+            ..self
+        }
+    }
+
+    pub fn body(self, body: impl Into<Body>) -> Self {
+        Self {
+            inner: self.inner.body(body),
+            ..self
+        }
+    }
+
+    /// Send the request, retrying it as the policy allows.
+    /// Once out of retries the last response is returned as is, even if it is an error status.
+    pub async fn send(self) -> Result<Response, reqwest::Error> {
+        let policy = &self.client.policy;
+        let limiter = &self.client.limiter;
+        let mut request = self.inner.build()?;
+        if request.timeout().is_none() {
+            *request.timeout_mut() = policy.timeout;
+        }
+        let idempotent = matches!(
+            *request.method(),
+            Method::GET | Method::HEAD | Method::PUT | Method::DELETE | Method::OPTIONS
+        );
+        let mut attempt = 0;
+        loop {
+            // Streaming bodies can't be cloned, those requests are sent once
+            let retry = request.try_clone().filter(|_| attempt < policy.max_retries);
+            let url = request.url().clone();
+            limiter.acquire().await;
+            let result = self.client.inner.execute(request).await;
+            let delay = match &result {
+                Ok(response) if response.status() == StatusCode::TOO_MANY_REQUESTS => {
+                    let delay = retry_after(response, policy)
+                        .unwrap_or_else(|| Some(policy.backoff(attempt)));
+                    if let Some(delay) = delay {
+                        limiter.throttle(delay);
+                    }
+                    delay
+                }
+                Ok(response) if idempotent && response.status().is_server_error() => {
+                    retry_after(response, policy).unwrap_or_else(|| Some(policy.backoff(attempt)))
+                }
+                Ok(_) => None,
+                // The request never left, so it can't have been applied
+                Err(e) if e.is_connect() => Some(policy.backoff(attempt)),
+                Err(e) if idempotent && (e.is_timeout() || e.is_request()) => {
+                    Some(policy.backoff(attempt))
+                }
+                Err(_) => None,
+            };
+            let (Some(delay), Some(next)) = (delay, retry) else {
+                return result;
+            };
+            match &result {
+                Ok(response) => warn!(
+                    "Request to {url} failed with {}, retrying in {} ms",
+                    response.status(),
+                    delay.as_millis()
+                ),
+                Err(e) => warn!(
+                    "Request to {url} failed: {e}, retrying in {} ms",
+                    delay.as_millis()
+                ),
+            }
+            sleep(delay).await;
+            attempt += 1;
+            request = next;
+        }
+    }
+}
+
+/// The `Retry-After` delay, `Some(None)` if it's longer than [`RequestPolicy::backoff_max`]
+/// and not worth waiting for.
+/// Only the delay in seconds form is supported, dates fall back to the backoff.
+fn retry_after(response: &Response, policy: &RequestPolicy) -> Option<Option<Duration>> {
+    response
+        .headers()
+        .get(RETRY_AFTER)?
+        .to_str()
+        .ok()?
+        .trim()
+        .parse()
+        .ok()
+        .map(Duration::from_secs)
+        .map(|delay| Some(delay).filter(|delay| *delay <= policy.backoff_max))
+}
@@ -1,7 +1,9 @@
 extern crate alloc;
 
 use std::sync::Arc;
+use std::time::Duration;
 
+use reqwest::StatusCode;
 use reqwest::header::{HeaderMap, HeaderValue};
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
@@ -12,13 +14,17 @@ pub use crate::membership_update::{
     MembershipUpdateModification, NotificationsPreference, PostMembershipUpdateRequest,
 };
 pub use crate::message_create::MessageModifyResponse;
-pub use api_error::APIError;
+pub use api_error::{APIError, ErrorKind};
+pub use attachment::Attachment;
+pub use http::{HttpClient, RateLimit, RequestBuilder, RequestPolicy};
 pub use models::*;
 pub use routes::*;
 
 pub mod api_error;
+mod attachment;
 mod client;
 pub(crate) mod custom_json;
+mod http;
 pub mod models;
 pub mod routes;
 pub(crate) mod serde_datetime;
@@ -84,7 +90,7 @@ impl From<i32> for ReactionType {
 #[derive(Clone)]
 pub struct ProntoClient {
     pub api_base_url: String,
-    pub http_client: reqwest::Client,
+    pub http_client: HttpClient,
 }
 
 #[derive(Debug, Error)]
@@ -95,6 +101,9 @@ pub enum NewClientError {
     HeaderParseError(#[from] reqwest::header::InvalidHeaderValue),
     #[error("Url parse error: {0}")]
     UrlParseError(#[from] url::ParseError),
+    /// A rate limit that would panic or never let a request through
+    #[error("Invalid rate limit: {0:?}")]
+    InvalidRateLimit(RateLimit),
 }
 
 #[derive(Debug, Error)]
@@ -107,24 +116,61 @@ pub enum ResponseError {
     DetailedSerdeJsonError(#[from] serde_path_to_error::Error<serde_json::Error>),
     #[error("Not JSON error: {0}")]
     NotJson(String),
+    /// An error status without a pronto error in the body
+    #[error("HTTP error {status}: {text}")]
+    HttpError { status: StatusCode, text: String },
     #[error("API error: {0}")]
-    ApiError(String),
+    ApiError(APIError),
 }
 
-impl From<APIError> for ResponseError {
-    fn from(e: APIError) -> Self {
-        ResponseError::ApiError(e.error)
-    }
+pub struct ProntoClientBuilder {
+    api_base_url: String,
+    pronto_api_token: String,
+    policy: RequestPolicy,
 }
 
-impl ProntoClient {
-    /// Create a new ProntoClient with the base url and api token.
-    pub fn new(api_base_url: String, pronto_api_token: &str) -> Result<Self, NewClientError> {
+impl ProntoClientBuilder {
+    pub fn policy(mut self, policy: RequestPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Timeout of a single attempt, `None` waits forever.
+    pub fn timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.policy.timeout = timeout;
+        self
+    }
+
+    pub fn max_retries(mut self, max_retries: u32) -> Self {
+        self.policy.max_retries = max_retries;
+        self
+    }
+
+    /// Delay before the first retry, doubling up to `max` on every further attempt.
+    pub fn backoff(mut self, base: Duration, max: Duration) -> Self {
+        self.policy.backoff_base = base;
+        self.policy.backoff_max = max;
+        self
+    }
+
+    /// Limit requests to `per_second` on average, allowing bursts of up to `burst` requests.
+    /// `per_second` has to be positive and finite and `burst` at least 1, or [`Self::build`] fails.
+    pub fn rate_limit(mut self, per_second: f64, burst: u32) -> Self {
+        self.policy.rate_limit = Some(RateLimit { per_second, burst });
+        self
+    }
+
+    pub fn build(self) -> Result<ProntoClient, NewClientError> {
+        if let Some(limit) = self.policy.rate_limit
+            && !limit.is_valid()
+        {
+            return Err(NewClientError::InvalidRateLimit(limit));
+        }
         // create the cookie store
-        let cookies = vec![format!("api_token={}", pronto_api_token)];
+        let cookies = vec![format!("api_token={}", self.pronto_api_token)];
         let jar = reqwest::cookie::Jar::default();
         for cookie in cookies {
-            jar.add_cookie_str(&cookie, &reqwest::Url::parse(&api_base_url)?);
+            jar.add_cookie_str(&cookie, &reqwest::Url::parse(&self.api_base_url)?);
         }
 
         let mut headers = HeaderMap::new();
@@ -135,7 +181,7 @@ impl ProntoClient {
         headers.insert("Accept-Language", HeaderValue::from_str("en-US,en;q=0.5")?);
         headers.insert(
             "Authorization",
-            HeaderValue::from_str(&format!("Bearer {pronto_api_token}"))?,
+            HeaderValue::from_str(&format!("Bearer {}", self.pronto_api_token))?,
         );
         let client = reqwest::Client::builder()
             .cookie_store(true)
@@ -143,11 +189,33 @@ impl ProntoClient {
             .default_headers(headers)
             .brotli(true)
             .build()?;
-        Ok(Self {
-            api_base_url,
-            http_client: client,
+        Ok(ProntoClient {
+            api_base_url: self.api_base_url,
+            http_client: HttpClient::new(client, self.policy),
         })
     }
+}
+
+impl From<APIError> for ResponseError {
+    fn from(e: APIError) -> Self {
+        ResponseError::ApiError(e)
+    }
+}
+
+impl ProntoClient {
+    /// Create a new ProntoClient with the base url and api token, using the default [`RequestPolicy`].
+    pub fn new(api_base_url: String, pronto_api_token: &str) -> Result<Self, NewClientError> {
+        Self::builder(api_base_url, pronto_api_token).build()
+    }
+
+    /// Start building a client to tune its [`RequestPolicy`].
+    pub fn builder(api_base_url: String, pronto_api_token: &str) -> ProntoClientBuilder {
+        ProntoClientBuilder {
+            api_base_url,
+            pronto_api_token: pronto_api_token.to_string(),
+            policy: RequestPolicy::default(),
+        }
+    }
 
     pub async fn upload_file(
         &self,
@@ -159,7 +227,8 @@ impl ProntoClient {
             &self.http_client,
             files::PutFileRequest {
                 file_name: filename.to_string(),
-                file_data: file,
+                content_type: None,
+                file_data: file.into(),
             },
         )
         .await?
@@ -167,12 +236,6 @@ impl ProntoClient {
     }
 }
 
-// TODO: All of this
-// GET https://stanfordohs.pronto.io/api/clients/files/0a43fa48-403c-4a4e-8af5-ca0c01bab35c/normalized?preset=PHOTO
-// Request = None
-// Response = {"data":{"original":{"mimetype":"image\/png","key":"0a43fa48-403c-4a4e-8af5-ca0c01bab35c","name":"image.png","width":1002,"height":832,"filesize":74720},"normalized":{"mimetype":"image\/png","key":"e6e3084c-7222-4241-85a6-1ee11d584a39","name":"image.png","width":1002,"height":832,"filesize":52600},"is_animated":false}}
-// When sending message, see {"ok":true,"message":{"id":89171261,"bubble_id":3738656,"user_id":5302428,"message":"","resource_id":null,"clickcount":0,"likecount":0,"dislikecount":0,"viewcount":0,"version":0,"user_edited_version":0,"user_edited_at":null,"created_at":"2024-09-17 15:45:15","updated_at":"2024-09-17 15:45:15","livestream_id":null,"videosession_id":null,"systemmessageparts":null,"uuid":"e0703e87-c181-4f2e-858c-a679e16ebdf9","task_id":null,"parentmessage_id":null,"firstchildmessage_id":null,"lastchildmessage_id":null,"systemevent":null,"reactionsummary":[],"lang":null,"videosession":null,"user":{"id":5302428,"firstname":"Ashwin","lastname":"Naren","username":null,"locale":"","lastseen":"2024-09-17 15:45:15","profilepic":true,"status":0,"created_at":"2023-08-04 00:44:12","updated_at":"2024-09-17 15:44:47","deactivated_at":null,"email_verified_at":"2024-09-15 23:34:54","phone_verified_at":null,"isverified":false,"dropinorder":0,"maxstreams":10,"autotranslate":false,"isonline":true,"lastpresencetime":"2024-09-17 15:44:06","acceptedtos":"2024-09-15 23:34:54","sentwelcomemsg":"2023-08-15 19:22:02","role":"user","mute":false,"muteuntil":null,"isbot":0,"fullname":"Ashwin Naren","hasactivity":true,"inactive":false,"language":"en","permissions":{"change_name":"system","change_email":"system","change_phone":"system","remove_user":"system","change_title":"admin","change_pronouns":"admin","change_own_name":false,"change_own_email":false,"change_own_phone":false,"change_own_title":true,"change_own_pronouns":true},"profilepicpath":"\/files\/users\/5302428\/profilepic?pronto_time=1695523284","profilepicurl":"https:\/\/files.chat.trypronto.com\/files\/users\/5302428\/profilepic?pronto_time=1695523284"},"mentions":[],"messagemedia":[{"message_id":89171261,"title":"image.png","url":"https:\/\/files.chat.trypronto.com\/files\/media\/3738656\/d56f7980-750b-11ef-9a28-6f7e119ffd69","uuid":"e6e3084c-7222-4241-85a6-1ee11d584a39","width":1002,"height":832,"filesize":52600,"duration":null,"updated_at":"2024-09-17 15:45:16","created_at":"2024-09-17 15:45:16","id":7081542,"mediatype":"PHOTO","urlmimetype":"image\/png","thumbnailmimetype":null,"path":"\/files\/media\/3738656\/d56f7980-750b-11ef-9a28-6f7e119ffd69","thumbnailpath":null}]}}
-
 // TODO: Important: we need more data before implementation ...
 // POST /api/v1/bubble.invite
 // Request = {"bubble_id":3844880,"invitations":[{"user_id":5302428}],"sendemails":false,"sendsms":false}
@@ -202,7 +265,7 @@ mod tests {
             .expect("Failed to load settings");
         let client = ProntoClient::new(
             "https://stanfordohs.pronto.io/api/".to_string(),
-            &settings.auth.expect("Failed to get auth section of settings, open prontus and authenticate before running these tests").api_key,
+            &settings.auth().expect("Failed to get auth section of settings, open prontus and authenticate before running these tests").api_key,
         );
         client.expect("Failed to create client")
     }
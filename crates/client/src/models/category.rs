@@ -14,6 +14,7 @@ pub struct UserCategory {
 pub struct Category {
     pub id: u64,
     pub title: String,
+    #[serde(alias = "sortorder")]
     pub sort_order: Option<u32>,
     #[serde(rename = "usercategory")]
     pub user_category: Option<UserCategory>,
@@ -68,6 +68,9 @@ pub struct Message {
     pub message_media: Vec<MessageMedia>,
     #[serde(default)]
     pub resource: Option<MessageResource>,
+    /// The client generated id the message was created with
+    #[serde(default)]
+    pub uuid: Option<String>,
     #[serde(with = "serde_datetime")]
     pub created_at: NaiveDateTime,
     // #[serde(default, with = "serde_datetime")]
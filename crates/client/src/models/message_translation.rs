@@ -0,0 +1,12 @@
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct MessageTranslation {
+    pub id: u64,
+    pub message_id: u64,
+    pub translation: String,
+    pub lang: String,
+    pub user_edited_version: u64,
+    pub created_at: String,
+    pub updated_at: String,
+}
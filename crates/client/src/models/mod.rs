@@ -7,6 +7,7 @@ mod member;
 mod membership;
 mod membership_info;
 mod message;
+mod message_translation;
 mod organization;
 mod property;
 mod task;
@@ -22,6 +23,7 @@ pub use member::Member;
 pub use membership::Membership;
 pub use membership_info::MembershipInfo;
 pub use message::{Message, MessageMedia, MessageResource, Reactions};
+pub use message_translation::MessageTranslation;
 pub use organization::Organization;
 pub use property::Property;
 pub use task::Task;
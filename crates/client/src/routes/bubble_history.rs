@@ -1,5 +1,5 @@
+use crate::HttpClient;
 use crate::Message;
-use reqwest::Client;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 
@@ -16,7 +16,7 @@ pub type GetBubbleHistoryResult = crate::APIResult<GetBubbleHistoryResponse>;
 
 pub async fn get(
     pronto_base_url: &str,
-    client: &Client,
+    client: &HttpClient,
     bubble_id: u64,
     latest_message_id: Option<u64>,
 ) -> Result<GetBubbleHistoryResult, crate::ResponseError> {
@@ -35,20 +35,9 @@ pub async fn get(
     .await?;
     let elapsed = initial_time.elapsed();
     log::debug!(target : "request_perf" , "Network: {} ms" , elapsed . as_millis ());
+    let status = r.status();
     let text = r.text().await?;
-    log::trace!("Response: {}", text);
-    let json = serde_json::from_str(&text);
-    match json {
-        Ok(json) => Ok(json),
-        Err(_e) => {
-            let json = serde_json::from_str::<GetBubbleHistoryResponse>(&text);
-            let e = json.unwrap_err();
-            log::error!("Error parsing json response: {:?}.", e);
-            let json = serde_json::from_str::<serde_json::Value>(&text);
-            if json.is_err() {
-                return Err(crate::ResponseError::NotJson(text));
-            }
-            Err(crate::ResponseError::from(e))
-        }
-    }
+    crate::api_error::parse_response::<GetBubbleHistoryResult, GetBubbleHistoryResponse>(
+        status, text,
+    )
 }
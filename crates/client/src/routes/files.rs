@@ -1,14 +1,17 @@
-// TODO: Test all this
+use crate::HttpClient;
 // PUT https://stanfordohs.pronto.io/api/files?filename=image.png
 // Request = [[ the image ]]
 // Response = {"data":{"key":"0a43fa48-403c-4a4e-8af5-ca0c01bab35c","expires":"2024-09-18T15:44:32Z","name":"image.png","size":74720,"type":"image/png"}}
 
-use reqwest::Client;
+use reqwest::Body;
+use reqwest::header::{CONTENT_TYPE, HeaderValue};
 use serde::{Deserialize, Serialize};
 
 pub struct PutFileRequest {
     pub file_name: String,
-    pub file_data: Vec<u8>,
+    /// Sent as the `Content-Type`, pronto guesses from the file name otherwise
+    pub content_type: Option<String>,
+    pub file_data: Body,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -29,31 +32,20 @@ pub type PutFileResult = crate::APIResult<PutFileResponse>;
 
 pub async fn put(
     pronto_base_url: &str,
-    client: &Client,
+    client: &HttpClient,
     request: PutFileRequest,
 ) -> Result<PutFileResult, crate::ResponseError> {
-    let r = client
-        .put(&format!(
-            "{}/api/files?filename={}",
-            pronto_base_url, request.file_name
-        ))
-        .body(request.file_data)
-        .send()
-        .await?;
-    let text = r.text().await?;
-    log::trace!("Response: {}", text);
-    let json = serde_json::from_str(&text);
-    match json {
-        Ok(json) => Ok(json),
-        Err(_e) => {
-            let json = serde_json::from_str::<PutFileResponse>(&text);
-            let e = json.unwrap_err();
-            log::error!("Error parsing json response: {:?}.", e);
-            let json = serde_json::from_str::<serde_json::Value>(&text);
-            if json.is_err() {
-                return Err(crate::ResponseError::NotJson(text));
-            }
-            Err(crate::ResponseError::from(e))
-        }
+    let mut r = client
+        .put(format!("{pronto_base_url}files"))
+        .query(&[("filename", &request.file_name)]);
+    if let Some(content_type) = request
+        .content_type
+        .and_then(|content_type| HeaderValue::from_str(&content_type).ok())
+    {
+        r = r.header(CONTENT_TYPE, content_type);
     }
+    let r = r.body(request.file_data).send().await?;
+    let status = r.status();
+    let text = r.text().await?;
+    crate::api_error::parse_response::<PutFileResult, PutFileResponse>(status, text)
 }
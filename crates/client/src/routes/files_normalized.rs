@@ -0,0 +1,52 @@
+use crate::HttpClient;
+use serde::{Deserialize, Serialize};
+
+// GET https://stanfordohs.pronto.io/api/clients/files/0a43fa48-403c-4a4e-8af5-ca0c01bab35c/normalized?preset=PHOTO
+// Request = None
+// Response = {"data":{"original":{"mimetype":"image\/png","key":"0a43fa48-403c-4a4e-8af5-ca0c01bab35c","name":"image.png","width":1002,"height":832,"filesize":74720},"normalized":{"mimetype":"image\/png","key":"e6e3084c-7222-4241-85a6-1ee11d584a39","name":"image.png","width":1002,"height":832,"filesize":52600},"is_animated":false}}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct NormalizedFile {
+    #[serde(rename = "mimetype")]
+    pub mime_type: String,
+    pub key: String,
+    pub name: String,
+    pub width: u64,
+    pub height: u64,
+    #[serde(rename = "filesize")]
+    pub file_size: u64,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct GetFileNormalizedResponseData {
+    pub original: NormalizedFile,
+    /// The copy pronto serves, its key is the one messages reference
+    pub normalized: NormalizedFile,
+    pub is_animated: bool,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct GetFileNormalizedResponse {
+    pub data: GetFileNormalizedResponseData,
+}
+
+pub type GetFileNormalizedResult = crate::APIResult<GetFileNormalizedResponse>;
+
+/// Have pronto process an uploaded image, `preset` is `PHOTO` for images sent in messages.
+pub async fn get(
+    pronto_base_url: &str,
+    client: &HttpClient,
+    key: &str,
+    preset: &str,
+) -> Result<GetFileNormalizedResult, crate::ResponseError> {
+    let r = client
+        .get(format!("{pronto_base_url}clients/files/{key}/normalized"))
+        .query(&[("preset", preset)])
+        .send()
+        .await?;
+    let status = r.status();
+    let text = r.text().await?;
+    crate::api_error::parse_response::<GetFileNormalizedResult, GetFileNormalizedResponse>(
+        status, text,
+    )
+}
@@ -1,7 +1,10 @@
+// POST https://stanfordohs.pronto.io/api/v1/message.create
+// Sending an uploaded image, the media uuid is the normalized key from files_normalized
+// Response = {"ok":true,"message":{"id":89171261,"bubble_id":3738656,"user_id":5302428,"message":"","resource_id":null,"clickcount":0,"likecount":0,"dislikecount":0,"viewcount":0,"version":0,"user_edited_version":0,"user_edited_at":null,"created_at":"2024-09-17 15:45:15","updated_at":"2024-09-17 15:45:15","livestream_id":null,"videosession_id":null,"systemmessageparts":null,"uuid":"e0703e87-c181-4f2e-858c-a679e16ebdf9","task_id":null,"parentmessage_id":null,"firstchildmessage_id":null,"lastchildmessage_id":null,"systemevent":null,"reactionsummary":[],"lang":null,"videosession":null,"user":{"id":5302428,"firstname":"Ashwin","lastname":"Naren","username":null,"locale":"","lastseen":"2024-09-17 15:45:15","profilepic":true,"status":0,"created_at":"2023-08-04 00:44:12","updated_at":"2024-09-17 15:44:47","deactivated_at":null,"email_verified_at":"2024-09-15 23:34:54","phone_verified_at":null,"isverified":false,"dropinorder":0,"maxstreams":10,"autotranslate":false,"isonline":true,"lastpresencetime":"2024-09-17 15:44:06","acceptedtos":"2024-09-15 23:34:54","sentwelcomemsg":"2023-08-15 19:22:02","role":"user","mute":false,"muteuntil":null,"isbot":0,"fullname":"Ashwin Naren","hasactivity":true,"inactive":false,"language":"en","permissions":{"change_name":"system","change_email":"system","change_phone":"system","remove_user":"system","change_title":"admin","change_pronouns":"admin","change_own_name":false,"change_own_email":false,"change_own_phone":false,"change_own_title":true,"change_own_pronouns":true},"profilepicpath":"\/files\/users\/5302428\/profilepic?pronto_time=1695523284","profilepicurl":"https:\/\/files.chat.trypronto.com\/files\/users\/5302428\/profilepic?pronto_time=1695523284"},"mentions":[],"messagemedia":[{"message_id":89171261,"title":"image.png","url":"https:\/\/files.chat.trypronto.com\/files\/media\/3738656\/d56f7980-750b-11ef-9a28-6f7e119ffd69","uuid":"e6e3084c-7222-4241-85a6-1ee11d584a39","width":1002,"height":832,"filesize":52600,"duration":null,"updated_at":"2024-09-17 15:45:16","created_at":"2024-09-17 15:45:16","id":7081542,"mediatype":"PHOTO","urlmimetype":"image\/png","thumbnailmimetype":null,"path":"\/files\/media\/3738656\/d56f7980-750b-11ef-9a28-6f7e119ffd69","thumbnailpath":null}]}}
+use crate::HttpClient;
 use crate::custom_json::ToJson;
 use crate::models::Message;
 use chrono::{DateTime, Utc};
-use reqwest::Client;
 use serde::{Deserialize, Serialize, Serializer};
 use serde_json::{Value, json};
 use uuid::Uuid;
@@ -61,48 +64,57 @@ impl Serialize for MessageModifyRequest {
 
 pub type MessageModifyResult = crate::APIResult<MessageModifyResponse>;
 
+/// A file uploaded with [`crate::files`] to attach to a new message
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct NewMessageMedia {
+    /// The key of the upload, or of its normalized copy for images
+    pub uuid: String,
+    pub title: String,
+    /// `PHOTO`, `VIDEO` or `FILE`
+    #[serde(rename = "mediatype")]
+    pub media_type: String,
+    #[serde(rename = "urlmimetype")]
+    pub mime_type: String,
+    #[serde(rename = "filesize")]
+    pub file_size: u64,
+    pub width: Option<u64>,
+    pub height: Option<u64>,
+}
+
+#[allow(clippy::too_many_arguments)]
 pub async fn post(
     pronto_base_url: &str,
-    client: &Client,
+    client: &HttpClient,
     channel_id: u64,
     message: String,
     user_id: u64,
     time: DateTime<Utc>,
     parent: Option<u64>,
-) -> Result<MessageModifyResult, reqwest::Error> {
-    let uuid = Uuid::new_v4().to_string();
+    media: &[NewMessageMedia],
+    uuid: &str,
+) -> Result<MessageModifyResult, crate::ResponseError> {
     let time_string = time.format("%Y-%m-%d %H:%M:%S").to_string();
-    let request = if parent.is_some() {
-        client
-            .post(format!("{pronto_base_url}v1/message.create"))
-            .json(&json!(
-                {
-                    "bubble_id": channel_id,
-                    "created_at": time_string,
-                    "message": message,
-                    "id": Value::Null,
-                    "sendState": "sending",
-                    "user_id": user_id,
-                    "uuid": uuid,
-                    "parentmessage_id": parent
-                }
-            ))
-    } else {
-        client
-            .post("https://stanfordohs.pronto.io/api/v1/message.create")
-            .json(&json!(
-            {
-                "bubble_id": channel_id,
-                "created_at": time_string,
-                "message": message,
-                "id": Value::Null,
-                "sendState": "sending",
-                "user_id": user_id,
-                "uuid": uuid
-            }
-                ))
-    };
-    let response = request.send().await?;
-    let response: MessageModifyResult = response.json().await?;
-    Ok(response)
+    let mut body = json!({
+        "bubble_id": channel_id,
+        "created_at": time_string,
+        "message": message,
+        "id": Value::Null,
+        "sendState": "sending",
+        "user_id": user_id,
+        "uuid": uuid
+    });
+    if parent.is_some() {
+        body["parentmessage_id"] = json!(parent);
+    }
+    if !media.is_empty() {
+        body["messagemedia"] = json!(media);
+    }
+    let r = client
+        .post(format!("{pronto_base_url}v1/message.create"))
+        .json(&body)
+        .send()
+        .await?;
+    let status = r.status();
+    let text = r.text().await?;
+    crate::api_error::parse_response::<MessageModifyResult, MessageModifyResponse>(status, text)
 }
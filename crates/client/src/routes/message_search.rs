@@ -1,4 +1,4 @@
-use crate::Member;
+use crate::Message;
 use serde::{Deserialize, Serialize};
 
 // GET /api/v1/message.search
@@ -34,13 +34,38 @@ impl Default for PostMessageSearchRequest {
     }
 }
 
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct MessageSearchResult {
+    pub message_id: u64,
+    pub message: Message,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct MessageSearchBubble {
+    pub bubble_id: u64,
+    #[serde(rename = "ishidden", default)]
+    pub is_hidden: bool,
+    #[serde(rename = "isdm", default)]
+    pub is_dm: bool,
+    #[serde(default)]
+    pub title: String,
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct PostMessageSearchResponse {
     pub ok: bool,
-    #[serde(rename = "pagesize")]
+    #[serde(default)]
+    pub current_page_offset: u64,
+    #[serde(rename = "pagesize", default)]
     pub page_size: u64,
-    #[serde(rename = "memberships")]
-    pub membership: Vec<Member>,
+    #[serde(default)]
+    pub next_page_offset: u64,
+    /// Not always sent, without it pages are followed until one comes back empty
+    #[serde(default)]
+    pub total_results: Option<u64>,
+    pub results: Vec<MessageSearchResult>,
+    #[serde(default)]
+    pub bubbles: Vec<MessageSearchBubble>,
 }
 
 pub type PostMessageSearchResult = crate::APIResult<PostMessageSearchResponse>;
@@ -51,3 +76,144 @@ client_macros::api!(
     PostMessageSearchResult,
     PostMessageSearchRequest
 );
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The files search captured at the top of this file
+    const EMPTY_RESPONSE: &str = r#"{"ok":true,"current_page_offset":0,"pagesize":25,"next_page_offset":25,"total_results":0,"results":[],"bubbles":[]}"#;
+
+    /// The messages search captured at the top of this file, cut down to its first result
+    const RESPONSE: &str = r#"
+        {
+            "ok": true,
+            "current_page_offset": 0,
+            "pagesize": 25,
+            "next_page_offset": 25,
+            "total_results": 191,
+            "results": [
+                {
+                    "message_id": 90639242,
+                    "message": {
+                        "id": 90639242,
+                        "bubble_id": 3738656,
+                        "user_id": 5302428,
+                        "message": "bump test",
+                        "resource_id": null,
+                        "clickcount": 0,
+                        "likecount": 0,
+                        "dislikecount": 0,
+                        "viewcount": 0,
+                        "version": 0,
+                        "user_edited_version": 0,
+                        "user_edited_at": null,
+                        "created_at": "2024-10-03 03:52:36",
+                        "updated_at": "2024-10-03 03:52:37",
+                        "livestream_id": null,
+                        "videosession_id": null,
+                        "systemmessageparts": null,
+                        "uuid": "6ec69c92-04e0-47b6-af59-19de6b455c4e",
+                        "task_id": null,
+                        "parentmessage_id": null,
+                        "firstchildmessage_id": null,
+                        "lastchildmessage_id": null,
+                        "systemevent": null,
+                        "reactionsummary": [],
+                        "lang": "en",
+                        "videosession": null,
+                        "resource": null,
+                        "messagemedia": [],
+                        "user": {
+                            "id": 5302428,
+                            "firstname": "Ashwin",
+                            "lastname": "Naren",
+                            "username": null,
+                            "locale": "en_US",
+                            "lastseen": "2024-10-03 04:35:47",
+                            "profilepic": true,
+                            "status": 0,
+                            "created_at": "2023-08-04 00:44:12",
+                            "updated_at": "2024-10-03 04:35:18",
+                            "deactivated_at": null,
+                            "email_verified_at": "2024-09-25 02:40:01",
+                            "phone_verified_at": null,
+                            "isverified": false,
+                            "dropinorder": 0,
+                            "maxstreams": 10,
+                            "autotranslate": false,
+                            "isonline": true,
+                            "lastpresencetime": "2024-10-03 04:35:18",
+                            "acceptedtos": "2024-09-25 02:40:01",
+                            "sentwelcomemsg": "2023-08-15 19:22:02",
+                            "role": "user",
+                            "mute": false,
+                            "muteuntil": null,
+                            "isbot": 0,
+                            "fullname": "Ashwin Naren",
+                            "hasactivity": true,
+                            "inactive": false,
+                            "language": "en",
+                            "permissions": {
+                                "change_name": "system",
+                                "change_email": "system",
+                                "change_phone": "system",
+                                "remove_user": "system",
+                                "change_title": "admin",
+                                "change_pronouns": "admin",
+                                "change_own_name": false,
+                                "change_own_email": false,
+                                "change_own_phone": false,
+                                "change_own_title": true,
+                                "change_own_pronouns": true
+                            },
+                            "profilepicpath": "/files/users/5302428/profilepic?pronto_time=1695523284",
+                            "profilepicurl": "https://files.chat.trypronto.com/files/users/5302428/profilepic?pronto_time=1695523284"
+                        },
+                        "task": null,
+                        "messagetrans": [],
+                        "mentions": []
+                    },
+                    "highlight": {
+                        "messagelangs": {
+                            "en": [
+                                "bump <pronto_hl>test</pronto_hl>"
+                            ]
+                        },
+                        "message": [
+                            "bump <pronto_hl>test</pronto_hl>"
+                        ]
+                    }
+                }
+            ],
+            "bubbles": [
+                {
+                    "bubble_id": 3738656,
+                    "ishidden": false,
+                    "isdm": true,
+                    "title": "Ashwin Naren"
+                }
+            ]
+        }
+    "#;
+
+    #[test]
+    fn test_captured_responses() {
+        let empty: PostMessageSearchResponse = serde_json::from_str(EMPTY_RESPONSE).unwrap();
+        assert_eq!(empty.total_results, Some(0));
+        assert!(empty.results.is_empty());
+
+        let response: PostMessageSearchResponse = serde_json::from_str(RESPONSE).unwrap();
+        assert_eq!(response.total_results, Some(191));
+        assert_eq!(response.results.len(), 1);
+        assert_eq!(response.results[0].message.id, 90639242);
+        assert!(response.bubbles[0].is_dm);
+    }
+
+    #[test]
+    fn test_minimal_response() {
+        let response: PostMessageSearchResponse =
+            serde_json::from_str(r#"{"ok":true,"results":[]}"#).unwrap();
+        assert_eq!(response.total_results, None);
+    }
+}
@@ -13,6 +13,7 @@ pub mod counts;
 pub mod device_ping;
 pub mod dm_create;
 pub mod files;
+pub mod files_normalized;
 pub mod membership_update;
 pub mod message_create;
 pub mod message_delete;
@@ -1,5 +1,5 @@
+use crate::HttpClient;
 use crate::UserInfo;
-use reqwest::Client;
 use serde::{Deserialize, Serialize};
 
 #[derive(Copy, Clone, Debug, Serialize, Deserialize)]
@@ -17,9 +17,9 @@ pub type GetUserInfoResult = crate::APIResult<GetUserInfoResponse>;
 
 pub async fn get(
     pronto_base_url: &str,
-    client: &Client,
+    client: &HttpClient,
     request: GetUserInfoRequest,
-) -> Result<GetUserInfoResult, reqwest::Error> {
+) -> Result<GetUserInfoResult, crate::ResponseError> {
     let r = if request.id.is_none() {
         client.get(format!("{pronto_base_url}v1/user.info"))
     } else {
@@ -29,6 +29,7 @@ pub async fn get(
     }
     .send()
     .await?;
-    let json = r.json().await?;
-    Ok(json)
+    let status = r.status();
+    let text = r.text().await?;
+    crate::api_error::parse_response::<GetUserInfoResult, GetUserInfoResponse>(status, text)
 }
@@ -4,6 +4,15 @@ use serde::{Deserialize, Serialize};
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct GetUserSearchResponse {
     pub data: Vec<UserInfo>,
+    #[serde(default)]
+    pub cursors: GetUserSearchCursors,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, Default)]
+pub struct GetUserSearchCursors {
+    pub prev: Option<String>,
+    /// Pass as [`GetUserSearchRequest::cursor`] to get the next page, `None` on the last page
+    pub next: Option<String>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize, Default)]
@@ -19,9 +28,11 @@ pub struct GetUserSearchRequest {
     pub page_size: u64,
     pub query: String,
     pub relation: GetUserSearchRelation,
+    pub cursor: Option<String>,
     // &filter[query]=test
     // page[size]=30
     // relation can be [all, connections, or "filter[bubble_ids][]=2747415"]
+    // cursor=<cursors.next of the previous page>
 }
 
 impl Default for GetUserSearchRequest {
@@ -30,6 +41,7 @@ impl Default for GetUserSearchRequest {
             query: "".to_string(),
             page_size: 30,
             relation: GetUserSearchRelation::default(),
+            cursor: None,
         }
     }
 }
@@ -38,29 +50,22 @@ pub type GetUserSearchResult = crate::APIResult<GetUserSearchResponse>;
 
 pub async fn get(
     pronto_base_url: &str,
-    client: &reqwest::Client,
+    client: &crate::HttpClient,
     request: GetUserSearchRequest,
 ) -> Result<GetUserSearchResult, crate::ResponseError> {
-    let mut query_url = format!("{pronto_base_url}{}", "clients/users/search");
-    query_url.push_str("?");
-    query_url.push_str(&format!("page[size]={}", request.page_size));
-    query_url.push_str("&");
-    query_url.push_str(&format!("filter[query]={}", request.query));
-    let r = client.get(query_url).send().await?;
-    let text = r.text().await?;
-    log::trace!("Response: {}", text);
-    let json = serde_json::from_str(&text);
-    match json {
-        Ok(json) => Ok(json),
-        Err(_) => {
-            let json = serde_json::from_str::<GetUserSearchResponse>(&text);
-            let e = json.unwrap_err();
-            log::error!("Error parsing json response: {:?}.", e);
-            let json = serde_json::from_str::<serde_json::Value>(&text);
-            if json.is_err() {
-                return Err(crate::ResponseError::NotJson(text));
-            }
-            Err(crate::ResponseError::from(e))
-        }
+    let mut query = vec![
+        ("page[size]", request.page_size.to_string()),
+        ("filter[query]", request.query),
+    ];
+    if let Some(cursor) = request.cursor {
+        query.push(("cursor", cursor));
     }
+    let r = client
+        .get(format!("{pronto_base_url}clients/users/search"))
+        .query(&query)
+        .send()
+        .await?;
+    let status = r.status();
+    let text = r.text().await?;
+    crate::api_error::parse_response::<GetUserSearchResult, GetUserSearchResponse>(status, text)
 }
@@ -37,9 +37,9 @@ pub type TokenLoginResult = crate::APIResult<TokenLoginResponse>;
 
 pub async fn post(
     pronto_base_url: &str,
-    client: &reqwest::Client,
+    client: &crate::HttpClient,
     login_tokens: Vec<String>,
-) -> Result<TokenLoginResult, reqwest::Error> {
+) -> Result<TokenLoginResult, crate::ResponseError> {
     let r = client
         .post(format!("{pronto_base_url}v1/user.tokenlogin"))
         .json(&TokenLoginRequest {
@@ -56,6 +56,7 @@ pub async fn post(
         })
         .send()
         .await?;
-    let json = r.json::<TokenLoginResult>().await?;
-    Ok(json)
+    let status = r.status();
+    let text = r.text().await?;
+    crate::api_error::parse_response::<TokenLoginResult, TokenLoginResponse>(status, text)
 }
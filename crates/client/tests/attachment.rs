@@ -0,0 +1,54 @@
+use client::Attachment;
+use mock_server::MockServer;
+use std::sync::{Arc, Mutex};
+
+#[tokio::test]
+async fn test_attachments() {
+    let (server, alice, bubble) = MockServer::start_with_bubble().await.unwrap();
+    let client = server.client(alice.id);
+
+    let log: Vec<u8> = (0..200_000).map(|i| (i % 251) as u8).collect();
+    let path = std::env::temp_dir().join(format!("client-attachment-{}.log", std::process::id()));
+    tokio::fs::write(&path, &log).await.unwrap();
+    let progress = Arc::new(Mutex::new(vec![]));
+    let file = Attachment::from_path(&path).await.unwrap().on_progress({
+        let progress = progress.clone();
+        move |sent, total| progress.lock().unwrap().push((sent, total))
+    });
+    let screenshot = Attachment::from_bytes("screenshot.png", vec![0x89, b'P', b'N', b'G']);
+    assert_eq!(screenshot.mime_type, "image/png");
+
+    let message = client
+        .send_attachments(
+            alice.id,
+            bubble.id,
+            "logs".to_string(),
+            None,
+            vec![file, screenshot],
+        )
+        .await
+        .unwrap()
+        .message;
+    tokio::fs::remove_file(&path).await.unwrap();
+
+    let progress = progress.lock().unwrap().clone();
+    assert_eq!(progress.last(), Some(&(log.len() as u64, log.len() as u64)));
+    assert!(progress.len() > 1);
+    let [file, screenshot] = &message.message_media[..] else {
+        panic!("expected two attachments, got {:?}", message.message_media);
+    };
+    assert_eq!(file.mediatype, "FILE");
+    assert_eq!(file.filesize, log.len() as u64);
+    assert_eq!(screenshot.mediatype, "PHOTO");
+    assert_eq!(screenshot.url_mimetype, "image/png");
+    let downloaded = client
+        .http_client
+        .get(&file.url)
+        .send()
+        .await
+        .unwrap()
+        .bytes()
+        .await
+        .unwrap();
+    assert_eq!(downloaded, log);
+}
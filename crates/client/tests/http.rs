@@ -0,0 +1,61 @@
+use client::{ErrorKind, NewClientError, ProntoClient};
+use mock_server::MockServer;
+use std::time::Duration;
+
+#[tokio::test]
+async fn test_retries() {
+    let (server, alice, bubble) = MockServer::start_with_bubble().await.unwrap();
+    let client = ProntoClient::builder(server.api_base_url(), &MockServer::token(alice.id))
+        .backoff(Duration::from_millis(1), Duration::from_millis(10))
+        .build()
+        .unwrap();
+
+    // Reads are retried through server errors
+    server.fail_next(2, 503, None);
+    assert_eq!(client.bubble_list().await.unwrap().bubbles.len(), 1);
+
+    // Throttling is retried for anything
+    server.fail_next(1, 429, Some(0));
+    client
+        .send_message(alice.id, bubble.id, "throttled".to_string(), None)
+        .await
+        .unwrap();
+
+    // Waiting longer than the backoff allows isn't worth it, and doesn't hold up other requests
+    server.fail_next(1, 429, Some(86400));
+    let error = client.bubble_list().await.unwrap_err();
+    assert_eq!(error.kind(), ErrorKind::RateLimited);
+    assert_eq!(client.bubble_list().await.unwrap().bubbles.len(), 1);
+
+    // A server error might have applied the write, so it isn't repeated
+    server.fail_next(1, 503, None);
+    let error = client
+        .send_message(alice.id, bubble.id, "failed".to_string(), None)
+        .await
+        .unwrap_err();
+    assert_eq!(error.kind(), ErrorKind::Server);
+    let history = client.bubble_history(bubble.id, None).await.unwrap();
+    assert_eq!(history.messages.len(), 1);
+}
+
+#[test]
+fn test_invalid_rate_limit() {
+    for (per_second, burst) in [
+        (0.0, 1),
+        (-1.0, 1),
+        (f64::NAN, 1),
+        (f64::INFINITY, 1),
+        (1.0, 0),
+    ] {
+        let result = ProntoClient::builder("http://localhost".to_string(), "token")
+            .rate_limit(per_second, burst)
+            .build();
+        assert!(matches!(result, Err(NewClientError::InvalidRateLimit(_))));
+    }
+    assert!(
+        ProntoClient::builder("http://localhost".to_string(), "token")
+            .rate_limit(0.5, 1)
+            .build()
+            .is_ok()
+    );
+}
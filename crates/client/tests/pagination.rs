@@ -0,0 +1,81 @@
+use client::PostBubbleMembershipSearchRequest;
+use client::message_search::PostMessageSearchRequest;
+use client::user_search::GetUserSearchRequest;
+use futures::{StreamExt, TryStreamExt};
+use mock_server::MockServer;
+
+#[tokio::test]
+async fn test_pagination() {
+    let (server, alice, bubble) = MockServer::start_with_bubble().await.unwrap();
+    let messages = {
+        let mut store = server.store().await;
+        for i in 0..40 {
+            let user = store.add_user("User", &i.to_string());
+            store.add_member(bubble.id, user.id);
+        }
+        (0..120)
+            .map(|i| store.add_message(bubble.id, alice.id, &format!("message {i}"), None))
+            .collect::<Vec<_>>()
+    };
+    let client = server.client(alice.id);
+
+    let history: Vec<_> = client
+        .bubble_history_stream(bubble.id, None, None)
+        .try_collect()
+        .await
+        .unwrap();
+    let ids: Vec<_> = history.iter().map(|m| m.id).collect();
+    let expected: Vec<_> = messages.iter().rev().map(|m| m.id).collect();
+    assert_eq!(ids, expected);
+
+    // Both bounds are exclusive
+    let history: Vec<_> = client
+        .bubble_history_stream(bubble.id, Some(messages[100].id), Some(messages[20].id))
+        .try_collect()
+        .await
+        .unwrap();
+    assert_eq!(history.len(), 79);
+    assert_eq!(history[0].id, messages[99].id);
+    assert_eq!(history[78].id, messages[21].id);
+
+    let newest: Vec<_> = client
+        .bubble_history_stream(bubble.id, None, None)
+        .take(3)
+        .try_collect()
+        .await
+        .unwrap();
+    assert_eq!(newest[2].id, messages[117].id);
+
+    let results: Vec<_> = client
+        .message_search_stream(PostMessageSearchRequest {
+            query: "message 1".to_string(),
+            size: 7,
+            ..Default::default()
+        })
+        .try_collect()
+        .await
+        .unwrap();
+    // message 1, 10-19 and 100-119
+    assert_eq!(results.len(), 31);
+
+    let users: Vec<_> = client
+        .user_search_stream(GetUserSearchRequest {
+            query: "user".to_string(),
+            page_size: 3,
+            ..Default::default()
+        })
+        .try_collect()
+        .await
+        .unwrap();
+    assert_eq!(users.len(), 40);
+
+    let members: Vec<_> = client
+        .bubble_membership_stream(PostBubbleMembershipSearchRequest {
+            bubble_id: bubble.id,
+            ..Default::default()
+        })
+        .try_collect()
+        .await
+        .unwrap();
+    assert_eq!(members.len(), 41);
+}
@@ -0,0 +1,105 @@
+//! Secrets kept as files, for machines where the system keyring doesn't keep them.
+//! Without a secret service, Linux only has the kernel keyring, which forgets everything on reboot.
+
+use keyring::Entry;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::{PoisonError, RwLock};
+
+static FILE_STORE: RwLock<Option<FileStore>> = RwLock::new(None);
+
+#[derive(Clone)]
+struct FileStore {
+    dir: PathBuf,
+    /// Files first, the keyring is only read to move secrets over
+    always: bool,
+}
+
+/// Keep secrets as files in `dir` when the keyring can't, or instead of it if `always`
+pub fn use_file_store(dir: PathBuf, always: bool) {
+    *FILE_STORE.write().unwrap_or_else(PoisonError::into_inner) = Some(FileStore { dir, always });
+}
+
+fn file_store() -> Option<FileStore> {
+    FILE_STORE
+        .read()
+        .unwrap_or_else(PoisonError::into_inner)
+        .clone()
+}
+
+/// The keyring has no storage to offer, as opposed to not having the secret
+fn unavailable(e: &keyring::Error) -> bool {
+    matches!(
+        e,
+        keyring::Error::PlatformFailure(_) | keyring::Error::NoStorageAccess(_)
+    )
+}
+
+fn read_file(store: &FileStore, name: &str) -> std::io::Result<Vec<u8>> {
+    std::fs::read(store.dir.join(name))
+}
+
+fn write_file(store: &FileStore, name: &str, secret: &[u8]) -> keyring::Result<()> {
+    let write = || -> std::io::Result<()> {
+        std::fs::create_dir_all(&store.dir)?;
+        let path = store.dir.join(name);
+        let temporary = path.with_extension("tmp");
+        let mut options = std::fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        options.open(&temporary)?.write_all(secret)?;
+        std::fs::rename(temporary, path)
+    };
+    write().map_err(|e| keyring::Error::PlatformFailure(Box::new(e)))
+}
+
+fn keyring_secret(name: &str) -> keyring::Result<Vec<u8>> {
+    Entry::new(crate::SERVICE, name)?.get_secret()
+}
+
+pub(crate) fn get_secret(name: &str) -> keyring::Result<Vec<u8>> {
+    let Some(store) = file_store() else {
+        return keyring_secret(name);
+    };
+    if store.always {
+        return match read_file(&store, name) {
+            Ok(secret) => Ok(secret),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                let secret = keyring_secret(name).map_err(|e| match e {
+                    e if unavailable(&e) => keyring::Error::NoEntry,
+                    e => e,
+                })?;
+                write_file(&store, name, &secret)?;
+                Ok(secret)
+            }
+            Err(e) => Err(keyring::Error::PlatformFailure(Box::new(e))),
+        };
+    }
+    match keyring_secret(name) {
+        Err(e) if matches!(e, keyring::Error::NoEntry) || unavailable(&e) => {
+            match read_file(&store, name) {
+                Ok(secret) => Ok(secret),
+                Err(not_found) if not_found.kind() == std::io::ErrorKind::NotFound => {
+                    Err(keyring::Error::NoEntry)
+                }
+                Err(io) => Err(keyring::Error::PlatformFailure(Box::new(io))),
+            }
+        }
+        result => result,
+    }
+}
+
+pub(crate) fn set_secret(name: &str, secret: &[u8]) -> keyring::Result<()> {
+    let store = file_store();
+    if let Some(store) = store.as_ref().filter(|store| store.always) {
+        return write_file(store, name, secret);
+    }
+    match Entry::new(crate::SERVICE, name).and_then(|entry| entry.set_secret(secret)) {
+        Err(e) if unavailable(&e) => match store {
+            Some(store) => write_file(&store, name, secret),
+            None => Err(e),
+        },
+        result => result,
+    }
+}
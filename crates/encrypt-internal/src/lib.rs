@@ -15,10 +15,16 @@
     while_true
 )]
 
+mod file_store;
+
 pub use aead::{Error, Result};
+pub use crypto_box::Nonce;
+use crypto_box::aead::rand_core::RngCore;
 use crypto_box::aead::{Aead, AeadCore, OsRng};
-use crypto_box::{ChaChaBox, Nonce, PublicKey, SecretKey, aead};
-use keyring::Entry;
+use crypto_box::{ChaChaBox, PublicKey, SecretKey, aead};
+use crypto_secretbox::{KeyInit, XChaCha20Poly1305};
+pub use file_store::use_file_store;
+pub use keyring;
 
 pub struct DMEncryption {
     pub current_user_secret_key: SecretKey,
@@ -56,13 +62,38 @@ impl DMEncryption {
     }
 }
 
+/// A symmetric key shared by every member of a bubble
+pub struct BubbleEncryption {
+    pub key: [u8; 32],
+}
+
+impl BubbleEncryption {
+    pub fn new(key: [u8; 32]) -> Self {
+        BubbleEncryption { key }
+    }
+
+    pub fn generate_key() -> [u8; 32] {
+        XChaCha20Poly1305::generate_key(&mut OsRng).into()
+    }
+
+    pub fn encrypt(&self, message: &[u8], nonce: &Nonce) -> Result<Vec<u8>> {
+        let cipher = XChaCha20Poly1305::new(&self.key.into());
+        let encrypted = cipher.encrypt(nonce, message)?;
+        Ok(encrypted)
+    }
+
+    pub fn decrypt(&self, encrypted: &[u8], nonce: &Nonce) -> Result<Vec<u8>> {
+        let cipher = XChaCha20Poly1305::new(&self.key.into());
+        let decrypted = cipher.decrypt(nonce, encrypted)?;
+        Ok(decrypted)
+    }
+}
+
 const SERVICE: &str = "prontus-encrypt";
 const DEFAULT_USER: &str = "com_prontus_default";
 
 pub fn load_secret_key() -> keyring::Result<[u8; 32]> {
-    let secret_vector = Entry::new(SERVICE, DEFAULT_USER)
-        .expect("Failed to load secret key, please report this issue to the keyring developers")
-        .get_secret()?;
+    let secret_vector = file_store::get_secret(DEFAULT_USER)?;
     if secret_vector.len() != 32 {
         return Err(keyring::Error::Invalid(
             "com_prontus_default".to_string(),
@@ -76,10 +107,23 @@ pub fn load_secret_key() -> keyring::Result<[u8; 32]> {
 }
 
 pub fn store_secret_key(secret_key: [u8; 32]) -> keyring::Result<()> {
-    let entry = Entry::new(SERVICE, DEFAULT_USER)
-        .expect("Failed to load secret key, please report this issue to the keyring developers");
-    entry.set_secret(&secret_key)?;
-    Ok(())
+    file_store::set_secret(DEFAULT_USER, &secret_key)
+}
+
+/// Another secret kept in the keyring next to the secret key, like a bubble key
+pub fn load_named_secret(name: &str) -> keyring::Result<Vec<u8>> {
+    file_store::get_secret(name)
+}
+
+pub fn store_named_secret(name: &str, secret: &[u8]) -> keyring::Result<()> {
+    file_store::set_secret(name, secret)
+}
+
+/// Random bytes for salts and one time keys
+pub fn random_bytes<const N: usize>() -> [u8; N] {
+    let mut bytes = [0u8; N];
+    OsRng.fill_bytes(&mut bytes);
+    bytes
 }
 
 #[derive(Copy, Clone, Debug, PartialEq)]
@@ -97,6 +141,19 @@ pub fn generate_key_pair() -> KeyPair {
     }
 }
 
+/// The key pair kept in the keyring, generated and stored there on first use
+pub fn load_or_generate_key_pair() -> keyring::Result<KeyPair> {
+    match load_secret_key() {
+        Ok(secret_key) => Ok(load_key_pair(secret_key)),
+        Err(keyring::Error::NoEntry) => {
+            let key_pair = generate_key_pair();
+            store_secret_key(key_pair.secret_key)?;
+            Ok(key_pair)
+        }
+        Err(e) => Err(e),
+    }
+}
+
 pub fn load_key_pair(secret_key: [u8; 32]) -> KeyPair {
     let secret_key = SecretKey::from_slice(&secret_key).unwrap();
     let public_key = secret_key.public_key();
@@ -124,4 +181,19 @@ mod tests {
         let decrypted_data = decrypt_dm.decrypt(&encrypted_data, &nonce).unwrap();
         assert_eq!(data.to_vec(), decrypted_data);
     }
+
+    #[test]
+    fn test_bubble_encryption() {
+        let bubble = BubbleEncryption::new(BubbleEncryption::generate_key());
+        let data = b"Hello, everyone!";
+        let nonce = DMEncryption::generate_random_nonce();
+        let encrypted_data = bubble.encrypt(data, &nonce).unwrap();
+        assert_eq!(
+            bubble.decrypt(&encrypted_data, &nonce).unwrap(),
+            data.to_vec()
+        );
+
+        let other = BubbleEncryption::new(BubbleEncryption::generate_key());
+        assert!(other.decrypt(&encrypted_data, &nonce).is_err());
+    }
 }
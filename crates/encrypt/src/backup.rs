@@ -0,0 +1,315 @@
+//! Getting the secret key onto another device, or back after losing the keyring.
+//!
+//! A backup is [`BACKUP_MAGIC`] followed by the base64 of:
+//!
+//! | bytes | field                                        |
+//! |-------|----------------------------------------------|
+//! | 1     | version, [`BACKUP_VERSION`]                  |
+//! | 12    | argon2id memory, iterations and parallelism  |
+//! | 16    | salt                                         |
+//! | 24    | nonce                                        |
+//! | rest  | the secret key, XChaCha20-Poly1305 encrypted |
+//!
+//! Linking a device needs no passphrase: the new device shows a one time public key as a
+//! [`DeviceLink::code`], the device with the secret key seals it to that with
+//! [`link_payload`] and only the new device can open the payload.
+//!
+//! Bubble keys aren't part of either, they're handed out sealed to the public key, so the
+//! new device takes them from the bubble history like the old one did.
+
+use crate::KeyPair;
+use argon2::{Argon2, Params};
+use base64::prelude::*;
+use encrypt_internal::{
+    BubbleEncryption, DMEncryption, generate_key_pair, keyring, load_key_pair,
+    load_or_generate_key_pair, load_secret_key, random_bytes, store_secret_key,
+};
+use thiserror::Error;
+
+/// Starts every key backup
+pub const BACKUP_MAGIC: &str = "prontus-key-backup:";
+/// The only version so far
+pub const BACKUP_VERSION: u8 = 1;
+/// Starts the code a new device shows to be linked
+pub const LINK_CODE_MAGIC: &str = "prontus-link:";
+/// Starts the secret key sealed to a link code
+pub const LINK_PAYLOAD_MAGIC: &str = "prontus-link-key:";
+
+const PARAMS_LEN: usize = 12;
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 24;
+/// The secret key and the Poly1305 tag
+const SEALED_KEY_LEN: usize = 32 + 16;
+/// Most argon2 memory a backup may ask for, in KiB, so opening one can't exhaust the memory
+const MAX_M_COST: u32 = 1 << 20; // 1 GiB
+/// Most argon2 iterations a backup may ask for, so opening one can't take forever
+const MAX_T_COST: u32 = 10;
+/// Most argon2 lanes a backup may ask for
+const MAX_P_COST: u32 = 16;
+
+#[derive(Debug, Error)]
+pub enum BackupError {
+    #[error("Base64 error: {0}")]
+    Base64Error(#[from] base64::DecodeError),
+    #[error("Keyring error: {0}")]
+    KeyringError(#[from] keyring::Error),
+    #[error("Argon2 error: {0}")]
+    Argon2Error(String),
+    #[error("Not a key backup")]
+    NotABackup,
+    #[error("Unsupported key backup version: {0}")]
+    UnsupportedVersion(u8),
+    #[error("Truncated key backup")]
+    Truncated,
+    #[error("Wrong passphrase, or the backup was changed")]
+    WrongPassphrase,
+    #[error("Not a device link code")]
+    InvalidLinkCode,
+    #[error("The key wasn't sealed for this link, or was changed")]
+    InvalidLinkPayload,
+    #[error("This device already has another secret key")]
+    KeyExists,
+    #[error("The linked key isn't the one published for this account")]
+    NotPublishedKey,
+}
+
+/// The key a passphrase unlocks, slow to compute so passphrases can't be guessed quickly
+fn derive_key(passphrase: &str, salt: &[u8], params: Params) -> Result<[u8; 32], BackupError> {
+    let mut key = [0u8; 32];
+    Argon2::new(argon2::Algorithm::Argon2id, argon2::Version::V0x13, params)
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .map_err(|e| BackupError::Argon2Error(e.to_string()))?;
+    Ok(key)
+}
+
+/// Encrypt `secret_key` with `passphrase`
+pub fn seal_backup(secret_key: [u8; 32], passphrase: &str) -> Result<String, BackupError> {
+    let params = Params::default();
+    let salt = random_bytes::<SALT_LEN>();
+    let key = derive_key(passphrase, &salt, params.clone())?;
+    let nonce = DMEncryption::generate_random_nonce();
+    let mut data = vec![BACKUP_VERSION];
+    for cost in [params.m_cost(), params.t_cost(), params.p_cost()] {
+        data.extend_from_slice(&cost.to_le_bytes());
+    }
+    data.extend_from_slice(&salt);
+    data.extend_from_slice(&nonce);
+    // Only fails for plaintexts far larger than a key
+    data.extend(
+        BubbleEncryption::new(key)
+            .encrypt(&secret_key, &nonce)
+            .unwrap(),
+    );
+    Ok(format!("{BACKUP_MAGIC}{}", BASE64_STANDARD.encode(data)))
+}
+
+/// The key pair in `backup`, without storing it
+pub fn open_backup(backup: &str, passphrase: &str) -> Result<KeyPair, BackupError> {
+    let encoded = backup
+        .trim()
+        .strip_prefix(BACKUP_MAGIC)
+        .ok_or(BackupError::NotABackup)?;
+    let data = BASE64_STANDARD.decode(encoded)?;
+    let version = *data.first().ok_or(BackupError::Truncated)?;
+    if version != BACKUP_VERSION {
+        return Err(BackupError::UnsupportedVersion(version));
+    }
+    if data.len() != 1 + PARAMS_LEN + SALT_LEN + NONCE_LEN + SEALED_KEY_LEN {
+        return Err(BackupError::Truncated);
+    }
+    let (params, rest) = data[1..].split_at(PARAMS_LEN);
+    let (salt, rest) = rest.split_at(SALT_LEN);
+    let (nonce, ciphertext) = rest.split_at(NONCE_LEN);
+    let cost =
+        |index: usize| u32::from_le_bytes(params[index * 4..index * 4 + 4].try_into().unwrap());
+    // The params come with the backup, anything past what we'd ever write isn't derived
+    let (m_cost, t_cost, p_cost) = (cost(0), cost(1), cost(2));
+    if m_cost > MAX_M_COST || t_cost > MAX_T_COST || p_cost > MAX_P_COST {
+        return Err(BackupError::Argon2Error(format!(
+            "Key backup asks for too costly parameters: m={m_cost} t={t_cost} p={p_cost}"
+        )));
+    }
+    let params = Params::new(m_cost, t_cost, p_cost, None)
+        .map_err(|e| BackupError::Argon2Error(e.to_string()))?;
+    let key = derive_key(passphrase, salt, params)?;
+    let secret_key = BubbleEncryption::new(key)
+        .decrypt(ciphertext, &DMEncryption::convert_nonce(nonce))
+        .map_err(|_| BackupError::WrongPassphrase)?;
+    Ok(load_key_pair(
+        secret_key.try_into().map_err(|_| BackupError::Truncated)?,
+    ))
+}
+
+/// This device's secret key encrypted with `passphrase`, the key pair is generated on first use
+pub fn export_backup(passphrase: &str) -> Result<String, BackupError> {
+    seal_backup(load_or_generate_key_pair()?.secret_key, passphrase)
+}
+
+/// Make `key_pair` the key pair of this device.
+/// Messages sealed to a different key it has can't be read after, so that takes `overwrite`.
+fn install(key_pair: KeyPair, overwrite: bool) -> Result<KeyPair, BackupError> {
+    match load_secret_key() {
+        Ok(existing) if existing == key_pair.secret_key => return Ok(key_pair),
+        Ok(_) if !overwrite => return Err(BackupError::KeyExists),
+        Ok(_) | Err(keyring::Error::NoEntry) => {}
+        Err(e) => return Err(e.into()),
+    }
+    store_secret_key(key_pair.secret_key)?;
+    Ok(key_pair)
+}
+
+/// Restore the key pair in `backup` on this device
+pub fn import_backup(
+    backup: &str,
+    passphrase: &str,
+    overwrite: bool,
+) -> Result<KeyPair, BackupError> {
+    install(open_backup(backup, passphrase)?, overwrite)
+}
+
+/// A one time key pair on the device to be linked, dropped once the key came through
+pub struct DeviceLink {
+    key_pair: KeyPair,
+}
+
+impl Default for DeviceLink {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DeviceLink {
+    pub fn new() -> Self {
+        Self {
+            key_pair: generate_key_pair(),
+        }
+    }
+
+    /// To enter on the device that has the secret key
+    pub fn code(&self) -> String {
+        format!(
+            "{LINK_CODE_MAGIC}{}",
+            BASE64_STANDARD.encode(self.key_pair.public_key)
+        )
+    }
+
+    /// The key pair sealed to [`Self::code`], without storing it
+    pub fn open(&self, payload: &str) -> Result<KeyPair, BackupError> {
+        let encoded = payload
+            .trim()
+            .strip_prefix(LINK_PAYLOAD_MAGIC)
+            .ok_or(BackupError::InvalidLinkPayload)?;
+        let data = BASE64_STANDARD.decode(encoded)?;
+        if data.len() != 32 + NONCE_LEN + SEALED_KEY_LEN {
+            return Err(BackupError::InvalidLinkPayload);
+        }
+        let (sender, rest) = data.split_at(32);
+        let (nonce, ciphertext) = rest.split_at(NONCE_LEN);
+        let secret_key = DMEncryption::new(self.key_pair.secret_key, sender.try_into().unwrap())
+            .decrypt(ciphertext, &DMEncryption::convert_nonce(nonce))
+            .map_err(|_| BackupError::InvalidLinkPayload)?;
+        Ok(load_key_pair(
+            secret_key
+                .try_into()
+                .map_err(|_| BackupError::InvalidLinkPayload)?,
+        ))
+    }
+
+    /// Make the key pair sealed to [`Self::code`] the key pair of this device.
+    /// Anyone who saw the code can seal a key to it, so it has to be the one `published` for
+    /// this account. A different key on this device is only replaced with `overwrite`.
+    pub fn finish(
+        &self,
+        payload: &str,
+        published: &[u8; 32],
+        overwrite: bool,
+    ) -> Result<KeyPair, BackupError> {
+        let key_pair = self.open(payload)?;
+        if key_pair.public_key != *published {
+            return Err(BackupError::NotPublishedKey);
+        }
+        install(key_pair, overwrite)
+    }
+}
+
+/// `secret_key` sealed to the device that shows `code`, from a one time key pair
+pub fn seal_link_payload(code: &str, secret_key: [u8; 32]) -> Result<String, BackupError> {
+    let public_key: [u8; 32] = BASE64_STANDARD
+        .decode(
+            code.trim()
+                .strip_prefix(LINK_CODE_MAGIC)
+                .ok_or(BackupError::InvalidLinkCode)?,
+        )?
+        .try_into()
+        .map_err(|_| BackupError::InvalidLinkCode)?;
+    let sender = generate_key_pair();
+    let nonce = DMEncryption::generate_random_nonce();
+    let mut data = sender.public_key.to_vec();
+    data.extend_from_slice(&nonce);
+    // Only fails for plaintexts far larger than a key
+    data.extend(
+        DMEncryption::new(sender.secret_key, public_key)
+            .encrypt(&secret_key, &nonce)
+            .unwrap(),
+    );
+    Ok(format!(
+        "{LINK_PAYLOAD_MAGIC}{}",
+        BASE64_STANDARD.encode(data)
+    ))
+}
+
+/// This device's secret key sealed to the device that shows `code`
+pub fn link_payload(code: &str) -> Result<String, BackupError> {
+    seal_link_payload(code, load_or_generate_key_pair()?.secret_key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backup() {
+        let key_pair = generate_key_pair();
+        let backup = seal_backup(key_pair.secret_key, "correct horse").unwrap();
+        assert_eq!(open_backup(&backup, "correct horse").unwrap(), key_pair);
+        assert!(matches!(
+            open_backup(&backup, "battery staple"),
+            Err(BackupError::WrongPassphrase)
+        ));
+        assert!(matches!(
+            open_backup(&backup[..backup.len() - 8], "correct horse"),
+            Err(BackupError::Truncated)
+        ));
+
+        // Costs past the limits are refused before deriving anything
+        let mut data = BASE64_STANDARD
+            .decode(backup.strip_prefix(BACKUP_MAGIC).unwrap())
+            .unwrap();
+        data[1..5].copy_from_slice(&u32::MAX.to_le_bytes());
+        let costly = format!("{BACKUP_MAGIC}{}", BASE64_STANDARD.encode(&data));
+        assert!(matches!(
+            open_backup(&costly, "correct horse"),
+            Err(BackupError::Argon2Error(_))
+        ));
+    }
+
+    #[test]
+    fn test_device_link() {
+        let key_pair = generate_key_pair();
+        let link = DeviceLink::new();
+        let payload = seal_link_payload(&link.code(), key_pair.secret_key).unwrap();
+        assert_eq!(link.open(&payload).unwrap(), key_pair);
+        assert!(matches!(
+            link.finish(&payload, &generate_key_pair().public_key, false),
+            Err(BackupError::NotPublishedKey)
+        ));
+        assert!(matches!(
+            DeviceLink::new().open(&payload),
+            Err(BackupError::InvalidLinkPayload)
+        ));
+        assert!(matches!(
+            seal_link_payload("Hello, World!", key_pair.secret_key),
+            Err(BackupError::InvalidLinkCode)
+        ));
+    }
+}
@@ -0,0 +1,347 @@
+//! End-to-end encryption of group bubbles.
+//!
+//! Every encrypted bubble has a symmetric [`BubbleKey`]. Whoever creates one posts a
+//! [`KeyHandout`] in the bubble: the key sealed with [`Encrypt`] to each member's public key.
+//! Messages are then [`Envelope`]s with [`Algorithm::XChaCha20Poly1305`] whose recipient is
+//! the id of the bubble key, so readers know which of the keys they kept to use.
+//!
+//! When the members change, the sender of the current key hands out a new one, so members
+//! who left can't read what comes after. Older keys are kept to read the history.
+
+use crate::envelope::KEY_ID_LEN;
+use crate::{Algorithm, DecryptionError, Encrypt, Envelope, KeyError, KeyId};
+use base64::prelude::*;
+use encrypt_internal::{BubbleEncryption, keyring, load_named_secret, store_named_secret};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, PoisonError, RwLock};
+
+/// Starts every key handout, it's not an [`Envelope`] and never shown as a message
+pub const HANDOUT_MAGIC: &str = "prontus-e2e-key:";
+/// The only version of the handout json so far
+pub const HANDOUT_VERSION: u8 = 1;
+
+const KEY_ID_CONTEXT: &str = "prontus 2024-10 bubble key id";
+
+/// The symmetric key of an encrypted bubble
+#[derive(Clone, PartialEq, Eq)]
+pub struct BubbleKey {
+    key: [u8; 32],
+}
+
+impl BubbleKey {
+    pub fn generate() -> Self {
+        Self::from_bytes(BubbleEncryption::generate_key())
+    }
+
+    pub fn from_bytes(key: [u8; 32]) -> Self {
+        Self { key }
+    }
+
+    pub fn to_bytes(&self) -> [u8; 32] {
+        self.key
+    }
+
+    /// Derived from the key, it can't be turned back into one
+    pub fn id(&self) -> KeyId {
+        let mut id = KeyId::default();
+        id.copy_from_slice(&blake3::derive_key(KEY_ID_CONTEXT, &self.key)[..KEY_ID_LEN]);
+        id
+    }
+
+    /// Encrypt `data` into an [`Envelope`], encoded to be sent as the text of a message.
+    /// Every member holds the same key, so the sender is the key id as well.
+    pub fn encrypt(&self, data: &str) -> String {
+        let id = self.id();
+        let cipher = BubbleEncryption::new(self.key);
+        Envelope::seal(
+            Algorithm::XChaCha20Poly1305,
+            id,
+            id,
+            data.as_bytes(),
+            |plaintext, nonce| cipher.encrypt(plaintext, nonce),
+        )
+        .encode()
+    }
+
+    pub fn decrypt(&self, data: &str) -> Result<String, DecryptionError> {
+        let envelope = Envelope::decode(data)?;
+        if envelope.algorithm != Algorithm::XChaCha20Poly1305 || envelope.recipient != self.id() {
+            return Err(DecryptionError::KeyMismatch);
+        }
+        let cipher = BubbleEncryption::new(self.key);
+        let plaintext = envelope.open(|ciphertext, nonce| cipher.decrypt(ciphertext, nonce))?;
+        Ok(String::from_utf8(plaintext)?)
+    }
+}
+
+/// A bubble key for every member, posted in the bubble as [`HANDOUT_MAGIC`] followed by
+/// the base64 of this as json
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KeyHandout {
+    pub version: u8,
+    pub key_id: KeyId,
+    /// Who handed the key out, they hand out the next one when the members change
+    pub sender_id: u64,
+    /// The key sealed to each member by the sender, encoded [`Envelope`]s keyed by user id
+    pub keys: BTreeMap<u64, String>,
+}
+
+impl KeyHandout {
+    /// `members` are the sender's [`Encrypt`] with every member, the sender included
+    pub fn new<'a>(
+        key: &BubbleKey,
+        sender_id: u64,
+        members: impl IntoIterator<Item = (u64, &'a Encrypt)>,
+    ) -> Self {
+        Self {
+            version: HANDOUT_VERSION,
+            key_id: key.id(),
+            sender_id,
+            keys: members
+                .into_iter()
+                .map(|(user_id, encrypt)| (user_id, encrypt.seal(&key.key).encode()))
+                .collect(),
+        }
+    }
+
+    pub fn is_handout(text: &str) -> bool {
+        text.trim().starts_with(HANDOUT_MAGIC)
+    }
+
+    pub fn encode(&self) -> String {
+        // Only plain fields, serializing can't fail
+        let json = serde_json::to_vec(self).unwrap();
+        format!("{HANDOUT_MAGIC}{}", BASE64_STANDARD.encode(json))
+    }
+
+    pub fn decode(text: &str) -> Result<Self, DecryptionError> {
+        let encoded = text
+            .trim()
+            .strip_prefix(HANDOUT_MAGIC)
+            .ok_or(DecryptionError::NotEncrypted)?;
+        let handout: Self = serde_json::from_slice(&BASE64_STANDARD.decode(encoded)?)?;
+        if handout.version != HANDOUT_VERSION {
+            return Err(DecryptionError::UnsupportedVersion(handout.version));
+        }
+        Ok(handout)
+    }
+
+    /// Who the key was handed out to
+    pub fn members(&self) -> Vec<u64> {
+        self.keys.keys().copied().collect()
+    }
+
+    /// The key handed out to `user_id`, `sender` is their [`Encrypt`] with the sender
+    pub fn open(&self, user_id: u64, sender: &Encrypt) -> Result<BubbleKey, DecryptionError> {
+        let sealed = self
+            .keys
+            .get(&user_id)
+            .ok_or(DecryptionError::KeyMismatch)?;
+        let key: [u8; 32] = sender
+            .open(&Envelope::decode(sealed)?)?
+            .try_into()
+            .map_err(|_| DecryptionError::Truncated)?;
+        let key = BubbleKey::from_bytes(key);
+        if key.id() != self.key_id {
+            return Err(DecryptionError::KeyMismatch);
+        }
+        Ok(key)
+    }
+}
+
+/// The key a bubble is encrypted with right now
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CurrentKey {
+    pub key_id: KeyId,
+    pub sender_id: u64,
+    /// The message the key was handed out in, only a later handout replaces it
+    pub message_id: u64,
+    pub members: Vec<u64>,
+}
+
+/// The bubble keys of one account, kept in the system keyring and cached here
+pub struct BubbleKeys {
+    user_id: u64,
+    keys: RwLock<HashMap<KeyId, Arc<BubbleKey>>>,
+    /// `None` for bubbles known not to be encrypted
+    current: RwLock<HashMap<u64, Option<CurrentKey>>>,
+}
+
+impl BubbleKeys {
+    /// Nothing is read from the keyring until a bubble is asked for
+    pub fn new(user_id: u64) -> Self {
+        Self {
+            user_id,
+            keys: RwLock::new(HashMap::new()),
+            current: RwLock::new(HashMap::new()),
+        }
+    }
+
+    fn current_entry(&self, bubble_id: u64) -> String {
+        format!("com_prontus_bubble_{}_{bubble_id}", self.user_id)
+    }
+
+    fn key_entry(key_id: &KeyId) -> String {
+        let hex = key_id
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect::<String>();
+        format!("com_prontus_bubble_key_{hex}")
+    }
+
+    /// What `bubble_id` is encrypted with, `None` if it isn't
+    pub fn current(&self, bubble_id: u64) -> Result<Option<CurrentKey>, KeyError> {
+        if let Some(current) = self
+            .current
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(&bubble_id)
+        {
+            return Ok(current.clone());
+        }
+        let current = match load_named_secret(&self.current_entry(bubble_id)) {
+            Ok(json) => serde_json::from_slice(&json).ok(),
+            Err(keyring::Error::NoEntry) => None,
+            Err(e) => return Err(e.into()),
+        };
+        self.current
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(bubble_id, current.clone());
+        Ok(current)
+    }
+
+    /// A key kept from a handout, `None` if this device never got it
+    pub fn key(&self, key_id: &KeyId) -> Result<Option<Arc<BubbleKey>>, KeyError> {
+        if let Some(key) = self
+            .keys
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(key_id)
+        {
+            return Ok(Some(key.clone()));
+        }
+        let key = match load_named_secret(&Self::key_entry(key_id)) {
+            Ok(key) => match <[u8; 32]>::try_from(key) {
+                Ok(key) => Arc::new(BubbleKey::from_bytes(key)),
+                Err(_) => return Ok(None),
+            },
+            Err(keyring::Error::NoEntry) => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        self.keys
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(*key_id, key.clone());
+        Ok(Some(key))
+    }
+
+    /// Keep a handed out key. It becomes the current key of `bubble_id` unless a later
+    /// handout was seen already, returns whether it did.
+    pub fn insert(
+        &self,
+        bubble_id: u64,
+        key: BubbleKey,
+        current: CurrentKey,
+    ) -> Result<bool, KeyError> {
+        let key_id = key.id();
+        if self.key(&key_id)?.is_none() {
+            store_named_secret(&Self::key_entry(&key_id), &key.to_bytes())?;
+            self.keys
+                .write()
+                .unwrap_or_else(PoisonError::into_inner)
+                .insert(key_id, Arc::new(key));
+        }
+        if self
+            .current(bubble_id)?
+            .is_some_and(|existing| existing.message_id >= current.message_id)
+        {
+            return Ok(false);
+        }
+        // Only plain fields, serializing can't fail
+        let json = serde_json::to_vec(&current).unwrap();
+        store_named_secret(&self.current_entry(bubble_id), &json)?;
+        self.current
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(bubble_id, Some(current));
+        Ok(true)
+    }
+
+    /// Encrypt `data` with the current key of `bubble_id`, `None` if it isn't encrypted
+    pub fn encrypt(&self, bubble_id: u64, data: &str) -> Result<Option<String>, KeyError> {
+        let Some(current) = self.current(bubble_id)? else {
+            return Ok(None);
+        };
+        let key = self
+            .key(&current.key_id)?
+            .ok_or_else(|| KeyError::MissingBubbleKey(bubble_id))?;
+        Ok(Some(key.encrypt(data)))
+    }
+
+    /// Decrypt the text of a message with whichever kept key it was encrypted with
+    pub fn decrypt(&self, data: &str) -> Result<String, DecryptionError> {
+        let envelope = Envelope::decode(data)?;
+        let key = self
+            .key(&envelope.recipient)?
+            .ok_or(DecryptionError::KeyMismatch)?;
+        key.decrypt(data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::PublicLookupService;
+    use encrypt_internal::{DMEncryption, generate_key_pair};
+
+    fn encrypt(secret_key: [u8; 32], public_key: [u8; 32]) -> Encrypt {
+        Encrypt {
+            dm_encryption: DMEncryption::new(secret_key, public_key),
+            lookup_service: Arc::new(PublicLookupService::with_directory(Default::default())),
+            trust: Default::default(),
+        }
+    }
+
+    #[test]
+    fn test_handout() {
+        let sender = generate_key_pair();
+        let member = generate_key_pair();
+        let outsider = generate_key_pair();
+        let key = BubbleKey::generate();
+        let handout = KeyHandout::new(
+            &key,
+            1,
+            [
+                (1, &encrypt(sender.secret_key, sender.public_key)),
+                (2, &encrypt(sender.secret_key, member.public_key)),
+            ],
+        );
+        let handout = KeyHandout::decode(&handout.encode()).unwrap();
+        assert_eq!(handout.members(), [1, 2]);
+
+        let opened = handout
+            .open(2, &encrypt(member.secret_key, sender.public_key))
+            .unwrap();
+        assert!(opened == key);
+        let opened = handout
+            .open(1, &encrypt(sender.secret_key, sender.public_key))
+            .unwrap();
+        assert!(opened == key);
+        assert!(matches!(
+            handout.open(3, &encrypt(outsider.secret_key, sender.public_key)),
+            Err(DecryptionError::KeyMismatch)
+        ));
+
+        let text = "Hello, everyone!";
+        let encrypted = key.encrypt(text);
+        assert!(Envelope::is_encrypted(&encrypted));
+        assert!(!KeyHandout::is_handout(&encrypted));
+        assert_eq!(opened.decrypt(&encrypted).unwrap(), text);
+        assert!(matches!(
+            BubbleKey::generate().decrypt(&encrypted),
+            Err(DecryptionError::KeyMismatch)
+        ));
+    }
+}
@@ -0,0 +1,206 @@
+//! The wire format of an encrypted message, sent as the text of a Pronto message.
+//!
+//! [`MAGIC`] followed by the base64 of:
+//!
+//! | bytes | field                                                  |
+//! |-------|--------------------------------------------------------|
+//! | 1     | version, [`VERSION`]                                   |
+//! | 1     | algorithm, an [`Algorithm`]                            |
+//! | 8     | key id of the sender's public key, see [`key_id`]      |
+//! | 8     | key id of the recipient's public key or bubble key     |
+//! | 24    | nonce                                                  |
+//! | rest  | ciphertext of the 18 header bytes followed by the text |
+//!
+//! Messages in encrypted bubbles use [`Algorithm::XChaCha20Poly1305`] with the bubble key,
+//! see [`crate::bubble`].
+//!
+//! crypto_box can't authenticate associated data, so the header is encrypted along with the
+//! text and compared after decryption instead. Changing it makes decryption fail.
+
+use crate::DecryptionError;
+use base64::prelude::*;
+use encrypt_internal::{DMEncryption, Nonce};
+
+/// Starts every encrypted message, anything else is plain text
+pub const MAGIC: &str = "prontus-e2e:";
+/// The only version so far, the layout after the version byte depends on it
+pub const VERSION: u8 = 1;
+
+pub type KeyId = [u8; KEY_ID_LEN];
+
+pub(crate) const KEY_ID_LEN: usize = 8;
+const NONCE_LEN: usize = 24;
+const HEADER_LEN: usize = 2 + 2 * KEY_ID_LEN;
+/// Poly1305 tag, the ciphertext is never shorter
+const TAG_LEN: usize = 16;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Algorithm {
+    /// X25519 key agreement with XChaCha20-Poly1305, crypto_box's `ChaChaBox`
+    X25519XChaCha20Poly1305 = 1,
+    /// XChaCha20-Poly1305 with the symmetric key of a bubble
+    XChaCha20Poly1305 = 2,
+}
+
+impl TryFrom<u8> for Algorithm {
+    type Error = DecryptionError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            1 => Ok(Algorithm::X25519XChaCha20Poly1305),
+            2 => Ok(Algorithm::XChaCha20Poly1305),
+            _ => Err(DecryptionError::UnsupportedAlgorithm(value)),
+        }
+    }
+}
+
+/// Identifies a public key without giving it away, the first bytes of its blake3 hash
+pub fn key_id(public_key: &[u8; 32]) -> KeyId {
+    let mut id = [0u8; KEY_ID_LEN];
+    id.copy_from_slice(&blake3::hash(public_key).as_bytes()[..KEY_ID_LEN]);
+    id
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Envelope {
+    pub version: u8,
+    pub algorithm: Algorithm,
+    pub sender: KeyId,
+    pub recipient: KeyId,
+    pub nonce: [u8; NONCE_LEN],
+    pub ciphertext: Vec<u8>,
+}
+
+impl Envelope {
+    /// Whether `text` is meant to be decrypted, it may still be malformed
+    pub fn is_encrypted(text: &str) -> bool {
+        text.trim().starts_with(MAGIC)
+    }
+
+    /// Encrypt the header followed by `data` with `cipher` under a fresh nonce
+    pub(crate) fn seal(
+        algorithm: Algorithm,
+        sender: KeyId,
+        recipient: KeyId,
+        data: &[u8],
+        cipher: impl FnOnce(&[u8], &Nonce) -> encrypt_internal::Result<Vec<u8>>,
+    ) -> Self {
+        let nonce = DMEncryption::generate_random_nonce();
+        let mut envelope = Envelope {
+            version: VERSION,
+            algorithm,
+            sender,
+            recipient,
+            nonce: Default::default(),
+            ciphertext: vec![],
+        };
+        envelope.nonce.copy_from_slice(&nonce);
+        let mut plaintext = envelope.header().to_vec();
+        plaintext.extend_from_slice(data);
+        // Only fails for plaintexts far larger than a message
+        envelope.ciphertext = cipher(&plaintext, &nonce).unwrap();
+        envelope
+    }
+
+    /// Decrypt with `cipher` and check the header that was encrypted along with the data
+    pub(crate) fn open(
+        &self,
+        cipher: impl FnOnce(&[u8], &Nonce) -> encrypt_internal::Result<Vec<u8>>,
+    ) -> Result<Vec<u8>, DecryptionError> {
+        let nonce = DMEncryption::convert_nonce(&self.nonce);
+        let plaintext = cipher(&self.ciphertext, &nonce)?;
+        plaintext
+            .strip_prefix(&self.header()[..])
+            .map(<[u8]>::to_vec)
+            .ok_or(DecryptionError::HeaderMismatch)
+    }
+
+    /// Everything before the nonce, which is also the start of the plaintext
+    pub fn header(&self) -> [u8; HEADER_LEN] {
+        let mut header = [0u8; HEADER_LEN];
+        header[0] = self.version;
+        header[1] = self.algorithm as u8;
+        header[2..2 + KEY_ID_LEN].copy_from_slice(&self.sender);
+        header[2 + KEY_ID_LEN..].copy_from_slice(&self.recipient);
+        header
+    }
+
+    pub fn encode(&self) -> String {
+        let mut data = Vec::with_capacity(HEADER_LEN + NONCE_LEN + self.ciphertext.len());
+        data.extend_from_slice(&self.header());
+        data.extend_from_slice(&self.nonce);
+        data.extend_from_slice(&self.ciphertext);
+        format!("{MAGIC}{}", BASE64_STANDARD.encode(&data))
+    }
+
+    /// Parse the text of a message, without decrypting it
+    pub fn decode(text: &str) -> Result<Self, DecryptionError> {
+        let encoded = text
+            .trim()
+            .strip_prefix(MAGIC)
+            .ok_or(DecryptionError::NotEncrypted)?;
+        let data = BASE64_STANDARD.decode(encoded)?;
+        let version = *data.first().ok_or(DecryptionError::Truncated)?;
+        if version != VERSION {
+            return Err(DecryptionError::UnsupportedVersion(version));
+        }
+        if data.len() < HEADER_LEN + NONCE_LEN + TAG_LEN {
+            return Err(DecryptionError::Truncated);
+        }
+        let algorithm = Algorithm::try_from(data[1])?;
+        let (header, rest) = data.split_at(HEADER_LEN);
+        let (nonce, ciphertext) = rest.split_at(NONCE_LEN);
+        let mut envelope = Envelope {
+            version,
+            algorithm,
+            sender: [0; KEY_ID_LEN],
+            recipient: [0; KEY_ID_LEN],
+            nonce: [0; NONCE_LEN],
+            ciphertext: ciphertext.to_vec(),
+        };
+        envelope.sender.copy_from_slice(&header[2..2 + KEY_ID_LEN]);
+        envelope
+            .recipient
+            .copy_from_slice(&header[2 + KEY_ID_LEN..]);
+        envelope.nonce.copy_from_slice(nonce);
+        Ok(envelope)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode() {
+        let envelope = Envelope {
+            version: VERSION,
+            algorithm: Algorithm::X25519XChaCha20Poly1305,
+            sender: [1; KEY_ID_LEN],
+            recipient: [2; KEY_ID_LEN],
+            nonce: [3; NONCE_LEN],
+            ciphertext: vec![4; TAG_LEN + 5],
+        };
+        assert_eq!(Envelope::decode(&envelope.encode()).unwrap(), envelope);
+
+        assert!(matches!(
+            Envelope::decode("Hello, World!"),
+            Err(DecryptionError::NotEncrypted)
+        ));
+        assert!(matches!(
+            Envelope::decode(MAGIC),
+            Err(DecryptionError::Truncated)
+        ));
+        let short = format!("{MAGIC}{}", BASE64_STANDARD.encode([VERSION, 1, 0]));
+        assert!(matches!(
+            Envelope::decode(&short),
+            Err(DecryptionError::Truncated)
+        ));
+        let future = format!("{MAGIC}{}", BASE64_STANDARD.encode([2; 64]));
+        assert!(matches!(
+            Envelope::decode(&future),
+            Err(DecryptionError::UnsupportedVersion(2))
+        ));
+    }
+}
@@ -15,20 +15,76 @@
     while_true
 )]
 
+mod backup;
+pub mod bubble;
+mod envelope;
 mod retrieval;
+mod trust;
 
-pub use crate::retrieval::PublicLookupService;
-use base64::prelude::*;
-use encrypt_internal::{DMEncryption, load_secret_key};
+pub use crate::backup::{
+    BackupError, DeviceLink, export_backup, import_backup, link_payload, open_backup, seal_backup,
+    seal_link_payload,
+};
+pub use crate::bubble::{BubbleKey, BubbleKeys, CurrentKey, KeyHandout};
+pub use crate::envelope::{Algorithm, Envelope, KeyId, MAGIC, VERSION, key_id};
+pub use crate::retrieval::{
+    DEFAULT_MAX_AGE, DEFAULT_URL, KeyDirectory, LookupBackend, LookupError, PublicLookupService,
+    lookup_key,
+};
+pub use crate::trust::{
+    TrustEntry, TrustError, TrustLevel, TrustStore, fingerprint, safety_number,
+};
+use encrypt_internal::{DMEncryption, load_or_generate_key_pair, load_secret_key};
+pub use encrypt_internal::{KeyPair, use_file_store};
+use log::warn;
 use std::fmt::Display;
 use std::string::FromUtf8Error;
+use std::sync::Arc;
 use thiserror::Error;
 
+#[derive(Debug, Error)]
+pub enum KeyError {
+    #[error("Keyring error: {0}")]
+    KeyringError(#[from] encrypt_internal::keyring::Error),
+    #[error("Lookup error: {0}")]
+    LookupError(#[from] LookupError),
+    #[error("Trust store error: {0}")]
+    TrustError(#[from] TrustError),
+    #[error("User {0} hasn't published a public key")]
+    NotPublished(u64),
+    #[error("The current key of bubble {0} isn't on this device")]
+    MissingBubbleKey(u64),
+}
+
+/// This device's entry for the key directory, its key pair is generated on first use.
+/// Others in `org_id` can send `user_id` encrypted messages once it's published.
+pub fn export_public_key(org_id: u64, user_id: u64) -> Result<KeyDirectory, KeyError> {
+    let key_pair = load_or_generate_key_pair()?;
+    Ok(KeyDirectory::publish(org_id, user_id, &key_pair.public_key))
+}
+
+/// This device's public key, the key pair is generated on first use
+pub fn public_key() -> Result<[u8; 32], KeyError> {
+    Ok(load_or_generate_key_pair()?.public_key)
+}
+
 #[derive(Debug, Error)]
 pub enum DecryptionError {
     Base64Error(#[from] base64::DecodeError),
     Utf8Error(#[from] FromUtf8Error),
     CryptoError(#[from] encrypt_internal::Error),
+    SerdeJsonError(#[from] serde_json::Error),
+    KeyError(#[from] KeyError),
+    /// The message doesn't start with [`MAGIC`], it's plain text
+    NotEncrypted,
+    /// Too short for the header, nonce and tag
+    Truncated,
+    UnsupportedVersion(u8),
+    UnsupportedAlgorithm(u8),
+    /// Encrypted for or by other keys than the ones of this conversation
+    KeyMismatch,
+    /// The header was changed after encryption
+    HeaderMismatch,
 }
 
 impl Display for DecryptionError {
@@ -37,70 +93,110 @@ impl Display for DecryptionError {
             DecryptionError::Base64Error(e) => write!(f, "Base64 error: {}", e),
             DecryptionError::Utf8Error(e) => write!(f, "UTF-8 error: {}", e),
             DecryptionError::CryptoError(e) => write!(f, "Crypto error: {}", e),
+            DecryptionError::SerdeJsonError(e) => write!(f, "Serde json error: {}", e),
+            DecryptionError::KeyError(e) => write!(f, "Key error: {}", e),
+            DecryptionError::NotEncrypted => write!(f, "Not an encrypted message"),
+            DecryptionError::Truncated => write!(f, "Truncated encrypted message"),
+            DecryptionError::UnsupportedVersion(v) => {
+                write!(f, "Unsupported encrypted message version: {}", v)
+            }
+            DecryptionError::UnsupportedAlgorithm(a) => {
+                write!(f, "Unsupported encryption algorithm: {}", a)
+            }
+            DecryptionError::KeyMismatch => write!(f, "Encrypted with other keys"),
+            DecryptionError::HeaderMismatch => write!(f, "Tampered encrypted message header"),
         }
     }
 }
 
 pub struct Encrypt {
     pub dm_encryption: DMEncryption,
-    pub lookup_service: PublicLookupService,
+    pub lookup_service: Arc<PublicLookupService>,
+    /// How far the other user's key can be trusted
+    pub trust: TrustLevel,
 }
 
 impl Encrypt {
-    /// Lookup other user and load current user's secret key
+    /// Lookup other user and load current user's secret key,
+    /// `None` if the other user hasn't published a key.
+    /// Their key is checked against the one first seen in `trust_store`.
     pub async fn new(
-        public_lookup_service: PublicLookupService,
+        lookup_service: Arc<PublicLookupService>,
+        trust_store: &TrustStore,
         org_id: u64,
         user_id: u64,
-    ) -> Option<Self> {
-        let key = public_lookup_service.lookup(org_id, user_id)?;
-        // TODO: handle error
-        let secret_key = load_secret_key().ok()?;
+    ) -> Result<Option<Self>, KeyError> {
+        let Some(key) = lookup_service.lookup(org_id, user_id).await? else {
+            return Ok(None);
+        };
+        let trust = trust_store.check(org_id, user_id, &key)?;
+        if trust == TrustLevel::Changed {
+            warn!(
+                "The public key of user {user_id} in organization {org_id} changed since it was \
+                 first seen, someone may be intercepting messages. Compare safety numbers with \
+                 them before trusting it."
+            );
+        }
+        let secret_key = load_secret_key()?;
         let dm_encryption = DMEncryption::new(secret_key, key);
-        Some(Self {
+        Ok(Some(Self {
             dm_encryption,
-            lookup_service: public_lookup_service,
-        })
+            lookup_service,
+            trust,
+        }))
     }
 
+    /// Key ids of the current user and the other user
+    fn key_ids(&self) -> (KeyId, KeyId) {
+        let dm = &self.dm_encryption;
+        (
+            key_id(&dm.current_user_secret_key.public_key().to_bytes()),
+            key_id(&dm.other_user_public_key.to_bytes()),
+        )
+    }
+
+    /// Encrypt `data` for the other user
+    pub fn seal(&self, data: &[u8]) -> Envelope {
+        let (sender, recipient) = self.key_ids();
+        Envelope::seal(
+            Algorithm::X25519XChaCha20Poly1305,
+            sender,
+            recipient,
+            data,
+            |plaintext, nonce| self.dm_encryption.encrypt(plaintext, nonce),
+        )
+    }
+
+    /// Decrypt an envelope sealed by either side of the conversation
+    pub fn open(&self, envelope: &Envelope) -> Result<Vec<u8>, DecryptionError> {
+        let (current, other) = self.key_ids();
+        let keys = (envelope.sender, envelope.recipient);
+        if envelope.algorithm != Algorithm::X25519XChaCha20Poly1305
+            || keys != (current, other) && keys != (other, current)
+        {
+            return Err(DecryptionError::KeyMismatch);
+        }
+        envelope.open(|ciphertext, nonce| self.dm_encryption.decrypt(ciphertext, nonce))
+    }
+
+    /// Encrypt `data` into an [`Envelope`], encoded to be sent as the text of a message
     pub fn encrypt(&self, data: &str) -> String {
-        let nonce = DMEncryption::generate_random_nonce();
-        let mut encrypted_data = self.dm_encryption.encrypt(data.as_bytes(), &nonce).unwrap();
-        let mut nonce_vec = nonce.to_vec();
-        let initial_len = nonce_vec.len() as u64;
-        let mut data = Vec::with_capacity(initial_len as usize + encrypted_data.len());
-        data.append(&mut initial_len.to_le_bytes().to_vec());
-        data.append(&mut nonce_vec);
-        data.append(&mut encrypted_data);
-        BASE64_STANDARD.encode(&data)
+        self.seal(data.as_bytes()).encode()
     }
 
+    /// Decrypt the text of a message sent by either side of the conversation
     pub fn decrypt(&self, data: &str) -> Result<String, DecryptionError> {
-        let decoded_data = BASE64_STANDARD.decode(data)?;
-        let initial_len = u64::from_le_bytes([
-            decoded_data[0],
-            decoded_data[1],
-            decoded_data[2],
-            decoded_data[3],
-            decoded_data[4],
-            decoded_data[5],
-            decoded_data[6],
-            decoded_data[7],
-        ]);
-        let nonce = decoded_data[8..initial_len as usize + 8].to_vec();
-        let decoded_data = &decoded_data[initial_len as usize + 8..];
-        let nonce = DMEncryption::convert_nonce(&nonce);
-        Ok(String::from_utf8(
-            self.dm_encryption.decrypt(&decoded_data, &nonce)?,
-        )?)
+        let envelope = Envelope::decode(data)?;
+        Ok(String::from_utf8(self.open(&envelope)?)?)
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::Encrypt;
     use crate::retrieval::PublicLookupService;
+    use crate::{DecryptionError, Encrypt, Envelope};
     use encrypt_internal::DMEncryption;
+    use std::sync::Arc;
 
     #[test]
     fn test_validity() {
@@ -111,22 +207,29 @@ mod tests {
                 current_user_keys.secret_key,
                 other_user_keys.public_key,
             ),
-            lookup_service: PublicLookupService {
-                organizations: Default::default(),
-            },
+            lookup_service: Arc::new(PublicLookupService::with_directory(Default::default())),
+            trust: Default::default(),
         };
         let decrypt = Encrypt {
             dm_encryption: DMEncryption::new(
                 other_user_keys.secret_key,
                 current_user_keys.public_key,
             ),
-            lookup_service: PublicLookupService {
-                organizations: Default::default(),
-            },
+            lookup_service: Arc::new(PublicLookupService::with_directory(Default::default())),
+            trust: Default::default(),
         };
         let data = "Hello, World!";
         let encrypted_data = encrypt.encrypt(data);
         let decrypted_data = decrypt.decrypt(&encrypted_data).unwrap();
         assert_eq!(data, decrypted_data);
+        // Your own messages are readable too
+        assert_eq!(encrypt.decrypt(&encrypted_data).unwrap(), data);
+
+        let mut envelope = Envelope::decode(&encrypted_data).unwrap();
+        envelope.recipient = envelope.sender;
+        assert!(matches!(
+            decrypt.decrypt(&envelope.encode()),
+            Err(DecryptionError::KeyMismatch)
+        ));
     }
 }
@@ -1,54 +1,228 @@
 use base64::Engine;
 use base64::prelude::BASE64_STANDARD;
+use log::warn;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{PoisonError, RwLock};
+use std::time::{Duration, Instant};
+use thiserror::Error;
 
-// TODO: get actual url
-static URL: &str = "https://github.com/arihant2math/prontus/";
+/// The public key directory, published with the source
+pub const DEFAULT_URL: &str =
+    "https://raw.githubusercontent.com/arihant2math/prontus/main/keys.json";
+/// How long fetched keys are trusted before the directory is fetched again
+pub const DEFAULT_MAX_AGE: Duration = Duration::from_secs(60 * 60);
+/// How long the expired directory is used after a failed fetch before fetching it again
+const RETRY_AFTER: Duration = Duration::from_secs(60);
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
-struct ServerResponse {
+#[derive(Debug, Error)]
+pub enum LookupError {
+    #[error("Request error: {0}")]
+    RequestError(#[from] reqwest::Error),
+    #[error("IO error: {0}")]
+    IoError(#[from] std::io::Error),
+    #[error("Serde json error: {0}")]
+    SerdeJsonError(#[from] serde_json::Error),
+    #[error("Invalid public key for {0}")]
+    InvalidKey(String),
+}
+
+/// The json file of the lookup service, public keys in base64 keyed by [`lookup_key`]
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct KeyDirectory {
     pub organizations: HashMap<String, String>,
 }
 
+impl KeyDirectory {
+    /// The entry that lets others in `org_id` find the public key of `user_id`,
+    /// to be merged into the published directory
+    pub fn publish(org_id: u64, user_id: u64, public_key: &[u8; 32]) -> Self {
+        Self {
+            organizations: HashMap::from([(
+                lookup_key(org_id, user_id),
+                BASE64_STANDARD.encode(public_key),
+            )]),
+        }
+    }
+}
+
+/// Where the key directory comes from
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LookupBackend {
+    Http(String),
+    /// A file on this machine, to self host or to test without a server
+    File(PathBuf),
+}
+
+impl Default for LookupBackend {
+    fn default() -> Self {
+        LookupBackend::Http(DEFAULT_URL.to_string())
+    }
+}
+
+impl LookupBackend {
+    /// http(s) urls are fetched, `file://` urls and anything else are paths
+    pub fn parse(location: &str) -> Self {
+        if location.starts_with("http://") || location.starts_with("https://") {
+            LookupBackend::Http(location.to_string())
+        } else {
+            let path = location.strip_prefix("file://").unwrap_or(location);
+            LookupBackend::File(PathBuf::from(path))
+        }
+    }
+
+    async fn fetch(&self) -> Result<KeyDirectory, LookupError> {
+        match self {
+            LookupBackend::Http(url) => Ok(reqwest::get(url)
+                .await?
+                .error_for_status()?
+                .json::<KeyDirectory>()
+                .await?),
+            LookupBackend::File(path) => Ok(serde_json::from_slice(&tokio::fs::read(path).await?)?),
+        }
+    }
+}
+
+struct Cached {
+    fetched_at: Instant,
+    /// The last fetch, whether or not it worked
+    tried_at: Instant,
+    directory: KeyDirectory,
+}
+
 /// Just a static json file with the somewhat protected keys for users
 /// It tries to prevent random access of keys from random organizations and discovery by hashing the keys.
-/// Theoretically it should be possible to search to see all users in a given org that use this system, but you need the org id.  
+/// Theoretically it should be possible to search to see all users in a given org that use this system, but you need the org id.
 pub struct PublicLookupService {
-    pub organizations: HashMap<String, String>,
+    backend: LookupBackend,
+    max_age: Duration,
+    cache: RwLock<Option<Cached>>,
 }
 
 impl PublicLookupService {
-    pub async fn fetch() -> Self {
-        let response = reqwest::get(URL)
-            .await
-            .unwrap()
-            .json::<ServerResponse>()
-            .await
-            .unwrap();
+    /// Nothing is fetched until the first lookup
+    pub fn new(backend: LookupBackend, max_age: Duration) -> Self {
         Self {
-            organizations: response.organizations,
+            backend,
+            max_age,
+            cache: RwLock::new(None),
         }
     }
 
-    pub fn lookup(&self, org_id: u64, user_id: u64) -> Option<[u8; 32]> {
-        let key = self.generate_key(org_id, user_id);
-        // TODO: return error if base64 fails.
+    /// A service that knows `directory` and never fetches anything
+    pub fn with_directory(directory: KeyDirectory) -> Self {
+        Self {
+            backend: LookupBackend::default(),
+            max_age: Duration::MAX,
+            cache: RwLock::new(Some(Cached {
+                fetched_at: Instant::now(),
+                tried_at: Instant::now(),
+                directory,
+            })),
+        }
+    }
+
+    /// Fetch the directory again, whether or not the cached one expired
+    pub async fn refresh(&self) -> Result<(), LookupError> {
+        let directory = self.backend.fetch().await?;
+        *self.cache.write().unwrap_or_else(PoisonError::into_inner) = Some(Cached {
+            fetched_at: Instant::now(),
+            tried_at: Instant::now(),
+            directory,
+        });
+        Ok(())
+    }
+
+    /// The public key of `user_id` in `org_id`, `None` if they haven't published one.
+    /// When the directory can't be fetched again, the expired one is used for a while.
+    pub async fn lookup(&self, org_id: u64, user_id: u64) -> Result<Option<[u8; 32]>, LookupError> {
+        let expired = self
+            .cache
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .as_ref()
+            .is_none_or(|cached| {
+                cached.fetched_at.elapsed() >= self.max_age
+                    && cached.tried_at.elapsed() >= RETRY_AFTER
+            });
+        if expired && let Err(e) = self.refresh().await {
+            let mut cache = self.cache.write().unwrap_or_else(PoisonError::into_inner);
+            let Some(cached) = cache.as_mut() else {
+                return Err(e);
+            };
+            warn!(
+                "Failed to fetch the key directory, using the one fetched {}s ago: {e}",
+                cached.fetched_at.elapsed().as_secs()
+            );
+            cached.tried_at = Instant::now();
+        }
+        let key = lookup_key(org_id, user_id);
+        let cache = self.cache.read().unwrap_or_else(PoisonError::into_inner);
+        let Some(encoded) = cache
+            .as_ref()
+            .and_then(|cached| cached.directory.organizations.get(&key))
+        else {
+            return Ok(None);
+        };
         BASE64_STANDARD
-            .decode(self.organizations.get(&key)?)
-            .ok()?
-            .try_into()
+            .decode(encoded)
             .ok()
+            .and_then(|public_key| public_key.try_into().ok())
+            .map(Some)
+            .ok_or(LookupError::InvalidKey(key))
     }
+}
+
+/// Where the key of `user_id` in `org_id` is in the directory, a hash so it can't be enumerated
+pub fn lookup_key(org_id: u64, user_id: u64) -> String {
+    let lookup_bytes = org_id
+        .to_le_bytes()
+        .iter()
+        .chain(user_id.to_le_bytes().iter())
+        .cloned()
+        .collect::<Vec<u8>>();
+    let lookup_hash = blake3::hash(&lookup_bytes);
+    lookup_hash.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_lookup() {
+        let public_key = encrypt_internal::generate_key_pair().public_key;
+        let path = std::env::temp_dir().join(format!("prontus-keys-{}.json", std::process::id()));
+        let directory = KeyDirectory::publish(1, 2, &public_key);
+        std::fs::write(&path, serde_json::to_string(&directory).unwrap()).unwrap();
+
+        let service = PublicLookupService::new(
+            LookupBackend::parse(&format!("file://{}", path.display())),
+            DEFAULT_MAX_AGE,
+        );
+        assert_eq!(service.lookup(1, 2).await.unwrap(), Some(public_key));
+        assert_eq!(service.lookup(1, 3).await.unwrap(), None);
+
+        // Served from the cache until it expires
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(service.lookup(1, 2).await.unwrap(), Some(public_key));
+
+        // And after, while it can't be fetched again
+        std::fs::write(&path, serde_json::to_string(&directory).unwrap()).unwrap();
+        let service = PublicLookupService::new(
+            LookupBackend::parse(&format!("file://{}", path.display())),
+            Duration::ZERO,
+        );
+        assert_eq!(service.lookup(1, 2).await.unwrap(), Some(public_key));
+        std::fs::remove_file(&path).unwrap();
+        let tried_at = || service.cache.read().unwrap().as_ref().unwrap().tried_at;
+        service.cache.write().unwrap().as_mut().unwrap().tried_at -= RETRY_AFTER;
+        assert_eq!(service.lookup(1, 2).await.unwrap(), Some(public_key));
+        assert!(tried_at().elapsed() < RETRY_AFTER);
 
-    pub fn generate_key(&self, org_id: u64, user_id: u64) -> String {
-        let lookup_bytes = org_id
-            .to_le_bytes()
-            .iter()
-            .chain(user_id.to_le_bytes().iter())
-            .cloned()
-            .collect::<Vec<u8>>();
-        let lookup_hash = blake3::hash(&lookup_bytes);
-        lookup_hash.to_string()
+        // Nothing to fall back to
+        let service = PublicLookupService::new(LookupBackend::File(path), Duration::ZERO);
+        assert!(service.lookup(1, 2).await.is_err());
     }
 }
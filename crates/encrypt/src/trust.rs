@@ -0,0 +1,266 @@
+//! Trust on first use for the public keys of others.
+//!
+//! The first key seen for a user is remembered. If the lookup service later serves another
+//! one, the key is marked [`TrustLevel::Changed`] until the user verifies it again, by
+//! comparing [`safety_number`]s with the other person out of band.
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::path::PathBuf;
+use std::sync::{PoisonError, RwLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+use thiserror::Error;
+
+const SAFETY_NUMBER_CONTEXT: &str = "prontus 2024-10 safety number";
+
+#[derive(Debug, Error)]
+pub enum TrustError {
+    #[error("IO error: {0}")]
+    IoError(#[from] std::io::Error),
+    #[error("Serde json error: {0}")]
+    SerdeJsonError(#[from] serde_json::Error),
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TrustLevel {
+    /// Seen before, but nobody compared safety numbers
+    #[default]
+    Unverified,
+    /// The user compared safety numbers with the owner of the key
+    Verified,
+    /// Another key than the one first seen, someone may be in the middle
+    Changed,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TrustEntry {
+    /// The [`fingerprint`] of the key last seen
+    pub fingerprint: String,
+    pub level: TrustLevel,
+    /// Unix timestamp of when the key was first seen
+    pub first_seen: u64,
+    /// The fingerprint before the key changed, until it's verified
+    pub previous: Option<String>,
+}
+
+/// The full blake3 hash of a public key in groups of four hex digits
+pub fn fingerprint(public_key: &[u8; 32]) -> String {
+    blake3::hash(public_key)
+        .to_hex()
+        .as_bytes()
+        .chunks(4)
+        .map(|group| String::from_utf8_lossy(group).into_owned())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// 30 digits for one side of a conversation, six groups of five
+fn safety_digits(user_id: u64, public_key: &[u8; 32]) -> String {
+    let mut input = user_id.to_le_bytes().to_vec();
+    input.extend_from_slice(public_key);
+    let hash = blake3::derive_key(SAFETY_NUMBER_CONTEXT, &input);
+    hash[..30]
+        .chunks(5)
+        .map(|chunk| {
+            let value = chunk.iter().fold(0u64, |value, &b| value << 8 | b as u64);
+            format!("{:05}", value % 100_000)
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// 60 digits both sides of a conversation get, whoever computes them.
+/// They only match if each side has the other's real key.
+pub fn safety_number(first: (u64, &[u8; 32]), second: (u64, &[u8; 32])) -> String {
+    let mut sides = [
+        safety_digits(first.0, first.1),
+        safety_digits(second.0, second.1),
+    ];
+    sides.sort();
+    sides.join(" ")
+}
+
+/// The keys seen so far, kept in a json file
+pub struct TrustStore {
+    path: Option<PathBuf>,
+    entries: RwLock<HashMap<String, TrustEntry>>,
+}
+
+impl TrustStore {
+    /// Read the store at `path`, empty if it doesn't exist yet
+    pub fn open(path: PathBuf) -> Result<Self, TrustError> {
+        let entries = match std::fs::read(&path) {
+            Ok(json) => serde_json::from_slice(&json)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Self {
+            path: Some(path),
+            entries: RwLock::new(entries),
+        })
+    }
+
+    /// A store that forgets everything when dropped
+    pub fn in_memory() -> Self {
+        Self {
+            path: None,
+            entries: RwLock::new(HashMap::new()),
+        }
+    }
+
+    fn key(org_id: u64, user_id: u64) -> String {
+        format!("{org_id}/{user_id}")
+    }
+
+    fn save(&self, entries: &HashMap<String, TrustEntry>) -> Result<(), TrustError> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        // Written next to it and renamed, so a crash never leaves half a store
+        let temporary = path.with_extension("json.tmp");
+        std::fs::write(&temporary, serde_json::to_vec_pretty(entries)?)?;
+        std::fs::rename(temporary, path)?;
+        Ok(())
+    }
+
+    pub fn entry(&self, org_id: u64, user_id: u64) -> Option<TrustEntry> {
+        self.entries
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(&Self::key(org_id, user_id))
+            .cloned()
+    }
+
+    /// Remember `public_key` the first time it's seen for `user_id` and compare it after
+    pub fn check(
+        &self,
+        org_id: u64,
+        user_id: u64,
+        public_key: &[u8; 32],
+    ) -> Result<TrustLevel, TrustError> {
+        let fingerprint = fingerprint(public_key);
+        let mut entries = self.entries.write().unwrap_or_else(PoisonError::into_inner);
+        let level = match entries.entry(Self::key(org_id, user_id)) {
+            Entry::Occupied(mut entry) => {
+                let entry = entry.get_mut();
+                if entry.fingerprint == fingerprint {
+                    return Ok(entry.level);
+                }
+                entry.previous = Some(std::mem::replace(&mut entry.fingerprint, fingerprint));
+                entry.level = TrustLevel::Changed;
+                entry.level
+            }
+            Entry::Vacant(entry) => {
+                let first_seen = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |since| since.as_secs());
+                entry.insert(TrustEntry {
+                    fingerprint,
+                    level: TrustLevel::Unverified,
+                    first_seen,
+                    previous: None,
+                });
+                TrustLevel::Unverified
+            }
+        };
+        self.save(&entries)?;
+        Ok(level)
+    }
+
+    /// Mark the key of `user_id` verified, if it's still the one with `fingerprint`.
+    /// Returns whether it was.
+    pub fn verify(&self, org_id: u64, user_id: u64, fingerprint: &str) -> Result<bool, TrustError> {
+        self.set_level(org_id, user_id, fingerprint, TrustLevel::Verified)
+    }
+
+    /// Take back a verification, the key is trusted as on first use again.
+    /// Only verified keys can be, a changed one stays changed until it's verified.
+    pub fn unverify(
+        &self,
+        org_id: u64,
+        user_id: u64,
+        fingerprint: &str,
+    ) -> Result<bool, TrustError> {
+        self.set_level(org_id, user_id, fingerprint, TrustLevel::Unverified)
+    }
+
+    fn set_level(
+        &self,
+        org_id: u64,
+        user_id: u64,
+        fingerprint: &str,
+        level: TrustLevel,
+    ) -> Result<bool, TrustError> {
+        let mut entries = self.entries.write().unwrap_or_else(PoisonError::into_inner);
+        let Some(entry) = entries.get_mut(&Self::key(org_id, user_id)) else {
+            return Ok(false);
+        };
+        if entry.fingerprint != fingerprint {
+            return Ok(false);
+        }
+        // Unverifying a changed key would accept it without anyone looking at it
+        if level == TrustLevel::Unverified && entry.level != TrustLevel::Verified {
+            return Ok(false);
+        }
+        entry.level = level;
+        entry.previous = None;
+        self.save(&entries)?;
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_trust_on_first_use() {
+        let store = TrustStore::in_memory();
+        let key = encrypt_internal::generate_key_pair().public_key;
+        let other = encrypt_internal::generate_key_pair().public_key;
+
+        assert_eq!(store.check(1, 2, &key).unwrap(), TrustLevel::Unverified);
+        assert!(store.verify(1, 2, &fingerprint(&key)).unwrap());
+        assert_eq!(store.check(1, 2, &key).unwrap(), TrustLevel::Verified);
+
+        assert_eq!(store.check(1, 2, &other).unwrap(), TrustLevel::Changed);
+        let entry = store.entry(1, 2).unwrap();
+        assert_eq!(entry.previous, Some(fingerprint(&key)));
+        // Stays changed until the new key is verified
+        assert_eq!(store.check(1, 2, &other).unwrap(), TrustLevel::Changed);
+        assert!(!store.verify(1, 2, &fingerprint(&key)).unwrap());
+        assert!(store.verify(1, 2, &fingerprint(&other)).unwrap());
+        assert_eq!(store.check(1, 2, &other).unwrap(), TrustLevel::Verified);
+    }
+
+    #[test]
+    fn test_unverify() {
+        let store = TrustStore::in_memory();
+        let key = encrypt_internal::generate_key_pair().public_key;
+        let other = encrypt_internal::generate_key_pair().public_key;
+
+        store.check(1, 2, &key).unwrap();
+        assert!(store.verify(1, 2, &fingerprint(&key)).unwrap());
+        assert!(store.unverify(1, 2, &fingerprint(&key)).unwrap());
+        assert_eq!(store.check(1, 2, &key).unwrap(), TrustLevel::Unverified);
+
+        // A changed key isn't accepted by unverifying it
+        assert_eq!(store.check(1, 2, &other).unwrap(), TrustLevel::Changed);
+        assert!(!store.unverify(1, 2, &fingerprint(&other)).unwrap());
+        assert_eq!(store.check(1, 2, &other).unwrap(), TrustLevel::Changed);
+        assert_eq!(store.entry(1, 2).unwrap().previous, Some(fingerprint(&key)));
+    }
+
+    #[test]
+    fn test_safety_number() {
+        let alice = encrypt_internal::generate_key_pair().public_key;
+        let bob = encrypt_internal::generate_key_pair().public_key;
+        let number = safety_number((1, &alice), (2, &bob));
+        assert_eq!(number, safety_number((2, &bob), (1, &alice)));
+        assert_eq!(number.split(' ').count(), 12);
+        assert!(number.split(' ').all(|group| group.len() == 5));
+        assert_ne!(number, safety_number((1, &alice), (2, &alice)));
+    }
+}
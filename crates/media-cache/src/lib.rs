@@ -0,0 +1,534 @@
+//! On disk cache for media served by pronto, like message images and profile pictures.
+//!
+//! Files are stored content addressed under `blobs/`, so the same image posted in several
+//! places is only kept once, and `index.json` maps urls to blobs.
+//! Once the blobs outgrow the size limit the least recently used entries are evicted.
+//!
+//! Only urls on the api or a pronto file host are downloaded with the given account's client,
+//! anything else is fetched without credentials so a link can't make us leak the token.
+
+use client::ProntoClient;
+use image::ImageFormat;
+use log::warn;
+use reqwest::header::CONTENT_TYPE;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::io::{self, Cursor};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use thiserror::Error;
+use tokio::fs;
+use tokio::io::AsyncWriteExt;
+
+const INDEX_FILE: &str = "index.json";
+const BLOB_DIR: &str = "blobs";
+/// Where pronto serves attachments and profile pictures from, besides the api itself
+const FILE_HOSTS: &[&str] = &["files.chat.trypronto.com"];
+/// Thumbnails are only made in these sizes, other sizes round up to the next one
+pub const THUMBNAIL_SIZES: [u32; 3] = [32, 64, 256];
+
+#[derive(Debug, Error)]
+pub enum CacheError {
+    #[error("IO error: {0}")]
+    Io(#[from] io::Error),
+    #[error("Request error: {0}")]
+    Request(#[from] reqwest::Error),
+    #[error("JSON error: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("Image error: {0}")]
+    Image(#[from] image::ImageError),
+    #[error("{0} is not an image")]
+    NotAnImage(String),
+    #[error("{0} is not an http url")]
+    InvalidUrl(String),
+    #[error("{0} is larger than the whole cache")]
+    TooLarge(String),
+}
+
+/// A file in the cache, `path` stays valid until it is evicted.
+#[derive(Clone, Debug)]
+pub struct CachedMedia {
+    pub path: PathBuf,
+    /// Hex encoded sha256 of the content
+    pub hash: String,
+    pub mime_type: String,
+    pub size: u64,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct Entry {
+    hash: String,
+    mime_type: String,
+    size: u64,
+    last_used: u64,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Index {
+    /// Keyed by url, or by `thumbnail:{size}:{hash}` for thumbnails
+    entries: HashMap<String, Entry>,
+    /// Bumped on every use, the entries with the lowest `last_used` are evicted first
+    clock: u64,
+}
+
+impl Index {
+    fn touch(&mut self, key: &str) -> Option<Entry> {
+        let entry = self.entries.get_mut(key)?;
+        self.clock += 1;
+        entry.last_used = self.clock;
+        Some(entry.clone())
+    }
+
+    fn insert(&mut self, key: String, mut entry: Entry) {
+        self.clock += 1;
+        entry.last_used = self.clock;
+        self.entries.insert(key, entry);
+    }
+
+    /// How many entries point at each blob
+    fn references(&self) -> HashMap<&str, (usize, u64)> {
+        let mut references = HashMap::new();
+        for entry in self.entries.values() {
+            references
+                .entry(entry.hash.as_str())
+                .or_insert((0, entry.size))
+                .0 += 1;
+        }
+        references
+    }
+
+    /// Size of the blobs on disk, a blob shared by several entries counts once
+    fn size(&self) -> u64 {
+        self.references().values().map(|(_, size)| size).sum()
+    }
+
+    /// Drop the least recently used entries other than `keep` until the blobs fit in `max_size`,
+    /// returning the hashes of the blobs no entry points at anymore.
+    fn evict(&mut self, max_size: u64, keep: &str) -> Vec<String> {
+        let mut size = self.size();
+        if size <= max_size {
+            return Vec::new();
+        }
+        let mut references: HashMap<String, usize> = self
+            .references()
+            .into_iter()
+            .map(|(hash, (count, _))| (hash.to_string(), count))
+            .collect();
+        let mut lru: Vec<(u64, String)> = self
+            .entries
+            .iter()
+            .filter(|(key, _)| *key != keep)
+            .map(|(key, entry)| (entry.last_used, key.clone()))
+            .collect();
+        lru.sort_unstable();
+        let mut unreferenced = Vec::new();
+        for (_, key) in lru {
+            if size <= max_size {
+                break;
+            }
+            let entry = self.entries.remove(&key).unwrap();
+            let count = references.get_mut(&entry.hash).unwrap();
+            *count -= 1;
+            if *count == 0 {
+                size -= entry.size;
+                unreferenced.push(entry.hash);
+            }
+        }
+        unreferenced
+    }
+}
+
+pub struct MediaCache {
+    /// For urls off pronto, carries no token or cookies
+    anonymous: reqwest::Client,
+    dir: PathBuf,
+    max_size: u64,
+    index: Mutex<Index>,
+    /// Held while writing the index so an older snapshot never overwrites a newer one
+    persist: tokio::sync::Mutex<()>,
+    /// Names downloads in progress
+    downloads: AtomicU64,
+}
+
+impl MediaCache {
+    /// Open the cache in `dir`, creating it if needed.
+    /// One cache serves every account, the client to download with is passed per request.
+    pub async fn open(dir: impl Into<PathBuf>, max_size: u64) -> Result<Self, CacheError> {
+        let dir = dir.into();
+        fs::create_dir_all(dir.join(BLOB_DIR)).await?;
+        let mut index = match fs::read(dir.join(INDEX_FILE)).await {
+            Ok(data) => serde_json::from_slice(&data).unwrap_or_else(|e| {
+                warn!("Discarding unreadable media cache index: {e}");
+                Index::default()
+            }),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Index::default(),
+            Err(e) => return Err(e.into()),
+        };
+        // Downloads cut short by a crash or a failed request
+        let mut blobs = fs::read_dir(dir.join(BLOB_DIR)).await?;
+        while let Some(file) = blobs.next_entry().await? {
+            if file.path().extension().is_some_and(|ext| ext == "partial") {
+                let _ = fs::remove_file(file.path()).await;
+            }
+        }
+        // Blobs may have been deleted from under us
+        index
+            .entries
+            .retain(|_, entry| blob_path(&dir, &entry.hash).exists());
+        Ok(Self {
+            anonymous: reqwest::Client::new(),
+            dir,
+            max_size,
+            index: Mutex::new(index),
+            persist: tokio::sync::Mutex::new(()),
+            downloads: AtomicU64::new(0),
+        })
+    }
+
+    /// Total size of the cached files in bytes
+    pub fn size(&self) -> u64 {
+        self.index.lock().unwrap().size()
+    }
+
+    /// Look up a url without going to the network.
+    pub fn cached(&self, url: &str) -> Option<CachedMedia> {
+        let entry = self.index.lock().unwrap().touch(url)?;
+        Some(self.media(entry))
+    }
+
+    /// Whether `url` is pronto's, so it may be sent the credentials of `client`
+    fn is_pronto(client: &ProntoClient, url: &reqwest::Url) -> bool {
+        let api = reqwest::Url::parse(&client.api_base_url).ok();
+        api.is_some_and(|api| api.origin() == url.origin())
+            || (url.scheme() == "https"
+                && url.port().is_none()
+                && url
+                    .host_str()
+                    .is_some_and(|host| FILE_HOSTS.contains(&host)))
+    }
+
+    /// Get a url from the cache, downloading it with `client` on a miss.
+    /// Files that need authentication can be cached too, if they're on pronto.
+    pub async fn get(&self, client: &ProntoClient, url: &str) -> Result<CachedMedia, CacheError> {
+        if let Some(media) = self.cached(url) {
+            return Ok(media);
+        }
+        let parsed = reqwest::Url::parse(url)
+            .ok()
+            .filter(|parsed| matches!(parsed.scheme(), "http" | "https"))
+            .ok_or_else(|| CacheError::InvalidUrl(url.to_string()))?;
+        let response = if Self::is_pronto(client, &parsed) {
+            client.http_client.get(parsed).send().await?
+        } else {
+            self.anonymous.get(parsed).send().await?
+        };
+        let mut response = response.error_for_status()?;
+        let mime_type = response
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or("application/octet-stream")
+            .to_string();
+        // Streamed to disk, videos can be far larger than we'd want in memory
+        let partial = self.partial_path();
+        let download = async {
+            let mut file = fs::File::create(&partial).await?;
+            let mut hasher = Sha256::new();
+            let mut size = 0;
+            while let Some(chunk) = response.chunk().await? {
+                hasher.update(&chunk);
+                size += chunk.len() as u64;
+                // It would only be evicted again, or push everything else out
+                if size > self.max_size {
+                    return Err(CacheError::TooLarge(url.to_string()));
+                }
+                file.write_all(&chunk).await?;
+            }
+            file.flush().await?;
+            Ok::<_, CacheError>((format!("{:x}", hasher.finalize()), size))
+        };
+        let (hash, size) = match download.await {
+            Ok(downloaded) => downloaded,
+            Err(e) => {
+                let _ = fs::remove_file(&partial).await;
+                return Err(e);
+            }
+        };
+        self.store(url.to_string(), mime_type, hash, size, &partial)
+            .await
+    }
+
+    /// Get a thumbnail of an image that fits in a `size` by `size` box, keeping the aspect ratio.
+    /// `size` is rounded up to one of [`THUMBNAIL_SIZES`], images already that small are kept as is.
+    pub async fn thumbnail(
+        &self,
+        client: &ProntoClient,
+        url: &str,
+        size: u32,
+    ) -> Result<CachedMedia, CacheError> {
+        let size = THUMBNAIL_SIZES
+            .into_iter()
+            .find(|allowed| *allowed >= size)
+            .unwrap_or(THUMBNAIL_SIZES[THUMBNAIL_SIZES.len() - 1]);
+        let original = self.get(client, url).await?;
+        if !original.mime_type.starts_with("image/") {
+            return Err(CacheError::NotAnImage(url.to_string()));
+        }
+        let key = format!("thumbnail:{size}:{}", original.hash);
+        if let Some(media) = self.cached(&key) {
+            return Ok(media);
+        }
+        let data = fs::read(&original.path).await?;
+        let (mime_type, thumbnail) =
+            tokio::task::spawn_blocking(move || make_thumbnail(&data, size))
+                .await
+                .expect("thumbnail task panicked")?;
+        let partial = self.partial_path();
+        fs::write(&partial, &thumbnail).await?;
+        let hash = format!("{:x}", Sha256::digest(&thumbnail));
+        self.store(
+            key,
+            mime_type.to_string(),
+            hash,
+            thumbnail.len() as u64,
+            &partial,
+        )
+        .await
+    }
+
+    /// Remove everything from the cache.
+    pub async fn clear(&self) -> Result<(), CacheError> {
+        let _persist = self.persist.lock().await;
+        *self.index.lock().unwrap() = Index::default();
+        fs::remove_dir_all(self.dir.join(BLOB_DIR)).await?;
+        fs::create_dir_all(self.dir.join(BLOB_DIR)).await?;
+        self.write_index().await
+    }
+
+    /// A fresh file to write a blob to before its hash is known
+    fn partial_path(&self) -> PathBuf {
+        let download = self.downloads.fetch_add(1, Ordering::Relaxed);
+        self.dir
+            .join(BLOB_DIR)
+            .join(format!("{}-{download}.partial", std::process::id()))
+    }
+
+    /// Move the finished `partial` file into place as the blob with `hash`.
+    /// Renamed so a crash never leaves a truncated blob under its hash.
+    /// All under the persist lock, so eviction can't remove the blob before it's in the index.
+    async fn store(
+        &self,
+        key: String,
+        mime_type: String,
+        hash: String,
+        size: u64,
+        partial: &Path,
+    ) -> Result<CachedMedia, CacheError> {
+        let _persist = self.persist.lock().await;
+        let path = blob_path(&self.dir, &hash);
+        if fs::try_exists(&path).await? {
+            fs::remove_file(partial).await?;
+        } else {
+            fs::create_dir_all(path.parent().unwrap()).await?;
+            fs::rename(partial, &path).await?;
+        }
+        let entry = Entry {
+            hash,
+            mime_type,
+            size,
+            last_used: 0,
+        };
+        let media = self.media(entry.clone());
+        let unreferenced = {
+            let mut index = self.index.lock().unwrap();
+            index.insert(key.clone(), entry);
+            index.evict(self.max_size, &key)
+        };
+        for hash in unreferenced {
+            if let Err(e) = fs::remove_file(blob_path(&self.dir, &hash)).await {
+                warn!("Failed to remove evicted media {hash}: {e}");
+            }
+        }
+        self.write_index().await?;
+        Ok(media)
+    }
+
+    /// Uses are only persisted along with the next write
+    async fn write_index(&self) -> Result<(), CacheError> {
+        let data = serde_json::to_vec(&*self.index.lock().unwrap())?;
+        let path = self.dir.join(INDEX_FILE);
+        let partial = path.with_extension("partial");
+        fs::write(&partial, data).await?;
+        fs::rename(&partial, &path).await?;
+        Ok(())
+    }
+
+    fn media(&self, entry: Entry) -> CachedMedia {
+        CachedMedia {
+            path: blob_path(&self.dir, &entry.hash),
+            hash: entry.hash,
+            mime_type: entry.mime_type,
+            size: entry.size,
+        }
+    }
+}
+
+/// Blobs are spread over directories named after the first byte of their hash
+fn blob_path(dir: &Path, hash: &str) -> PathBuf {
+    dir.join(BLOB_DIR).join(&hash[..2]).join(hash)
+}
+
+/// Photos come out much smaller as jpeg, anything with transparency stays png
+fn make_thumbnail(data: &[u8], size: u32) -> Result<(&'static str, Vec<u8>), image::ImageError> {
+    let image = image::load_from_memory(data)?;
+    // Never scaled up, that would only make it larger
+    let image = if image.width() <= size && image.height() <= size {
+        image
+    } else {
+        image.thumbnail(size, size)
+    };
+    let mut output = Cursor::new(Vec::new());
+    if image.color().has_alpha() {
+        image.write_to(&mut output, ImageFormat::Png)?;
+        Ok(("image/png", output.into_inner()))
+    } else {
+        image.to_rgb8().write_to(&mut output, ImageFormat::Jpeg)?;
+        Ok(("image/jpeg", output.into_inner()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{DynamicImage, RgbImage};
+    use mock_server::MockServer;
+
+    async fn setup(max_size: u64) -> (MockServer, ProntoClient, MediaCache, tempfile::TempDir) {
+        let server = MockServer::start().await.unwrap();
+        let user = server.store().await.add_user("Media", "Cache");
+        let client = server.client(user.id);
+        let dir = tempfile::tempdir().unwrap();
+        let cache = MediaCache::open(dir.path(), max_size).await.unwrap();
+        (server, client, cache, dir)
+    }
+
+    async fn add_file(server: &MockServer, name: &str, mime_type: &str, data: Vec<u8>) -> String {
+        let key = server.store().await.add_file(name, mime_type, data);
+        format!("{}files/{key}", server.api_base_url())
+    }
+
+    #[tokio::test]
+    async fn test_cache_hits_offline() {
+        let (server, client, cache, dir) = setup(1024).await;
+        let first = add_file(&server, "a.txt", "text/plain", b"hello".to_vec()).await;
+        let second = add_file(&server, "b.txt", "text/plain", b"hello".to_vec()).await;
+
+        let media = cache.get(&client, &first).await.unwrap();
+        assert_eq!(media.mime_type, "text/plain");
+        assert_eq!(fs::read(&media.path).await.unwrap(), b"hello");
+        // Same content, one blob
+        assert_eq!(cache.get(&client, &second).await.unwrap().hash, media.hash);
+        assert_eq!(cache.size(), 5);
+
+        // Every request would fail now, hits must not touch the network
+        server.fail_next(100, 500, None);
+        assert_eq!(cache.get(&client, &first).await.unwrap().hash, media.hash);
+
+        drop(cache);
+        let reopened = MediaCache::open(dir.path(), 1024).await.unwrap();
+        assert!(reopened.cached(&second).is_some());
+    }
+
+    #[tokio::test]
+    async fn test_eviction() {
+        let (server, client, cache, dir) = setup(25).await;
+        let mut urls = Vec::new();
+        for i in 0..3 {
+            let data = vec![i; 10];
+            urls.push(add_file(&server, "file", "application/octet-stream", data).await);
+        }
+        let oldest = cache.get(&client, &urls[0]).await.unwrap();
+        cache.get(&client, &urls[1]).await.unwrap();
+        // Using the oldest makes the second one least recently used
+        cache.get(&client, &urls[0]).await.unwrap();
+        cache.get(&client, &urls[2]).await.unwrap();
+
+        assert!(cache.cached(&urls[0]).is_some());
+        assert!(cache.cached(&urls[1]).is_none());
+        assert!(cache.cached(&urls[2]).is_some());
+        assert_eq!(cache.size(), 20);
+        assert!(oldest.path.exists());
+
+        // Larger than the whole cache, the download is given up on and nothing is left behind
+        let large = add_file(&server, "large", "application/octet-stream", vec![0; 30]).await;
+        assert!(matches!(
+            cache.get(&client, &large).await,
+            Err(CacheError::TooLarge(_))
+        ));
+        let mut blobs = fs::read_dir(dir.path().join(BLOB_DIR)).await.unwrap();
+        while let Some(file) = blobs.next_entry().await.unwrap() {
+            assert!(file.path().extension().is_none_or(|ext| ext != "partial"));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_credentials_stay_on_pronto() {
+        let (server, client, cache, _dir) = setup(1024).await;
+        let api = reqwest::Url::parse(&server.api_base_url()).unwrap();
+        assert!(MediaCache::is_pronto(
+            &client,
+            &api.join("files/a").unwrap()
+        ));
+        let file_host = "https://files.chat.trypronto.com/files/users/1/profilepic";
+        assert!(MediaCache::is_pronto(
+            &client,
+            &reqwest::Url::parse(file_host).unwrap()
+        ));
+        for url in [
+            "https://attacker.example/",
+            "http://files.chat.trypronto.com/files/a",
+            "https://files.chat.trypronto.com.attacker.example/",
+        ] {
+            assert!(!MediaCache::is_pronto(
+                &client,
+                &reqwest::Url::parse(url).unwrap()
+            ));
+        }
+        assert!(matches!(
+            cache.get(&client, "file:///etc/passwd").await,
+            Err(CacheError::InvalidUrl(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_thumbnail() {
+        let (server, client, cache, _dir) = setup(1 << 20).await;
+        let mut png = Cursor::new(Vec::new());
+        DynamicImage::ImageRgb8(RgbImage::new(64, 32))
+            .write_to(&mut png, ImageFormat::Png)
+            .unwrap();
+        let image = add_file(&server, "a.png", "image/png", png.into_inner()).await;
+        let text = add_file(&server, "a.txt", "text/plain", b"hello".to_vec()).await;
+
+        // Rounded up to 32
+        let thumbnail = cache.thumbnail(&client, &image, 16).await.unwrap();
+        assert_eq!(thumbnail.mime_type, "image/jpeg");
+        let decoded = image::load_from_memory(&fs::read(&thumbnail.path).await.unwrap()).unwrap();
+        assert_eq!((decoded.width(), decoded.height()), (32, 16));
+        assert_eq!(
+            cache.thumbnail(&client, &image, 32).await.unwrap().hash,
+            thumbnail.hash
+        );
+        // Capped at 256, and not scaled up
+        let large = cache.thumbnail(&client, &image, 100_000).await.unwrap();
+        let decoded = image::load_from_memory(&fs::read(&large.path).await.unwrap()).unwrap();
+        assert_eq!((decoded.width(), decoded.height()), (64, 32));
+
+        assert!(matches!(
+            cache.thumbnail(&client, &text, 16).await,
+            Err(CacheError::NotAnImage(_))
+        ));
+    }
+}
@@ -0,0 +1,738 @@
+use crate::store::Store;
+use crate::{AppState, auth_signature};
+use axum::body::Bytes;
+use axum::extract::{FromRequestParts, Path, Query, Request, State};
+use axum::http::header::{CONTENT_TYPE, HOST, RETRY_AFTER};
+use axum::http::request::Parts;
+use axum::http::{HeaderMap, HeaderValue, StatusCode};
+use axum::middleware::{self, Next};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post, put};
+use axum::{Json, Router};
+use client::bubble_create::{PostBubbleCreateRequest, PostBubbleCreateResponse};
+use client::bubble_history::GetBubbleHistoryResponse;
+use client::bubble_info::{GetBubbleInfoRequest, GetBubbleInfoResponse};
+use client::bubble_list::GetBubbleListResponse;
+use client::bubble_mark::{PostBubbleMarkRequest, PostBubbleMarkResponse};
+use client::bubble_membership_search::PostBubbleMembershipSearchResponse;
+use client::dm_create::{PostDMCreateRequest, PostDMCreateResponse};
+use client::files::{PutFileResponse, PutFileResponseData};
+use client::files_normalized::{
+    GetFileNormalizedResponse, GetFileNormalizedResponseData, NormalizedFile,
+};
+use client::message_create::NewMessageMedia;
+use client::message_delete::DeleteMessageResponse;
+use client::message_edit::MessageEditRequest;
+use client::message_search::{
+    MessageSearchBubble, MessageSearchResult, PostMessageSearchRequest, PostMessageSearchResponse,
+};
+use client::pusher_auth::{PusherAuthRequest, PusherAuthResponse};
+use client::reaction_add::ReactionModifyRequest;
+use client::task_complete::{PostTaskCompleteRequest, PostTaskResponse};
+use client::task_list::{PostTaskListRequest, PostTaskListResponse};
+use client::user_info::{GetUserInfoRequest, GetUserInfoResponse};
+use client::user_search::{GetUserSearchCursors, GetUserSearchResponse};
+use client::{
+    Member, MessageMedia, MessageModifyResponse, PostBubbleMembershipSearchRequest, TaskInfo,
+};
+use serde::Deserialize;
+use serde_json::json;
+use std::collections::HashSet;
+use std::sync::Arc;
+
+/// Page size of `bubble.history`, same as pronto
+const PAGE_SIZE: usize = 50;
+/// Page size of `bubble.membershipsearch`
+const MEMBERSHIP_PAGE_SIZE: usize = 30;
+
+type ApiResult<T> = Result<Json<T>, ApiError>;
+
+/// Error responses in the shape pronto uses, so the client parses them as [`client::APIError`]
+pub(crate) struct ApiError(StatusCode, &'static str);
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        (self.0, Json(json!({ "ok": false, "error": self.1 }))).into_response()
+    }
+}
+
+const NOT_FOUND: ApiError = ApiError(StatusCode::NOT_FOUND, "NOT_FOUND");
+const FORBIDDEN: ApiError = ApiError(StatusCode::FORBIDDEN, "FORBIDDEN");
+const BAD_REQUEST: ApiError = ApiError(StatusCode::BAD_REQUEST, "BAD_REQUEST");
+const UNAUTHORIZED: ApiError = ApiError(StatusCode::UNAUTHORIZED, "UNAUTHENTICATED");
+
+/// The user the bearer token belongs to
+pub(crate) struct CurrentUser(u64);
+
+impl FromRequestParts<Arc<AppState>> for CurrentUser {
+    type Rejection = ApiError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &Arc<AppState>,
+    ) -> Result<Self, Self::Rejection> {
+        let user_id = parts
+            .headers
+            .get("Authorization")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer mock-token-"))
+            .and_then(|id| id.parse().ok())
+            .ok_or(UNAUTHORIZED)?;
+        if !state.store.read().await.users.contains_key(&user_id) {
+            return Err(UNAUTHORIZED);
+        }
+        Ok(Self(user_id))
+    }
+}
+
+/// Serve failures queued with [`crate::MockServer::fail_next`] before reaching any handler
+async fn inject_failures(
+    State(state): State<Arc<AppState>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let failure = state.failures.lock().unwrap().pop_front();
+    let Some(failure) = failure else {
+        return next.run(request).await;
+    };
+    let status = StatusCode::from_u16(failure.status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+    let mut response = (
+        status,
+        Json(json!({ "ok": false, "error": "INJECTED_FAILURE" })),
+    )
+        .into_response();
+    if let Some(retry_after) = failure.retry_after {
+        response
+            .headers_mut()
+            .insert(RETRY_AFTER, HeaderValue::from(retry_after));
+    }
+    response
+}
+
+pub(crate) fn router(state: Arc<AppState>) -> Router<Arc<AppState>> {
+    Router::new()
+        .route("/v1/user.info", get(user_info))
+        .route("/clients/users/search", get(user_search))
+        .route("/v3/bubble.list", get(bubble_list))
+        .route("/v2/bubble.info", get(bubble_info))
+        .route("/v1/bubble.history", get(bubble_history))
+        .route("/v1/bubble.create", post(bubble_create))
+        .route("/v1/bubble.mark", post(bubble_mark))
+        .route(
+            "/v1/bubble.membershipsearch",
+            post(bubble_membership_search),
+        )
+        .route("/v1/dm.create", post(dm_create))
+        .route("/v1/message.create", post(message_create))
+        .route("/v1/message.edit", post(message_edit))
+        .route("/v1/message.delete", post(message_delete))
+        .route("/v1/message.search", post(message_search))
+        .route("/v1/message.addreaction", post(reaction_add))
+        .route("/v1/message.removereaction", post(reaction_remove))
+        .route("/v1/task.list", post(task_list))
+        .route("/v1/task.create", post(task_create))
+        .route("/v1/task.complete", post(task_complete))
+        .route("/v1/task.uncomplete", post(task_uncomplete))
+        .route("/v1/pusher.auth", post(pusher_auth))
+        .route("/files", put(files))
+        .route("/files/{key}", get(file_download))
+        .route("/clients/files/{key}/normalized", get(file_normalized))
+        .fallback(async || ApiError(StatusCode::NOT_FOUND, "NOT_IMPLEMENTED"))
+        .layer(middleware::from_fn_with_state(state, inject_failures))
+}
+
+/// Check the bubble exists and the user is a member of it
+fn member_bubble(store: &Store, user_id: u64, bubble_id: u64) -> Result<(), ApiError> {
+    if !store.bubbles.contains_key(&bubble_id) {
+        return Err(NOT_FOUND);
+    }
+    if !store.is_member(bubble_id, user_id) {
+        return Err(FORBIDDEN);
+    }
+    Ok(())
+}
+
+async fn user_info(
+    State(state): State<Arc<AppState>>,
+    CurrentUser(user_id): CurrentUser,
+    body: Bytes,
+) -> ApiResult<GetUserInfoResponse> {
+    // The client sends the id as a json body even though this is a GET
+    let id = serde_json::from_slice::<GetUserInfoRequest>(&body)
+        .ok()
+        .and_then(|request| request.id)
+        .unwrap_or(user_id);
+    let store = state.store.read().await;
+    let user = store.users.get(&id).cloned().ok_or(NOT_FOUND)?;
+    Ok(Json(GetUserInfoResponse { ok: true, user }))
+}
+
+#[derive(Deserialize)]
+struct UserSearchQuery {
+    #[serde(rename = "page[size]")]
+    page_size: usize,
+    #[serde(rename = "filter[query]", default)]
+    query: String,
+    /// The id of the last user of the previous page
+    cursor: Option<u64>,
+}
+
+async fn user_search(
+    State(state): State<Arc<AppState>>,
+    CurrentUser(_): CurrentUser,
+    Query(query): Query<UserSearchQuery>,
+) -> ApiResult<GetUserSearchResponse> {
+    let store = state.store.read().await;
+    let needle = query.query.to_lowercase();
+    let mut data: Vec<_> = store
+        .users
+        .values()
+        .filter(|user| query.cursor.is_none_or(|cursor| user.id > cursor))
+        .filter(|user| user.fullname.to_lowercase().contains(&needle))
+        .take(query.page_size + 1)
+        .cloned()
+        .collect();
+    let next = if data.len() > query.page_size {
+        data.truncate(query.page_size);
+        data.last().map(|user| user.id.to_string())
+    } else {
+        None
+    };
+    Ok(Json(GetUserSearchResponse {
+        data,
+        cursors: GetUserSearchCursors { prev: None, next },
+    }))
+}
+
+async fn bubble_list(
+    State(state): State<Arc<AppState>>,
+    CurrentUser(user_id): CurrentUser,
+) -> ApiResult<GetBubbleListResponse> {
+    let store = state.store.read().await;
+    let memberships: Vec<_> = store
+        .memberships
+        .iter()
+        .filter(|m| m.user_id == user_id)
+        .cloned()
+        .collect();
+    Ok(Json(GetBubbleListResponse {
+        ok: true,
+        bubbles: memberships
+            .iter()
+            .filter_map(|m| store.bubbles.get(&m.bubble_id).cloned())
+            .collect(),
+        stats: memberships
+            .iter()
+            .map(|m| store.stats(m.bubble_id, user_id))
+            .collect(),
+        memberships,
+    }))
+}
+
+async fn bubble_info(
+    State(state): State<Arc<AppState>>,
+    CurrentUser(user_id): CurrentUser,
+    Query(request): Query<GetBubbleInfoRequest>,
+) -> ApiResult<GetBubbleInfoResponse> {
+    let store = state.store.read().await;
+    member_bubble(&store, user_id, request.bubble_id)?;
+    let stats = store.stats(request.bubble_id, user_id);
+    Ok(Json(GetBubbleInfoResponse {
+        ok: true,
+        bubble: store.bubbles[&request.bubble_id].clone(),
+        stats: vec![client::bubble_info::BubbleStats {
+            bubble_id: stats.bubble_id,
+            mark: stats.mark as u64,
+            updated: Some(stats.updated),
+            unread: stats.unread as u64,
+            unread_mentions: stats.unread_mentions as u64,
+            latest_message_id: stats.latest_message_id,
+            latest_message_created_at: stats.latest_message_created_at,
+            unclaimed_task_count: stats.unclaimed_task_count as u64,
+        }],
+    }))
+}
+
+#[derive(Deserialize)]
+struct BubbleHistoryQuery {
+    bubble_id: u64,
+    latest: Option<u64>,
+}
+
+async fn bubble_history(
+    State(state): State<Arc<AppState>>,
+    CurrentUser(user_id): CurrentUser,
+    Query(query): Query<BubbleHistoryQuery>,
+) -> ApiResult<GetBubbleHistoryResponse> {
+    let store = state.store.read().await;
+    member_bubble(&store, user_id, query.bubble_id)?;
+    let messages: Vec<_> = store
+        .history(query.bubble_id)
+        .filter(|m| query.latest.is_none_or(|latest| m.id < latest))
+        .take(PAGE_SIZE)
+        .cloned()
+        .collect();
+    let ids: HashSet<_> = messages.iter().map(|m| m.id).collect();
+    let parent_messages = messages
+        .iter()
+        .filter_map(|m| m.parent_message_id)
+        .filter(|id| !ids.contains(id))
+        .collect::<HashSet<_>>()
+        .into_iter()
+        .filter_map(|id| store.messages.get(&id).cloned())
+        .collect();
+    Ok(Json(GetBubbleHistoryResponse {
+        ok: true,
+        pagesize: PAGE_SIZE as u64,
+        messages,
+        parent_messages,
+    }))
+}
+
+async fn bubble_membership_search(
+    State(state): State<Arc<AppState>>,
+    CurrentUser(user_id): CurrentUser,
+    Json(request): Json<PostBubbleMembershipSearchRequest>,
+) -> ApiResult<PostBubbleMembershipSearchResponse> {
+    let store = state.store.read().await;
+    member_bubble(&store, user_id, request.bubble_id)?;
+    let membership = store
+        .memberships
+        .iter()
+        .filter(|m| m.bubble_id == request.bubble_id)
+        .filter(|m| request.include_self || m.user_id != user_id)
+        .skip(request.page.saturating_sub(1) as usize * MEMBERSHIP_PAGE_SIZE)
+        .take(MEMBERSHIP_PAGE_SIZE)
+        .map(|m| Member {
+            id: m.id as i64,
+            user_id: m.user_id,
+            bubble_id: m.bubble_id,
+            mark: m.mark,
+            mute: m.mute,
+            created_at: m.created_at.clone(),
+            updated_at: m.updated_at.clone(),
+            markupdated: m.mark_updated.clone(),
+            banned: m.banned,
+            reactions: m.reactions,
+            is_pinned: m.is_pinned,
+            user: store.users[&m.user_id].clone(),
+        })
+        .collect();
+    Ok(Json(PostBubbleMembershipSearchResponse {
+        ok: true,
+        page_size: MEMBERSHIP_PAGE_SIZE as u64,
+        membership,
+    }))
+}
+
+async fn bubble_create(
+    State(state): State<Arc<AppState>>,
+    CurrentUser(user_id): CurrentUser,
+    Json(request): Json<PostBubbleCreateRequest>,
+) -> ApiResult<PostBubbleCreateResponse> {
+    let mut store = state.store.write().await;
+    if request.organization_id != store.organization.id {
+        return Err(FORBIDDEN);
+    }
+    let bubble = store.add_bubble(&request.title, user_id, &[]);
+    Ok(Json(PostBubbleCreateResponse { ok: true, bubble }))
+}
+
+async fn bubble_mark(
+    State(state): State<Arc<AppState>>,
+    CurrentUser(user_id): CurrentUser,
+    Json(request): Json<PostBubbleMarkRequest>,
+) -> ApiResult<PostBubbleMarkResponse> {
+    let mut store = state.store.write().await;
+    member_bubble(&store, user_id, request.bubble_id)?;
+    store.mark(request.bubble_id, user_id, request.message_id);
+    Ok(Json(PostBubbleMarkResponse { ok: true }))
+}
+
+async fn dm_create(
+    State(state): State<Arc<AppState>>,
+    CurrentUser(user_id): CurrentUser,
+    Json(request): Json<PostDMCreateRequest>,
+) -> ApiResult<PostDMCreateResponse> {
+    let mut store = state.store.write().await;
+    if !store.users.contains_key(&request.user_id) {
+        return Err(NOT_FOUND);
+    }
+    let bubble = store.dm(user_id, request.user_id);
+    Ok(Json(PostDMCreateResponse { ok: true, bubble }))
+}
+
+/// The body sent by the client, `client::message_create::MessageModifyRequest` is serialize only
+#[derive(Deserialize)]
+struct MessageCreateRequest {
+    bubble_id: u64,
+    message: String,
+    #[serde(rename = "parentmessage_id")]
+    parent_message_id: Option<u64>,
+    #[serde(default, rename = "messagemedia")]
+    media: Vec<NewMessageMedia>,
+    uuid: Option<String>,
+}
+
+async fn message_create(
+    State(state): State<Arc<AppState>>,
+    CurrentUser(user_id): CurrentUser,
+    headers: HeaderMap,
+    Json(request): Json<MessageCreateRequest>,
+) -> ApiResult<MessageModifyResponse> {
+    let host = headers
+        .get(HOST)
+        .and_then(|host| host.to_str().ok())
+        .unwrap_or("localhost")
+        .to_string();
+    let mut store = state.store.write().await;
+    member_bubble(&store, user_id, request.bubble_id)?;
+    if let Some(parent) = request.parent_message_id
+        && store
+            .messages
+            .get(&parent)
+            .is_none_or(|m| m.bubble_id != request.bubble_id)
+    {
+        return Err(BAD_REQUEST);
+    }
+    let mut media = Vec::with_capacity(request.media.len());
+    for new in request.media {
+        if !store.files.contains_key(&new.uuid) {
+            return Err(BAD_REQUEST);
+        }
+        let created_at = chrono::Utc::now().format("%Y-%m-%d %H:%M:%S").to_string();
+        media.push(MessageMedia {
+            id: 0,
+            message_id: 0,
+            url: format!("http://{host}/api/files/{}", new.uuid),
+            path: Some(format!("/api/files/{}", new.uuid)),
+            uuid: new.uuid,
+            title: Some(new.title),
+            mediatype: new.media_type,
+            url_mimetype: new.mime_type,
+            width: new.width.unwrap_or_default(),
+            height: new.height.unwrap_or_default(),
+            filesize: new.file_size,
+            updated_at: created_at.clone(),
+            created_at,
+        });
+    }
+    let message = store.add_message_with_media(
+        request.bubble_id,
+        user_id,
+        &request.message,
+        request.parent_message_id,
+        media,
+        request.uuid,
+    );
+    Ok(Json(MessageModifyResponse { ok: true, message }))
+}
+
+async fn message_edit(
+    State(state): State<Arc<AppState>>,
+    CurrentUser(user_id): CurrentUser,
+    Json(request): Json<MessageEditRequest>,
+) -> ApiResult<MessageModifyResponse> {
+    let mut store = state.store.write().await;
+    let message = store.messages.get(&request.message_id).ok_or(NOT_FOUND)?;
+    if message.user_id != user_id {
+        return Err(FORBIDDEN);
+    }
+    let message = store
+        .edit_message(request.message_id, &request.message)
+        .ok_or(NOT_FOUND)?;
+    Ok(Json(MessageModifyResponse { ok: true, message }))
+}
+
+#[derive(Deserialize)]
+struct MessageDeleteRequest {
+    message_id: u64,
+}
+
+async fn message_delete(
+    State(state): State<Arc<AppState>>,
+    CurrentUser(user_id): CurrentUser,
+    Json(request): Json<MessageDeleteRequest>,
+) -> ApiResult<DeleteMessageResponse> {
+    let mut store = state.store.write().await;
+    let message = store.messages.get(&request.message_id).ok_or(NOT_FOUND)?;
+    if message.user_id != user_id {
+        return Err(FORBIDDEN);
+    }
+    store.delete_message(request.message_id);
+    Ok(Json(DeleteMessageResponse { ok: true }))
+}
+
+/// Case insensitive substring search over the bubbles the user is in, newest first
+async fn message_search(
+    State(state): State<Arc<AppState>>,
+    CurrentUser(user_id): CurrentUser,
+    Json(request): Json<PostMessageSearchRequest>,
+) -> ApiResult<PostMessageSearchResponse> {
+    let store = state.store.read().await;
+    let needle = request.query.to_lowercase();
+    let matches: Vec<_> = store
+        .messages
+        .values()
+        .rev()
+        .filter(|m| store.is_member(m.bubble_id, user_id))
+        .filter(|m| m.message.to_lowercase().contains(&needle))
+        .collect();
+    let results: Vec<_> = matches
+        .iter()
+        .skip(request.from as usize)
+        .take(request.size as usize)
+        .map(|m| MessageSearchResult {
+            message_id: m.id,
+            message: (*m).clone(),
+        })
+        .collect();
+    let bubbles = results
+        .iter()
+        .map(|r| r.message.bubble_id)
+        .collect::<HashSet<_>>()
+        .into_iter()
+        .map(|id| {
+            let bubble = &store.bubbles[&id];
+            MessageSearchBubble {
+                bubble_id: bubble.id,
+                is_hidden: false,
+                is_dm: bubble.is_dm,
+                title: bubble.title.clone(),
+            }
+        })
+        .collect();
+    Ok(Json(PostMessageSearchResponse {
+        ok: true,
+        current_page_offset: request.from,
+        page_size: request.size,
+        next_page_offset: request.from + request.size,
+        total_results: Some(matches.len() as u64),
+        results,
+        bubbles,
+    }))
+}
+
+async fn react(
+    state: &AppState,
+    user_id: u64,
+    request: ReactionModifyRequest,
+    add: bool,
+) -> ApiResult<MessageModifyResponse> {
+    let mut store = state.store.write().await;
+    let message = store.messages.get(&request.message_id).ok_or(NOT_FOUND)?;
+    member_bubble(&store, user_id, message.bubble_id)?;
+    let message = store
+        .react(request.message_id, user_id, request.reaction_type_id, add)
+        .ok_or(NOT_FOUND)?;
+    Ok(Json(MessageModifyResponse { ok: true, message }))
+}
+
+async fn reaction_add(
+    State(state): State<Arc<AppState>>,
+    CurrentUser(user_id): CurrentUser,
+    Json(request): Json<ReactionModifyRequest>,
+) -> ApiResult<MessageModifyResponse> {
+    react(&state, user_id, request, true).await
+}
+
+async fn reaction_remove(
+    State(state): State<Arc<AppState>>,
+    CurrentUser(user_id): CurrentUser,
+    Json(request): Json<ReactionModifyRequest>,
+) -> ApiResult<MessageModifyResponse> {
+    react(&state, user_id, request, false).await
+}
+
+async fn task_list(
+    State(state): State<Arc<AppState>>,
+    CurrentUser(user_id): CurrentUser,
+    Json(request): Json<PostTaskListRequest>,
+) -> ApiResult<PostTaskListResponse> {
+    let store = state.store.read().await;
+    let tasks = store
+        .tasks
+        .values()
+        .filter(|task| {
+            task.organization_id == request.organization_id
+                && (task.assigneeuser_id == user_id || task.user_id == user_id)
+                && task.completed.is_some() == request.completed
+        })
+        .cloned()
+        .collect();
+    Ok(Json(PostTaskListResponse {
+        ok: true,
+        pagesize: PAGE_SIZE as i64,
+        tasks,
+        hasmore: false,
+    }))
+}
+
+async fn task_create(
+    State(state): State<Arc<AppState>>,
+    CurrentUser(user_id): CurrentUser,
+    Json(request): Json<TaskInfo>,
+) -> ApiResult<PostTaskResponse> {
+    let mut store = state.store.write().await;
+    let assignee_id = request.assigneeuser_id as u64;
+    if !store.users.contains_key(&assignee_id) {
+        return Err(NOT_FOUND);
+    }
+    let task = store.add_task(
+        user_id,
+        assignee_id,
+        &request.title,
+        &request.notes,
+        &request.due,
+    );
+    Ok(Json(PostTaskResponse { ok: true, task }))
+}
+
+async fn set_task_completed(
+    state: &AppState,
+    user_id: u64,
+    request: PostTaskCompleteRequest,
+    completed: bool,
+) -> ApiResult<PostTaskResponse> {
+    let mut store = state.store.write().await;
+    let task = store.tasks.get(&request.task_id).ok_or(NOT_FOUND)?;
+    if task.assigneeuser_id != user_id && task.user_id != user_id {
+        return Err(FORBIDDEN);
+    }
+    let task = store
+        .set_task_completed(request.task_id, completed)
+        .ok_or(NOT_FOUND)?;
+    Ok(Json(PostTaskResponse { ok: true, task }))
+}
+
+async fn task_complete(
+    State(state): State<Arc<AppState>>,
+    CurrentUser(user_id): CurrentUser,
+    Json(request): Json<PostTaskCompleteRequest>,
+) -> ApiResult<PostTaskResponse> {
+    set_task_completed(&state, user_id, request, true).await
+}
+
+async fn task_uncomplete(
+    State(state): State<Arc<AppState>>,
+    CurrentUser(user_id): CurrentUser,
+    Json(request): Json<PostTaskCompleteRequest>,
+) -> ApiResult<PostTaskResponse> {
+    set_task_completed(&state, user_id, request, false).await
+}
+
+async fn pusher_auth(
+    State(state): State<Arc<AppState>>,
+    CurrentUser(user_id): CurrentUser,
+    Json(request): Json<PusherAuthRequest>,
+) -> ApiResult<PusherAuthResponse> {
+    let store = state.store.read().await;
+    let channel = request.channel_name.as_str();
+    let allowed = if let Some(id) = channel.strip_prefix("private-user.") {
+        id.parse() == Ok(user_id)
+    } else if let Some(id) = channel.strip_prefix("private-organization.") {
+        id.parse() == Ok(store.organization.id)
+    } else if let Some(rest) = channel.strip_prefix("private-bubble.") {
+        rest.split_once('.')
+            .and_then(|(id, _)| id.parse().ok())
+            .is_some_and(|id| {
+                store.is_member(id, user_id) && store.bubble_channel(id).as_deref() == Some(channel)
+            })
+    } else {
+        false
+    };
+    if !allowed {
+        return Err(FORBIDDEN);
+    }
+    Ok(Json(PusherAuthResponse {
+        auth: auth_signature(&request.socket_id, channel),
+    }))
+}
+
+#[derive(Deserialize)]
+struct FilesQuery {
+    filename: String,
+}
+
+async fn files(
+    State(state): State<Arc<AppState>>,
+    CurrentUser(_): CurrentUser,
+    Query(query): Query<FilesQuery>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> ApiResult<PutFileResponse> {
+    let guessed = match query.filename.rsplit_once('.').map(|(_, ext)| ext) {
+        Some("png") => "image/png",
+        Some("jpg" | "jpeg") => "image/jpeg",
+        Some("gif") => "image/gif",
+        Some("pdf") => "application/pdf",
+        Some("txt") => "text/plain",
+        _ => "application/octet-stream",
+    };
+    let r#type = headers
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or(guessed)
+        .to_string();
+    let size = body.len() as u64;
+    let key = state
+        .store
+        .write()
+        .await
+        .add_file(&query.filename, &r#type, body.to_vec());
+    let expires = (chrono::Utc::now() + chrono::Duration::hours(1))
+        .format("%Y-%m-%dT%H:%M:%SZ")
+        .to_string();
+    Ok(Json(PutFileResponse {
+        data: PutFileResponseData {
+            key,
+            expires,
+            name: query.filename,
+            size,
+            r#type,
+        },
+    }))
+}
+
+async fn file_download(
+    State(state): State<Arc<AppState>>,
+    Path(key): Path<String>,
+) -> Result<Response, ApiError> {
+    let store = state.store.read().await;
+    let file = store.files.get(&key).ok_or(NOT_FOUND)?;
+    Ok(([(CONTENT_TYPE, file.mime_type.clone())], file.data.clone()).into_response())
+}
+
+#[derive(Deserialize)]
+struct NormalizedQuery {
+    preset: String,
+}
+
+/// Images aren't processed, the normalized copy is the upload itself
+async fn file_normalized(
+    State(state): State<Arc<AppState>>,
+    CurrentUser(_): CurrentUser,
+    Path(key): Path<String>,
+    Query(query): Query<NormalizedQuery>,
+) -> ApiResult<GetFileNormalizedResponse> {
+    let store = state.store.read().await;
+    let file = store.files.get(&key).ok_or(NOT_FOUND)?;
+    if query.preset != "PHOTO" || !file.mime_type.starts_with("image/") {
+        return Err(BAD_REQUEST);
+    }
+    let normalized = NormalizedFile {
+        mime_type: file.mime_type.clone(),
+        key,
+        name: file.name.clone(),
+        width: 0,
+        height: 0,
+        file_size: file.data.len() as u64,
+    };
+    Ok(Json(GetFileNormalizedResponse {
+        data: GetFileNormalizedResponseData {
+            original: normalized.clone(),
+            normalized,
+            is_animated: false,
+        },
+    }))
+}
@@ -0,0 +1,344 @@
+//! # Mock server
+//! A local stand-in for the Pronto API and its pusher socket, so the client, the indexer,
+//! bots and the UI handlers can be tested without a real organization.
+//!
+//! ```no_run
+//! # async fn example() {
+//! use mock_server::MockServer;
+//!
+//! let (server, alice, bubble) = MockServer::start_with_bubble().await.unwrap();
+//! let client = server.client(alice.id);
+//! client
+//!     .send_message(alice.id, bubble.id, "hello".to_string(), None)
+//!     .await
+//!     .unwrap();
+//! # }
+//! ```
+mod api;
+mod socket;
+mod store;
+
+use axum::Router;
+use axum::routing::get;
+use client::{Bubble, ProntoClient, UserInfo};
+use std::collections::VecDeque;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::AtomicU64;
+use std::sync::{Arc, Mutex};
+pub use store::{Event, File, Store};
+use tokio::net::TcpListener;
+use tokio::sync::{RwLock, RwLockWriteGuard, broadcast, oneshot};
+
+pub(crate) struct AppState {
+    pub store: RwLock<Store>,
+    pub events: broadcast::Sender<Event>,
+    /// Tells every open socket to close, to exercise reconnecting
+    pub kicks: broadcast::Sender<()>,
+    pub next_socket: AtomicU64,
+    pub failures: Mutex<VecDeque<Failure>>,
+}
+
+/// A canned error response for the next api request
+#[derive(Copy, Clone, Debug)]
+pub(crate) struct Failure {
+    pub status: u16,
+    pub retry_after: Option<u64>,
+}
+
+/// The channel auth the mock pusher accepts, handed out by `pusher.auth`
+pub(crate) fn auth_signature(socket_id: &str, channel: &str) -> String {
+    format!("mock:{socket_id}:{channel}")
+}
+
+/// A mock Pronto server listening on a random local port, shut down when dropped.
+pub struct MockServer {
+    addr: SocketAddr,
+    state: Arc<AppState>,
+    shutdown: Option<oneshot::Sender<()>>,
+}
+
+impl MockServer {
+    /// Bind to a free port on localhost and start serving in the background.
+    pub async fn start() -> io::Result<Self> {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).await?;
+        let addr = listener.local_addr()?;
+        let (events, _) = broadcast::channel(1024);
+        let (kicks, _) = broadcast::channel(1);
+        let state = Arc::new(AppState {
+            store: RwLock::new(Store::new(events.clone())),
+            events,
+            kicks,
+            next_socket: AtomicU64::new(1),
+            failures: Mutex::new(VecDeque::new()),
+        });
+        let app = Router::new()
+            .nest("/api", api::router(state.clone()))
+            .route("/app/{key}", get(socket::upgrade))
+            .with_state(state.clone());
+        let (shutdown, shutdown_rx) = oneshot::channel();
+        tokio::spawn(async move {
+            let _ = axum::serve(listener, app)
+                .with_graceful_shutdown(async {
+                    let _ = shutdown_rx.await;
+                })
+                .await;
+        });
+        Ok(Self {
+            addr,
+            state,
+            shutdown: Some(shutdown),
+        })
+    }
+
+    /// A server with Alice in a General bubble, what most tests start from
+    pub async fn start_with_bubble() -> io::Result<(Self, UserInfo, Bubble)> {
+        let server = Self::start().await?;
+        let (alice, bubble) = {
+            let mut store = server.store().await;
+            let alice = store.add_user("Alice", "Example");
+            let bubble = store.add_bubble("General", alice.id, &[]);
+            (alice, bubble)
+        };
+        Ok((server, alice, bubble))
+    }
+
+    /// The base url to hand to [`ProntoClient::new`]
+    pub fn api_base_url(&self) -> String {
+        format!("http://{}/api/", self.addr)
+    }
+
+    /// The url of the fake pusher socket
+    pub fn pusher_url(&self) -> String {
+        format!("ws://{}/app/mock?protocol=7", self.addr)
+    }
+
+    /// The api token of a user in the store
+    pub fn token(user_id: u64) -> String {
+        format!("mock-token-{user_id}")
+    }
+
+    /// A client authenticated as the given user.
+    pub fn client(&self, user_id: u64) -> ProntoClient {
+        ProntoClient::new(self.api_base_url(), &Self::token(user_id))
+            .expect("the mock server url is always valid")
+    }
+
+    /// Lock the store to seed or inspect it.
+    /// Changes made through the store are published on the socket like the api would.
+    pub async fn store(&self) -> RwLockWriteGuard<'_, Store> {
+        self.state.store.write().await
+    }
+
+    /// Close every open pusher socket, clients are expected to reconnect.
+    pub fn drop_connections(&self) {
+        let _ = self.state.kicks.send(());
+    }
+
+    /// Answer the next `count` api requests with `status` instead of handling them,
+    /// optionally with a `Retry-After` header in seconds.
+    pub fn fail_next(&self, count: usize, status: u16, retry_after: Option<u64>) {
+        let mut failures = self.state.failures.lock().unwrap();
+        failures.extend(std::iter::repeat_n(
+            Failure {
+                status,
+                retry_after,
+            },
+            count,
+        ));
+    }
+}
+
+impl Drop for MockServer {
+    fn drop(&mut self) {
+        if let Some(shutdown) = self.shutdown.take() {
+            let _ = shutdown.send(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use client::{ErrorKind, ReactionType};
+    use pusher::{
+        PusherClient, PusherServerEventType, PusherServerMessage, PusherServerMessageWrapper,
+    };
+
+    #[tokio::test]
+    async fn test_messages() {
+        let server = MockServer::start().await.unwrap();
+        let (alice, bob, bubble) = {
+            let mut store = server.store().await;
+            let alice = store.add_user("Alice", "Example");
+            let bob = store.add_user("Bob", "Example");
+            let bubble = store.add_bubble("General", alice.id, &[bob.id]);
+            (alice, bob, bubble)
+        };
+        let client = server.client(bob.id);
+
+        let user = client.current_user_info().await.unwrap().user;
+        assert_eq!(user.id, bob.id);
+        let list = client.bubble_list().await.unwrap();
+        assert_eq!(list.bubbles.len(), 1);
+
+        let first = client
+            .send_message(bob.id, bubble.id, "first".to_string(), None)
+            .await
+            .unwrap()
+            .message;
+        let reply = client
+            .send_message_with_uuid(bob.id, bubble.id, "reply".to_string(), Some(first.id), "r1")
+            .await
+            .unwrap()
+            .message;
+        assert_eq!(reply.uuid.as_deref(), Some("r1"));
+        let reacted = client
+            .add_reaction(first.id, ReactionType::Like)
+            .await
+            .unwrap()
+            .message;
+        assert_eq!(reacted.reactions[0].users, vec![bob.id]);
+
+        let history = client.bubble_history(bubble.id, None).await.unwrap();
+        assert_eq!(history.messages[0].id, reply.id);
+        assert_eq!(history.messages[1].first_child_message_id, Some(reply.id));
+        let older = client
+            .bubble_history(bubble.id, Some(reply.id))
+            .await
+            .unwrap();
+        assert_eq!(older.messages.len(), 1);
+
+        // Alice can't edit Bob's message
+        let error = server
+            .client(alice.id)
+            .edit_message(first.id, "edited".to_string())
+            .await
+            .unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::Forbidden);
+        assert_eq!(error.api_error().unwrap().error, "FORBIDDEN");
+        let error = client.bubble_info(u64::MAX).await.unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::NotFound);
+        let error = server.client(u64::MAX).bubble_list().await.unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::Unauthorized);
+    }
+
+    #[tokio::test]
+    async fn test_pusher() {
+        let (server, alice, bubble) = MockServer::start_with_bubble().await.unwrap();
+        let client = Arc::new(server.client(alice.id));
+        let pusher_client = PusherClient::with_url(client.clone(), server.pusher_url()).await;
+        let mut messages = pusher_client.server_messages().await;
+        pusher_client.init().await;
+        let channel = format!("private-bubble.{}.{}", bubble.id, bubble.channel_code);
+        pusher_client.subscribe(channel.clone()).await;
+        loop {
+            if let PusherServerMessageWrapper::PusherServerMessage(
+                PusherServerMessage::SubscriptionSucceeded(succeeded),
+            ) = messages.recv().await.unwrap()
+            {
+                assert_eq!(succeeded.channel, channel);
+                break;
+            }
+        }
+
+        let sent = client
+            .send_message(alice.id, bubble.id, "hello".to_string(), None)
+            .await
+            .unwrap()
+            .message;
+        loop {
+            if let PusherServerMessageWrapper::PusherServerMessage(PusherServerMessage::Event(
+                event,
+            )) = messages.recv().await.unwrap()
+            {
+                let PusherServerEventType::PusherServerMessageAddedEvent(added) = event.event
+                else {
+                    panic!("expected a message added event, got {:?}", event.event);
+                };
+                assert_eq!(added.message.id, sent.id);
+                break;
+            }
+        }
+        pusher_client.shutdown().await;
+    }
+
+    /// Wait for the server to confirm a subscription to `channel`
+    async fn subscribed(
+        messages: &mut tokio::sync::broadcast::Receiver<PusherServerMessageWrapper>,
+        channel: &str,
+    ) {
+        loop {
+            if let PusherServerMessageWrapper::PusherServerMessage(
+                PusherServerMessage::SubscriptionSucceeded(succeeded),
+            ) = messages.recv().await.unwrap()
+                && succeeded.channel == channel
+            {
+                return;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_pusher_resubscribe() {
+        let (server, alice, bubble) = MockServer::start_with_bubble().await.unwrap();
+        let client = Arc::new(server.client(alice.id));
+        let pusher_client = PusherClient::with_url(client, server.pusher_url()).await;
+        let mut messages = pusher_client.server_messages().await;
+        // Before the first connection, it's sent once there is one
+        let channel = format!("private-bubble.{}.{}", bubble.id, bubble.channel_code);
+        pusher_client.subscribe(channel.clone()).await;
+        subscribed(&mut messages, &channel).await;
+
+        server.drop_connections();
+        loop {
+            if let PusherServerMessageWrapper::Disconnected = messages.recv().await.unwrap() {
+                break;
+            }
+        }
+        subscribed(&mut messages, &channel).await;
+        pusher_client.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_pusher_backfill() {
+        let (server, alice, bubble) = MockServer::start_with_bubble().await.unwrap();
+        let client = Arc::new(server.client(alice.id));
+        let pusher_client = PusherClient::with_url(client.clone(), server.pusher_url()).await;
+        let mut messages = pusher_client.server_messages().await;
+        let channel = format!("private-bubble.{}.{}", bubble.id, bubble.channel_code);
+        pusher_client.subscribe(channel.clone()).await;
+        subscribed(&mut messages, &channel).await;
+
+        server.drop_connections();
+        loop {
+            if let PusherServerMessageWrapper::Disconnected = messages.recv().await.unwrap() {
+                break;
+            }
+        }
+        let missed = client
+            .send_message(alice.id, bubble.id, "missed".to_string(), None)
+            .await
+            .unwrap()
+            .message;
+
+        // Replayed before the reconnect is announced
+        let mut replayed = vec![];
+        loop {
+            match messages.recv().await.unwrap() {
+                PusherServerMessageWrapper::PusherServerMessage(PusherServerMessage::Event(
+                    event,
+                )) => {
+                    if let PusherServerEventType::PusherServerMessageAddedEvent(added) = event.event
+                    {
+                        replayed.push(added.message.id);
+                    }
+                }
+                PusherServerMessageWrapper::Reconnected => break,
+                _ => {}
+            }
+        }
+        assert_eq!(replayed, vec![missed.id]);
+        pusher_client.shutdown().await;
+    }
+}
@@ -0,0 +1,118 @@
+use crate::store::Event;
+use crate::{AppState, auth_signature};
+use axum::extract::ws::{Message, Utf8Bytes, WebSocket, WebSocketUpgrade};
+use axum::extract::{Path, State};
+use axum::response::Response;
+use log::debug;
+use pusher::{PusherClientSubscribe, RawPusherMessage};
+use serde::Serialize;
+use serde_json::{Value, json};
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use tokio::sync::broadcast::error::RecvError;
+
+const ACTIVITY_TIMEOUT: u64 = 120;
+
+pub(crate) async fn upgrade(
+    State(state): State<Arc<AppState>>,
+    Path(_key): Path<String>,
+    ws: WebSocketUpgrade,
+) -> Response {
+    ws.on_upgrade(move |socket| session(state, socket))
+}
+
+/// Pusher sends `data` as a json encoded string
+fn frame(event: &str, channel: Option<&str>, data: impl Serialize) -> Message {
+    let raw = RawPusherMessage {
+        event: event.to_string(),
+        data: Value::String(serde_json::to_string(&data).unwrap()),
+        channel: channel.map(str::to_string),
+    };
+    Message::Text(Utf8Bytes::from(serde_json::to_string(&raw).unwrap()))
+}
+
+/// Unlike events, errors carry their data as an object
+fn error(code: Option<u16>, message: &str) -> Message {
+    let raw = RawPusherMessage {
+        event: "pusher:error".to_string(),
+        data: json!({ "code": code, "message": message }),
+        channel: None,
+    };
+    Message::Text(Utf8Bytes::from(serde_json::to_string(&raw).unwrap()))
+}
+
+async fn session(state: Arc<AppState>, mut socket: WebSocket) {
+    let id = state.next_socket.fetch_add(1, Ordering::Relaxed);
+    let socket_id = format!("{id}.{id}");
+    let mut events = state.events.subscribe();
+    let mut kicks = state.kicks.subscribe();
+    let mut subscriptions = HashSet::new();
+    let established = frame(
+        "pusher:connection_established",
+        None,
+        json!({ "socket_id": socket_id, "activity_timeout": ACTIVITY_TIMEOUT }),
+    );
+    if socket.send(established).await.is_err() {
+        return;
+    }
+    loop {
+        let reply = tokio::select! {
+            message = socket.recv() => match message {
+                Some(Ok(Message::Text(text))) => handle(&socket_id, &mut subscriptions, text.as_str()),
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => return,
+                Some(Ok(_)) => None,
+            },
+            event = events.recv() => match event {
+                Ok(Event { channel, event, data }) if subscriptions.contains(&channel) => {
+                    Some(frame(&event, Some(&channel), data))
+                }
+                Ok(_) => None,
+                Err(RecvError::Lagged(_)) => None,
+                Err(RecvError::Closed) => return,
+            },
+            _ = kicks.recv() => {
+                let _ = socket.send(Message::Close(None)).await;
+                return;
+            }
+        };
+        if let Some(reply) = reply
+            && socket.send(reply).await.is_err()
+        {
+            return;
+        }
+    }
+}
+
+/// Handle a frame from the client, returning the reply if there is one.
+fn handle(socket_id: &str, subscriptions: &mut HashSet<String>, text: &str) -> Option<Message> {
+    let Ok(raw) = serde_json::from_str::<RawPusherMessage>(text) else {
+        return Some(error(None, "Invalid JSON"));
+    };
+    match raw.event.as_str() {
+        "pusher:ping" => Some(frame("pusher:pong", None, json!({}))),
+        "pusher:pong" => None,
+        "pusher:subscribe" => {
+            let Ok(subscribe) = serde_json::from_value::<PusherClientSubscribe>(raw.data) else {
+                return Some(error(Some(4000), "Invalid subscribe"));
+            };
+            if subscribe.auth != auth_signature(socket_id, &subscribe.channel) {
+                return Some(error(
+                    Some(4009),
+                    &format!("Invalid signature for {}", subscribe.channel),
+                ));
+            }
+            debug!("Socket {socket_id} subscribed to {}", subscribe.channel);
+            subscriptions.insert(subscribe.channel.clone());
+            Some(frame(
+                "pusher_internal:subscription_succeeded",
+                Some(&subscribe.channel),
+                json!({}),
+            ))
+        }
+        event => {
+            debug!("Ignoring {event} from socket {socket_id}");
+            None
+        }
+    }
+}
@@ -0,0 +1,508 @@
+use chrono::{NaiveDateTime, Utc};
+use client::{
+    Bubble, BubbleStats, Membership, Message, MessageMedia, Organization, Reactions, Task, UserInfo,
+};
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap};
+use tokio::sync::broadcast;
+
+const DATE_FORMAT: &str = "%Y-%m-%d %H:%M:%S";
+
+fn now() -> NaiveDateTime {
+    // Pronto only has second precision
+    NaiveDateTime::parse_from_str(&Utc::now().format(DATE_FORMAT).to_string(), DATE_FORMAT).unwrap()
+}
+
+fn timestamp() -> String {
+    now().format(DATE_FORMAT).to_string()
+}
+
+/// An event published on the fake pusher socket.
+#[derive(Clone, Debug)]
+pub struct Event {
+    pub channel: String,
+    pub event: String,
+    pub data: Value,
+}
+
+/// A file uploaded through the files endpoint
+#[derive(Clone, Debug)]
+pub struct File {
+    pub name: String,
+    pub mime_type: String,
+    pub data: Vec<u8>,
+}
+
+/// In-memory state behind the mock server, a single organization with its users, bubbles,
+/// messages and tasks. Seed it directly through [`crate::MockServer::store`].
+pub struct Store {
+    next_id: u64,
+    pub organization: Organization,
+    pub users: BTreeMap<u64, UserInfo>,
+    pub bubbles: BTreeMap<u64, Bubble>,
+    pub memberships: Vec<Membership>,
+    pub messages: BTreeMap<u64, Message>,
+    pub tasks: BTreeMap<u64, Task>,
+    pub files: HashMap<String, File>,
+    events: broadcast::Sender<Event>,
+}
+
+impl Store {
+    pub(crate) fn new(events: broadcast::Sender<Event>) -> Self {
+        Self {
+            next_id: 1,
+            organization: organization(1),
+            users: BTreeMap::new(),
+            bubbles: BTreeMap::new(),
+            memberships: vec![],
+            messages: BTreeMap::new(),
+            tasks: BTreeMap::new(),
+            files: HashMap::new(),
+            events,
+        }
+    }
+
+    fn id(&mut self) -> u64 {
+        self.next_id += 1;
+        self.next_id
+    }
+
+    /// Send an event to every socket subscribed to `channel`.
+    pub fn publish(
+        &self,
+        channel: impl Into<String>,
+        event: impl Into<String>,
+        data: impl Serialize,
+    ) {
+        let _ = self.events.send(Event {
+            channel: channel.into(),
+            event: event.into(),
+            data: serde_json::to_value(data).unwrap(),
+        });
+    }
+
+    pub fn add_user(&mut self, firstname: &str, lastname: &str) -> UserInfo {
+        let user = UserInfo {
+            id: self.id(),
+            firstname: firstname.to_string(),
+            lastname: lastname.to_string(),
+            pronouns: None,
+            profile_picture_url: None,
+            profile_picture_path: None,
+            verified: false,
+            online: true,
+            role: "user".to_string(),
+            mute: false,
+            has_mobile_app: None,
+            fullname: format!("{firstname} {lastname}"),
+            has_activity: true,
+            inactive: false,
+            language: Some("en".to_string()),
+            organizations: vec![self.organization.clone()],
+        };
+        self.users.insert(user.id, user.clone());
+        user
+    }
+
+    /// Create a bubble owned by `owner` with the given members, the owner is always a member.
+    pub fn add_bubble(&mut self, title: &str, owner: u64, members: &[u64]) -> Bubble {
+        let bubble = bubble(self.id(), title, owner, false);
+        self.bubbles.insert(bubble.id, bubble.clone());
+        self.add_member(bubble.id, owner);
+        for &member in members {
+            self.add_member(bubble.id, member);
+        }
+        bubble
+    }
+
+    /// Get the direct message bubble between two users, creating it if needed.
+    pub fn dm(&mut self, user_id: u64, partner_id: u64) -> Bubble {
+        let existing = self.bubbles.values().find(|bubble| {
+            bubble.is_dm
+                && self.is_member(bubble.id, user_id)
+                && self.is_member(bubble.id, partner_id)
+        });
+        if let Some(bubble) = existing {
+            return bubble.clone();
+        }
+        let mut bubble = bubble(self.id(), "", user_id, true);
+        bubble.dm_partner = self.users.get(&partner_id).cloned();
+        self.bubbles.insert(bubble.id, bubble.clone());
+        self.add_member(bubble.id, user_id);
+        self.add_member(bubble.id, partner_id);
+        bubble
+    }
+
+    pub fn add_member(&mut self, bubble_id: u64, user_id: u64) {
+        if self.is_member(bubble_id, user_id) {
+            return;
+        }
+        let membership = membership(
+            self.id(),
+            bubble_id,
+            user_id,
+            self.users.get(&user_id).cloned(),
+        );
+        self.memberships.push(membership);
+    }
+
+    pub fn is_member(&self, bubble_id: u64, user_id: u64) -> bool {
+        self.membership(bubble_id, user_id).is_some()
+    }
+
+    pub fn membership(&self, bubble_id: u64, user_id: u64) -> Option<&Membership> {
+        self.memberships
+            .iter()
+            .find(|m| m.bubble_id == bubble_id && m.user_id == user_id)
+    }
+
+    pub fn bubble_channel(&self, bubble_id: u64) -> Option<String> {
+        self.bubbles
+            .get(&bubble_id)
+            .map(|bubble| format!("private-bubble.{}.{}", bubble.id, bubble.channel_code))
+    }
+
+    /// Add a message and publish it to the bubble channel like pronto would.
+    pub fn add_message(
+        &mut self,
+        bubble_id: u64,
+        user_id: u64,
+        text: &str,
+        parent_message_id: Option<u64>,
+    ) -> Message {
+        self.add_message_with_media(bubble_id, user_id, text, parent_message_id, vec![], None)
+    }
+
+    /// Add a message with attachments, the ids of the media are filled in.
+    /// `uuid` is the client generated id, a new one is made up when it's missing.
+    pub fn add_message_with_media(
+        &mut self,
+        bubble_id: u64,
+        user_id: u64,
+        text: &str,
+        parent_message_id: Option<u64>,
+        mut message_media: Vec<MessageMedia>,
+        uuid: Option<String>,
+    ) -> Message {
+        let id = self.id();
+        for media in &mut message_media {
+            media.id = self.id();
+            media.message_id = id as i64;
+        }
+        let message = Message {
+            id,
+            user_id,
+            bubble_id,
+            message: text.to_string(),
+            user: self.users[&user_id].clone(),
+            system_event: None,
+            parent_message_id,
+            first_child_message_id: None,
+            last_child_message_id: None,
+            reactions: vec![],
+            message_media,
+            resource: None,
+            uuid: Some(uuid.unwrap_or_else(|| uuid::Uuid::new_v4().to_string())),
+            created_at: now(),
+        };
+        self.messages.insert(message.id, message.clone());
+        if let Some(parent) = parent_message_id.and_then(|id| self.messages.get_mut(&id)) {
+            parent.first_child_message_id.get_or_insert(message.id);
+            parent.last_child_message_id = Some(message.id);
+        }
+        if let Some(channel) = self.bubble_channel(bubble_id) {
+            self.publish(
+                channel,
+                "App\\Events\\MessageAdded",
+                pusher::PusherServerMessageAddedEvent {
+                    message: message.clone(),
+                },
+            );
+        }
+        message
+    }
+
+    pub fn edit_message(&mut self, message_id: u64, text: &str) -> Option<Message> {
+        let message = self.messages.get_mut(&message_id)?;
+        message.message = text.to_string();
+        let message = message.clone();
+        self.message_updated(&message);
+        Some(message)
+    }
+
+    pub fn delete_message(&mut self, message_id: u64) -> Option<Message> {
+        let message = self.messages.remove(&message_id)?;
+        if let Some(channel) = self.bubble_channel(message.bubble_id) {
+            self.publish(
+                channel,
+                "App\\Events\\MessageRemoved",
+                pusher::PusherServerMessageRemovedEvent {
+                    message: pusher::MessageId { id: message.id },
+                },
+            );
+        }
+        Some(message)
+    }
+
+    /// Add or remove a reaction, returns the updated message.
+    pub fn react(
+        &mut self,
+        message_id: u64,
+        user_id: u64,
+        reaction_type_id: u64,
+        add: bool,
+    ) -> Option<Message> {
+        let message = self.messages.get_mut(&message_id)?;
+        match message
+            .reactions
+            .iter_mut()
+            .find(|r| r.id == reaction_type_id)
+        {
+            Some(reaction) => reaction.users.retain(|&id| id != user_id),
+            None => message.reactions.push(Reactions {
+                id: reaction_type_id,
+                count: 0,
+                users: vec![],
+            }),
+        }
+        let reaction = message
+            .reactions
+            .iter_mut()
+            .find(|r| r.id == reaction_type_id)
+            .unwrap();
+        if add {
+            reaction.users.push(user_id);
+        }
+        reaction.count = reaction.users.len() as u64;
+        message.reactions.retain(|r| r.count > 0);
+        let message = message.clone();
+        self.message_updated(&message);
+        Some(message)
+    }
+
+    fn message_updated(&self, message: &Message) {
+        if let Some(channel) = self.bubble_channel(message.bubble_id) {
+            self.publish(
+                channel,
+                "App\\Events\\MessageUpdated",
+                pusher::PusherServerMessageUpdatedEvent {
+                    message: message.clone(),
+                },
+            );
+        }
+    }
+
+    /// Messages in a bubble, newest first
+    pub fn history(&self, bubble_id: u64) -> impl Iterator<Item = &Message> {
+        self.messages
+            .values()
+            .rev()
+            .filter(move |m| m.bubble_id == bubble_id)
+    }
+
+    pub fn stats(&self, bubble_id: u64, user_id: u64) -> BubbleStats {
+        let mark = self.membership(bubble_id, user_id).map_or(0, |m| m.mark);
+        let latest = self.history(bubble_id).next();
+        BubbleStats {
+            bubble_id,
+            mark: mark as u32,
+            updated: timestamp(),
+            unread: self
+                .history(bubble_id)
+                .filter(|m| m.id > mark && m.user_id != user_id)
+                .count() as u32,
+            unread_mentions: 0,
+            latest_message_id: latest.map_or(0, |m| m.id),
+            latest_message_created_at: latest.map(|m| m.created_at.format(DATE_FORMAT).to_string()),
+            unclaimed_task_count: 0,
+        }
+    }
+
+    pub fn mark(&mut self, bubble_id: u64, user_id: u64, message_id: u64) -> bool {
+        let Some(membership) = self
+            .memberships
+            .iter_mut()
+            .find(|m| m.bubble_id == bubble_id && m.user_id == user_id)
+        else {
+            return false;
+        };
+        membership.mark = message_id;
+        membership.mark_updated = timestamp();
+        true
+    }
+
+    pub fn add_task(
+        &mut self,
+        user_id: u64,
+        assignee_id: u64,
+        title: &str,
+        notes: &str,
+        due: &str,
+    ) -> Task {
+        let created_at = timestamp();
+        let task = Task {
+            id: self.id(),
+            assigneeuser_id: assignee_id,
+            bubble_id: None,
+            organization_id: self.organization.id,
+            user_id,
+            notes: notes.to_string(),
+            remindedassignee: false,
+            title: title.to_string(),
+            uuid: uuid::Uuid::new_v4().to_string(),
+            assigneeuser: self.users[&assignee_id].clone(),
+            user: self.users[&user_id].clone(),
+            taskmedia: vec![],
+            completed: None,
+            due: due.to_string(),
+            reminder_local: None,
+            reminder_utc: None,
+            created_at: created_at.clone(),
+            updated_at: created_at,
+        };
+        self.tasks.insert(task.id, task.clone());
+        task
+    }
+
+    pub fn set_task_completed(&mut self, task_id: u64, completed: bool) -> Option<Task> {
+        let task = self.tasks.get_mut(&task_id)?;
+        task.completed = completed.then(timestamp);
+        task.updated_at = timestamp();
+        let task = task.clone();
+        self.publish(
+            format!("private-user.{}", task.assigneeuser_id),
+            "App\\Events\\TaskUpdated",
+            pusher::PusherServerTaskUpdatedEvent { task: task.clone() },
+        );
+        Some(task)
+    }
+
+    pub fn add_file(&mut self, name: &str, mime_type: &str, data: Vec<u8>) -> String {
+        let key = uuid::Uuid::new_v4().to_string();
+        self.files.insert(
+            key.clone(),
+            File {
+                name: name.to_string(),
+                mime_type: mime_type.to_string(),
+                data,
+            },
+        );
+        key
+    }
+}
+
+fn organization(id: u64) -> Organization {
+    let created_at = now();
+    Organization {
+        id,
+        name: "Mock Organization".to_string(),
+        created_at,
+        updated_at: created_at,
+        profilepic: 0,
+        profilepicupdated: timestamp(),
+        tasks_enabled: true,
+        uuid: uuid::Uuid::new_v4().to_string(),
+        shortname: "mock".to_string(),
+        announcements_enabled: true,
+        grant_create_announcement: true,
+        grant_create_group: true,
+        grant_add_user: true,
+        grant_search_org: true,
+        grant_create_dm: true,
+        create_announcement: "member".to_string(),
+        create_group: "member".to_string(),
+        add_user: "member".to_string(),
+        search_org: "member".to_string(),
+        create_dm: "member".to_string(),
+        integrations_enabled: false,
+        grant_delete_any_announcement: false,
+        delete_any_announcement: "owner".to_string(),
+        meetings_enabled: false,
+        audio_messages_enabled: false,
+        maxstreams: 10,
+        imports_enabled: false,
+        search_enabled: true,
+        create_api_tokens: "owner".to_string(),
+        bubble_membership_cap: 1000,
+        badgecount_writing_enabled: 0,
+        badgecount_reading_enabled: 0,
+        experimental_notifications_enabled: 0,
+        supergroups_enabled: false,
+        meetings_captions_enabled: false,
+        giphy_rating: "g".to_string(),
+        user_title_enabled: false,
+        user_pronouns_enabled: true,
+        profilepicurl: String::new(),
+        profilepicpath: String::new(),
+        create_group_announcement: "member".to_string(),
+        grant_create_group_announcement: true,
+    }
+}
+
+fn bubble(id: u64, title: &str, owner: u64, is_dm: bool) -> Bubble {
+    let member = || "member".to_string();
+    Bubble {
+        id,
+        channel_code: uuid::Uuid::new_v4().simple().to_string(),
+        user_id: owner,
+        title: title.to_string(),
+        is_dm,
+        voice_only: false,
+        delete_any_message: "owner".to_string(),
+        change_title: member(),
+        grant_change_title: true,
+        change_category: member(),
+        grant_change_category: true,
+        add_member: member(),
+        grant_add_member: true,
+        remove_member: "owner".to_string(),
+        grant_remove_member: false,
+        leave_group: member(),
+        grant_leave_group: true,
+        delete_group: "owner".to_string(),
+        grant_delete_group: Some(false),
+        set_role: "owner".to_string(),
+        create_announcement: member(),
+        assign_task: member(),
+        create_message: member(),
+        grant_create_message: true,
+        is_supergroup: Some(false),
+        archived: 0,
+        dm_partner: None,
+        category: None,
+        memberships: None,
+        pinned_message: None,
+        pinned_message_user: None,
+    }
+}
+
+fn membership(id: u64, bubble_id: u64, user_id: u64, user: Option<UserInfo>) -> Membership {
+    let created_at = timestamp();
+    Membership {
+        id,
+        user_id,
+        bubble_id,
+        mark: 0,
+        friends: false,
+        system: false,
+        mute: false,
+        created_at: created_at.clone(),
+        updated_at: created_at.clone(),
+        mark_updated: created_at,
+        is_drop_in: false,
+        banned: false,
+        reactions: true,
+        notification_rollup: false,
+        alias: None,
+        is_hidden: false,
+        removed_by: None,
+        meetings: false,
+        mute_until: None,
+        is_pinned: false,
+        role: "member".to_string(),
+        snooze: None,
+        notification_preference: "ALL".to_string(),
+        user,
+    }
+}
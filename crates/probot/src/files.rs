@@ -0,0 +1,17 @@
+use client::{Attachment, Message, ProntoClient, ResponseError};
+
+/// Send files as the bot user, handy for posting screenshots or logs back to a bubble.
+/// `message` is sent along with the files and can be empty.
+pub async fn send_files(
+    client: &ProntoClient,
+    bubble_id: u64,
+    message: String,
+    parent_message_id: Option<u64>,
+    attachments: Vec<Attachment>,
+) -> Result<Message, ResponseError> {
+    let user_id = client.current_user_info().await?.user.id;
+    let response = client
+        .send_attachments(user_id, bubble_id, message, parent_message_id, attachments)
+        .await?;
+    Ok(response.message)
+}
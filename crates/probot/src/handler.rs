@@ -1,7 +1,9 @@
-use client::ProntoClient;
+use client::{ErrorKind, ProntoClient, ResponseError};
+use log::{error, warn};
 use pusher::PusherServerEventType;
 use std::error;
 use std::future::Future;
+use std::ops::ControlFlow;
 use std::pin::Pin;
 use std::sync::Arc;
 
@@ -15,6 +17,25 @@ pub trait Handler {
         pronto_client: Arc<ProntoClient>,
         input: PusherServerEventType,
     ) -> Result<(), Self::Error>;
+
+    /// Called with the errors returned by [`Handler::handle`], returning [`ControlFlow::Break`] stops the bot.
+    /// Errors are ignored by default, see [`log_error`] for handlers with a concrete error type.
+    fn on_error(&self, _error: Self::Error) -> ControlFlow<()> {
+        ControlFlow::Continue(())
+    }
+}
+
+/// Log a handler error, breaking if pronto no longer accepts the bot's token
+/// since every further request would fail too.
+pub fn log_error(error: &(dyn error::Error + 'static)) -> ControlFlow<()> {
+    if let Some(e) = error.downcast_ref::<ResponseError>() {
+        if e.kind() == ErrorKind::Unauthorized {
+            error!("The bot token was rejected: {e}");
+            return ControlFlow::Break(());
+        }
+    }
+    warn!("Handler failed: {error}");
+    ControlFlow::Continue(())
 }
 
 pub struct FunctionHandler<F> {
@@ -35,6 +56,10 @@ where
     ) -> Result<(), Self::Error> {
         async { (self.function)(pronto_client, input).await }.await
     }
+
+    fn on_error(&self, error: Self::Error) -> ControlFlow<()> {
+        log_error(&error)
+    }
 }
 
 /// Convert a function into a pusher event handler
@@ -101,6 +126,10 @@ impl Handler for CommandHandler {
             .await?;
         Ok(())
     }
+
+    fn on_error(&self, error: Self::Error) -> ControlFlow<()> {
+        log_error(&*error)
+    }
 }
 
 pub struct NoopHandler;
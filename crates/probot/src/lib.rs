@@ -50,18 +50,23 @@
 /// ```
 ///
 ///
+mod files;
 mod handler;
 
-pub use handler::{handler, Command, CommandHandler, Handler, NoopHandler};
+pub use files::send_files;
+pub use handler::{handler, log_error, Command, CommandHandler, Handler, NoopHandler};
 use std::collections::HashMap;
 use std::convert::Infallible;
 
 pub use client;
 pub use client::ProntoClient;
-use log::{error, warn};
+use log::{error, info, warn};
 pub use pusher;
 use pusher::{PusherClient, PusherServerMessage, PusherServerMessageWrapper};
+use tokio::sync::broadcast::error::RecvError;
+use futures::stream::{FuturesUnordered, StreamExt};
 use std::error;
+use std::ops::ControlFlow;
 use std::sync::Arc;
 
 pub trait TokenLoader {
@@ -87,9 +92,14 @@ impl TokenLoader for HashMap<u64, String> {
     }
 }
 
-pub struct Bot<T: Handler> {
+/// One logged in organization, with its own pusher connection
+struct Organization {
     client: Arc<ProntoClient>,
     pusher_client: PusherClient,
+}
+
+pub struct Bot<T: Handler> {
+    organizations: Vec<Organization>,
     handler: T,
     inited: bool,
 }
@@ -97,10 +107,40 @@ pub struct Bot<T: Handler> {
 impl<T: Handler> Bot<T> {
     /// Initialize bot with a client and handler, init() needs to be called after this function before run() is called.
     pub async fn new(client: Arc<ProntoClient>, handler: T) -> Self {
-        let pusher_client = PusherClient::new(client.clone()).await;
+        Self::with_clients(vec![client], handler).await
+    }
+
+    /// Initialize bot with a client per organization, the handler runs for events from all of them
+    /// and gets the client of the organization the event came from.
+    pub async fn with_clients(clients: Vec<Arc<ProntoClient>>, handler: T) -> Self {
+        let mut organizations = Vec::with_capacity(clients.len());
+        for client in clients {
+            let pusher_client = PusherClient::new(client.clone()).await;
+            organizations.push(Organization {
+                client,
+                pusher_client,
+            });
+        }
+        Self {
+            organizations,
+            handler,
+            inited: false,
+        }
+    }
+
+    /// Initialize bot with an existing pusher client, such as one made with [`PusherClient::replay`]
+    /// to run the handler against recorded traffic.
+    /// A replaying pusher client does not need init() to be called, run() returns when the recording ends.
+    pub fn with_pusher_client(
+        client: Arc<ProntoClient>,
+        pusher_client: PusherClient,
+        handler: T,
+    ) -> Self {
         Self {
-            client,
-            pusher_client,
+            organizations: vec![Organization {
+                client,
+                pusher_client,
+            }],
             handler,
             inited: false,
         }
@@ -111,27 +151,53 @@ impl<T: Handler> Bot<T> {
     pub async fn init(&mut self) {
         assert!(!self.inited);
         self.inited = true;
-        self.pusher_client.init().await;
-        let user_info = self.client.user_info(None).await.unwrap().user;
-        self.pusher_client
-            .subscribe(format!(
-                "private-organization.{}",
-                user_info.organizations[0].id
-            ))
-            .await;
-        self.pusher_client
-            .subscribe(format!("private-user.{}", user_info.id))
-            .await;
+        for organization in &self.organizations {
+            organization.pusher_client.init().await;
+            let user_info = organization.client.user_info(None).await.unwrap().user;
+            for id in user_info.organizations.iter().map(|o| o.id) {
+                organization
+                    .pusher_client
+                    .subscribe(format!("private-organization.{}", id))
+                    .await;
+            }
+            organization
+                .pusher_client
+                .subscribe(format!("private-user.{}", user_info.id))
+                .await;
+        }
     }
 
     /// init() must be called before this function or it will panic.
+    /// Returns when pusher shuts down for every organization or the handler asks to stop from [`Handler::on_error`].
     pub async fn run(&self) {
+        let mut organizations: FuturesUnordered<_> = self
+            .organizations
+            .iter()
+            .map(|organization| self.run_organization(organization))
+            .collect();
+        while let Some(flow) = organizations.next().await {
+            if flow.is_break() {
+                break;
+            }
+        }
+    }
+
+    async fn run_organization(&self, organization: &Organization) -> ControlFlow<()> {
+        let mut server_messages = organization.pusher_client.server_messages().await;
         loop {
-            let message = self.pusher_client.server_messages().await.recv().await;
+            let message = server_messages.recv().await;
             match message {
                 Ok(PusherServerMessageWrapper::PusherServerMessage(message)) => match message {
                     PusherServerMessage::Event(event) => {
-                        let _ = self.handler.handle(self.client.clone(), event.event).await;
+                        if let Err(e) = self
+                            .handler
+                            .handle(organization.client.clone(), event.event)
+                            .await
+                        {
+                            if self.handler.on_error(e).is_break() {
+                                return ControlFlow::Break(());
+                            }
+                        }
                     }
                     PusherServerMessage::Error(e) => {
                         error!("Received error: {:?}", e);
@@ -141,6 +207,13 @@ impl<T: Handler> Bot<T> {
                     }
                     _ => {}
                 },
+                Ok(PusherServerMessageWrapper::Disconnected) => {
+                    warn!("Lost connection to pusher, reconnecting");
+                }
+                Ok(PusherServerMessageWrapper::Reconnected) => {
+                    info!("Reconnected to pusher");
+                }
+                Err(RecvError::Closed) => return ControlFlow::Continue(()),
                 Err(e) => {
                     error!("Error receiving message: {:?}", e);
                 }
@@ -151,20 +224,21 @@ impl<T: Handler> Bot<T> {
 }
 
 pub struct BotBuilder<T: Handler> {
-    client: Option<Arc<ProntoClient>>,
+    clients: Vec<Arc<ProntoClient>>,
     handler: Option<T>,
 }
 
 impl<T: Handler> BotBuilder<T> {
     pub fn new() -> Self {
         Self {
-            client: None,
+            clients: Vec::new(),
             handler: None,
         }
     }
 
+    /// Add a client, call this once per organization the bot should run in
     pub fn client(mut self, client: Arc<ProntoClient>) -> Self {
-        self.client = Some(client);
+        self.clients.push(client);
         self
     }
 
@@ -174,7 +248,7 @@ impl<T: Handler> BotBuilder<T> {
         token_loader: impl TokenLoader,
         user_id: u64,
     ) -> Self {
-        self.client = Some(Arc::new(
+        self.clients.push(Arc::new(
             ProntoClient::new(
                 base_url,
                 &token_loader.load(user_id).await.unwrap().unwrap(),
@@ -190,6 +264,7 @@ impl<T: Handler> BotBuilder<T> {
     }
 
     pub async fn build(self) -> Bot<T> {
-        Bot::new(self.client.unwrap(), self.handler.unwrap()).await
+        assert!(!self.clients.is_empty(), "A bot needs at least one client");
+        Bot::with_clients(self.clients, self.handler.unwrap()).await
     }
 }
@@ -12,7 +12,11 @@ async fn main() {
         .unwrap(),
     );
 
-    let pusher_client = PusherClient::new(client).await;
+    // Set PUSHER_RECORDING to a path to save the traffic for PusherClient::replay
+    let pusher_client = match std::env::var("PUSHER_RECORDING") {
+        Ok(path) => PusherClient::recording(client, path).await.unwrap(),
+        Err(_) => PusherClient::new(client).await,
+    };
     let mut sub = pusher_client.server_messages().await;
     pusher_client.init().await;
     loop {
@@ -0,0 +1,108 @@
+use crate::{PusherServerEvent, PusherServerEventType, PusherServerMessageAddedEvent};
+use client::{Message, ProntoClient, ResponseError};
+use log::{error, warn};
+use std::collections::{HashMap, HashSet};
+
+/// Upper bound on history pages fetched per bubble when catching up,
+/// so a long outage doesn't turn into thousands of requests.
+const MAX_PAGES_PER_BUBBLE: usize = 10;
+
+/// Tracks the latest message seen in each bubble, so messages sent while disconnected
+/// can be fetched from the bubble history and replayed as if they came from pusher.
+#[derive(Default)]
+pub(crate) struct Backfill {
+    latest: HashMap<u64, u64>,
+    /// Messages replayed by the last catch up, live events duplicating them are dropped
+    replayed: HashSet<u64>,
+}
+
+impl Backfill {
+    /// Record the latest message of every bubble as the point to catch up from.
+    pub async fn init(&mut self, client: &ProntoClient) -> Result<(), ResponseError> {
+        let bubbles = client.bubble_list().await?;
+        for stats in bubbles.stats {
+            self.latest
+                .entry(stats.bubble_id)
+                .or_insert(stats.latest_message_id);
+        }
+        Ok(())
+    }
+
+    /// Track a live message, returns false if it was already replayed and should be dropped.
+    pub fn observe(&mut self, message: &Message) -> bool {
+        let latest = self.latest.entry(message.bubble_id).or_insert(message.id);
+        *latest = (*latest).max(message.id);
+        !self.replayed.remove(&message.id)
+    }
+
+    /// Fetch every message newer than the latest one seen in its bubble,
+    /// returned as message added events in the order they were sent.
+    pub async fn catch_up(
+        &mut self,
+        client: &ProntoClient,
+    ) -> Result<Vec<PusherServerEvent>, ResponseError> {
+        self.replayed.clear();
+        let bubbles = client.bubble_list().await?;
+        let mut missed = vec![];
+        for stats in bubbles.stats {
+            let Some(&seen) = self.latest.get(&stats.bubble_id) else {
+                // Joined while disconnected, so there is nothing to compare against
+                self.latest.insert(stats.bubble_id, stats.latest_message_id);
+                continue;
+            };
+            if stats.latest_message_id <= seen {
+                continue;
+            }
+            let Some(bubble) = bubbles.bubbles.iter().find(|b| b.id == stats.bubble_id) else {
+                continue;
+            };
+            let channel = format!("private-bubble.{}.{}", bubble.id, bubble.channel_code);
+            match Self::history_since(client, bubble.id, seen).await {
+                Ok(messages) => {
+                    for message in messages {
+                        self.observe(&message);
+                        self.replayed.insert(message.id);
+                        missed.push((channel.clone(), message));
+                    }
+                }
+                Err(e) => error!(
+                    "Failed to fetch missed messages in bubble {}: {e}",
+                    bubble.id
+                ),
+            }
+        }
+        missed.sort_by_key(|(_, message)| message.id);
+        Ok(missed
+            .into_iter()
+            .map(|(channel, message)| PusherServerEvent {
+                channel,
+                event: PusherServerEventType::PusherServerMessageAddedEvent(
+                    PusherServerMessageAddedEvent { message },
+                ),
+            })
+            .collect())
+    }
+
+    /// Page backwards through a bubble's history until reaching `seen`.
+    async fn history_since(
+        client: &ProntoClient,
+        bubble_id: u64,
+        seen: u64,
+    ) -> Result<Vec<Message>, ResponseError> {
+        let mut messages = vec![];
+        let mut before = None;
+        for _ in 0..MAX_PAGES_PER_BUBBLE {
+            let page = client.bubble_history(bubble_id, before).await?.messages;
+            let done = page.last().is_none_or(|m| m.id <= seen);
+            before = page.last().map(|m| m.id);
+            messages.extend(page.into_iter().filter(|m| m.id > seen));
+            if done {
+                return Ok(messages);
+            }
+        }
+        warn!(
+            "Stopped catching up on bubble {bubble_id} after {MAX_PAGES_PER_BUBBLE} pages, older messages were skipped"
+        );
+        Ok(messages)
+    }
+}
@@ -0,0 +1,313 @@
+use crate::backfill::Backfill;
+use crate::recording::Recorder;
+use crate::{
+    PusherClientMessage, PusherClientMessageWrapper, PusherServerConnectionEstablished,
+    PusherServerEvent, PusherServerEventType, PusherServerMessage, PusherServerMessageWrapper,
+};
+use client::ProntoClient;
+use futures_util::future::join_all;
+use futures_util::stream::SplitSink;
+use futures_util::{SinkExt, StreamExt};
+use log::{debug, error, info, warn};
+use rand::Rng;
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::sync::{RwLock, broadcast, mpsc, watch};
+use tokio::time::{Instant, sleep};
+use tokio_tungstenite::tungstenite::{self, Bytes, Message, Utf8Bytes};
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream, connect_async};
+
+pub(crate) const PUSHER_URL: &str = "wss://ws-mt1.pusher.com/app/f44139496d9b75f37d27?protocol=7&client=js&version=8.3.0&flash=false";
+
+const BACKOFF_BASE: Duration = Duration::from_secs(1);
+const BACKOFF_MAX: Duration = Duration::from_secs(60);
+/// Used until pusher tells us the real activity timeout
+const DEFAULT_ACTIVITY_TIMEOUT: Duration = Duration::from_secs(120);
+/// How long to wait for any response to a `pusher:ping` before declaring the connection dead
+const PONG_TIMEOUT: Duration = Duration::from_secs(30);
+
+type WsStream = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+/// State shared between the [`crate::PusherClient`] handles and the connection thread.
+pub(crate) struct Shared {
+    /// Every channel that has been subscribed to, these are subscribed to again on every connection
+    pub subscriptions: RwLock<HashSet<String>>,
+    /// The details of the current connection, `None` while disconnected
+    pub details: watch::Sender<Option<PusherServerConnectionEstablished>>,
+}
+
+enum SessionEnd {
+    /// The connection was lost and should be re-established
+    Disconnected,
+    /// Either the client asked to shut down or pusher refused the connection for good
+    Shutdown,
+}
+
+/// Exponential backoff with jitter, so a fleet of clients doesn't reconnect in lockstep.
+fn backoff(attempt: u32) -> Duration {
+    let delay = BACKOFF_BASE
+        .saturating_mul(2u32.saturating_pow(attempt))
+        .min(BACKOFF_MAX);
+    let half = delay / 2;
+    half + rand::thread_rng().gen_range(Duration::ZERO..=half)
+}
+
+fn text(message: &PusherClientMessage) -> Message {
+    Message::Text(Utf8Bytes::from(message.to_string()))
+}
+
+/// Owns the websocket and keeps it alive, reconnecting and resubscribing whenever it drops.
+pub(crate) struct Connection {
+    url: String,
+    client: Arc<ProntoClient>,
+    shared: Arc<Shared>,
+    server_messages: broadcast::Sender<PusherServerMessageWrapper>,
+    client_messages: mpsc::Receiver<PusherClientMessageWrapper>,
+    /// Number of consecutive attempts that failed to establish a connection
+    attempt: u32,
+    /// Whether a connection has been established before, which makes any later one a reconnect
+    has_connected: bool,
+    backfill: Backfill,
+    recorder: Option<Recorder>,
+}
+
+impl Connection {
+    pub fn new(
+        url: String,
+        client: Arc<ProntoClient>,
+        shared: Arc<Shared>,
+        server_messages: broadcast::Sender<PusherServerMessageWrapper>,
+        client_messages: mpsc::Receiver<PusherClientMessageWrapper>,
+        recorder: Option<Recorder>,
+    ) -> Self {
+        Self {
+            url,
+            client,
+            shared,
+            server_messages,
+            client_messages,
+            attempt: 0,
+            has_connected: false,
+            backfill: Backfill::default(),
+            recorder,
+        }
+    }
+
+    pub async fn run(mut self) {
+        loop {
+            let end = match connect_async(&self.url).await {
+                Ok((stream, _)) => self.session(stream).await,
+                Err(e) => {
+                    warn!("Failed to connect to pusher: {e}");
+                    SessionEnd::Disconnected
+                }
+            };
+            if let SessionEnd::Shutdown = end {
+                break;
+            }
+            let delay = backoff(self.attempt);
+            self.attempt = self.attempt.saturating_add(1);
+            info!("Reconnecting to pusher in {} ms", delay.as_millis());
+            if self.wait(delay).await {
+                break;
+            }
+        }
+        self.shared.details.send_replace(None);
+        let _ = self
+            .server_messages
+            .send(PusherServerMessageWrapper::Shutdown);
+    }
+
+    /// Sleep before the next reconnect attempt, returns true if a shutdown was requested meanwhile.
+    async fn wait(&mut self, delay: Duration) -> bool {
+        let deadline = sleep(delay);
+        tokio::pin!(deadline);
+        loop {
+            tokio::select! {
+                _ = &mut deadline => return false,
+                message = self.client_messages.recv() => match message {
+                    Some(PusherClientMessageWrapper::Shutdown) | None => return true,
+                    // Subscriptions are replayed after reconnecting, and pongs are meaningless now
+                    Some(message) => debug!("Dropping {:?} while disconnected", message),
+                },
+            }
+        }
+    }
+
+    async fn session(&mut self, stream: WsStream) -> SessionEnd {
+        let (mut sink, mut stream) = stream.split();
+        let mut activity_timeout = DEFAULT_ACTIVITY_TIMEOUT;
+        let mut awaiting_pong = false;
+        let mut established = false;
+        let idle = sleep(activity_timeout);
+        tokio::pin!(idle);
+
+        let end = loop {
+            tokio::select! {
+                frame = stream.next() => {
+                    awaiting_pong = false;
+                    idle.as_mut().reset(Instant::now() + activity_timeout);
+                    match frame {
+                        Some(Ok(Message::Text(frame))) => {
+                            if let Some(recorder) = &mut self.recorder
+                                && let Err(e) = recorder.record(frame.as_str())
+                            {
+                                error!("Failed to record pusher frame: {e}");
+                            }
+                            let message = PusherServerMessage::from(frame.as_str().to_string());
+                            let connected = match &message {
+                                PusherServerMessage::ConnectionEstablished(details) => {
+                                    activity_timeout = Duration::from_secs(details.activity_timeout);
+                                    idle.as_mut().reset(Instant::now() + activity_timeout);
+                                    // Under the lock, so a channel subscribed to meanwhile is sent
+                                    // either here or by `subscribe`, never twice or not at all
+                                    let subscriptions = self.shared.subscriptions.read().await;
+                                    self.shared.details.send_replace(Some(details.clone()));
+                                    Some((details.socket_id.clone(), subscriptions.clone()))
+                                }
+                                PusherServerMessage::Other(raw) if raw.event == "pusher:ping" => {
+                                    if let Err(e) = sink.send(text(&PusherClientMessage::Pong)).await {
+                                        error!("Failed to send pong to pusher: {e}");
+                                        break SessionEnd::Disconnected;
+                                    }
+                                    None
+                                }
+                                PusherServerMessage::Event(PusherServerEvent {
+                                    event: PusherServerEventType::PusherServerMessageAddedEvent(event),
+                                    ..
+                                }) if !self.backfill.observe(&event.message) => continue,
+                                _ => None,
+                            };
+                            let _ = self
+                                .server_messages
+                                .send(PusherServerMessageWrapper::PusherServerMessage(message));
+                            if let Some((socket_id, channels)) = connected {
+                                established = true;
+                                self.attempt = 0;
+                                if let Err(e) = self.subscribe_all(&mut sink, &socket_id, channels).await {
+                                    error!("Failed to subscribe to pusher channels: {e}");
+                                    break SessionEnd::Disconnected;
+                                }
+                                if self.has_connected {
+                                    self.replay_missed().await;
+                                    info!("Reconnected to pusher");
+                                    let _ = self.server_messages.send(PusherServerMessageWrapper::Reconnected);
+                                } else if let Err(e) = self.backfill.init(&self.client).await {
+                                    warn!("Failed to get the latest messages, messages missed while disconnected will not be replayed: {e}");
+                                }
+                            }
+                        }
+                        Some(Ok(Message::Ping(_))) => {
+                            let _ = self.server_messages.send(PusherServerMessageWrapper::Ping);
+                            if let Err(e) = sink.send(Message::Pong(Bytes::new())).await {
+                                error!("Failed to send pong to pusher: {e}");
+                                break SessionEnd::Disconnected;
+                            }
+                        }
+                        Some(Ok(Message::Close(frame))) => {
+                            // Pusher uses 4000-4099 for errors that retrying will not fix
+                            if let Some(frame) = frame
+                                && (4000..4100).contains(&u16::from(frame.code))
+                            {
+                                error!("Pusher closed the connection: {}", frame.reason);
+                                break SessionEnd::Shutdown;
+                            }
+                            warn!("Pusher closed the connection");
+                            break SessionEnd::Disconnected;
+                        }
+                        Some(Ok(_)) => {}
+                        Some(Err(e)) => {
+                            error!("Error reading from pusher: {e}");
+                            break SessionEnd::Disconnected;
+                        }
+                        None => {
+                            warn!("Pusher connection ended");
+                            break SessionEnd::Disconnected;
+                        }
+                    }
+                }
+                message = self.client_messages.recv() => {
+                    let frame = match message {
+                        Some(PusherClientMessageWrapper::PusherClientMessage(message)) => text(&message),
+                        Some(PusherClientMessageWrapper::Pong) => Message::Pong(Bytes::new()),
+                        Some(PusherClientMessageWrapper::Shutdown) | None => {
+                            let _ = sink.send(Message::Close(None)).await;
+                            break SessionEnd::Shutdown;
+                        }
+                    };
+                    if let Err(e) = sink.send(frame).await {
+                        error!("Error writing to pusher: {e}");
+                        break SessionEnd::Disconnected;
+                    }
+                }
+                _ = &mut idle => {
+                    if awaiting_pong {
+                        warn!("Pusher did not answer our ping, assuming the connection is dead");
+                        break SessionEnd::Disconnected;
+                    }
+                    awaiting_pong = true;
+                    idle.as_mut().reset(Instant::now() + PONG_TIMEOUT);
+                    if let Err(e) = sink.send(text(&PusherClientMessage::Ping)).await {
+                        error!("Failed to send ping to pusher: {e}");
+                        break SessionEnd::Disconnected;
+                    }
+                }
+            }
+        };
+
+        if established {
+            self.has_connected = true;
+            if let SessionEnd::Disconnected = end {
+                self.shared.details.send_replace(None);
+                let _ = self
+                    .server_messages
+                    .send(PusherServerMessageWrapper::Disconnected);
+            }
+        }
+        end
+    }
+
+    /// Send the messages that were missed while disconnected before any live events.
+    /// Live events keep queueing up in the socket until this returns.
+    async fn replay_missed(&mut self) {
+        match self.backfill.catch_up(&self.client).await {
+            Ok(events) => {
+                info!(
+                    "Replaying {} messages missed while disconnected",
+                    events.len()
+                );
+                for event in events {
+                    let _ =
+                        self.server_messages
+                            .send(PusherServerMessageWrapper::PusherServerMessage(
+                                PusherServerMessage::Event(event),
+                            ));
+                }
+            }
+            Err(e) => error!("Failed to fetch messages missed while disconnected: {e}"),
+        }
+    }
+
+    /// Authenticate `channels` against the new socket id and subscribe to them, those subscribed
+    /// to before the first connection or the previous one dropped.
+    async fn subscribe_all(
+        &self,
+        sink: &mut SplitSink<WsStream, Message>,
+        socket_id: &str,
+        channels: HashSet<String>,
+    ) -> Result<(), tungstenite::Error> {
+        let messages = join_all(channels.iter().map(|channel| {
+            PusherClientMessage::subscribe(self.client.clone(), socket_id, channel)
+        }))
+        .await;
+        for (channel, message) in channels.iter().zip(messages) {
+            match message {
+                Ok(message) => sink.send(text(&message)).await?,
+                Err(e) => error!("Failed to authenticate pusher channel {channel}: {e}"),
+            }
+        }
+        Ok(())
+    }
+}
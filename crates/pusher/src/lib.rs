@@ -1,58 +1,34 @@
+mod backfill;
+mod connection;
 mod message;
+mod recording;
 
+use crate::connection::{Connection, PUSHER_URL, Shared};
+use crate::recording::{Recorder, Replay};
 use client::ProntoClient;
-use futures_util::{SinkExt, StreamExt};
 use log::error;
 pub use message::*;
+pub use recording::{RecordedFrame, ReplaySpeed, load as load_recording};
+use std::collections::HashSet;
+use std::io;
+use std::path::Path;
 use std::sync::Arc;
 use std::thread;
-use std::time::Duration;
-use tokio::net::TcpStream;
-use tokio::sync::{RwLock, broadcast, mpsc};
-use tokio_tungstenite::tungstenite::{Bytes, Message, Utf8Bytes};
-use tokio_tungstenite::{MaybeTlsStream, WebSocketStream, connect_async};
+use tokio::sync::{Notify, RwLock, broadcast, mpsc, watch};
 
 #[derive(Clone, Debug)]
 pub enum PusherServerMessageWrapper {
     PusherServerMessage(PusherServerMessage),
     Ping,
+    /// The connection was lost, a reconnect is being attempted in the background
+    Disconnected,
+    /// The connection was re-established and all channels were resubscribed to.
+    /// Messages sent while disconnected have been replayed as message added events by the time
+    /// this is received, any other events from that period are lost and should be refetched.
+    Reconnected,
     Shutdown,
 }
 
-async fn read_task(
-    stream: Arc<RwLock<WebSocketStream<MaybeTlsStream<TcpStream>>>>,
-    message_output: broadcast::Sender<PusherServerMessageWrapper>,
-) {
-    loop {
-        let message = stream.write().await.next().await;
-        let message = match message {
-            Some(message) => message,
-            _ => {
-                break;
-            }
-        };
-        match message {
-            Ok(Message::Text(message)) => {
-                let data: PusherServerMessage =
-                    PusherServerMessage::from(message.as_str().to_string());
-                let _ = message_output
-                    .send(PusherServerMessageWrapper::PusherServerMessage(data.into()));
-            }
-            Ok(Message::Ping(_)) => {
-                let _ = message_output.send(PusherServerMessageWrapper::Ping);
-            }
-            Ok(Message::Close(_)) => {
-                let _ = message_output.send(PusherServerMessageWrapper::Shutdown);
-                break;
-            }
-            Err(e) => {
-                error!("Error: {:?}", e);
-            }
-            _ => {}
-        }
-    }
-}
-
 #[derive(Clone, Debug)]
 pub enum PusherClientMessageWrapper {
     PusherClientMessage(PusherClientMessage),
@@ -60,163 +36,176 @@ pub enum PusherClientMessageWrapper {
     Shutdown,
 }
 
-async fn write_task(
-    stream: Arc<RwLock<WebSocketStream<MaybeTlsStream<TcpStream>>>>,
-    mut message_input: mpsc::Receiver<PusherClientMessageWrapper>,
-) {
-    loop {
-        let message = message_input.recv().await;
-        let message = match message {
-            Some(message) => message,
-            _ => {
-                break;
-            }
-        };
-        match message {
-            PusherClientMessageWrapper::PusherClientMessage(pcm) => {
-                let _ = stream
-                    .write()
-                    .await
-                    .send(Message::Text(Utf8Bytes::from(pcm.to_string())))
-                    .await;
-            }
-            PusherClientMessageWrapper::Pong => {
-                let _ = stream.write().await.send(Message::Pong(Bytes::new())).await;
-            }
-            PusherClientMessageWrapper::Shutdown => {
-                let _ = stream.write().await.send(Message::Close(None)).await;
-                break;
-            }
-        }
-    }
-}
-
-async fn ping_task(
-    mut server_messages: broadcast::Receiver<PusherServerMessageWrapper>,
-    client_message: mpsc::Sender<PusherClientMessageWrapper>,
-) {
-    // Respond to all pings with pongs to ensure the connection doesn't close
-    loop {
-        let message = server_messages.recv().await;
-        let message = match message {
-            Ok(message) => message,
-            _ => {
-                break;
-            }
-        };
-        match message {
-            PusherServerMessageWrapper::Ping => {
-                let _ = client_message.send(PusherClientMessageWrapper::Pong).await;
-            }
-            PusherServerMessageWrapper::Shutdown => {
-                let _ = client_message
-                    .send(PusherClientMessageWrapper::Shutdown)
-                    .await;
-                break;
-            }
-            _ => {}
-        }
-    }
+#[tokio::main]
+async fn task_thread(connection: Connection) {
+    connection.run().await;
 }
 
 #[tokio::main]
-async fn task_thread(
-    ws_stream: Arc<RwLock<WebSocketStream<MaybeTlsStream<TcpStream>>>>,
-    server_messages_tx: broadcast::Sender<PusherServerMessageWrapper>,
-    server_messages_rx: broadcast::Receiver<PusherServerMessageWrapper>,
-    client_messages_tx: mpsc::Sender<PusherClientMessageWrapper>,
-    client_messages_rx: mpsc::Receiver<PusherClientMessageWrapper>,
-) {
-    let write_task = write_task(ws_stream.clone(), client_messages_rx);
-    let ping_task = ping_task(server_messages_rx, client_messages_tx);
-    let read_task = read_task(ws_stream, server_messages_tx);
-    let wt = tokio::task::spawn(write_task);
-    let pt = tokio::task::spawn(ping_task);
-    let rt = tokio::task::spawn(read_task);
-    let _ = tokio::join!(wt, pt, rt);
-    // TODO: Remove below after testing (the join should be enough)
-    loop {
-        tokio::time::sleep(Duration::from_secs(5)).await;
-    }
+async fn replay_thread(replay: Replay) {
+    replay.run().await;
 }
 
 /// A pusher client handles sending and receiving messages to and from pusher.
 /// The communication happens on a thread because tokio task scoping is not great for this,
 /// although this might change in the future.
+///
+/// Dropped connections are detected (close frames, read errors and unanswered pings)
+/// and re-established with exponential backoff, after which every subscribed channel is
+/// re-authenticated and subscribed to again, and messages missed in the meantime are replayed.
 #[derive(Clone)]
 pub struct PusherClient {
-    client: Arc<ProntoClient>,
+    /// `None` when replaying a recording, subscribing then only remembers the channel
+    client: Option<Arc<ProntoClient>>,
+    shared: Arc<Shared>,
     server_messages: Arc<RwLock<broadcast::Receiver<PusherServerMessageWrapper>>>,
     client_message: Arc<RwLock<mpsc::Sender<PusherClientMessageWrapper>>>,
-    details: Arc<RwLock<Option<PusherServerConnectionEstablished>>>,
+    details: watch::Receiver<Option<PusherServerConnectionEstablished>>,
+    /// Set when replaying, notified whenever a receiver is handed out
+    replay_start: Option<Arc<Notify>>,
 }
 
 impl PusherClient {
-    /// Connect to the pusher socket, spawn the communication thread, and initialize channels
+    /// Spawn the communication thread, which connects to the pusher socket, and initialize channels
     pub async fn new(client: Arc<ProntoClient>) -> Self {
-        // TODO: make this portable
-        let (ws_stream, _) = connect_async("wss://ws-mt1.pusher.com/app/f44139496d9b75f37d27?protocol=7&client=js&version=8.3.0&flash=false")
-            .await
-            .unwrap();
-        let ws_stream = Arc::new(RwLock::new(ws_stream));
-        let (message_output_tx, message_output_rx) = broadcast::channel(128);
-        let (message_input_tx, message_input_rx) = mpsc::channel(128);
+        Self::connect(client, PUSHER_URL.to_string(), None)
+    }
+
+    /// Same as [`PusherClient::new`], but connects to another pusher compatible socket.
+    pub async fn with_url(client: Arc<ProntoClient>, url: impl Into<String>) -> Self {
+        Self::connect(client, url.into(), None)
+    }
+
+    /// Same as [`PusherClient::new`], but every frame received is also appended to a JSONL recording at `path`,
+    /// which can be fed back through [`PusherClient::replay`].
+    pub async fn recording(client: Arc<ProntoClient>, path: impl AsRef<Path>) -> io::Result<Self> {
+        Ok(Self::connect(
+            client,
+            PUSHER_URL.to_string(),
+            Some(Recorder::create(path)?),
+        ))
+    }
+
+    /// Replay a recording made with [`PusherClient::recording`] instead of connecting to pusher.
+    /// Frames are sent once the first receiver is created with [`PusherClient::server_messages`],
+    /// and a [`PusherServerMessageWrapper::Shutdown`] follows the last one.
+    /// Subscribing only remembers the channel, no requests are made.
+    pub fn replay(path: impl AsRef<Path>, speed: ReplaySpeed) -> io::Result<Self> {
+        let frames = recording::load(path)?;
+        let start = Arc::new(Notify::new());
+        let (this, message_output_tx, message_input_rx) = Self::channels(None, Some(start.clone()));
+        // Lets init() return straight away, the frame itself is still replayed in order
+        let details =
+            frames.iter().find_map(
+                |frame| match PusherServerMessage::from(frame.data.clone()) {
+                    PusherServerMessage::ConnectionEstablished(details) => Some(details),
+                    _ => None,
+                },
+            );
+        this.shared.details.send_replace(details);
+
+        thread::spawn({
+            let replay = Replay::new(
+                frames,
+                speed,
+                this.shared.clone(),
+                message_output_tx,
+                message_input_rx,
+                start,
+            );
+            move || {
+                replay_thread(replay);
+            }
+        });
+        Ok(this)
+    }
 
+    fn connect(client: Arc<ProntoClient>, url: String, recorder: Option<Recorder>) -> Self {
+        let (this, message_output_tx, message_input_rx) =
+            Self::channels(Some(client.clone()), None);
         thread::spawn({
-            let message_output_rx = message_output_tx.subscribe();
-            let message_input_tx = message_input_tx.clone();
+            let connection = Connection::new(
+                url,
+                client,
+                this.shared.clone(),
+                message_output_tx,
+                message_input_rx,
+                recorder,
+            );
             move || {
-                task_thread(
-                    ws_stream,
-                    message_output_tx,
-                    message_output_rx,
-                    message_input_tx,
-                    message_input_rx,
-                );
+                task_thread(connection);
             }
         });
+        this
+    }
 
-        let client = Self {
+    fn channels(
+        client: Option<Arc<ProntoClient>>,
+        replay_start: Option<Arc<Notify>>,
+    ) -> (
+        Self,
+        broadcast::Sender<PusherServerMessageWrapper>,
+        mpsc::Receiver<PusherClientMessageWrapper>,
+    ) {
+        // Large enough to hold a burst of messages replayed after reconnecting
+        let (message_output_tx, message_output_rx) = broadcast::channel(1024);
+        let (message_input_tx, message_input_rx) = mpsc::channel(128);
+        let (details_tx, details_rx) = watch::channel(None);
+        let shared = Arc::new(Shared {
+            subscriptions: RwLock::new(HashSet::new()),
+            details: details_tx,
+        });
+        let this = Self {
             client,
+            shared,
             server_messages: Arc::new(RwLock::new(message_output_rx)),
             client_message: Arc::new(RwLock::new(message_input_tx)),
-            details: Arc::new(RwLock::new(None)),
+            details: details_rx,
+            replay_start,
         };
-
-        client
+        (this, message_output_tx, message_input_rx)
     }
 
+    /// Wait until the connection to pusher has been established.
     pub async fn init(&self) {
-        loop {
-            let message = self.server_messages().await.recv().await.unwrap();
-            match message {
-                PusherServerMessageWrapper::PusherServerMessage(
-                    PusherServerMessage::ConnectionEstablished(details),
-                ) => {
-                    let mut details_guard = self.details.write().await;
-                    *details_guard = Some(details);
-                    break;
-                }
-                _ => {}
-            }
-        }
+        let mut details = self.details.clone();
+        let _ = details.wait_for(|details| details.is_some()).await;
     }
 
-    /// Get authentication and subscribe to a channel
+    /// Get authentication and subscribe to a channel.
+    /// The subscription is remembered and renewed whenever the connection is re-established.
     pub async fn subscribe(&self, channel: String) {
-        let details = self.details.read().await.clone().unwrap();
-        let message =
-            PusherClientMessage::subscribe(self.client.clone(), &details.socket_id, &channel).await;
-        let _ = self
-            .client_message()
-            .await
-            .send(PusherClientMessageWrapper::PusherClientMessage(message))
-            .await;
+        let details = {
+            let mut subscriptions = self.shared.subscriptions.write().await;
+            subscriptions.insert(channel.clone());
+            self.details.borrow().clone()
+        };
+        let Some(client) = self.client.clone() else {
+            return;
+        };
+        // While disconnected the subscription happens as part of connecting
+        let Some(details) = details else {
+            return;
+        };
+        match PusherClientMessage::subscribe(client, &details.socket_id, &channel).await {
+            Ok(message) => {
+                let _ = self
+                    .client_message()
+                    .await
+                    .send(PusherClientMessageWrapper::PusherClientMessage(message))
+                    .await;
+            }
+            Err(e) => error!("Failed to authenticate pusher channel {channel}: {e}"),
+        }
     }
 
     /// Get a broadcast receiver for server messages
     pub async fn server_messages(&self) -> broadcast::Receiver<PusherServerMessageWrapper> {
-        self.server_messages.read().await.resubscribe()
+        let receiver = self.server_messages.read().await.resubscribe();
+        if let Some(start) = &self.replay_start {
+            start.notify_one();
+        }
+        receiver
     }
 
     /// Get a mpsc sender for sending client messages
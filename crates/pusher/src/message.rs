@@ -2,7 +2,7 @@ use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use std::sync::Arc;
 
-use client::ProntoClient;
+use client::{ProntoClient, ResponseError};
 
 macro_rules! create_event {
     ($raw:expr, $event_type:ident, $data_type:ty) => {{
@@ -23,26 +23,37 @@ pub struct PusherClientSubscribe {
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum PusherClientMessage {
     Subscribe(PusherClientSubscribe),
+    /// Protocol level keepalive, pusher answers with `pusher:pong`
+    Ping,
+    /// Answer to a protocol level `pusher:ping` from the server
+    Pong,
 }
 
 impl PusherClientMessage {
-    pub async fn subscribe(client: Arc<ProntoClient>, socket_id: &str, channel: &str) -> Self {
-        let auth = client.pusher_auth(socket_id, channel).await.unwrap();
-        Self::Subscribe(PusherClientSubscribe {
+    pub async fn subscribe(
+        client: Arc<ProntoClient>,
+        socket_id: &str,
+        channel: &str,
+    ) -> Result<Self, ResponseError> {
+        let auth = client.pusher_auth(socket_id, channel).await?;
+        Ok(Self::Subscribe(PusherClientSubscribe {
             auth: auth.auth,
             channel: channel.to_string(),
-        })
+        }))
     }
 
     pub fn to_string(&self) -> String {
-        match self {
-            Self::Subscribe(sub) => serde_json::to_string(&RawPusherMessage {
-                event: "pusher:subscribe".to_string(),
-                data: serde_json::to_value(&sub).unwrap(),
-                channel: None,
-            })
-            .unwrap(),
-        }
+        let (event, data) = match self {
+            Self::Subscribe(sub) => ("pusher:subscribe", serde_json::to_value(sub).unwrap()),
+            Self::Ping => ("pusher:ping", Value::Object(Default::default())),
+            Self::Pong => ("pusher:pong", Value::Object(Default::default())),
+        };
+        serde_json::to_string(&RawPusherMessage {
+            event: event.to_string(),
+            data,
+            channel: None,
+        })
+        .unwrap()
     }
 }
 
@@ -159,6 +170,42 @@ pub struct PusherServerTaskUpdatedEvent {
     pub task: client::Task,
 }
 
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PusherServerCategoryUpdatedEvent {
+    pub category: client::Category,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ChangedBubble {
+    pub id: u64,
+    pub updated_at: String,
+}
+
+/// Only says which bubble changed, the bubble itself has to be fetched again.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PusherServerBubbleChangedEvent {
+    pub bubble: ChangedBubble,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RemovedBubble {
+    pub id: u64,
+    pub updated_at: String,
+    #[serde(rename = "wasHidden", default)]
+    pub was_hidden: bool,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PusherServerBubbleRemovedEvent {
+    pub bubble: RemovedBubble,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PusherServerMessageTranslationAddedEvent {
+    #[serde(rename = "messagetrans")]
+    pub translation: client::MessageTranslation,
+}
+
 // RawPusherMessage { event: "App\\Events\\CategoryUpdated", data: String("{\"category\":{\"id\":679345,\"organization_
 // id\":2245,\"created_at\":\"2023-08-13 04:44:16\",\"updated_at\":\"2023-08-13 04:44:16\",\"sortorder\":149,\"title\":\"Clubs\",\"externalid\":null,\"usercategory\":{\"id\":9689,\"user_id\":5302428,\"category_id\":679345,\"alias\":\"test\",\"created_at\":\"2024-10-03 03:35:32\",\"updated_at\":\"2024-10-03 03:35:32\"}}}"), channel: Some("private-user.5302428") }
 
@@ -209,6 +256,10 @@ pub enum PusherServerEventType {
     PusherServerAnnouncementUpdatedEvent(PusherServerAnnouncementUpdatedEvent),
     PusherServerAnnouncementRemovedEvent(PusherServerAnnouncementRemovedEvent),
     PusherServerTaskUpdatedEvent(PusherServerTaskUpdatedEvent),
+    PusherServerCategoryUpdatedEvent(PusherServerCategoryUpdatedEvent),
+    PusherServerBubbleChangedEvent(PusherServerBubbleChangedEvent),
+    PusherServerBubbleRemovedEvent(PusherServerBubbleRemovedEvent),
+    PusherServerMessageTranslationAddedEvent(PusherServerMessageTranslationAddedEvent),
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -365,6 +416,34 @@ impl From<String> for PusherServerMessage {
                     PusherServerTaskUpdatedEvent
                 )
             }
+            "App\\Events\\CategoryUpdated" => {
+                create_event!(
+                    raw,
+                    PusherServerCategoryUpdatedEvent,
+                    PusherServerCategoryUpdatedEvent
+                )
+            }
+            "App\\Events\\BubbleChanged" => {
+                create_event!(
+                    raw,
+                    PusherServerBubbleChangedEvent,
+                    PusherServerBubbleChangedEvent
+                )
+            }
+            "App\\Events\\BubbleRemoved" => {
+                create_event!(
+                    raw,
+                    PusherServerBubbleRemovedEvent,
+                    PusherServerBubbleRemovedEvent
+                )
+            }
+            "App\\Events\\MessageTransAdded" => {
+                create_event!(
+                    raw,
+                    PusherServerMessageTranslationAddedEvent,
+                    PusherServerMessageTranslationAddedEvent
+                )
+            }
             _ => Self::Other(raw),
         }
     }
@@ -0,0 +1,239 @@
+use crate::connection::Shared;
+use crate::{
+    PusherClientMessageWrapper, PusherServerMessage, PusherServerMessageWrapper, RawPusherMessage,
+};
+use log::debug;
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::io::{self, LineWriter, Write};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::{Notify, broadcast, mpsc};
+use tokio::time::sleep;
+
+/// A single frame received from pusher, one per line in a recording.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RecordedFrame {
+    /// Wall clock time the frame was received at, in milliseconds since the unix epoch
+    pub timestamp: u64,
+    /// Milliseconds since the recording started, used to reproduce the original timing
+    pub elapsed: u64,
+    /// The channel the frame was sent on, if any
+    pub channel: Option<String>,
+    /// The frame exactly as it came off the socket
+    pub data: String,
+}
+
+/// How fast a recording is replayed.
+#[derive(Copy, Clone, Debug)]
+pub enum ReplaySpeed {
+    /// Send every frame as soon as the previous one was sent.
+    /// Recordings longer than the broadcast channel capacity should be replayed with [`ReplaySpeed::Scaled`],
+    /// otherwise slow receivers will lag.
+    Instant,
+    /// Keep the recorded gaps between frames, divided by the given factor.
+    /// Factors that aren't finite and positive replay like [`ReplaySpeed::Instant`].
+    Scaled(f64),
+}
+
+impl ReplaySpeed {
+    /// [`ReplaySpeed::Scaled`] by `factor`, or [`ReplaySpeed::Instant`] if it isn't finite and positive
+    pub fn scaled(factor: f64) -> Self {
+        if factor.is_finite() && factor > 0.0 {
+            Self::Scaled(factor)
+        } else {
+            Self::Instant
+        }
+    }
+
+    /// How long to wait for a frame recorded `gap` milliseconds after the previous one
+    fn delay(self, gap: u64) -> Duration {
+        match self {
+            Self::Scaled(factor) if factor.is_finite() && factor > 0.0 => {
+                // Tiny factors overflow a duration, those gaps are as good as forever
+                Duration::try_from_secs_f64(gap as f64 / 1000.0 / factor).unwrap_or(Duration::MAX)
+            }
+            Self::Scaled(_) | Self::Instant => Duration::ZERO,
+        }
+    }
+}
+
+/// Read every frame of a recording made with [`crate::PusherClient::recording`].
+pub fn load(path: impl AsRef<Path>) -> io::Result<Vec<RecordedFrame>> {
+    std::fs::read_to_string(path)?
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| Ok(serde_json::from_str(line)?))
+        .collect()
+}
+
+/// Appends every incoming text frame of a connection to a JSONL file.
+/// Outgoing frames are not recorded, they contain channel auth signatures and aren't needed to replay.
+pub(crate) struct Recorder {
+    file: LineWriter<File>,
+    start: Instant,
+}
+
+impl Recorder {
+    pub fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self {
+            file: LineWriter::new(file),
+            start: Instant::now(),
+        })
+    }
+
+    pub fn record(&mut self, data: &str) -> io::Result<()> {
+        let channel = serde_json::from_str::<RawPusherMessage>(data)
+            .ok()
+            .and_then(|raw| raw.channel);
+        let frame = RecordedFrame {
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis() as u64,
+            elapsed: self.start.elapsed().as_millis() as u64,
+            channel,
+            data: data.to_string(),
+        };
+        serde_json::to_writer(&mut self.file, &frame)?;
+        self.file.write_all(b"\n")
+    }
+}
+
+/// Stands in for [`crate::connection::Connection`], feeding a recording through the same channels.
+pub(crate) struct Replay {
+    frames: Vec<RecordedFrame>,
+    speed: ReplaySpeed,
+    shared: Arc<Shared>,
+    server_messages: broadcast::Sender<PusherServerMessageWrapper>,
+    client_messages: mpsc::Receiver<PusherClientMessageWrapper>,
+    /// Notified when the first receiver is handed out, so nothing is sent before anyone listens
+    start: Arc<Notify>,
+}
+
+impl Replay {
+    pub fn new(
+        frames: Vec<RecordedFrame>,
+        speed: ReplaySpeed,
+        shared: Arc<Shared>,
+        server_messages: broadcast::Sender<PusherServerMessageWrapper>,
+        client_messages: mpsc::Receiver<PusherClientMessageWrapper>,
+        start: Arc<Notify>,
+    ) -> Self {
+        Self {
+            frames,
+            speed,
+            shared,
+            server_messages,
+            client_messages,
+            start,
+        }
+    }
+
+    pub async fn run(mut self) {
+        let start = self.start.clone();
+        tokio::select! {
+            _ = start.notified() => {}
+            _ = self.shutdown_requested() => return,
+        }
+        let frames = std::mem::take(&mut self.frames);
+        let mut previous = frames.first().map_or(0, |frame| frame.elapsed);
+        for frame in frames {
+            let delay = self.speed.delay(frame.elapsed.saturating_sub(previous));
+            previous = frame.elapsed;
+            tokio::select! {
+                _ = sleep(delay) => {}
+                _ = self.shutdown_requested() => break,
+            }
+            let message = PusherServerMessage::from(frame.data);
+            if let PusherServerMessage::ConnectionEstablished(details) = &message {
+                self.shared.details.send_replace(Some(details.clone()));
+            }
+            let _ = self
+                .server_messages
+                .send(PusherServerMessageWrapper::PusherServerMessage(message));
+        }
+        let _ = self
+            .server_messages
+            .send(PusherServerMessageWrapper::Shutdown);
+    }
+
+    /// Resolves once a shutdown is requested, anything else sent by the client is dropped.
+    async fn shutdown_requested(&mut self) {
+        loop {
+            match self.client_messages.recv().await {
+                Some(PusherClientMessageWrapper::Shutdown) | None => return,
+                Some(message) => debug!("Dropping {:?} while replaying", message),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{PusherClient, PusherServerEventType};
+
+    const CONNECTION_ESTABLISHED: &str = r#"{"event":"pusher:connection_established","data":"{\"socket_id\":\"1234.5678\",\"activity_timeout\":120}"}"#;
+    const BUBBLE_CHANGED: &str = r#"{"event":"App\\Events\\BubbleChanged","data":"{\"bubble\":{\"id\":3832006,\"updated_at\":\"2024-10-02 18:36:12\"}}","channel":"private-user.5302428"}"#;
+    const TRANSLATION_ADDED: &str = r#"{"event":"App\\Events\\MessageTransAdded","data":"{\"messagetrans\":{\"message_id\":90641609,\"translation\":\"BAHAHAHA\",\"user_edited_version\":0,\"updated_at\":\"2024-10-03 04:47:23\",\"created_at\":\"2024-10-03 04:47:23\",\"id\":910634,\"lang\":\"en\"}}","channel":"private-bubble.3832006.OAOxiNFFvXM94frhiyO7kAq4wIMNG9Zhz52nNVLW"}"#;
+
+    #[test]
+    fn replay_delay() {
+        assert_eq!(
+            ReplaySpeed::scaled(2.0).delay(1000),
+            Duration::from_millis(500)
+        );
+        for factor in [0.0, -1.0, f64::NAN, f64::INFINITY] {
+            assert!(matches!(ReplaySpeed::scaled(factor), ReplaySpeed::Instant));
+            assert_eq!(ReplaySpeed::Scaled(factor).delay(1000), Duration::ZERO);
+        }
+        assert_eq!(
+            ReplaySpeed::Scaled(f64::MIN_POSITIVE).delay(1000),
+            Duration::MAX
+        );
+    }
+
+    #[tokio::test]
+    async fn record_and_replay() {
+        let path =
+            std::env::temp_dir().join(format!("pusher-recording-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let mut recorder = Recorder::create(&path).unwrap();
+        for frame in [CONNECTION_ESTABLISHED, BUBBLE_CHANGED, TRANSLATION_ADDED] {
+            recorder.record(frame).unwrap();
+        }
+        drop(recorder);
+
+        let frames = load(&path).unwrap();
+        assert_eq!(frames.len(), 3);
+        assert_eq!(frames[0].channel, None);
+        assert_eq!(frames[1].channel.as_deref(), Some("private-user.5302428"));
+
+        let pusher_client = PusherClient::replay(&path, ReplaySpeed::Instant).unwrap();
+        pusher_client.init().await;
+        let mut receiver = pusher_client.server_messages().await;
+        let mut events = vec![];
+        loop {
+            match receiver.recv().await.unwrap() {
+                PusherServerMessageWrapper::PusherServerMessage(PusherServerMessage::Event(
+                    event,
+                )) => events.push(event.event),
+                PusherServerMessageWrapper::Shutdown => break,
+                _ => {}
+            }
+        }
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(matches!(
+            events[0],
+            PusherServerEventType::PusherServerBubbleChangedEvent(ref event) if event.bubble.id == 3832006
+        ));
+        assert!(matches!(
+            events[1],
+            PusherServerEventType::PusherServerMessageTranslationAddedEvent(ref event) if event.translation.message_id == 90641609
+        ));
+    }
+}
@@ -1,28 +1,25 @@
 use std::error::Error;
 use std::io::stdin;
 
-use milli::{GeoSortStrategy, TermsMatchingStrategy, TimeBudget};
-use search::Search;
+use client::ProntoClient;
+use search::{ParsedQuery, Search, SearchQuery};
+use settings::Settings;
 use std::io::BufRead;
 use std::path::PathBuf;
 use std::sync::Arc;
 use std::thread;
 
-fn message_index_location() -> PathBuf {
-    PathBuf::from("D:\\temp_message_index")
-}
-
 #[tokio::main]
-async fn indexer_thread() {
-    tokio::fs::create_dir_all(&message_index_location())
-        .await
-        .unwrap();
+async fn indexer_thread(client: Arc<ProntoClient>, organization_id: u64, index_path: PathBuf) {
     let indexer = Arc::new(
         search::MessageIndexer::new(
-            &message_index_location(),
+            client,
+            organization_id,
+            &index_path,
             search::IndexerSettings::default(),
         )
-        .await,
+        .await
+        .expect("Failed to open the index"),
     );
     tokio::task::spawn({
         let indexer = indexer.clone();
@@ -43,13 +40,29 @@ async fn indexer_thread() {
     }
 }
 
+/// Indexes the messages of the logged in account and searches them with queries read from stdin
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error + Send + Sync>> {
-    tokio::fs::create_dir_all(message_index_location()).await?;
-    thread::spawn(|| {
-        indexer_thread();
+    let settings = Settings::load().await?;
+    let auth = settings
+        .auth()
+        .expect("Open prontus and log in before running this example")
+        .clone();
+    let organization_id = auth
+        .organization_id
+        .expect("Open prontus once more to record the organization of this login");
+    let index_path = settings
+        .search
+        .messages
+        .unwrap_or_default()
+        .index_path(&auth);
+    let client = Arc::new(ProntoClient::new(auth.base_url.clone(), &auth.api_key)?);
+    thread::spawn({
+        let index_path = index_path.clone();
+        move || indexer_thread(client, organization_id, index_path)
     });
-    let mut search = Search::new(&message_index_location());
+    tokio::fs::create_dir_all(&index_path).await?;
+    let mut search = Search::new(&index_path, search::map_size(None))?;
     println!("Init complete");
 
     loop {
@@ -57,27 +70,19 @@ async fn main() -> Result<(), Box<dyn Error + Send + Sync>> {
         let query = stdin().lock().lines().next().unwrap().unwrap();
 
         println!("Query: {}", query);
-        let results = search.search(
-            (!query.trim().is_empty()).then(|| query.trim()),
-            TermsMatchingStrategy::Last,
-            milli::score_details::ScoringStrategy::Skip,
-            false,
-            &None,
-            &None,
-            GeoSortStrategy::default(),
-            0,
-            20,
-            None,
-            TimeBudget::max(),
-            None,
-            None,
-        )?;
-        println!("Results in {} seconds", results.elapsed.as_secs_f32());
-        for result in results.results {
+        // from: and in: need names resolved to ids, which this example doesn't do
+        let parsed = ParsedQuery::parse(&query);
+        let results = search.search(&SearchQuery::new().parsed(&parsed))?;
+        println!(
+            "{} results in {} seconds",
+            results.total,
+            results.elapsed.as_secs_f32()
+        );
+        for hit in results.results {
             println!(
                 "{}: {}",
-                result.1.get("user_fullname").unwrap(),
-                result.1.get("message").unwrap()
+                hit.document.get("user_fullname").unwrap(),
+                hit.snippet
             );
         }
     }
@@ -0,0 +1,196 @@
+//! What else is indexed next to messages: attachments, announcements and tasks.
+//!
+//! Every document has a `kind`. Messages keep their numeric id as the primary key,
+//! the other kinds are keyed `{kind}-{id}` so they never collide with one.
+
+use chrono::{DateTime, NaiveDateTime};
+use client::{Announcement, Message, MessageMedia, Task};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DocumentKind {
+    #[default]
+    Message,
+    /// A file or image attached to a message
+    Media,
+    Announcement,
+    Task,
+}
+
+impl DocumentKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            DocumentKind::Message => "message",
+            DocumentKind::Media => "media",
+            DocumentKind::Announcement => "announcement",
+            DocumentKind::Task => "task",
+        }
+    }
+}
+
+/// Fields holding the text of a document, in the order it's read
+const TEXT_FIELDS: [&str; 5] = [
+    "title",
+    "message",
+    "filename",
+    "message_resource_title",
+    "message_resource_snippet",
+];
+
+/// The text of a document, to embed or to cut a snippet from
+pub(crate) fn document_text(document: &Map<String, Value>) -> String {
+    TEXT_FIELDS
+        .iter()
+        .filter_map(|&field| document.get(field).and_then(Value::as_str))
+        .filter(|text| !text.trim().is_empty())
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Server timestamps come as `2024-10-08 16:05:17` or RFC 3339
+fn timestamp(date: &str) -> i64 {
+    NaiveDateTime::parse_from_str(date, "%Y-%m-%d %H:%M:%S")
+        .map(|date| date.and_utc().timestamp())
+        .or_else(|_| DateTime::parse_from_rfc3339(date).map(|date| date.timestamp()))
+        .unwrap_or_default()
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct StoredMedia {
+    pub id: String,
+    pub kind: DocumentKind,
+    pub media_id: u64,
+    pub message_id: u64,
+    pub bubble_id: u64,
+    pub user_id: u64,
+    pub user_fullname: String,
+    pub title: Option<String>,
+    /// The last part of the url, usually the name of the uploaded file
+    pub filename: String,
+    pub mimetype: String,
+    pub mediatype: String,
+    pub url: String,
+    pub created_at: String,
+    pub created_at_timestamp: i64,
+}
+
+impl StoredMedia {
+    pub fn id(media_id: u64) -> String {
+        format!("media-{media_id}")
+    }
+
+    /// A document per attachment of `message`
+    pub fn from_message(message: &Message) -> Vec<Self> {
+        message
+            .message_media
+            .iter()
+            .map(|media| Self::new(message, media))
+            .collect()
+    }
+
+    fn new(message: &Message, media: &MessageMedia) -> Self {
+        let filename = media
+            .url
+            .split(['?', '#'])
+            .next()
+            .and_then(|path| path.rsplit('/').next())
+            .unwrap_or_default()
+            .to_string();
+        Self {
+            id: Self::id(media.id),
+            kind: DocumentKind::Media,
+            media_id: media.id,
+            message_id: message.id,
+            bubble_id: message.bubble_id,
+            user_id: message.user_id,
+            user_fullname: message.user.fullname.clone(),
+            title: media.title.clone(),
+            filename,
+            mimetype: media.url_mimetype.clone(),
+            mediatype: media.mediatype.clone(),
+            url: media.url.clone(),
+            created_at: message.created_at.to_string(),
+            created_at_timestamp: message.created_at.and_utc().timestamp(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct StoredAnnouncement {
+    pub id: String,
+    pub kind: DocumentKind,
+    pub announcement_id: u64,
+    pub user_id: u64,
+    pub user_fullname: String,
+    pub message: String,
+    pub created_at: String,
+    pub created_at_timestamp: i64,
+}
+
+impl StoredAnnouncement {
+    pub fn id(announcement_id: u64) -> String {
+        format!("announcement-{announcement_id}")
+    }
+}
+
+impl From<Announcement> for StoredAnnouncement {
+    fn from(value: Announcement) -> Self {
+        Self {
+            id: Self::id(value.id),
+            kind: DocumentKind::Announcement,
+            announcement_id: value.id,
+            user_id: value.senderuser_id,
+            user_fullname: value.sender.fullname,
+            message: value.announcement,
+            created_at_timestamp: timestamp(&value.created_at),
+            created_at: value.created_at,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct StoredTask {
+    pub id: String,
+    pub kind: DocumentKind,
+    pub task_id: u64,
+    /// Who created the task
+    pub user_id: u64,
+    pub user_fullname: String,
+    pub assignee_id: u64,
+    pub bubble_id: Option<u64>,
+    pub title: String,
+    /// The notes of the task
+    pub message: String,
+    pub completed: bool,
+    pub due: String,
+    pub created_at: String,
+    pub created_at_timestamp: i64,
+}
+
+impl StoredTask {
+    pub fn id(task_id: u64) -> String {
+        format!("task-{task_id}")
+    }
+}
+
+impl From<Task> for StoredTask {
+    fn from(value: Task) -> Self {
+        Self {
+            id: Self::id(value.id),
+            kind: DocumentKind::Task,
+            task_id: value.id,
+            user_id: value.user_id,
+            user_fullname: value.user.fullname,
+            assignee_id: value.assigneeuser_id,
+            bubble_id: value.bubble_id,
+            title: value.title,
+            message: value.notes,
+            completed: value.completed.is_some(),
+            due: value.due,
+            created_at_timestamp: timestamp(&value.created_at),
+            created_at: value.created_at,
+        }
+    }
+}
@@ -0,0 +1,121 @@
+//! Embeddings for searching messages by meaning, computed on this machine.
+//!
+//! Vectors are stored in the index with milli's user provided embedder under [`EMBEDDER_NAME`],
+//! milli never calls out to a model itself.
+
+use crate::SearchError;
+use milli::update::Setting;
+use milli::vector::settings::EmbeddingSettings;
+use std::collections::BTreeMap;
+
+/// The embedder the vectors are stored under in the index
+pub(crate) const EMBEDDER_NAME: &str = "local";
+
+/// Turns text into vectors, texts with similar meanings get vectors close together
+pub trait Embedder: Send + Sync {
+    /// Identifies the model, everything indexed is embedded again when it changes
+    fn name(&self) -> &str;
+    fn dimensions(&self) -> usize;
+    /// One vector of [`Self::dimensions`] per text. This can take a while, don't call it
+    /// from async code directly.
+    fn embed(&self, texts: &[&str]) -> Result<Vec<Vec<f32>>, SearchError>;
+}
+
+/// How the embedder is declared in the index settings
+pub(crate) fn embedder_settings(
+    embedder: &dyn Embedder,
+) -> Result<BTreeMap<String, Setting<EmbeddingSettings>>, SearchError> {
+    let settings: EmbeddingSettings = serde_json::from_value(serde_json::json!({
+        "source": "userProvided",
+        "dimensions": embedder.dimensions(),
+    }))?;
+    Ok(BTreeMap::from([(
+        EMBEDDER_NAME.to_string(),
+        Setting::Set(settings),
+    )]))
+}
+
+/// The embedder milli searches the stored vectors with, the query vector comes from us
+pub(crate) fn milli_embedder(embedder: &dyn Embedder) -> milli::vector::Embedder {
+    milli::vector::Embedder::UserProvided(milli::vector::manual::Embedder::new(
+        milli::vector::manual::EmbedderOptions {
+            dimensions: embedder.dimensions(),
+            distribution: None,
+        },
+    ))
+}
+
+#[cfg(feature = "local-embedder")]
+pub use local::LocalEmbedder;
+
+#[cfg(feature = "local-embedder")]
+mod local {
+    use super::Embedder;
+    use crate::SearchError;
+    use fastembed::{
+        InitOptionsUserDefined, Pooling, TextEmbedding, TokenizerFiles, UserDefinedEmbeddingModel,
+    };
+    use std::path::Path;
+    use std::sync::{Mutex, PoisonError};
+
+    /// A sentence embedding model run on the CPU with onnxruntime, like all-MiniLM-L6-v2.
+    /// It's read from disk, nothing is downloaded.
+    pub struct LocalEmbedder {
+        name: String,
+        dimensions: usize,
+        model: Mutex<TextEmbedding>,
+    }
+
+    impl LocalEmbedder {
+        /// Load `model.onnx`, `tokenizer.json`, `config.json`, `special_tokens_map.json`
+        /// and `tokenizer_config.json` from `dir`
+        pub fn from_dir(dir: &Path) -> Result<Self, SearchError> {
+            let read = |file: &str| std::fs::read(dir.join(file));
+            let model = UserDefinedEmbeddingModel::new(
+                read("model.onnx")?,
+                TokenizerFiles {
+                    tokenizer_file: read("tokenizer.json")?,
+                    config_file: read("config.json")?,
+                    special_tokens_map_file: read("special_tokens_map.json")?,
+                    tokenizer_config_file: read("tokenizer_config.json")?,
+                },
+            )
+            .with_pooling(Pooling::Mean);
+            let model =
+                TextEmbedding::try_new_from_user_defined(model, InitOptionsUserDefined::default())
+                    .map_err(|e| SearchError::EmbedError(e.to_string()))?;
+            let dimensions = model
+                .embed(vec![""], None)
+                .map_err(|e| SearchError::EmbedError(e.to_string()))?
+                .first()
+                .map_or(0, Vec::len);
+            let name = dir
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_default();
+            Ok(Self {
+                name: format!("{name}-{dimensions}"),
+                dimensions,
+                model: Mutex::new(model),
+            })
+        }
+    }
+
+    impl Embedder for LocalEmbedder {
+        fn name(&self) -> &str {
+            &self.name
+        }
+
+        fn dimensions(&self) -> usize {
+            self.dimensions
+        }
+
+        fn embed(&self, texts: &[&str]) -> Result<Vec<Vec<f32>>, SearchError> {
+            self.model
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .embed(texts.to_vec(), None)
+                .map_err(|e| SearchError::EmbedError(e.to_string()))
+        }
+    }
+}
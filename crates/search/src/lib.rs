@@ -1,30 +1,91 @@
+mod documents;
+mod embed;
 mod index;
+mod maintenance;
 mod message_index;
+mod query;
 
+use crate::documents::document_text;
+use crate::embed::{EMBEDDER_NAME, milli_embedder};
 use crate::message_index::get_index;
-pub use message_index::{IndexerSettings, MessageIndexer};
+pub use documents::{DocumentKind, StoredAnnouncement, StoredMedia, StoredTask};
+pub use embed::Embedder;
+#[cfg(feature = "local-embedder")]
+pub use embed::LocalEmbedder;
+pub use maintenance::{Maintenance, compact, rebuild};
+pub use message_index::{IndexUpdate, IndexerSettings, MessageIndexer, map_size};
 pub use milli;
+use milli::heed::RoTxn;
 use milli::score_details::ScoringStrategy;
-use milli::tokenizer::Language;
+use milli::tokenizer::TokenizerBuilder;
 use milli::{
-    AscDesc, DefaultSearchLogger, DocumentId, GeoSortStrategy, Index, SearchContext,
+    AscDesc, DefaultSearchLogger, DocumentId, FacetDistribution, Filter, FormatOptions,
+    GeoSortStrategy, Index, MatcherBuilder, MatchingWords, Member, OrderBy, SearchContext,
     TermsMatchingStrategy, TimeBudget, execute_search, filtered_universe,
 };
+use query::kind_filter;
+pub use query::{Has, Is, ParsedQuery, SearchQuery, SortOrder};
+use serde::Serialize;
 use serde_json::{Map, Value};
-use std::path::PathBuf;
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::Arc;
 use std::time::{Duration, Instant};
 use thiserror::Error;
 
+/// Put around the matched words in [`SearchHit::snippet`]
+pub const HIGHLIGHT_PRE: &str = "<mark>";
+pub const HIGHLIGHT_POST: &str = "</mark>";
+/// Words of a message kept around the matches in a snippet
+const SNIPPET_WORDS: usize = 30;
+
 pub struct Search {
     index: Index,
     logger: DefaultSearchLogger,
+    embedder: Option<Arc<dyn Embedder>>,
 }
 
+#[derive(Default, Serialize)]
 pub struct SearchResults {
-    pub results: Vec<(DocumentId, Map<String, Value>)>,
+    pub results: Vec<SearchHit>,
+    /// Every match, not only the page in `results`
+    pub total: u64,
+    pub facets: Facets,
     pub elapsed: Duration,
 }
 
+#[derive(Serialize)]
+pub struct SearchHit {
+    pub id: DocumentId,
+    pub document: Map<String, Value>,
+    /// The title, message and filename of the document, on their own lines
+    pub text: String,
+    /// Where the query matched in [`Self::text`]
+    pub matches: Vec<MatchPosition>,
+    /// The text cropped around the matches, which are highlighted
+    /// with [`HIGHLIGHT_PRE`] and [`HIGHLIGHT_POST`]
+    pub snippet: String,
+    /// Messages of the same bubble right before the hit, oldest first
+    pub before: Vec<Map<String, Value>>,
+    /// Messages of the same bubble right after the hit, oldest first
+    pub after: Vec<Map<String, Value>>,
+}
+
+/// A matched word in the text of a hit, in bytes
+#[derive(Debug, Serialize)]
+pub struct MatchPosition {
+    pub start: usize,
+    pub length: usize,
+}
+
+/// How many documents match per bubble and per user, keyed by their ids, and per kind
+#[derive(Debug, Default, Serialize)]
+pub struct Facets {
+    pub bubbles: BTreeMap<u64, u64>,
+    pub users: BTreeMap<u64, u64>,
+    pub kinds: BTreeMap<String, u64>,
+}
+
 #[derive(Debug, Error)]
 pub enum SearchError {
     #[error("Serde json error: {0}")]
@@ -33,76 +94,283 @@ pub enum SearchError {
     MilliError(#[from] milli::Error),
     #[error("Heed error: {0}")]
     MilliHeedError(#[from] milli::heed::Error),
+    #[error("Documents error: {0}")]
+    DocumentsError(#[from] milli::documents::Error),
+    #[error("IO error: {0}")]
+    IoError(#[from] std::io::Error),
+    #[error("Response error: {0}")]
+    ResponseError(#[from] client::ResponseError),
+    #[error("Join error: {0}")]
+    JoinError(#[from] tokio::task::JoinError),
+    #[error("The indexer stopped")]
+    IndexerStopped,
+    #[error("Maintenance is running on the index")]
+    Closed,
+    #[error("Embed error: {0}")]
+    EmbedError(String),
 }
 
 impl Search {
-    pub fn new(index_path: &PathBuf) -> Self {
-        Search {
-            index: get_index(index_path).unwrap(),
+    /// Open the index in `index_path` with the `map_size` its indexer uses, see [`map_size`]
+    pub fn new(index_path: &Path, map_size: usize) -> Result<Self, SearchError> {
+        Ok(Search {
+            index: get_index(index_path, map_size)?,
             logger: DefaultSearchLogger,
-        }
+            embedder: None,
+        })
+    }
+
+    /// Rank by meaning too, with the embedder the index was built with
+    pub fn with_embedder(mut self, embedder: Arc<dyn Embedder>) -> Self {
+        self.embedder = Some(embedder);
+        self
     }
 
-    pub fn search(
-        &mut self,
-        query: Option<&str>,
-        terms_matching_strategy: TermsMatchingStrategy,
-        scoring_strategy: ScoringStrategy,
-        exhaustive_number_hits: bool,
-        sort_criteria: &Option<Vec<AscDesc>>,
-        distinct: &Option<String>,
-        geo_strategy: GeoSortStrategy,
-        from: usize,
-        length: usize,
-        words_limit: Option<usize>,
-        time_budget: TimeBudget,
-        ranking_score_threshold: Option<f64>,
-        locales: Option<&Vec<Language>>,
-    ) -> Result<SearchResults, SearchError> {
+    /// Run a query, along with how many matches there are per bubble and user
+    pub fn search(&mut self, query: &SearchQuery) -> Result<SearchResults, SearchError> {
         let txn = self.index.read_txn()?;
         let start = Instant::now();
-        let mut ctx = SearchContext::new(&self.index, &txn)?;
-        let universe = filtered_universe(ctx.index, ctx.txn, &None)?;
-        let docs = execute_search(
-            &mut ctx,
-            query,
-            terms_matching_strategy,
-            scoring_strategy,
-            exhaustive_number_hits,
-            universe,
-            sort_criteria,
-            distinct,
-            geo_strategy,
-            from,
-            length,
-            words_limit,
-            &mut DefaultSearchLogger,
-            &mut self.logger,
-            time_budget,
-            ranking_score_threshold,
-            locales,
-        )?;
-        let elapsed = start.elapsed();
-        let documents = self
-            .index
-            .documents(&txn, docs.documents_ids.iter().copied())
-            .unwrap()
-            .into_iter()
-            .map(|(id, obkv)| {
-                let mut object = serde_json::Map::default();
-                for (fid, fid_name) in self.index.fields_ids_map(&txn).unwrap().iter() {
-                    if let Some(value) = obkv.get(fid) {
-                        let value: Value = serde_json::from_slice(value).unwrap();
-                        object.insert(fid_name.to_owned(), value);
-                    }
+        let filter_expression = query.filter();
+        let filter = match &filter_expression {
+            Some(expression) => Filter::from_str(expression)?,
+            None => None,
+        };
+        let created_at = || Member::Field("created_at_timestamp".to_string());
+        let sort_criteria = match query.sort {
+            SortOrder::Relevance => None,
+            SortOrder::NewestFirst => Some(vec![AscDesc::Desc(created_at())]),
+            SortOrder::OldestFirst => Some(vec![AscDesc::Asc(created_at())]),
+        };
+        let semantic = match (&self.embedder, &query.text) {
+            (Some(embedder), Some(text)) if query.semantic_ratio > 0.0 => Some((embedder, text)),
+            _ => None,
+        };
+        let (documents_ids, candidates, matching_words) = match semantic {
+            Some((embedder, text)) => {
+                let vector = embedder
+                    .embed(&[text.as_str()])?
+                    .pop()
+                    .ok_or_else(|| SearchError::EmbedError("No embedding".to_string()))?;
+                let mut search = milli::Search::new(&txn, &self.index);
+                search
+                    .query(text)
+                    .terms_matching_strategy(TermsMatchingStrategy::Last)
+                    .offset(query.offset)
+                    .limit(query.limit)
+                    .semantic(
+                        EMBEDDER_NAME.to_string(),
+                        Arc::new(milli_embedder(embedder.as_ref())),
+                        false,
+                        Some(vector),
+                    );
+                if let Some(filter) = filter {
+                    search.filter(filter);
+                }
+                if let Some(sort_criteria) = sort_criteria {
+                    search.sort_criteria(sort_criteria);
                 }
-                (id, object)
-            })
-            .collect::<Vec<_>>();
+                let (result, _) = search.execute_hybrid(query.semantic_ratio)?;
+                (
+                    result.documents_ids,
+                    result.candidates,
+                    result.matching_words,
+                )
+            }
+            None => {
+                let mut ctx = SearchContext::new(&self.index, &txn)?;
+                let universe = filtered_universe(ctx.index, ctx.txn, &filter)?;
+                let docs = execute_search(
+                    &mut ctx,
+                    query.text.as_deref(),
+                    TermsMatchingStrategy::Last,
+                    ScoringStrategy::Skip,
+                    false,
+                    universe,
+                    &sort_criteria,
+                    &None,
+                    GeoSortStrategy::default(),
+                    query.offset,
+                    query.limit,
+                    None,
+                    &mut DefaultSearchLogger,
+                    &mut self.logger,
+                    TimeBudget::max(),
+                    None,
+                    None,
+                )?;
+                let matching_words = match docs.located_query_terms {
+                    Some(located_query_terms) => MatchingWords::new(ctx, located_query_terms),
+                    None => MatchingWords::default(),
+                };
+                (docs.documents_ids, docs.candidates, matching_words)
+            }
+        };
+
+        let mut distribution = FacetDistribution::new(&txn, &self.index);
+        distribution
+            .facets([
+                ("bubble_id", OrderBy::Count),
+                ("user_id", OrderBy::Count),
+                ("kind", OrderBy::Count),
+            ])
+            .candidates(candidates.clone());
+        let distribution = distribution.execute()?;
+        let counts = |field: &str| -> BTreeMap<u64, u64> {
+            distribution
+                .get(field)
+                .into_iter()
+                .flatten()
+                .filter_map(|(value, count)| Some((value.parse::<f64>().ok()? as u64, *count)))
+                .collect()
+        };
+        let facets = Facets {
+            bubbles: counts("bubble_id"),
+            users: counts("user_id"),
+            kinds: distribution
+                .get("kind")
+                .into_iter()
+                .flatten()
+                .map(|(kind, count)| (kind.clone(), *count))
+                .collect(),
+        };
+
+        let mut tokenizer_builder = TokenizerBuilder::default();
+        tokenizer_builder.create_char_map(true);
+        let mut matcher_builder = MatcherBuilder::new(matching_words, tokenizer_builder.build());
+        matcher_builder.highlight_prefix(HIGHLIGHT_PRE.to_string());
+        matcher_builder.highlight_suffix(HIGHLIGHT_POST.to_string());
+
+        let mut hits = vec![];
+        for (id, document) in read_documents(&self.index, &txn, documents_ids)? {
+            let text = document_text(&document);
+            let mut matcher = matcher_builder.build(&text, None);
+            let matches = matcher
+                .matches(&[])
+                .into_iter()
+                .map(|bounds| MatchPosition {
+                    start: bounds.start,
+                    length: bounds.length,
+                })
+                .collect();
+            let snippet = matcher
+                .format(FormatOptions {
+                    highlight: true,
+                    crop: Some(SNIPPET_WORDS),
+                })
+                .into_owned();
+            let (before, after) = match query.context {
+                0 => (vec![], vec![]),
+                context => self.context(&txn, &document, context)?,
+            };
+            hits.push(SearchHit {
+                id,
+                document,
+                text,
+                matches,
+                snippet,
+                before,
+                after,
+            });
+        }
+        let elapsed = start.elapsed();
         drop(txn);
         Ok(SearchResults {
-            results: documents,
+            results: hits,
+            total: candidates.len(),
+            facets,
             elapsed,
         })
     }
+
+    /// Up to `count` messages on each side of `document` in its bubble, oldest first.
+    /// Only messages have ids that are numbers, other kinds get no context.
+    fn context(
+        &self,
+        txn: &RoTxn,
+        document: &Map<String, Value>,
+        count: usize,
+    ) -> Result<(Vec<Map<String, Value>>, Vec<Map<String, Value>>), SearchError> {
+        let field = |name| document.get(name).and_then(Value::as_u64);
+        let (Some(id), Some(bubble_id)) = (field("id"), field("bubble_id")) else {
+            return Ok((vec![], vec![]));
+        };
+        let id_field = || Member::Field("id".to_string());
+        let only_messages = kind_filter(&[DocumentKind::Message]);
+        let mut before = sorted_documents(
+            &self.index,
+            txn,
+            &format!("bubble_id = {bubble_id} AND id < {id} AND {only_messages}"),
+            AscDesc::Desc(id_field()),
+            count,
+        )?;
+        before.reverse();
+        let after = sorted_documents(
+            &self.index,
+            txn,
+            &format!("bubble_id = {bubble_id} AND id > {id} AND {only_messages}"),
+            AscDesc::Asc(id_field()),
+            count,
+        )?;
+        Ok((before, after))
+    }
+}
+
+/// The first `count` documents matching `filter` in the order of `sort`
+pub(crate) fn sorted_documents(
+    index: &Index,
+    txn: &RoTxn,
+    filter: &str,
+    sort: AscDesc,
+    count: usize,
+) -> Result<Vec<Map<String, Value>>, SearchError> {
+    let mut ctx = SearchContext::new(index, txn)?;
+    let universe = filtered_universe(ctx.index, ctx.txn, &Filter::from_str(filter)?)?;
+    let docs = execute_search(
+        &mut ctx,
+        None,
+        TermsMatchingStrategy::Last,
+        ScoringStrategy::Skip,
+        false,
+        universe,
+        &Some(vec![sort]),
+        &None,
+        GeoSortStrategy::default(),
+        0,
+        count,
+        None,
+        &mut DefaultSearchLogger,
+        &mut DefaultSearchLogger,
+        TimeBudget::max(),
+        None,
+        None,
+    )?;
+    Ok(read_documents(index, txn, docs.documents_ids)?
+        .into_iter()
+        .map(|(_, document)| document)
+        .collect())
+}
+
+/// Decode stored documents back to json
+pub(crate) fn read_documents(
+    index: &Index,
+    txn: &RoTxn,
+    ids: impl IntoIterator<Item = DocumentId>,
+) -> Result<Vec<(DocumentId, Map<String, Value>)>, SearchError> {
+    let fields_ids_map = index.fields_ids_map(txn)?;
+    let mut documents = vec![];
+    for (id, obkv) in index.documents(txn, ids)? {
+        let mut object = serde_json::Map::default();
+        for (fid, fid_name) in fields_ids_map.iter() {
+            // Embeddings are of no use to whoever reads the documents
+            if fid_name == "_vectors" {
+                continue;
+            }
+            if let Some(value) = obkv.get(fid) {
+                let value: Value = serde_json::from_slice(value)?;
+                object.insert(fid_name.to_owned(), value);
+            }
+        }
+        documents.push((id, object));
+    }
+    Ok(documents)
 }
@@ -0,0 +1,55 @@
+//! Operations on a whole index, asked for by the user.
+//!
+//! Nothing may use the index meanwhile: stop its [`crate::MessageIndexer`] and let go of any
+//! [`crate::Search`] first, these wait for the index to be closed. Opening it again fails
+//! with [`SearchError::Closed`] until they're done.
+
+use crate::SearchError;
+use crate::message_index::{ClosedIndex, INDEX_INFO_FILE, open_index, remove_index_files};
+use milli::heed::CompactionOption;
+use serde::Deserialize;
+use std::io::ErrorKind;
+use std::path::Path;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Maintenance {
+    Compact,
+    Rebuild,
+}
+
+impl Maintenance {
+    /// Run on the index in `index_path`, opened with `map_size` like its indexer does.
+    /// This blocks until it's done.
+    pub fn run(self, index_path: &Path, map_size: usize) -> Result<(), SearchError> {
+        match self {
+            Maintenance::Compact => compact(index_path, map_size),
+            Maintenance::Rebuild => rebuild(index_path),
+        }
+    }
+}
+
+/// Give the space left by removed documents back to the disk.
+/// LMDB reuses free pages but never shrinks its file, a compacted copy replaces it.
+pub fn compact(index_path: &Path, map_size: usize) -> Result<(), SearchError> {
+    let _closed = ClosedIndex::close(index_path);
+    let compacted = index_path.join("data.mdb.compacted");
+    if compacted.exists() {
+        std::fs::remove_file(&compacted)?;
+    }
+    let index = open_index(index_path, map_size)?;
+    index.copy_to_file(&compacted, CompactionOption::Enabled)?;
+    index.prepare_for_closing().wait();
+    std::fs::rename(&compacted, index_path.join("data.mdb"))?;
+    Ok(())
+}
+
+/// Throw the index away, the next indexer fetches everything again
+pub fn rebuild(index_path: &Path) -> Result<(), SearchError> {
+    let _closed = ClosedIndex::close(index_path);
+    remove_index_files(index_path)?;
+    match std::fs::remove_file(index_path.join(INDEX_INFO_FILE)) {
+        Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
@@ -1,42 +1,135 @@
-use client::{Message, ProntoClient};
+use crate::documents::{DocumentKind, StoredAnnouncement, StoredMedia, StoredTask, document_text};
+use crate::embed::{EMBEDDER_NAME, Embedder, embedder_settings};
+use crate::query::kind_filter;
+use crate::{SearchError, read_documents, sorted_documents};
+use client::{Announcement, Message, ProntoClient, Task};
 use dashmap::DashMap;
-use log::{debug, info};
-use milli::Index;
+use futures::TryStreamExt;
+use log::{debug, info, warn};
 use milli::documents::{DocumentsBatchBuilder, DocumentsBatchReader};
 use milli::heed::EnvOpenOptions;
-use milli::update::{IndexDocuments, IndexDocumentsConfig, IndexerConfig, Settings};
+use milli::update::{
+    ClearDocuments, IndexDocuments, IndexDocumentsConfig, IndexerConfig, Settings,
+};
+use milli::{AscDesc, Filter, Index, Member};
 use serde::{Deserialize, Serialize};
-use std::error::Error;
-use std::io::Cursor;
-use std::path::PathBuf;
-use std::sync::{Arc, Mutex};
-use std::time::Duration;
-use sysinfo::Disks;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::io::{Cursor, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::pin::pin;
+use std::sync::{Arc, LazyLock, Mutex, PoisonError};
 use tokio::sync::mpsc;
 
-#[allow(unused)]
-pub fn message_index_location() -> PathBuf {
-    PathBuf::from(r#"D:\tmp-milli-message-index-location"#.to_string())
+/// What has been indexed so far, next to the LMDB files
+pub(crate) const INDEX_INFO_FILE: &str = "index_info.json";
+
+/// Indexes opened by this process, LMDB refuses to open an environment twice
+static OPENED: LazyLock<Mutex<HashMap<PathBuf, Opened>>> = LazyLock::new(Default::default);
+
+enum Opened {
+    Index(Index),
+    /// Its files are being replaced, see [`ClosedIndex`]
+    Closed,
+}
+
+/// LMDB map size of an index without a size limit
+const UNLIMITED_MAP_SIZE: usize = 64 * 1024 * 1024 * 1024; // 64 GiB
+/// Room past the size limit, it's only checked between pages of history
+const MAP_SIZE_HEADROOM: usize = 1024 * 1024 * 1024; // 1 GiB
+
+/// LMDB map size of an index limited to `max_size` by [`IndexerSettings::max_size`].
+/// Whatever opens an index has to ask for the same map size, it's the most the file may grow to.
+pub fn map_size(max_size: Option<usize>) -> usize {
+    match max_size {
+        Some(max_size) => max_size
+            .saturating_add(max_size / 2)
+            .saturating_add(MAP_SIZE_HEADROOM),
+        None => UNLIMITED_MAP_SIZE,
+    }
 }
 
-pub fn get_index(dataset: &PathBuf) -> milli::Result<Index> {
-    let disks = Disks::new_with_refreshed_list();
-    let max_disk_space = disks
-        .list()
-        .iter()
-        .max_by(|&d1, &d2| d1.total_space().cmp(&d2.total_space()))
-        .map(|d| d.total_space());
+/// Open the index in `dataset` with a map of `map_size` bytes, see [`map_size`].
+/// Whoever already has it open shares theirs.
+pub fn get_index(dataset: &Path, map_size: usize) -> Result<Index, SearchError> {
+    let mut opened = OPENED.lock().unwrap_or_else(PoisonError::into_inner);
+    match opened.get(dataset) {
+        Some(Opened::Index(index)) => return Ok(index.clone()),
+        Some(Opened::Closed) => return Err(SearchError::Closed),
+        None => {}
+    }
+
+    let index = open_index(dataset, map_size)?;
+    opened.insert(dataset.to_path_buf(), Opened::Index(index.clone()));
+    Ok(index)
+}
+
+/// Open the index in `dataset` without sharing it, see [`get_index`]
+pub(crate) fn open_index(dataset: &Path, map_size: usize) -> milli::Result<Index> {
     let mut options = EnvOpenOptions::new();
-    options.map_size(max_disk_space
-        .unwrap_or(128 * 1024 * 1024 * 1024) // 128 GB
-        as usize);
+    options.map_size(map_size);
+
+    Index::new(options, dataset, true)
+}
+
+/// Keeps the index in a directory closed while its files are replaced,
+/// [`get_index`] fails until this is dropped
+pub(crate) struct ClosedIndex {
+    dataset: PathBuf,
+}
+
+impl ClosedIndex {
+    /// Close the index in `dataset`, waiting for whoever still has it open to let go
+    pub(crate) fn close(dataset: &Path) -> Self {
+        let previous = OPENED
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(dataset.to_path_buf(), Opened::Closed);
+        if let Some(Opened::Index(index)) = previous {
+            index.prepare_for_closing().wait();
+        }
+        Self {
+            dataset: dataset.to_path_buf(),
+        }
+    }
+}
+
+impl Drop for ClosedIndex {
+    fn drop(&mut self) {
+        OPENED
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(&self.dataset);
+    }
+}
 
-    Index::new(options, dataset.to_str().unwrap(), true)
+/// Delete the LMDB files of the index in `dataset`, close it first
+pub(crate) fn remove_index_files(dataset: &Path) -> std::io::Result<()> {
+    for file in ["data.mdb", "lock.mdb"] {
+        match std::fs::remove_file(dataset.join(file)) {
+            Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Open the index in `dataset` and read enough of it to tell whether it's intact
+fn open_checked(dataset: &Path, map_size: usize) -> Result<Index, SearchError> {
+    let index = get_index(dataset, map_size)?;
+    {
+        let txn = index.read_txn()?;
+        index.number_of_documents(&txn)?;
+        index.fields_ids_map(&txn)?;
+        index.documents_ids(&txn)?;
+    }
+    Ok(index)
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct StoredMessage {
     pub id: u64,
+    pub kind: DocumentKind,
     pub user_id: u64,
     pub user_firstname: String,
     pub user_lastname: String,
@@ -48,6 +141,15 @@ pub struct StoredMessage {
     pub first_child_message_id: Option<u64>,
     pub last_child_message_id: Option<u64>,
     pub created_at: String,
+    /// `created_at` as a unix timestamp, to filter and sort on
+    #[serde(default)]
+    pub created_at_timestamp: i64,
+    /// Has a url or a link preview
+    #[serde(default)]
+    pub has_link: bool,
+    /// Is a thread reply or has replies
+    #[serde(default)]
+    pub is_thread: bool,
     pub message_resource_id: Option<u64>,
     pub message_resource_providerurl: Option<String>,
     pub message_resource_snippet: Option<String>,
@@ -58,8 +160,13 @@ pub struct StoredMessage {
 
 impl From<Message> for StoredMessage {
     fn from(value: Message) -> Self {
+        let has_link = value.resource.is_some()
+            || value.message.contains("http://")
+            || value.message.contains("https://");
+        let is_thread = value.parent_message_id.is_some() || value.first_child_message_id.is_some();
         Self {
             id: value.id,
+            kind: DocumentKind::Message,
             user_id: value.user_id,
             user_firstname: value.user.firstname,
             user_lastname: value.user.lastname,
@@ -71,6 +178,9 @@ impl From<Message> for StoredMessage {
             first_child_message_id: value.first_child_message_id,
             last_child_message_id: value.last_child_message_id,
             created_at: value.created_at.to_string(),
+            created_at_timestamp: value.created_at.and_utc().timestamp(),
+            has_link,
+            is_thread,
             message_resource_id: value.resource.as_ref().map(|r| r.id),
             message_resource_providerurl: value.resource.as_ref().map(|r| r.providerurl.clone()),
             message_resource_snippet: value.resource.as_ref().map(|r| r.snippet.clone()),
@@ -107,167 +217,283 @@ impl BubbleIndexInfo {
     }
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+/// Which model the vectors in the index come from
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct EmbeddingsInfo {
+    pub model: Option<String>,
+    /// Documents from this internal id on were indexed before the model was set
+    pub backfill_from: Option<u32>,
+}
+
+/// Bump when what's stored for a document changes, everything is indexed again.
+/// 1: every document has a `kind`
+/// 2: link previews are searched
+const SCHEMA_VERSION: u32 = 2;
+
+#[derive(Debug, Default, Serialize, Deserialize)]
 pub struct MessageIndexInfo {
+    /// Indexes written before there was a version are 0
+    #[serde(default)]
+    pub schema_version: u32,
     #[serde(default)]
     pub bubbles: DashMap<u64, BubbleIndexInfo>,
+    /// The newest message evicted from each bubble to stay under the size limit,
+    /// nothing up to it is indexed again
+    #[serde(default)]
+    pub floors: DashMap<u64, u64>,
+    #[serde(default)]
+    pub embeddings: Mutex<EmbeddingsInfo>,
 }
 
 impl MessageIndexInfo {
-    // TODO: use load function
-    // pub fn load(path: &PathBuf) -> Result<Self, Box<dyn Error + Send + Sync>> {
-    //     Ok(serde_json::from_reader(std::fs::File::open(path)?)?)
-    // }
+    /// Read what has been indexed so far, nothing if the index is new
+    pub fn load(path: &Path) -> Result<Self, SearchError> {
+        if !path.exists() {
+            return Ok(Self {
+                schema_version: SCHEMA_VERSION,
+                ..Self::default()
+            });
+        }
+        Ok(serde_json::from_reader(std::fs::File::open(path)?)?)
+    }
 
-    pub fn save(&self, path: &PathBuf) -> Result<(), Box<dyn Error + Send + Sync>> {
-        serde_json::to_writer(std::fs::File::create(path)?, &self)?;
+    /// The index lost its documents, fetch every bubble again down to its floor
+    fn forget_progress(&self) {
+        self.bubbles.clear();
+        self.embeddings
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .backfill_from = None;
+    }
+
+    /// Written aside and moved in place, a crash can't leave half a file
+    pub fn save(&self, path: &Path) -> Result<(), SearchError> {
+        let written = path.with_extension("json.tmp");
+        serde_json::to_writer(std::fs::File::create(&written)?, &self)?;
+        std::fs::rename(written, path)?;
         Ok(())
     }
 }
 
+/// Documents embedded again per [`MessageIndexer::execute`] after the model changed
+const EMBED_BACKFILL_BATCH: usize = 256;
+/// Messages evicted per bubble and [`MessageIndexer::execute`] while over the size limit
+const EVICT_BATCH: usize = 100;
+/// The newest messages of a bubble are never evicted
+const KEEP_PER_BUBBLE: usize = 50;
+
 #[derive(Clone, Default)]
 pub struct IndexerSettings {
-    /// Virtual limit imposed on index size
+    /// Virtual limit imposed on index size, the oldest messages are evicted past it
     pub max_size: Option<usize>,
+    /// Store an embedding of every message, to search them by meaning
+    pub embedder: Option<Arc<dyn Embedder>>,
+}
+
+/// A change seen live, e.g. over pusher
+#[derive(Clone, Debug)]
+pub enum IndexUpdate {
+    /// A new or edited message, replacing what's indexed under its id along with its media
+    Upsert(Message),
+    /// Remove a message by id, along with its media
+    Remove(u64),
+    UpsertAnnouncement(Announcement),
+    RemoveAnnouncement(u64),
+    UpsertTask(Task),
 }
 
+/// Indexes the messages of one organization, give each organization its own index directory
 pub struct MessageIndexer {
-    client: ProntoClient,
+    client: Arc<ProntoClient>,
+    /// The organization `client` is logged in to, whose tasks are indexed
+    organization_id: u64,
     info: MessageIndexInfo,
-    index_info_path: String,
+    index_info_path: PathBuf,
     index: Index,
-    mpsc_rx: Arc<Mutex<mpsc::Receiver<Message>>>,
+    mpsc_rx: Arc<tokio::sync::Mutex<mpsc::Receiver<Message>>>,
     mpsc_tx: Arc<mpsc::Sender<Message>>,
+    live_rx: tokio::sync::Mutex<mpsc::UnboundedReceiver<IndexUpdate>>,
+    live_tx: mpsc::UnboundedSender<IndexUpdate>,
     indexer_settings: IndexerSettings,
 }
 
 impl MessageIndexer {
-    pub async fn new(index_path: &PathBuf, indexer_settings: IndexerSettings) -> Self {
-        let index_info_path = index_path.join("index_info.json");
-        tokio::fs::create_dir_all(index_info_path.parent().unwrap())
-            .await
-            .unwrap();
-        if !index_info_path.exists() {
-            let message_index_info = MessageIndexInfo {
-                bubbles: DashMap::new(),
-            };
-            serde_json::to_writer(
-                std::fs::File::create(&index_info_path).unwrap(),
-                &message_index_info,
-            )
-            .unwrap();
-        }
-        let message_index_info: MessageIndexInfo =
-            serde_json::from_reader(std::fs::File::open(&index_info_path).unwrap()).unwrap();
+    /// Open or create the index in `index_path`, messages are fetched with `client`,
+    /// logged in to `organization_id`
+    pub async fn new(
+        client: Arc<ProntoClient>,
+        organization_id: u64,
+        index_path: &Path,
+        indexer_settings: IndexerSettings,
+    ) -> Result<Self, SearchError> {
+        tokio::fs::create_dir_all(index_path).await?;
+        let index_info_path = index_path.join(INDEX_INFO_FILE);
+        let (mut info, info_lost) = match MessageIndexInfo::load(&index_info_path) {
+            Ok(info) => (info, false),
+            Err(e) => {
+                warn!("{} is unreadable: {e}", index_info_path.display());
+                (MessageIndexInfo::default(), true)
+            }
+        };
 
-        let client = ProntoClient::new(
-            "https://stanfordohs.pronto.io/api/".to_string(),
-            "p10LpQ2V7bIJFkkhHlBNBGthClYHEUvXj2eVzpzQ.1258569865",
-        )
-        .unwrap();
+        let map_size = map_size(indexer_settings.max_size);
+        let index = match open_checked(index_path, map_size) {
+            Ok(index) => index,
+            Err(e) => {
+                warn!(
+                    "Rebuilding the corrupted index in {}: {e}",
+                    index_path.display()
+                );
+                let dataset = index_path.to_path_buf();
+                tokio::task::spawn_blocking(move || {
+                    let _closed = ClosedIndex::close(&dataset);
+                    remove_index_files(&dataset)
+                })
+                .await??;
+                info.forget_progress();
+                get_index(index_path, map_size)?
+            }
+        };
+        // Nothing tells what's in the index anymore, or it's stored the old way
+        if info_lost || info.schema_version != SCHEMA_VERSION {
+            info!("Indexing everything again for schema version {SCHEMA_VERSION}");
+            clear_documents(&index)?;
+            info.forget_progress();
+            info.schema_version = SCHEMA_VERSION;
+        }
+        let model = indexer_settings
+            .embedder
+            .as_ref()
+            .map(|embedder| embedder.name().to_string());
+        {
+            let mut embeddings = info
+                .embeddings
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner);
+            let changed = embeddings.model != model;
+            if changed {
+                // Vectors of another model can't be compared with the new ones
+                if embeddings.model.is_some() {
+                    reset_embedder(&index)?;
+                }
+                *embeddings = EmbeddingsInfo {
+                    backfill_from: model.as_ref().map(|_| 0),
+                    model,
+                };
+            }
+        }
+        configure(&index, indexer_settings.embedder.as_deref())?;
+        info.save(&index_info_path)?;
 
         let (tx, rx) = mpsc::channel(512);
+        let (live_tx, live_rx) = mpsc::unbounded_channel();
 
-        Self {
+        Ok(Self {
             client,
-            index: get_index(index_path).unwrap(),
-            mpsc_rx: Arc::new(Mutex::new(rx)),
-            info: message_index_info,
-            index_info_path: index_info_path.to_str().unwrap().to_string(),
+            organization_id,
+            index,
+            mpsc_rx: Arc::new(tokio::sync::Mutex::new(rx)),
+            info,
+            index_info_path,
             mpsc_tx: Arc::new(tx),
+            live_rx: tokio::sync::Mutex::new(live_rx),
+            live_tx,
             indexer_settings,
-        }
+        })
+    }
+
+    /// What the messages are embedded with, queries have to be embedded with it too
+    pub fn embedder(&self) -> Option<Arc<dyn Embedder>> {
+        self.indexer_settings.embedder.clone()
+    }
+
+    /// Queue a live change, it's written by the next [`Self::flush`] or [`Self::execute`]
+    pub fn queue(&self, update: IndexUpdate) {
+        // The receiver lives as long as the indexer
+        let _ = self.live_tx.send(update);
     }
 
-    pub async fn fastforward(&self) -> Result<(), Box<dyn Error + Send + Sync>> {
+    /// Write the queued live changes and whatever [`Self::fastforward`] fetched so far.
+    /// Unlike [`Self::execute`] this doesn't talk to the server and ignores the size limit,
+    /// so edits and deletions show up in results within seconds.
+    pub async fn flush(&self) -> Result<(), SearchError> {
+        self.write(Vec::new()).await
+    }
+
+    /// Fetch the messages sent since the last run, they're indexed by the next [`Self::execute`]
+    pub async fn fastforward(&self) -> Result<(), SearchError> {
+        self.fetch_announcements_and_tasks().await?;
         let bubble_list = &self.client.bubble_list().await?;
         // This clone is necessary so that we don't process updates from the execution function,
         // which will update the latest message after it receives messages via the mpsc.
         let mut handles = vec![];
         for (bubble, info) in self.info.bubbles.clone() {
-            let stats = bubble_list
+            // Bubbles that were left aren't listed anymore
+            let Some(stats) = bubble_list
                 .stats
                 .iter()
                 .find(|stat| stat.bubble_id == bubble)
-                .unwrap();
+            else {
+                continue;
+            };
             if stats.latest_message_id != info.latest_message {
                 let handle = tokio::task::spawn({
                     let client = self.client.clone();
                     let mpsc_tx = self.mpsc_tx.clone();
                     async move {
-                        loop {
-                            let messages = client
-                                .bubble_history(bubble, Some(info.latest_message))
+                        let mut messages = pin!(client.bubble_history_stream(
+                            bubble,
+                            None,
+                            Some(info.latest_message)
+                        ));
+                        while let Some(message) = messages.try_next().await? {
+                            mpsc_tx
+                                .send(message)
                                 .await
-                                .unwrap();
-                            let should_break = messages
-                                .messages
-                                .iter()
-                                .any(|m| m.id <= info.latest_message);
-                            for message in messages.messages {
-                                mpsc_tx.send(message).await.unwrap();
-                            }
-                            if should_break {
-                                break;
-                            }
+                                .map_err(|_| SearchError::IndexerStopped)?;
                         }
+                        Ok::<_, SearchError>(())
                     }
                 });
                 handles.push(handle);
             }
         }
-        futures::future::join_all(handles).await;
-        self.info
-            .save(&PathBuf::from(self.index_info_path.clone()))?;
+        for result in futures::future::join_all(handles).await {
+            result??;
+        }
+        self.info.save(&self.index_info_path)?;
         Ok(())
     }
 
-    pub async fn execute(&self) -> Result<(), Box<dyn Error + Send + Sync>> {
-        // Do nothing if the index is too large
-        if self.index.on_disk_size().unwrap()
-            > self.indexer_settings.max_size.unwrap_or(usize::MAX) as u64
-        {
-            return Ok(());
+    /// Queue the announcements and tasks the server has, there are few enough to refetch
+    async fn fetch_announcements_and_tasks(&self) -> Result<(), SearchError> {
+        let (announcements, incomplete, complete) = futures::join!(
+            self.client.announcement_list("RECEIVED".to_string()),
+            self.client.task_list(self.organization_id, false),
+            self.client.task_list(self.organization_id, true),
+        );
+        for announcement in announcements?.announcements {
+            self.queue(IndexUpdate::UpsertAnnouncement(announcement));
         }
+        for task in incomplete?.tasks.into_iter().chain(complete?.tasks) {
+            self.queue(IndexUpdate::UpsertTask(task));
+        }
+        Ok(())
+    }
 
-        let filterable_fields = vec![
-            "user_id".to_string(),
-            "bubble_id".to_string(),
-            "parent_message_id".to_string(),
-        ];
-        let searchable_fields = vec!["message".to_string(), "user_fullname".to_string()];
-
-        let bubble_list = &self.client.bubble_list().await?;
-        let mut wtxn =
-            tokio::time::timeout(Duration::from_secs(2), async { self.index.write_txn() })
-                .await??;
-        let config = IndexerConfig::default();
-        let mut settings = Settings::new(&mut wtxn, &self.index, &config);
-        let searchable_fields = searchable_fields.iter().map(|s| s.to_string()).collect();
-        settings.set_searchable_fields(searchable_fields);
-        let filterable_fields = filterable_fields.iter().map(|s| s.to_string()).collect();
-        settings.set_filterable_fields(filterable_fields);
-        settings.set_primary_key("id".to_string());
-
-        settings.execute(|_| (), || false)?;
-
-        let indexing_config = IndexDocumentsConfig::default();
-
-        let builder = IndexDocuments::new(
-            &mut wtxn,
-            &self.index,
-            &config,
-            indexing_config,
-            |_| (),
-            || false,
-        )
-        .unwrap();
+    /// Index another page of history for every bubble and whatever [`Self::fastforward`] fetched
+    pub async fn execute(&self) -> Result<(), SearchError> {
+        // The file doesn't shrink when documents are removed, count the pages in use
+        if let Some(max_size) = self.indexer_settings.max_size
+            && self.index.used_size()? > max_size as u64
+        {
+            return self.evict().await;
+        }
 
-        let mut documents_batch = DocumentsBatchBuilder::new(Vec::new());
         debug!("Getting messages");
+        let bubble_list = &self.client.bubble_list().await?;
         let mut tasks = vec![];
         for channel in bubble_list.bubbles.clone() {
-            let channel = channel.clone();
             tasks.push({
                 let future = if let Some(index_info) = &self.info.bubbles.get(&channel.id) {
                     if index_info.upwards_index_complete {
@@ -282,77 +508,362 @@ impl MessageIndexer {
                     Some(self.client.bubble_history(channel.id, None))
                 };
                 async move {
-                    if let Some(future) = future {
-                        (channel.id, future.await.unwrap().messages)
-                    } else {
-                        (channel.id, Default::default())
+                    match future {
+                        Some(future) => Ok((channel.id, future.await?.messages)),
+                        None => Ok::<_, SearchError>((channel.id, Vec::new())),
                     }
                 }
             });
         }
-
-        let new_messages = futures::future::join_all(tasks).await;
+        let new_messages = futures::future::try_join_all(tasks).await?;
         debug!("Messages received");
+
+        let mut backfill = vec![];
         for (id, messages) in new_messages {
-            if messages.len() > 0 {
-                if let Some(index_info) = &self.info.bubbles.get(&id) {
+            // Anything older was evicted, backfilling is done
+            let reached_floor = messages.last().is_some_and(|last| self.evicted(last));
+            let index_info = self.info.bubbles.get(&id).map(|v| *v);
+            match (index_info, messages.first(), messages.last()) {
+                (Some(index_info), Some(_), Some(last)) if last.id == index_info.first_message => {
+                    self.info.bubbles.insert(id, index_info.complete());
+                }
+                (Some(index_info), Some(first), Some(last)) => {
                     info!(
                         "Backtrack {}: {} -> {}",
-                        id,
-                        index_info.first_message,
-                        messages.last().unwrap().id
-                    );
-                } else {
-                    info!(
-                        "New channel {}: None -> {}",
-                        id,
-                        messages.last().unwrap().id
+                        id, index_info.first_message, last.id
                     );
+                    self.info
+                        .bubbles
+                        .insert(id, index_info.extend(last.id, first.id));
                 }
-            }
-            for message in messages.clone().into_iter() {
-                documents_batch
-                    .append_json_array(
-                        serde_json::to_string(&StoredMessage::from(message))
-                            .unwrap()
-                            .as_bytes(),
-                    )
-                    .unwrap();
-            }
-            if let Some(index_info) = &self.info.bubbles.get(&id).map(|v| v.clone()) {
-                if messages.last().map(|m| m.id) == Some(index_info.first_message) {
-                    self.info.bubbles.insert(id, index_info.complete());
-                } else if messages.len() > 0 {
-                    self.info.bubbles.insert(
-                        id,
-                        index_info
-                            .extend(messages.last().unwrap().id, messages.first().unwrap().id),
-                    );
-                } else {
+                // Nothing older is left
+                (Some(index_info), _, _) => {
                     self.info.bubbles.insert(id, index_info.complete());
                 }
-            } else {
-                if messages.len() > 0 {
+                (None, Some(first), Some(last)) => {
+                    info!("New channel {}: None -> {}", id, last.id);
                     self.info.bubbles.insert(
                         id,
                         BubbleIndexInfo {
-                            latest_message: messages.first().unwrap().id,
-                            first_message: messages.last().unwrap().id,
+                            latest_message: first.id,
+                            first_message: last.id,
                             upwards_index_complete: false,
                         },
                     );
                 }
+                (None, _, _) => {}
+            }
+            let index_info = self.info.bubbles.get(&id).map(|v| *v);
+            if reached_floor && let Some(index_info) = index_info {
+                self.info.bubbles.insert(id, index_info.complete());
+            }
+            backfill.extend(messages);
+        }
+        self.write(backfill).await?;
+        self.backfill_embeddings().await
+    }
+
+    /// Remove the oldest messages of every bubble to get back under the size limit,
+    /// keeping the newest [`KEEP_PER_BUBBLE`] of each
+    async fn evict(&self) -> Result<(), SearchError> {
+        let bubbles: Vec<u64> = self.info.bubbles.iter().map(|entry| *entry.key()).collect();
+        let mut floors = vec![];
+        let mut evicted = vec![];
+        {
+            let txn = self.index.read_txn()?;
+            let only_messages = kind_filter(&[DocumentKind::Message]);
+            for bubble_id in bubbles {
+                let expression = format!("bubble_id = {bubble_id} AND {only_messages}");
+                let indexed = match Filter::from_str(&expression)? {
+                    Some(filter) => filter.evaluate(&txn, &self.index)?.len() as usize,
+                    None => 0,
+                };
+                let count = indexed.saturating_sub(KEEP_PER_BUBBLE).min(EVICT_BATCH);
+                if count == 0 {
+                    continue;
+                }
+                let oldest: Vec<u64> = sorted_documents(
+                    &self.index,
+                    &txn,
+                    &expression,
+                    AscDesc::Asc(Member::Field("id".to_string())),
+                    count,
+                )?
+                .iter()
+                .filter_map(|document| document.get("id")?.as_u64())
+                .collect();
+                if let Some(&floor) = oldest.last() {
+                    floors.push((bubble_id, floor));
+                }
+                evicted.extend(oldest);
+            }
+        }
+        if evicted.is_empty() {
+            debug!("Over the size limit with nothing left to evict");
+            return Ok(());
+        }
+
+        info!(
+            "Evicting {} messages to stay under the size limit",
+            evicted.len()
+        );
+        for (bubble_id, floor) in floors {
+            self.info.floors.insert(bubble_id, floor);
+            let index_info = self.info.bubbles.get(&bubble_id).map(|v| *v);
+            if let Some(index_info) = index_info {
+                self.info.bubbles.insert(bubble_id, index_info.complete());
+            }
+        }
+        let mut removed: Vec<String> = evicted.iter().map(u64::to_string).collect();
+        removed.extend(self.stale_media(&evicted, &[])?);
+        self.index_documents(vec![], removed).await?;
+        self.info.save(&self.index_info_path)
+    }
+
+    /// Whether `message` is as old as what was evicted from its bubble
+    fn evicted(&self, message: &Message) -> bool {
+        self.info
+            .floors
+            .get(&message.bubble_id)
+            .is_some_and(|floor| message.id <= *floor)
+    }
+
+    /// Embed another batch of the documents indexed before the embedder was set or changed
+    async fn backfill_embeddings(&self) -> Result<(), SearchError> {
+        let Some(from) = self
+            .info
+            .embeddings
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .backfill_from
+        else {
+            return Ok(());
+        };
+        let (next, documents) = {
+            let txn = self.index.read_txn()?;
+            let ids: Vec<u32> = self
+                .index
+                .documents_ids(&txn)?
+                .iter()
+                .filter(|&id| id >= from)
+                .take(EMBED_BACKFILL_BATCH)
+                .collect();
+            let documents = read_documents(&self.index, &txn, ids.iter().copied())?
+                .into_iter()
+                .map(|(_, document)| Value::Object(document))
+                .collect();
+            (ids.last().map(|id| id + 1), documents)
+        };
+        self.index_documents(documents, vec![]).await?;
+        self.info
+            .embeddings
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .backfill_from = next;
+        self.info.save(&self.index_info_path)?;
+        Ok(())
+    }
+
+    /// Index `messages` along with everything queued, later changes to a message win
+    async fn write(&self, messages: Vec<Message>) -> Result<(), SearchError> {
+        let mut changes: HashMap<u64, Option<Message>> = HashMap::new();
+        for message in messages {
+            changes.insert(message.id, Some(message));
+        }
+        {
+            let mut mpsc_rx = self.mpsc_rx.lock().await;
+            debug!("Processing {} messages", mpsc_rx.len());
+            while let Ok(message) = mpsc_rx.try_recv() {
+                self.track(&message);
+                changes.insert(message.id, Some(message));
+            }
+        }
+        // Announcements and tasks, keyed by document id
+        let mut other_changes: HashMap<String, Option<Value>> = HashMap::new();
+        {
+            let mut live_rx = self.live_rx.lock().await;
+            while let Ok(update) = live_rx.try_recv() {
+                match update {
+                    IndexUpdate::Upsert(message) => {
+                        self.track(&message);
+                        changes.insert(message.id, Some(message));
+                    }
+                    IndexUpdate::Remove(id) => {
+                        changes.insert(id, None);
+                    }
+                    IndexUpdate::UpsertAnnouncement(announcement) => {
+                        let document = StoredAnnouncement::from(announcement);
+                        other_changes
+                            .insert(document.id.clone(), Some(serde_json::to_value(document)?));
+                    }
+                    IndexUpdate::RemoveAnnouncement(id) => {
+                        other_changes.insert(StoredAnnouncement::id(id), None);
+                    }
+                    IndexUpdate::UpsertTask(task) => {
+                        let document = StoredTask::from(task);
+                        other_changes
+                            .insert(document.id.clone(), Some(serde_json::to_value(document)?));
+                    }
+                }
+            }
+        }
+        if changes.is_empty() && other_changes.is_empty() {
+            return Ok(());
+        }
+
+        let changed_messages: Vec<u64> = changes.keys().copied().collect();
+        let mut upserts = vec![];
+        let mut removed = vec![];
+        for (id, change) in changes {
+            match change {
+                // Edits to evicted messages don't bring them back
+                Some(message) if self.evicted(&message) => {}
+                Some(message) => {
+                    for media in StoredMedia::from_message(&message) {
+                        upserts.push(serde_json::to_value(media)?);
+                    }
+                    upserts.push(serde_json::to_value(StoredMessage::from(message))?);
+                }
+                None => removed.push(id.to_string()),
+            }
+        }
+        for (id, change) in other_changes {
+            match change {
+                Some(document) => upserts.push(document),
+                None => removed.push(id),
             }
         }
-        debug!("Receiving channel messages");
-        debug!("Processing {} messages", self.mpsc_rx.lock().unwrap().len());
-        while !self.mpsc_rx.lock().unwrap().is_empty() {
-            let message = self.mpsc_rx.lock().unwrap().recv().await.unwrap();
-            if let Some(index_info) = &self.info.bubbles.get(&message.bubble_id) {
+        removed.extend(self.stale_media(&changed_messages, &upserts)?);
+        self.index_documents(upserts, removed).await?;
+        self.info.save(&self.index_info_path)?;
+
+        Ok(())
+    }
+
+    /// Ids of the indexed media of `messages` that aren't among `upserts` anymore,
+    /// because the message was deleted or the attachment removed in an edit
+    fn stale_media(&self, messages: &[u64], upserts: &[Value]) -> Result<Vec<String>, SearchError> {
+        if messages.is_empty() {
+            return Ok(vec![]);
+        }
+        let ids = messages
+            .iter()
+            .map(u64::to_string)
+            .collect::<Vec<_>>()
+            .join(", ");
+        let expression = format!(
+            "kind = {} AND message_id IN [{ids}]",
+            DocumentKind::Media.as_str()
+        );
+        let Some(filter) = Filter::from_str(&expression)? else {
+            return Ok(vec![]);
+        };
+        let txn = self.index.read_txn()?;
+        let indexed = filter.evaluate(&txn, &self.index)?;
+        Ok(read_documents(&self.index, &txn, indexed)?
+            .into_iter()
+            .filter_map(|(_, document)| document.get("id")?.as_str().map(str::to_string))
+            .filter(|id| {
+                !upserts
+                    .iter()
+                    .any(|upsert| upsert["id"].as_str() == Some(id.as_str()))
+            })
+            .collect())
+    }
+
+    /// Add or replace the `upserts` documents and remove the documents with the `removed` ids
+    async fn index_documents(
+        &self,
+        upserts: Vec<Value>,
+        removed: Vec<String>,
+    ) -> Result<(), SearchError> {
+        let vectors = self.embed(&upserts).await?;
+        let adding = !upserts.is_empty();
+        let mut documents_batch = DocumentsBatchBuilder::new(Vec::new());
+        for (document, vector) in upserts.into_iter().zip(vectors) {
+            append(&mut documents_batch, document, vector)?;
+        }
+
+        // Indexing blocks for a while, keep it off the async workers like the embedding
+        let index = self.index.clone();
+        tokio::task::spawn_blocking(move || -> Result<(), SearchError> {
+            let mut wtxn = index.write_txn()?;
+            let config = IndexerConfig::default();
+            let indexing_config = IndexDocumentsConfig::default();
+            let mut builder = IndexDocuments::new(
+                &mut wtxn,
+                &index,
+                &config,
+                indexing_config,
+                |_| (),
+                || false,
+            )?;
+
+            if adding {
+                let documents_batch = documents_batch.into_inner()?;
+                let documents = DocumentsBatchReader::from_reader(Cursor::new(documents_batch))?;
+                let (adding, user_error) = builder.add_documents(documents)?;
+                user_error.map_err(milli::Error::from)?;
+                builder = adding;
+            }
+            if !removed.is_empty() {
+                let (removing, user_error) = builder.remove_documents(removed)?;
+                user_error.map_err(milli::Error::from)?;
+                builder = removing;
+            }
+            builder.execute()?;
+            debug!("Committing");
+            wtxn.commit()?;
+            Ok(())
+        })
+        .await?
+    }
+
+    /// A vector per document when there is an embedder, documents without text get none
+    async fn embed(&self, documents: &[Value]) -> Result<Vec<Option<Vec<f32>>>, SearchError> {
+        let Some(embedder) = self.indexer_settings.embedder.clone() else {
+            return Ok(vec![None; documents.len()]);
+        };
+        let texts: Vec<String> = documents
+            .iter()
+            .map(|document| document.as_object().map(document_text).unwrap_or_default())
+            .collect();
+        // Models take their time on the CPU, keep them off the async workers
+        tokio::task::spawn_blocking(move || -> Result<_, SearchError> {
+            let with_text: Vec<&str> = texts
+                .iter()
+                .map(String::as_str)
+                .filter(|text| !text.trim().is_empty())
+                .collect();
+            if with_text.is_empty() {
+                return Ok(vec![None; texts.len()]);
+            }
+            let mut vectors = embedder.embed(&with_text)?.into_iter();
+            Ok(texts
+                .iter()
+                .map(|text| {
+                    if text.trim().is_empty() {
+                        None
+                    } else {
+                        vectors.next()
+                    }
+                })
+                .collect())
+        })
+        .await?
+    }
+
+    /// Extend what's known to be indexed with a message that arrived out of band.
+    /// Only newer messages count, an edit to an old one says nothing about the ones around it.
+    fn track(&self, message: &Message) {
+        match self.info.bubbles.get(&message.bubble_id).map(|v| *v) {
+            Some(index_info) if message.id > index_info.latest_message => {
                 self.info
                     .bubbles
                     .insert(message.bubble_id, index_info.extend(message.id, message.id));
-            } else {
+            }
+            Some(_) => {}
+            None => {
                 self.info.bubbles.insert(
                     message.bubble_id,
                     BubbleIndexInfo {
@@ -362,25 +873,76 @@ impl MessageIndexer {
                     },
                 );
             }
-            documents_batch
-                .append_json_array(
-                    serde_json::to_string(&StoredMessage::from(message))
-                        .unwrap()
-                        .as_bytes(),
-                )
-                .unwrap();
         }
-        let documents_batch = documents_batch.into_inner()?;
-        let documents = DocumentsBatchReader::from_reader(Cursor::new(documents_batch))?;
-        let (builder, user_error) = builder.add_documents(documents)?;
-        user_error?;
-        builder.execute()?;
-        debug!("Committing");
-        wtxn.commit()?;
+    }
+}
 
-        self.info
-            .save(&PathBuf::from(self.index_info_path.clone()))?;
+/// Which fields are searched, filtered and sorted on, the primary key and the embedder
+fn configure(index: &Index, embedder: Option<&dyn Embedder>) -> Result<(), SearchError> {
+    let filterable_fields = vec![
+        "id".to_string(),
+        "kind".to_string(),
+        "message_id".to_string(),
+        "user_id".to_string(),
+        "bubble_id".to_string(),
+        "parent_message_id".to_string(),
+        "created_at_timestamp".to_string(),
+        "has_link".to_string(),
+        "is_thread".to_string(),
+    ];
+    let sortable_fields = vec!["id".to_string(), "created_at_timestamp".to_string()];
+    let searchable_fields = vec![
+        "title".to_string(),
+        "message".to_string(),
+        "filename".to_string(),
+        "mimetype".to_string(),
+        "user_fullname".to_string(),
+        "message_resource_title".to_string(),
+        "message_resource_snippet".to_string(),
+    ];
 
-        Ok(())
+    let mut wtxn = index.write_txn()?;
+    let config = IndexerConfig::default();
+    let mut settings = Settings::new(&mut wtxn, index, &config);
+    settings.set_searchable_fields(searchable_fields);
+    settings.set_filterable_fields(filterable_fields.into_iter().collect());
+    settings.set_sortable_fields(sortable_fields.into_iter().collect());
+    settings.set_primary_key("id".to_string());
+    if let Some(embedder) = embedder {
+        settings.set_embedder_settings(embedder_settings(embedder)?);
+    }
+    settings.execute(|_| (), || false)?;
+    wtxn.commit()?;
+    Ok(())
+}
+
+fn clear_documents(index: &Index) -> Result<(), SearchError> {
+    let mut wtxn = index.write_txn()?;
+    ClearDocuments::new(&mut wtxn, index).execute()?;
+    wtxn.commit()?;
+    Ok(())
+}
+
+/// Drop the stored vectors, along with the embedder they came from
+fn reset_embedder(index: &Index) -> Result<(), SearchError> {
+    let mut wtxn = index.write_txn()?;
+    let config = IndexerConfig::default();
+    let mut settings = Settings::new(&mut wtxn, index, &config);
+    settings.reset_embedder_settings();
+    settings.execute(|_| (), || false)?;
+    wtxn.commit()?;
+    Ok(())
+}
+
+fn append(
+    documents_batch: &mut DocumentsBatchBuilder<Vec<u8>>,
+    mut document: Value,
+    vector: Option<Vec<f32>>,
+) -> Result<(), SearchError> {
+    if let Some(vector) = vector {
+        document["_vectors"] = serde_json::json!({ EMBEDDER_NAME: vector });
     }
+    let json = serde_json::to_string(&document)?;
+    documents_batch.append_json_array(json.as_bytes())?;
+    Ok(())
 }
@@ -0,0 +1,389 @@
+//! The query syntax of the search box and what it turns into for milli.
+//!
+//! `from:@alice in:#general before:2024-10-01 has:link is:thread "exact phrase"`
+//! filters on who sent a message, where, when and what it contains.
+//! `is:message`, `is:file`, `is:announcement` and `is:task` pick the kinds of documents.
+//! Everything else, quoted phrases included, is searched for as text.
+
+use crate::documents::DocumentKind;
+use chrono::{NaiveDate, NaiveTime};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Has {
+    /// A url or a link preview
+    Link,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Is {
+    /// A thread reply or a message with replies
+    Thread,
+}
+
+/// What the user typed, names are still to be resolved to ids by the caller
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ParsedQuery {
+    pub text: String,
+    /// Users after `from:`, without the `@`
+    pub from: Vec<String>,
+    /// Bubbles after `in:`, without the `#`
+    pub channels: Vec<String>,
+    /// Sent before the start of this day
+    pub before: Option<NaiveDate>,
+    /// Sent after the end of this day
+    pub after: Option<NaiveDate>,
+    pub has: Vec<Has>,
+    pub is: Vec<Is>,
+    /// Everything is searched when empty
+    pub kinds: Vec<DocumentKind>,
+}
+
+impl ParsedQuery {
+    /// Operators that don't parse, like `before:someday`, are searched for as text
+    pub fn parse(input: &str) -> Self {
+        let mut query = ParsedQuery::default();
+        let mut text = vec![];
+        for token in tokenize(input) {
+            let Some((key, value)) = token.split_once(':') else {
+                text.push(token);
+                continue;
+            };
+            let value = value.trim_matches('"');
+            let parsed = match key.to_lowercase().as_str() {
+                "from" if !value.is_empty() => {
+                    query.from.push(value.trim_start_matches('@').to_string());
+                    true
+                }
+                "in" if !value.is_empty() => {
+                    query
+                        .channels
+                        .push(value.trim_start_matches('#').to_string());
+                    true
+                }
+                "before" => parse_date(value)
+                    .map(|date| query.before = Some(date))
+                    .is_some(),
+                "after" => parse_date(value)
+                    .map(|date| query.after = Some(date))
+                    .is_some(),
+                "on" => parse_date(value)
+                    .map(|date| {
+                        query.after = date.pred_opt();
+                        query.before = date.succ_opt();
+                    })
+                    .is_some(),
+                "has" if value.eq_ignore_ascii_case("link") => {
+                    query.has.push(Has::Link);
+                    true
+                }
+                "is" if value.eq_ignore_ascii_case("thread") => {
+                    query.is.push(Is::Thread);
+                    true
+                }
+                "is" => match parse_kind(value) {
+                    Some(kind) => {
+                        query.kinds.push(kind);
+                        true
+                    }
+                    None => false,
+                },
+                _ => false,
+            };
+            if !parsed {
+                text.push(token);
+            }
+        }
+        query.text = text.join(" ");
+        query
+    }
+}
+
+fn parse_kind(value: &str) -> Option<DocumentKind> {
+    match value.to_lowercase().as_str() {
+        "message" => Some(DocumentKind::Message),
+        "file" | "media" => Some(DocumentKind::Media),
+        "announcement" => Some(DocumentKind::Announcement),
+        "task" => Some(DocumentKind::Task),
+        _ => None,
+    }
+}
+
+fn parse_date(value: &str) -> Option<NaiveDate> {
+    NaiveDate::parse_from_str(value, "%Y-%m-%d").ok()
+}
+
+/// Split on whitespace, keeping quoted phrases (and `key:"quoted value"`) together
+fn tokenize(input: &str) -> Vec<String> {
+    let mut tokens = vec![];
+    let mut token = String::new();
+    let mut quoted = false;
+    for c in input.chars() {
+        match c {
+            '"' => {
+                quoted = !quoted;
+                token.push(c);
+            }
+            c if c.is_whitespace() && !quoted => {
+                if !token.is_empty() {
+                    tokens.push(std::mem::take(&mut token));
+                }
+            }
+            c => token.push(c),
+        }
+    }
+    if !token.is_empty() {
+        tokens.push(token);
+    }
+    tokens
+}
+
+/// The order of results
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SortOrder {
+    #[default]
+    Relevance,
+    NewestFirst,
+    OldestFirst,
+}
+
+/// A search of the message index, built up from a [`ParsedQuery`] or directly
+#[derive(Clone, Debug, PartialEq)]
+pub struct SearchQuery {
+    pub(crate) text: Option<String>,
+    from_users: Vec<u64>,
+    in_bubbles: Vec<u64>,
+    before: Option<i64>,
+    after: Option<i64>,
+    has_link: bool,
+    thread: bool,
+    kinds: Vec<DocumentKind>,
+    pub(crate) sort: SortOrder,
+    pub(crate) offset: usize,
+    pub(crate) limit: usize,
+    pub(crate) context: usize,
+    pub(crate) semantic_ratio: f32,
+}
+
+impl Default for SearchQuery {
+    fn default() -> Self {
+        Self {
+            text: None,
+            from_users: vec![],
+            in_bubbles: vec![],
+            before: None,
+            after: None,
+            has_link: false,
+            thread: false,
+            kinds: vec![],
+            sort: SortOrder::default(),
+            offset: 0,
+            limit: 20,
+            context: 0,
+            semantic_ratio: 0.0,
+        }
+    }
+}
+
+impl SearchQuery {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Words and quoted phrases to look for, blank text matches everything
+    pub fn text(mut self, text: &str) -> Self {
+        let text = text.trim();
+        self.text = (!text.is_empty()).then(|| text.to_string());
+        self
+    }
+
+    /// Only messages by this user, or any of the users given this way
+    pub fn from_user(mut self, user_id: u64) -> Self {
+        self.from_users.push(user_id);
+        self
+    }
+
+    /// Only messages in this bubble, or any of the bubbles given this way
+    pub fn in_bubble(mut self, bubble_id: u64) -> Self {
+        self.in_bubbles.push(bubble_id);
+        self
+    }
+
+    /// Only messages sent before this unix timestamp
+    pub fn before(mut self, timestamp: i64) -> Self {
+        self.before = Some(timestamp);
+        self
+    }
+
+    /// Only messages sent at or after this unix timestamp
+    pub fn after(mut self, timestamp: i64) -> Self {
+        self.after = Some(timestamp);
+        self
+    }
+
+    pub fn has_link(mut self) -> Self {
+        self.has_link = true;
+        self
+    }
+
+    pub fn thread(mut self) -> Self {
+        self.thread = true;
+        self
+    }
+
+    /// Only documents of this kind, or any of the kinds given this way
+    pub fn kind(mut self, kind: DocumentKind) -> Self {
+        if !self.kinds.contains(&kind) {
+            self.kinds.push(kind);
+        }
+        self
+    }
+
+    pub fn sort(mut self, sort: SortOrder) -> Self {
+        self.sort = sort;
+        self
+    }
+
+    pub fn offset(mut self, offset: usize) -> Self {
+        self.offset = offset;
+        self
+    }
+
+    pub fn limit(mut self, limit: usize) -> Self {
+        self.limit = limit;
+        self
+    }
+
+    /// How many messages of the same bubble to return before and after each hit
+    pub fn context(mut self, context: usize) -> Self {
+        self.context = context;
+        self
+    }
+
+    /// How much to rank by meaning rather than by words, from 0 to 1.
+    /// Only used when the search has an [`crate::Embedder`] and there is text.
+    pub fn semantic(mut self, ratio: f32) -> Self {
+        self.semantic_ratio = ratio.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Apply the text, dates and `has:`/`is:` operators of a parsed query.
+    /// `from:` and `in:` name users and bubbles, the caller resolves them with
+    /// [`Self::from_user`] and [`Self::in_bubble`].
+    pub fn parsed(mut self, parsed: &ParsedQuery) -> Self {
+        self = self.text(&parsed.text);
+        if let Some(before) = parsed.before {
+            self = self.before(start_of_day(before));
+        }
+        if let Some(after) = parsed.after.and_then(|after| after.succ_opt()) {
+            self = self.after(start_of_day(after));
+        }
+        for has in &parsed.has {
+            match has {
+                Has::Link => self = self.has_link(),
+            }
+        }
+        for is in &parsed.is {
+            match is {
+                Is::Thread => self = self.thread(),
+            }
+        }
+        for &kind in &parsed.kinds {
+            self = self.kind(kind);
+        }
+        self
+    }
+
+    /// The milli filter expression, if anything is filtered on
+    pub fn filter(&self) -> Option<String> {
+        let mut conditions = vec![];
+        if !self.from_users.is_empty() {
+            conditions.push(format!("user_id IN [{}]", join(&self.from_users)));
+        }
+        if !self.in_bubbles.is_empty() {
+            conditions.push(format!("bubble_id IN [{}]", join(&self.in_bubbles)));
+        }
+        if let Some(before) = self.before {
+            conditions.push(format!("created_at_timestamp < {before}"));
+        }
+        if let Some(after) = self.after {
+            conditions.push(format!("created_at_timestamp >= {after}"));
+        }
+        if self.has_link {
+            conditions.push("has_link = true".to_string());
+        }
+        if self.thread {
+            conditions.push("is_thread = true".to_string());
+        }
+        if !self.kinds.is_empty() {
+            conditions.push(kind_filter(&self.kinds));
+        }
+        (!conditions.is_empty()).then(|| conditions.join(" AND "))
+    }
+}
+
+pub(crate) fn kind_filter(kinds: &[DocumentKind]) -> String {
+    let names = kinds
+        .iter()
+        .map(|kind| kind.as_str())
+        .collect::<Vec<_>>()
+        .join(", ");
+    format!("kind IN [{names}]")
+}
+
+fn start_of_day(date: NaiveDate) -> i64 {
+    date.and_time(NaiveTime::MIN).and_utc().timestamp()
+}
+
+fn join(ids: &[u64]) -> String {
+    ids.iter()
+        .map(u64::to_string)
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let parsed = ParsedQuery::parse(
+            r#"from:@alice in:#general before:2024-10-01 has:link is:thread "exact phrase" lunch"#,
+        );
+        assert_eq!(parsed.text, r#""exact phrase" lunch"#);
+        assert_eq!(parsed.from, ["alice"]);
+        assert_eq!(parsed.channels, ["general"]);
+        assert_eq!(parsed.before, NaiveDate::from_ymd_opt(2024, 10, 1));
+        assert_eq!(parsed.has, [Has::Link]);
+        assert_eq!(parsed.is, [Is::Thread]);
+
+        let parsed = ParsedQuery::parse(r#"from:"Alice Smith" before:soon https://example.com"#);
+        assert_eq!(parsed.from, ["Alice Smith"]);
+        assert_eq!(parsed.before, None);
+        assert_eq!(parsed.text, "before:soon https://example.com");
+    }
+
+    #[test]
+    fn test_filter() {
+        let parsed = ParsedQuery::parse("on:2024-10-01 has:link");
+        let query = SearchQuery::new()
+            .parsed(&parsed)
+            .from_user(1)
+            .from_user(2)
+            .in_bubble(3);
+        assert_eq!(query.text, None);
+        assert_eq!(
+            query.filter().unwrap(),
+            "user_id IN [1, 2] AND bubble_id IN [3] AND created_at_timestamp < 1727827200 \
+             AND created_at_timestamp >= 1727740800 AND has_link = true"
+        );
+        assert_eq!(SearchQuery::new().text("  ").filter(), None);
+
+        let parsed = ParsedQuery::parse("is:file is:message report");
+        assert_eq!(parsed.text, "report");
+        assert_eq!(
+            SearchQuery::new().parsed(&parsed).filter().unwrap(),
+            "kind IN [media, message]"
+        );
+    }
+}
@@ -67,6 +67,7 @@ impl Default for MessageAppearance {
     }
 }
 
+/// One logged in account, pronto gives every organization its own user and token
 #[derive(Clone, Debug, Default, Serialize, Deserialize)]
 pub struct Auth {
     #[serde(default)]
@@ -75,6 +76,34 @@ pub struct Auth {
     pub saved_phone: Option<String>,
     pub api_key: String,
     pub base_url: String,
+    /// Missing for accounts saved before multiple organizations were supported
+    #[serde(default)]
+    pub user_id: Option<u64>,
+    #[serde(default)]
+    pub organization_id: Option<u64>,
+    #[serde(default)]
+    pub organization_name: Option<String>,
+}
+
+impl Auth {
+    /// A name for the files kept per account, like the local store
+    pub fn key(&self) -> String {
+        let host = self
+            .base_url
+            .split("://")
+            .last()
+            .and_then(|url| url.split(['/', ':']).next())
+            .unwrap_or_default();
+        match self.user_id {
+            Some(user_id) => format!("{host}-{user_id}"),
+            None => host.to_string(),
+        }
+    }
+
+    fn is_same_account(&self, other: &Auth) -> bool {
+        self.base_url == other.base_url
+            && (self.user_id.is_none() || other.user_id.is_none() || self.user_id == other.user_id)
+    }
 }
 
 #[derive(Clone, Debug, Default, Serialize, Deserialize)]
@@ -101,10 +130,27 @@ pub struct Options {
     pub read_messages: bool,
 }
 
-#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
 pub struct MessagesSearchIndex {
+    /// Directory holding the indexes, `~/.prontus/search` when empty
     pub path: String,
     pub max_size: u64,
+    /// Directory of an ONNX sentence embedding model with its tokenizer files,
+    /// messages are also searched by meaning when set
+    #[serde(default)]
+    pub embedding_model: Option<String>,
+}
+
+impl MessagesSearchIndex {
+    /// Where the index of an account is kept, each organization gets its own
+    pub fn index_path(&self, account: &Auth) -> PathBuf {
+        let dir = if self.path.is_empty() {
+            prontus_dir().join("search")
+        } else {
+            PathBuf::from(&self.path)
+        };
+        dir.join(account.key())
+    }
 }
 
 #[derive(Clone, Debug, Default, Serialize, Deserialize)]
@@ -113,6 +159,51 @@ pub struct Search {
     pub messages: Option<MessagesSearchIndex>,
 }
 
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Encryption {
+    /// Where public keys for encrypted DMs are looked up, an http(s) url or the path of a
+    /// json file. The public directory when unset, read on startup.
+    #[serde(default)]
+    pub key_directory: Option<String>,
+    /// Keep secret keys as files in the prontus directory instead of the system keyring.
+    /// Defaults to on for Linux, where without a secret service the keyring forgets them on
+    /// reboot. Files are also used whenever the keyring can't store anything. Read on startup.
+    #[serde(default)]
+    pub file_key_store: Option<bool>,
+}
+
+const fn default_media_cache_size() -> u64 {
+    512 * 1024 * 1024
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct MediaCache {
+    /// Defaults to `media` in the prontus directory
+    #[serde(default)]
+    pub path: Option<String>,
+    /// In bytes, least recently used media is evicted past this
+    #[serde(default = "default_media_cache_size")]
+    pub max_size: u64,
+}
+
+impl Default for MediaCache {
+    fn default() -> Self {
+        MediaCache {
+            path: None,
+            max_size: default_media_cache_size(),
+        }
+    }
+}
+
+impl MediaCache {
+    pub fn path(&self) -> PathBuf {
+        self.path
+            .as_ref()
+            .map(PathBuf::from)
+            .unwrap_or_else(|| prontus_dir().join("media"))
+    }
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Update {
     pub notify: bool,
@@ -132,8 +223,15 @@ impl Default for Update {
 
 #[derive(Clone, Debug, Default, Serialize, Deserialize)]
 pub struct Settings {
+    /// Every account that is logged in
+    #[serde(default)]
+    pub accounts: Vec<Auth>,
+    /// Index into `accounts` of the one shown in the ui
     #[serde(default)]
-    pub auth: Option<Auth>,
+    pub current_account: usize,
+    /// The single account of older settings files, moved into `accounts` on load
+    #[serde(default, skip_serializing)]
+    auth: Option<Auth>,
     #[serde(default)]
     pub appearance: Appearance,
     #[serde(default)]
@@ -141,7 +239,11 @@ pub struct Settings {
     #[serde(default)]
     pub search: Search,
     #[serde(default)]
+    pub encryption: Encryption,
+    #[serde(default)]
     pub update: Update,
+    #[serde(default)]
+    pub media_cache: MediaCache,
 }
 
 static MIGRATIONS_PERFORMED: AtomicBool = AtomicBool::new(false);
@@ -187,18 +289,54 @@ impl Settings {
         if path.exists() {
             // TODO: switch to OpenOptions
             let mut data = tokio::fs::read_to_string(&path).await?;
-            unsafe {
-                Ok(simd_json::from_str(&mut data)
+            let mut settings: Self = unsafe {
+                simd_json::from_str(&mut data)
                     .inspect_err(|e| {
                         error!("Error parsing settings: {:?}", e);
                     })
-                    .unwrap_or_default())
+                    .unwrap_or_default()
+            };
+            if let Some(auth) = settings.auth.take() {
+                info!("Moving the saved login into the account list");
+                settings.add_account(auth);
             }
+            Ok(settings)
         } else {
             Ok(Self::default())
         }
     }
 
+    /// The account shown in the ui
+    pub fn auth(&self) -> Option<&Auth> {
+        self.accounts
+            .get(self.current_account)
+            .or(self.accounts.first())
+    }
+
+    /// Add an account or update the saved one, returning its index
+    pub fn add_account(&mut self, auth: Auth) -> usize {
+        match self
+            .accounts
+            .iter()
+            .position(|account| account.is_same_account(&auth))
+        {
+            Some(index) => {
+                self.accounts[index] = auth;
+                index
+            }
+            None => {
+                self.accounts.push(auth);
+                self.accounts.len() - 1
+            }
+        }
+    }
+
+    /// Log out of every account
+    pub fn clear_accounts(&mut self) {
+        self.accounts.clear();
+        self.current_account = 0;
+    }
+
     pub async fn save(&self) -> Result<()> {
         debug!("Saving settings");
         let path = Self::path();
@@ -224,10 +362,38 @@ pub fn prontus_dir() -> PathBuf {
 
 #[cfg(test)]
 mod tests {
-    use crate::Settings;
+    use crate::{Auth, Settings};
 
     #[tokio::test]
     async fn load() {
         Settings::load().await.unwrap();
     }
+
+    #[test]
+    fn accounts() {
+        let mut settings = Settings::default();
+        let first = settings.add_account(Auth {
+            api_key: "a".to_string(),
+            base_url: "https://stanfordohs.pronto.io/api/".to_string(),
+            ..Default::default()
+        });
+        // The same account once its user is known
+        let same = settings.add_account(Auth {
+            api_key: "b".to_string(),
+            base_url: "https://stanfordohs.pronto.io/api/".to_string(),
+            user_id: Some(1),
+            ..Default::default()
+        });
+        let other = settings.add_account(Auth {
+            api_key: "c".to_string(),
+            base_url: "https://other.pronto.io/api/".to_string(),
+            user_id: Some(2),
+            ..Default::default()
+        });
+        assert_eq!((first, same, other), (0, 0, 1));
+        assert_eq!(settings.auth().unwrap().api_key, "b");
+        assert_eq!(settings.accounts[0].key(), "stanfordohs.pronto.io-1");
+        settings.current_account = 1;
+        assert_eq!(settings.auth().unwrap().key(), "other.pronto.io-2");
+    }
 }
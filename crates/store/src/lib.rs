@@ -0,0 +1,665 @@
+//! Local copy of the account so the app starts instantly and keeps working offline.
+//!
+//! Models are kept as the json pronto sends with just enough columns to query them.
+//! Everything here can be refetched, so a schema change simply drops the old tables.
+//! The [outbox](OutboxEntry) is the exception and survives schema changes.
+
+use client::{Announcement, Bubble, BubbleStats, Membership, Message, Task, UserInfo};
+use rusqlite::{Connection, OptionalExtension, Transaction, params};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use std::path::Path;
+use std::sync::Mutex;
+use thiserror::Error;
+
+mod outbox;
+
+pub use outbox::{OutboxEntry, OutboxOp};
+
+/// Bump when the tables change
+const SCHEMA_VERSION: i32 = 1;
+
+const SCHEMA: &str = "
+    CREATE TABLE meta (key TEXT PRIMARY KEY, value TEXT NOT NULL);
+    CREATE TABLE users (id INTEGER PRIMARY KEY, user TEXT NOT NULL);
+    CREATE TABLE bubbles (
+        id INTEGER PRIMARY KEY,
+        position INTEGER NOT NULL,
+        bubble TEXT NOT NULL,
+        stats TEXT,
+        membership TEXT
+    );
+    CREATE TABLE messages (id INTEGER PRIMARY KEY, bubble_id INTEGER NOT NULL, message TEXT NOT NULL);
+    CREATE INDEX messages_bubble ON messages (bubble_id, id);
+    CREATE TABLE tasks (id INTEGER PRIMARY KEY, position INTEGER NOT NULL, task TEXT NOT NULL);
+    CREATE TABLE announcements (
+        id INTEGER PRIMARY KEY,
+        position INTEGER NOT NULL,
+        announcement TEXT NOT NULL
+    );
+";
+
+/// Not dropped with the rest, pending changes can't be refetched
+const OUTBOX_SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS outbox (
+        seq INTEGER PRIMARY KEY AUTOINCREMENT,
+        uuid TEXT NOT NULL UNIQUE,
+        op TEXT NOT NULL,
+        attempted INTEGER NOT NULL
+    );
+";
+
+const TABLES: [&str; 6] = [
+    "meta",
+    "users",
+    "bubbles",
+    "messages",
+    "tasks",
+    "announcements",
+];
+
+#[derive(Debug, Error)]
+pub enum StoreError {
+    #[error("SQLite error: {0}")]
+    Sqlite(#[from] rusqlite::Error),
+    #[error("JSON error: {0}")]
+    Json(#[from] serde_json::Error),
+}
+
+pub type Result<T> = std::result::Result<T, StoreError>;
+
+/// A bubble as shown in the channel list
+pub type Channel = (Bubble, Option<BubbleStats>, Option<Membership>);
+
+pub struct Store {
+    conn: Mutex<Connection>,
+}
+
+impl Store {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        Self::init(Connection::open(path)?)
+    }
+
+    pub fn open_in_memory() -> Result<Self> {
+        Self::init(Connection::open_in_memory()?)
+    }
+
+    fn init(conn: Connection) -> Result<Self> {
+        conn.pragma_update_and_check(None, "journal_mode", "WAL", |_| Ok(()))?;
+        let version: i32 = conn.pragma_query_value(None, "user_version", |row| row.get(0))?;
+        if version != SCHEMA_VERSION {
+            for table in TABLES {
+                conn.execute_batch(&format!("DROP TABLE IF EXISTS {table}"))?;
+            }
+            conn.execute_batch(SCHEMA)?;
+            conn.pragma_update(None, "user_version", SCHEMA_VERSION)?;
+        }
+        conn.execute_batch(OUTBOX_SCHEMA)?;
+        Ok(Self {
+            conn: Mutex::new(conn),
+        })
+    }
+
+    fn read<T>(&self, f: impl FnOnce(&Connection) -> Result<T>) -> Result<T> {
+        f(&self.conn.lock().unwrap())
+    }
+
+    fn write<T>(&self, f: impl FnOnce(&Transaction) -> Result<T>) -> Result<T> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        let result = f(&tx)?;
+        tx.commit()?;
+        Ok(result)
+    }
+
+    /// Forget everything
+    pub fn clear(&self) -> Result<()> {
+        self.write(clear)
+    }
+
+    /// The account the store belongs to
+    pub fn current_user(&self) -> Result<Option<UserInfo>> {
+        self.read(|conn| {
+            conn.query_row(
+                "SELECT value FROM meta WHERE key = 'current_user'",
+                [],
+                |row| row.get::<_, String>(0),
+            )
+            .optional()?
+            .map(|json| from_json(&json))
+            .transpose()
+        })
+    }
+
+    /// Switching to another account drops everything cached for the previous one.
+    pub fn set_current_user(&self, user: &UserInfo) -> Result<()> {
+        let previous = self.current_user()?;
+        self.write(|tx| {
+            if previous.is_some_and(|previous| previous.id != user.id) {
+                clear(tx)?;
+            }
+            tx.execute(
+                "INSERT OR REPLACE INTO meta (key, value) VALUES ('current_user', ?1)",
+                [to_json(user)?],
+            )?;
+            upsert_user(tx, user)
+        })
+    }
+
+    pub fn users(&self) -> Result<Vec<UserInfo>> {
+        self.read(|conn| query(conn, "SELECT user FROM users", []))
+    }
+
+    pub fn upsert_users<'a>(&self, users: impl IntoIterator<Item = &'a UserInfo>) -> Result<()> {
+        self.write(|tx| {
+            for user in users {
+                upsert_user(tx, user)?;
+            }
+            Ok(())
+        })
+    }
+
+    /// The channel list in the order it was stored
+    pub fn channels(&self) -> Result<Vec<Channel>> {
+        self.read(|conn| {
+            let mut statement =
+                conn.prepare("SELECT bubble, stats, membership FROM bubbles ORDER BY position")?;
+            let rows = statement.query_map([], |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, Option<String>>(1)?,
+                    row.get::<_, Option<String>>(2)?,
+                ))
+            })?;
+            let mut channels = Vec::new();
+            for row in rows {
+                let (bubble, stats, membership) = row?;
+                channels.push((
+                    from_json(&bubble)?,
+                    stats.as_deref().map(from_json).transpose()?,
+                    membership.as_deref().map(from_json).transpose()?,
+                ));
+            }
+            Ok(channels)
+        })
+    }
+
+    /// Replace the channel list with a fresh one from the server.
+    /// Messages of bubbles that are gone are dropped too.
+    pub fn replace_channels(&self, channels: &[Channel]) -> Result<()> {
+        self.write(|tx| {
+            tx.execute("DELETE FROM bubbles", [])?;
+            for (position, (bubble, stats, membership)) in channels.iter().enumerate() {
+                tx.execute(
+                    "INSERT INTO bubbles (id, position, bubble, stats, membership)
+                     VALUES (?1, ?2, ?3, ?4, ?5)",
+                    params![
+                        bubble.id,
+                        position,
+                        to_json(bubble)?,
+                        stats.as_ref().map(to_json).transpose()?,
+                        membership.as_ref().map(to_json).transpose()?,
+                    ],
+                )?;
+            }
+            tx.execute(
+                "DELETE FROM messages WHERE bubble_id NOT IN (SELECT id FROM bubbles)",
+                [],
+            )?;
+            Ok(())
+        })
+    }
+
+    /// Update a bubble, new bubbles go to the end of the list.
+    pub fn upsert_bubble(&self, bubble: &Bubble) -> Result<()> {
+        self.write(|tx| {
+            tx.execute(
+                "INSERT INTO bubbles (id, position, bubble)
+                 VALUES (?1, (SELECT COALESCE(MAX(position), -1) + 1 FROM bubbles), ?2)
+                 ON CONFLICT (id) DO UPDATE SET bubble = excluded.bubble",
+                params![bubble.id, to_json(bubble)?],
+            )?;
+            Ok(())
+        })
+    }
+
+    pub fn update_stats(&self, stats: &BubbleStats) -> Result<()> {
+        self.write(|tx| {
+            tx.execute(
+                "UPDATE bubbles SET stats = ?2 WHERE id = ?1",
+                params![stats.bubble_id, to_json(stats)?],
+            )?;
+            Ok(())
+        })
+    }
+
+    pub fn update_membership(&self, membership: &Membership) -> Result<()> {
+        self.write(|tx| {
+            tx.execute(
+                "UPDATE bubbles SET membership = ?2 WHERE id = ?1",
+                params![membership.bubble_id, to_json(membership)?],
+            )?;
+            Ok(())
+        })
+    }
+
+    pub fn remove_bubble(&self, bubble_id: u64) -> Result<()> {
+        self.write(|tx| {
+            tx.execute("DELETE FROM bubbles WHERE id = ?1", [bubble_id])?;
+            tx.execute("DELETE FROM messages WHERE bubble_id = ?1", [bubble_id])?;
+            Ok(())
+        })
+    }
+
+    /// Up to `limit` messages of a bubble older than `before`, newest first like `bubble.history`.
+    pub fn messages(
+        &self,
+        bubble_id: u64,
+        before: Option<u64>,
+        limit: usize,
+    ) -> Result<Vec<Message>> {
+        self.read(|conn| {
+            query(
+                conn,
+                "SELECT message FROM messages WHERE bubble_id = ?1 AND id < ?2
+                 ORDER BY id DESC LIMIT ?3",
+                params![bubble_id, before.unwrap_or(i64::MAX as u64), limit],
+            )
+        })
+    }
+
+    pub fn message(&self, message_id: u64) -> Result<Option<Message>> {
+        self.read(|conn| {
+            conn.query_row(
+                "SELECT message FROM messages WHERE id = ?1",
+                [message_id],
+                |row| row.get::<_, String>(0),
+            )
+            .optional()?
+            .map(|json| from_json(&json))
+            .transpose()
+        })
+    }
+
+    /// The stored parents of replies whose parent isn't in `messages`,
+    /// like `parent_messages` in a `bubble.history` response.
+    pub fn parents(&self, messages: &[Message]) -> Result<Vec<Message>> {
+        let mut parents: Vec<Message> = Vec::new();
+        for parent_id in messages.iter().filter_map(|m| m.parent_message_id) {
+            if messages.iter().chain(&parents).any(|m| m.id == parent_id) {
+                continue;
+            }
+            if let Some(parent) = self.message(parent_id)? {
+                parents.push(parent);
+            }
+        }
+        Ok(parents)
+    }
+
+    pub fn upsert_messages<'a>(
+        &self,
+        messages: impl IntoIterator<Item = &'a Message>,
+    ) -> Result<()> {
+        self.write(|tx| {
+            for message in messages {
+                upsert_message(tx, message)?;
+            }
+            Ok(())
+        })
+    }
+
+    /// Reconcile with a page of `bubble.history` fetched with the same `before`.
+    /// The page is contiguous, so stored messages in its range that are missing from it were deleted.
+    pub fn sync_history(
+        &self,
+        bubble_id: u64,
+        before: Option<u64>,
+        messages: &[Message],
+    ) -> Result<()> {
+        let oldest = messages.iter().map(|message| message.id).min().unwrap_or(0);
+        self.write(|tx| {
+            tx.execute(
+                "DELETE FROM messages WHERE bubble_id = ?1 AND id >= ?2 AND id < ?3",
+                params![bubble_id, oldest, before.unwrap_or(i64::MAX as u64)],
+            )?;
+            for message in messages {
+                upsert_message(tx, message)?;
+            }
+            Ok(())
+        })
+    }
+
+    /// Change a stored message in place, returning it if it was stored.
+    pub fn update_message(
+        &self,
+        message_id: u64,
+        f: impl FnOnce(&mut Message),
+    ) -> Result<Option<Message>> {
+        self.write(|tx| {
+            let Some(json) = tx
+                .query_row(
+                    "SELECT message FROM messages WHERE id = ?1",
+                    [message_id],
+                    |row| row.get::<_, String>(0),
+                )
+                .optional()?
+            else {
+                return Ok(None);
+            };
+            let mut message = from_json(&json)?;
+            f(&mut message);
+            upsert_message(tx, &message)?;
+            Ok(Some(message))
+        })
+    }
+
+    pub fn remove_message(&self, message_id: u64) -> Result<()> {
+        self.write(|tx| {
+            tx.execute("DELETE FROM messages WHERE id = ?1", [message_id])?;
+            Ok(())
+        })
+    }
+
+    pub fn tasks(&self) -> Result<Vec<Task>> {
+        self.read(|conn| query(conn, "SELECT task FROM tasks ORDER BY position", []))
+    }
+
+    pub fn replace_tasks(&self, tasks: &[Task]) -> Result<()> {
+        self.write(|tx| {
+            replace_positioned(
+                tx,
+                "tasks",
+                "task",
+                tasks.iter().map(|task| (task.id, task)),
+            )
+        })
+    }
+
+    /// New tasks go to the end of the list
+    pub fn upsert_task(&self, task: &Task) -> Result<()> {
+        self.write(|tx| upsert_positioned(tx, "tasks", "task", task.id, task, false))
+    }
+
+    pub fn remove_task(&self, task_id: u64) -> Result<()> {
+        self.write(|tx| {
+            tx.execute("DELETE FROM tasks WHERE id = ?1", [task_id])?;
+            Ok(())
+        })
+    }
+
+    pub fn announcements(&self) -> Result<Vec<Announcement>> {
+        self.read(|conn| {
+            query(
+                conn,
+                "SELECT announcement FROM announcements ORDER BY position",
+                [],
+            )
+        })
+    }
+
+    pub fn replace_announcements(&self, announcements: &[Announcement]) -> Result<()> {
+        self.write(|tx| {
+            replace_positioned(
+                tx,
+                "announcements",
+                "announcement",
+                announcements
+                    .iter()
+                    .map(|announcement| (announcement.id, announcement)),
+            )
+        })
+    }
+
+    /// New announcements go to the top of the list
+    pub fn upsert_announcement(&self, announcement: &Announcement) -> Result<()> {
+        self.write(|tx| {
+            upsert_positioned(
+                tx,
+                "announcements",
+                "announcement",
+                announcement.id,
+                announcement,
+                true,
+            )
+        })
+    }
+
+    pub fn remove_announcement(&self, announcement_id: u64) -> Result<()> {
+        self.write(|tx| {
+            tx.execute("DELETE FROM announcements WHERE id = ?1", [announcement_id])?;
+            Ok(())
+        })
+    }
+}
+
+fn to_json<T: Serialize>(value: &T) -> Result<String> {
+    Ok(serde_json::to_string(value)?)
+}
+
+fn from_json<T: DeserializeOwned>(json: &str) -> Result<T> {
+    Ok(serde_json::from_str(json)?)
+}
+
+/// Run a query selecting a single json column
+fn query<T: DeserializeOwned>(
+    conn: &Connection,
+    sql: &str,
+    params: impl rusqlite::Params,
+) -> Result<Vec<T>> {
+    let mut statement = conn.prepare(sql)?;
+    let rows = statement.query_map(params, |row| row.get::<_, String>(0))?;
+    let mut values = Vec::new();
+    for row in rows {
+        values.push(from_json(&row?)?);
+    }
+    Ok(values)
+}
+
+fn clear(tx: &Transaction) -> Result<()> {
+    for table in TABLES {
+        tx.execute(&format!("DELETE FROM {table}"), [])?;
+    }
+    tx.execute("DELETE FROM outbox", [])?;
+    Ok(())
+}
+
+fn upsert_user(tx: &Transaction, user: &UserInfo) -> Result<()> {
+    tx.execute(
+        "INSERT OR REPLACE INTO users (id, user) VALUES (?1, ?2)",
+        params![user.id, to_json(user)?],
+    )?;
+    Ok(())
+}
+
+/// Messages carry their author, which is worth keeping too
+fn upsert_message(tx: &Transaction, message: &Message) -> Result<()> {
+    tx.execute(
+        "INSERT OR REPLACE INTO messages (id, bubble_id, message) VALUES (?1, ?2, ?3)",
+        params![message.id, message.bubble_id, to_json(message)?],
+    )?;
+    upsert_user(tx, &message.user)
+}
+
+fn replace_positioned<'a, T: Serialize + 'a>(
+    tx: &Transaction,
+    table: &str,
+    column: &str,
+    values: impl Iterator<Item = (u64, &'a T)>,
+) -> Result<()> {
+    tx.execute(&format!("DELETE FROM {table}"), [])?;
+    for (position, (id, value)) in values.enumerate() {
+        tx.execute(
+            &format!("INSERT INTO {table} (id, position, {column}) VALUES (?1, ?2, ?3)"),
+            params![id, position, to_json(value)?],
+        )?;
+    }
+    Ok(())
+}
+
+fn upsert_positioned<T: Serialize>(
+    tx: &Transaction,
+    table: &str,
+    column: &str,
+    id: u64,
+    value: &T,
+    front: bool,
+) -> Result<()> {
+    let position = if front {
+        "COALESCE(MIN(position), 1) - 1"
+    } else {
+        "COALESCE(MAX(position), -1) + 1"
+    };
+    tx.execute(
+        &format!(
+            "INSERT INTO {table} (id, position, {column})
+             VALUES (?1, (SELECT {position} FROM {table}), ?2)
+             ON CONFLICT (id) DO UPDATE SET {column} = excluded.{column}"
+        ),
+        params![id, to_json(value)?],
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mock_server::MockServer;
+
+    fn channels(response: client::bubble_list::GetBubbleListResponse) -> Vec<Channel> {
+        response
+            .bubbles
+            .iter()
+            .map(|bubble| {
+                let stats = response.stats.iter().find(|s| s.bubble_id == bubble.id);
+                let membership = response
+                    .memberships
+                    .iter()
+                    .find(|m| m.bubble_id == bubble.id);
+                (bubble.clone(), stats.cloned(), membership.cloned())
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_sync() {
+        let server = MockServer::start().await.unwrap();
+        let (alice, bob, bubble, first, second, third) = {
+            let mut store = server.store().await;
+            let alice = store.add_user("Alice", "A");
+            let bob = store.add_user("Bob", "B");
+            let bubble = store.add_bubble("General", alice.id, &[alice.id, bob.id]);
+            let first = store.add_message(bubble.id, alice.id, "first", None);
+            let second = store.add_message(bubble.id, bob.id, "second", None);
+            let third = store.add_message(bubble.id, alice.id, "third", None);
+            (alice, bob, bubble, first, second, third)
+        };
+        let client = server.client(alice.id);
+        let local = Store::open_in_memory().unwrap();
+
+        local.set_current_user(&alice).unwrap();
+        local
+            .replace_channels(&channels(client.bubble_list().await.unwrap()))
+            .unwrap();
+        let history = client.bubble_history(bubble.id, None).await.unwrap();
+        local
+            .sync_history(bubble.id, None, &history.messages)
+            .unwrap();
+
+        // Updating the bubble keeps the membership that came with the list
+        local.upsert_bubble(&bubble).unwrap();
+        let stored = local.channels().unwrap();
+        assert_eq!(stored.len(), 1);
+        assert_eq!(stored[0].0.id, bubble.id);
+        assert!(stored[0].2.is_some());
+        let ids = |messages: Vec<Message>| messages.iter().map(|m| m.id).collect::<Vec<_>>();
+        assert_eq!(
+            ids(local.messages(bubble.id, None, 10).unwrap()),
+            [third.id, second.id, first.id]
+        );
+        assert_eq!(
+            ids(local.messages(bubble.id, Some(third.id), 1).unwrap()),
+            [second.id]
+        );
+        let mut users = local.users().unwrap();
+        users.sort_by_key(|user| user.id);
+        assert_eq!(users.len(), 2);
+        assert_eq!(users[1].id, bob.id);
+
+        // Deleted on the server while we weren't looking
+        server.store().await.delete_message(second.id);
+        let history = client.bubble_history(bubble.id, None).await.unwrap();
+        local
+            .sync_history(bubble.id, None, &history.messages)
+            .unwrap();
+        assert_eq!(
+            ids(local.messages(bubble.id, None, 10).unwrap()),
+            [third.id, first.id]
+        );
+
+        let reply = server
+            .store()
+            .await
+            .add_message(bubble.id, bob.id, "reply", Some(first.id));
+        let history = client.bubble_history(bubble.id, None).await.unwrap();
+        local
+            .sync_history(bubble.id, None, &history.messages)
+            .unwrap();
+        let latest = local.messages(bubble.id, None, 1).unwrap();
+        assert_eq!(ids(latest.clone()), [reply.id]);
+        assert_eq!(ids(local.parents(&latest).unwrap()), [first.id]);
+
+        let edited = local
+            .update_message(first.id, |message| message.message = "edited".to_string())
+            .unwrap()
+            .unwrap();
+        assert_eq!(edited.message, "edited");
+        assert_eq!(local.message(first.id).unwrap().unwrap().message, "edited");
+
+        local.replace_channels(&[]).unwrap();
+        assert!(local.messages(bubble.id, None, 10).unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_account_switch() {
+        let server = MockServer::start().await.unwrap();
+        let (alice, bob) = {
+            let mut store = server.store().await;
+            let alice = store.add_user("Alice", "A");
+            let bob = store.add_user("Bob", "B");
+            store.add_bubble("General", alice.id, &[alice.id, bob.id]);
+            store.add_task(alice.id, alice.id, "Write tests", "", "2025-01-01 00:00:00");
+            (alice, bob)
+        };
+        let client = server.client(alice.id);
+        let local = Store::open_in_memory().unwrap();
+
+        local.set_current_user(&alice).unwrap();
+        local
+            .replace_channels(&channels(client.bubble_list().await.unwrap()))
+            .unwrap();
+        let tasks = client
+            .task_list(alice.organizations[0].id, false)
+            .await
+            .unwrap()
+            .tasks;
+        local.replace_tasks(&tasks).unwrap();
+        assert_eq!(local.tasks().unwrap().len(), 1);
+
+        // Same user again keeps the data
+        local.set_current_user(&alice).unwrap();
+        assert_eq!(local.channels().unwrap().len(), 1);
+
+        local
+            .push_outbox(&OutboxEntry {
+                uuid: "pending".to_string(),
+                op: OutboxOp::Delete { message_id: 1 },
+                attempted: false,
+            })
+            .unwrap();
+        local.set_current_user(&bob).unwrap();
+        assert_eq!(local.current_user().unwrap().unwrap().id, bob.id);
+        assert!(local.channels().unwrap().is_empty());
+        assert!(local.tasks().unwrap().is_empty());
+        assert!(local.outbox().unwrap().is_empty());
+    }
+}
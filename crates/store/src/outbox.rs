@@ -0,0 +1,135 @@
+//! Changes made while the server couldn't be reached, replayed in order once it can.
+
+use crate::{Result, Store, from_json, to_json};
+use rusqlite::params;
+use serde::{Deserialize, Serialize};
+
+/// Something the user did that still has to reach the server
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(
+    tag = "kind",
+    rename_all = "camelCase",
+    rename_all_fields = "camelCase"
+)]
+pub enum OutboxOp {
+    Send {
+        bubble_id: u64,
+        user_id: u64,
+        message: String,
+        parent_message_id: Option<u64>,
+    },
+    Edit {
+        message_id: u64,
+        message: String,
+    },
+    Delete {
+        message_id: u64,
+    },
+    Reaction {
+        message_id: u64,
+        reaction_id: u64,
+        active: bool,
+    },
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OutboxEntry {
+    /// Identifies the entry, sends create their message with it
+    pub uuid: String,
+    pub op: OutboxOp,
+    /// A previous try may have reached the server before failing
+    pub attempted: bool,
+}
+
+impl Store {
+    /// Pending changes, oldest first
+    pub fn outbox(&self) -> Result<Vec<OutboxEntry>> {
+        self.read(|conn| {
+            let mut statement =
+                conn.prepare("SELECT uuid, op, attempted FROM outbox ORDER BY seq")?;
+            let rows = statement.query_map([], |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, bool>(2)?,
+                ))
+            })?;
+            let mut entries = Vec::new();
+            for row in rows {
+                let (uuid, op, attempted) = row?;
+                entries.push(OutboxEntry {
+                    uuid,
+                    op: from_json(&op)?,
+                    attempted,
+                });
+            }
+            Ok(entries)
+        })
+    }
+
+    pub fn push_outbox(&self, entry: &OutboxEntry) -> Result<()> {
+        self.write(|tx| {
+            tx.execute(
+                "INSERT INTO outbox (uuid, op, attempted) VALUES (?1, ?2, ?3)",
+                params![entry.uuid, to_json(&entry.op)?, entry.attempted],
+            )?;
+            Ok(())
+        })
+    }
+
+    pub fn mark_outbox_attempted(&self, uuid: &str) -> Result<()> {
+        self.write(|tx| {
+            tx.execute("UPDATE outbox SET attempted = 1 WHERE uuid = ?1", [uuid])?;
+            Ok(())
+        })
+    }
+
+    pub fn remove_outbox(&self, uuid: &str) -> Result<()> {
+        self.write(|tx| {
+            tx.execute("DELETE FROM outbox WHERE uuid = ?1", [uuid])?;
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn send(uuid: &str, message: &str) -> OutboxEntry {
+        OutboxEntry {
+            uuid: uuid.to_string(),
+            op: OutboxOp::Send {
+                bubble_id: 1,
+                user_id: 2,
+                message: message.to_string(),
+                parent_message_id: None,
+            },
+            attempted: false,
+        }
+    }
+
+    #[test]
+    fn test_outbox_order() {
+        let store = Store::open_in_memory().unwrap();
+        store.push_outbox(&send("b", "first")).unwrap();
+        store
+            .push_outbox(&OutboxEntry {
+                uuid: "a".to_string(),
+                op: OutboxOp::Delete { message_id: 3 },
+                attempted: false,
+            })
+            .unwrap();
+        store.push_outbox(&send("c", "third")).unwrap();
+        store.mark_outbox_attempted("b").unwrap();
+        store.remove_outbox("a").unwrap();
+
+        let outbox = store.outbox().unwrap();
+        let uuids: Vec<_> = outbox.iter().map(|entry| entry.uuid.as_str()).collect();
+        assert_eq!(uuids, ["b", "c"]);
+        assert!(outbox[0].attempted);
+        assert!(!outbox[1].attempted);
+        assert!(matches!(&outbox[1].op, OutboxOp::Send { message, .. } if message == "third"));
+    }
+}
@@ -1,6 +1,6 @@
 use client::ProntoClient;
 use client::user_login::{DeviceInfo, UserLoginRequest};
-use log::info;
+use log::{info, warn};
 use settings::Settings;
 use tauri::command;
 use ui_lib::BackendError;
@@ -34,34 +34,47 @@ pub async fn send_code(email: String, code: String) -> Result<(), BackendError>
     .unwrap()
     .to_result()
     .unwrap();
-    let token = &response.users[0].login_token;
-    // "https://stanfordohs.pronto.io/api/"
-    let base_url = format!(
-        "https://{}.pronto.io/api/",
-        response.users[0].user.organizations[0].shortname
-    );
+    // Every organization the email belongs to comes back as its own user
     let mut settings = Settings::load().await?;
-    let auth = settings::Auth {
-        base_url: base_url.clone(),
-        api_key: token.clone(),
-        saved_email: None,
-        saved_phone: None,
+    let mut first = None;
+    let mut error = None;
+    for user in &response.users {
+        let Some(organization) = user.user.organizations.first() else {
+            continue;
+        };
+        // "https://stanfordohs.pronto.io/api/"
+        let base_url = format!("https://{}.pronto.io/api/", organization.shortname);
+        let client = ProntoClient::new(base_url.clone(), &user.login_token).unwrap();
+        // TODO: Standardize device info
+        // One organization failing shouldn't keep the others from logging in
+        let login = match client.user_token_login(&user.login_token).await {
+            Ok(login) => login,
+            Err(e) => {
+                warn!("Failed to log in to {}: {e}", organization.name);
+                error.get_or_insert(e);
+                continue;
+            }
+        };
+        let Some(login_user) = login.users.first() else {
+            warn!("Logging in to {} returned no user", organization.name);
+            continue;
+        };
+        let index = settings.add_account(settings::Auth {
+            base_url,
+            api_key: login_user.access_token.clone(),
+            saved_email: None,
+            saved_phone: None,
+            user_id: Some(user.user.id),
+            organization_id: Some(organization.id),
+            organization_name: Some(organization.name.clone()),
+        });
+        first.get_or_insert(index);
+        info!("Logged in to {}", organization.name);
+    }
+    let Some(first) = first else {
+        return Err(error.map_or(BackendError::NotAuthenticated, Into::into));
     };
-    settings.auth = Some(auth);
-    settings.save().await?;
-
-    let client = ProntoClient::new(base_url.clone(), token).unwrap();
-    // TODO: Standardize device info
-    let response = client.user_token_login(token).await?;
-
-    info!("Login successful");
-
-    let mut settings = Settings::load().await?;
-    settings
-        .auth
-        .as_mut()
-        .ok_or(BackendError::NotAuthenticated)?
-        .api_key = response.users[0].access_token.clone();
+    settings.current_account = first;
     settings.save().await?;
     Ok(())
 }
@@ -129,6 +129,7 @@ pub async fn set_channel_mute(
     };
 
     let state = state.try_inner()?;
+    state.persist(|store| store.update_membership(&membership.membership));
     let mut channel_list = state
         .channel_list
         .write()
@@ -154,6 +155,7 @@ pub async fn set_channel_pin(
     };
 
     let state = state.try_inner()?;
+    state.persist(|store| store.update_membership(&membership.membership));
     let current_channel_id = state
         .current_channel
         .read()
@@ -183,6 +185,7 @@ pub async fn set_channel_alias(
     };
 
     let state = state.try_inner()?;
+    state.persist(|store| store.update_membership(&membership.membership));
     let current_channel_id = state
         .current_channel
         .read()
@@ -223,6 +226,7 @@ pub async fn set_channel_notifications(
     };
 
     let state = state.try_inner()?;
+    state.persist(|store| store.update_membership(&membership.membership));
     let current_channel_id = state
         .current_channel
         .read()
@@ -318,6 +322,7 @@ pub async fn delete_channel(
     let state = state.try_inner()?;
 
     state.client.delete_bubble(channel_id).await?;
+    state.persist(|store| store.remove_bubble(channel_id));
     // TODO: update bubble
     Ok(())
 }
@@ -0,0 +1,506 @@
+//! Keys for encrypted DMs and bubbles: this device's key pair and the public keys of others.
+//!
+//! Bubbles are encrypted with a key of their own, handed out in the bubble to every member
+//! with a published public key. Messages are encrypted on the way out of the outbox and
+//! decrypted where they come in, the rest of the app only ever sees plain text.
+
+use client::{Message, PostBubbleMembershipSearchRequest};
+use encrypt::{
+    BackupError, BubbleKey, BubbleKeys, CurrentKey, DecryptionError, DeviceLink, Encrypt, Envelope,
+    KeyDirectory, KeyError, KeyHandout, TrustLevel,
+};
+use log::warn;
+use serde::Serialize;
+use store::OutboxOp;
+use tauri::{State, command};
+use ui_lib::{AppData, AppState, BackendError};
+
+/// This device's entry for the public key directory of the current organization.
+/// The key pair is generated and kept in the system keyring on first use,
+/// others can send encrypted DMs once the entry is published.
+#[command]
+pub async fn export_public_key(state: State<'_, AppState>) -> Result<KeyDirectory, BackendError> {
+    let state = state.try_inner()?;
+    let organization_id = organization_id(state)?;
+    let user_id = state.user_info.id;
+
+    // The keyring may ask the user to unlock it
+    Ok(
+        tokio::task::spawn_blocking(move || encrypt::export_public_key(organization_id, user_id))
+            .await
+            .map_err(std::io::Error::other)??,
+    )
+}
+
+/// Whether `user_id` published a key, so DMs with them can be encrypted
+#[command]
+pub async fn has_public_key(
+    state: State<'_, AppState>,
+    user_id: u64,
+) -> Result<bool, BackendError> {
+    let state = state.try_inner()?;
+
+    Ok(state
+        .key_lookup
+        .lookup(organization_id(state)?, user_id)
+        .await?
+        .is_some())
+}
+
+/// This device's secret key encrypted with `passphrase`, to restore it with [`import_key_backup`]
+#[command]
+pub async fn export_key_backup(passphrase: String) -> Result<String, BackendError> {
+    // Deriving the key takes a moment, and the keyring may ask the user to unlock it
+    Ok(
+        tokio::task::spawn_blocking(move || encrypt::export_backup(&passphrase))
+            .await
+            .map_err(std::io::Error::other)??,
+    )
+}
+
+/// Restore a backup made with [`export_key_backup`].
+/// A different key on this device is only replaced with `overwrite`, whatever was sent to it
+/// can't be read after.
+#[command]
+pub async fn import_key_backup(
+    backup: String,
+    passphrase: String,
+    overwrite: bool,
+) -> Result<(), BackendError> {
+    tokio::task::spawn_blocking(move || encrypt::import_backup(&backup, &passphrase, overwrite))
+        .await
+        .map_err(std::io::Error::other)??;
+    Ok(())
+}
+
+/// Start linking this device to one that has the secret key.
+/// The code is entered there with [`link_device`], which returns the payload for
+/// [`finish_device_link`].
+#[command]
+pub async fn start_device_link(state: State<'_, AppState>) -> Result<String, BackendError> {
+    let state = state.try_inner()?;
+    let link = DeviceLink::new();
+    let code = link.code();
+    *state
+        .device_link
+        .write()
+        .map_err(|_| BackendError::RwLockWriteError)? = Some(link);
+    Ok(code)
+}
+
+/// This device's secret key sealed to the device that showed `code`
+#[command]
+pub async fn link_device(code: String) -> Result<String, BackendError> {
+    // The keyring may ask the user to unlock it
+    Ok(
+        tokio::task::spawn_blocking(move || encrypt::link_payload(&code))
+            .await
+            .map_err(std::io::Error::other)??,
+    )
+}
+
+/// Take the secret key of the other device, if it's the one published for this account.
+/// A different key on this device is only replaced with `overwrite`, like
+/// [`import_key_backup`]. The link is used up unless that's what stopped it.
+#[command]
+pub async fn finish_device_link(
+    state: State<'_, AppState>,
+    payload: String,
+    overwrite: bool,
+) -> Result<(), BackendError> {
+    let state = state.try_inner()?;
+    let link = state
+        .device_link
+        .write()
+        .map_err(|_| BackendError::RwLockWriteError)?
+        .take()
+        .ok_or(BackupError::InvalidLinkPayload)?;
+    let user_id = state.user_info.id;
+    let published = state
+        .key_lookup
+        .lookup(organization_id(state)?, user_id)
+        .await?
+        .ok_or(KeyError::NotPublished(user_id))?;
+    let (link, result) = tokio::task::spawn_blocking(move || {
+        let result = link.finish(&payload, &published, overwrite);
+        (link, result)
+    })
+    .await
+    .map_err(std::io::Error::other)?;
+    if let Err(BackupError::KeyExists) = result {
+        *state
+            .device_link
+            .write()
+            .map_err(|_| BackendError::RwLockWriteError)? = Some(link);
+    }
+    result?;
+    Ok(())
+}
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct KeyTrust {
+    pub fingerprint: String,
+    /// The same for both sides only if each has the other's real key
+    pub safety_number: String,
+    pub level: TrustLevel,
+    /// The key before it changed, until the new one is verified
+    pub previous_fingerprint: Option<String>,
+}
+
+/// How far the published key of `user_id` can be trusted, `None` if they haven't published one.
+/// The first key seen for them is remembered, a different one later shows as changed.
+#[command]
+pub async fn get_key_trust(
+    state: State<'_, AppState>,
+    user_id: u64,
+) -> Result<Option<KeyTrust>, BackendError> {
+    let state = state.try_inner()?;
+    let organization_id = organization_id(state)?;
+    let Some(public_key) = state.key_lookup.lookup(organization_id, user_id).await? else {
+        return Ok(None);
+    };
+    let level = state
+        .trust_store
+        .check(organization_id, user_id, &public_key)
+        .map_err(KeyError::from)?;
+    // The keyring may ask the user to unlock it
+    let own_key = tokio::task::spawn_blocking(encrypt::public_key)
+        .await
+        .map_err(std::io::Error::other)??;
+
+    Ok(Some(KeyTrust {
+        fingerprint: encrypt::fingerprint(&public_key),
+        safety_number: encrypt::safety_number(
+            (state.user_info.id, &own_key),
+            (user_id, &public_key),
+        ),
+        level,
+        previous_fingerprint: state
+            .trust_store
+            .entry(organization_id, user_id)
+            .and_then(|entry| entry.previous),
+    }))
+}
+
+/// Mark the key of `user_id` verified, after comparing safety numbers with them, or take it back.
+/// `false` if their key isn't the one with `fingerprint` anymore, or a changed key is taken back.
+#[command]
+pub async fn verify_public_key(
+    state: State<'_, AppState>,
+    user_id: u64,
+    fingerprint: String,
+    verified: bool,
+) -> Result<bool, BackendError> {
+    let state = state.try_inner()?;
+    let organization_id = organization_id(state)?;
+    let trust_store = &state.trust_store;
+
+    Ok(if verified {
+        trust_store.verify(organization_id, user_id, &fingerprint)
+    } else {
+        trust_store.unverify(organization_id, user_id, &fingerprint)
+    }
+    .map_err(KeyError::from)?)
+}
+
+/// The organization public keys are published under, the one this account is logged in to
+fn organization_id(state: &AppData) -> Result<u64, BackendError> {
+    state
+        .account
+        .organization_id
+        .ok_or(BackendError::NotAuthenticated)
+}
+
+/// Run `f` on the bubble keys on a blocking thread,
+/// keys that aren't cached yet come from the keyring, which may ask the user to unlock it
+async fn with_bubble_keys<T, F>(state: &AppData, f: F) -> Result<T, BackendError>
+where
+    F: FnOnce(&BubbleKeys) -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let bubble_keys = state.bubble_keys.clone();
+    Ok(tokio::task::spawn_blocking(move || f(&bubble_keys))
+        .await
+        .map_err(std::io::Error::other)?)
+}
+
+/// Every member of `bubble_id`, a page at a time
+async fn bubble_members(state: &AppData, bubble_id: u64) -> Result<Vec<u64>, BackendError> {
+    let mut members = vec![];
+    for page in 1.. {
+        let response = state
+            .client
+            .bubble_membership(PostBubbleMembershipSearchRequest {
+                bubble_id,
+                page,
+                ..Default::default()
+            })
+            .await?;
+        let last = response.membership.len() < response.page_size as usize;
+        members.extend(response.membership.iter().map(|member| member.user_id));
+        if last || response.membership.is_empty() {
+            break;
+        }
+    }
+    members.sort_unstable();
+    members.dedup();
+    Ok(members)
+}
+
+/// Members a bubble key isn't handed out to
+#[derive(Clone, Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LeftOut {
+    /// They haven't published a public key
+    pub unpublished: Vec<u64>,
+    /// Their key changed since it was first seen, until it's verified again
+    pub changed: Vec<u64>,
+}
+
+/// Members of `bubble_id` with a published key that can be trusted, sorted by id,
+/// and those left out
+async fn readers(
+    state: &AppData,
+    bubble_id: u64,
+) -> Result<(Vec<(u64, Encrypt)>, LeftOut), BackendError> {
+    let organization_id = organization_id(state)?;
+    let mut readers = vec![];
+    let mut left_out = LeftOut::default();
+    for user_id in bubble_members(state, bubble_id).await? {
+        match Encrypt::new(
+            state.key_lookup.clone(),
+            &state.trust_store,
+            organization_id,
+            user_id,
+        )
+        .await?
+        {
+            Some(encrypt) if encrypt.trust == TrustLevel::Changed => left_out.changed.push(user_id),
+            Some(encrypt) => readers.push((user_id, encrypt)),
+            None => left_out.unpublished.push(user_id),
+        }
+    }
+    if !readers.iter().any(|(id, _)| *id == state.user_info.id) {
+        return Err(KeyError::NotPublished(state.user_info.id).into());
+    }
+    Ok((readers, left_out))
+}
+
+/// Post a new key for `readers`, everything sent to the bubble after is encrypted with it
+async fn hand_out_key(
+    state: &AppData,
+    bubble_id: u64,
+    readers: &[(u64, Encrypt)],
+) -> Result<(), BackendError> {
+    let key = BubbleKey::generate();
+    let handout = KeyHandout::new(
+        &key,
+        state.user_info.id,
+        readers.iter().map(|(user_id, encrypt)| (*user_id, encrypt)),
+    );
+    let response = state
+        .client
+        .send_message(state.user_info.id, bubble_id, handout.encode(), None)
+        .await?;
+    let current = CurrentKey {
+        key_id: handout.key_id,
+        sender_id: handout.sender_id,
+        message_id: response.message.id,
+        members: handout.members(),
+    };
+    with_bubble_keys(state, move |keys| keys.insert(bubble_id, key, current)).await??;
+    Ok(())
+}
+
+/// Encrypt everything sent to `bubble_id` from now on, or start over with a new key.
+/// Returns the members who can't read it, because they haven't published a public key
+/// or theirs changed and isn't verified yet.
+#[command]
+pub async fn encrypt_bubble(
+    state: State<'_, AppState>,
+    bubble_id: u64,
+) -> Result<LeftOut, BackendError> {
+    let state = state.try_inner()?;
+    let (readers, left_out) = readers(state, bubble_id).await?;
+    hand_out_key(state, bubble_id, &readers).await?;
+    Ok(left_out)
+}
+
+/// Whether messages sent to `bubble_id` are encrypted
+#[command]
+pub async fn is_bubble_encrypted(
+    state: State<'_, AppState>,
+    bubble_id: u64,
+) -> Result<bool, BackendError> {
+    let state = state.try_inner()?;
+
+    Ok(with_bubble_keys(state, move |keys| keys.current(bubble_id))
+        .await??
+        .is_some())
+}
+
+/// Hand out a new key when the members of an encrypted bubble changed, if it's up to us:
+/// the sender of the current key does it, or the first remaining member who had it
+/// when the sender left.
+pub async fn rekey_bubble(state: &AppData, bubble_id: u64) -> Result<(), BackendError> {
+    let Some(current) = with_bubble_keys(state, move |keys| keys.current(bubble_id)).await?? else {
+        return Ok(());
+    };
+    let (readers, _) = readers(state, bubble_id).await?;
+    let ids = readers.iter().map(|(id, _)| *id).collect::<Vec<_>>();
+    let mut handed_out = current.members.clone();
+    handed_out.sort_unstable();
+    if ids == handed_out {
+        return Ok(());
+    }
+    let rekeyer = if ids.contains(&current.sender_id) {
+        Some(current.sender_id)
+    } else {
+        ids.iter().copied().find(|id| handed_out.contains(id))
+    };
+    if rekeyer == Some(state.user_info.id) {
+        hand_out_key(state, bubble_id, &readers).await?;
+    }
+    Ok(())
+}
+
+/// Keep the key of a handout addressed to us
+async fn take_handout(state: &AppData, message: &Message) -> Result<(), BackendError> {
+    let handout = KeyHandout::decode(&message.message)?;
+    // Only the sender can seal the keys, but the claim has to match who posted it
+    if handout.sender_id != message.user_id {
+        return Err(DecryptionError::KeyMismatch.into());
+    }
+    if !handout.keys.contains_key(&state.user_info.id) {
+        return Ok(());
+    }
+    let key_id = handout.key_id;
+    let key = match with_bubble_keys(state, move |keys| keys.key(&key_id)).await?? {
+        Some(key) => BubbleKey::clone(&key),
+        None => {
+            let sender = Encrypt::new(
+                state.key_lookup.clone(),
+                &state.trust_store,
+                organization_id(state)?,
+                handout.sender_id,
+            )
+            .await?
+            .ok_or(KeyError::NotPublished(handout.sender_id))?;
+            handout.open(state.user_info.id, &sender)?
+        }
+    };
+    let bubble_id = message.bubble_id;
+    let current = CurrentKey {
+        key_id: handout.key_id,
+        sender_id: handout.sender_id,
+        message_id: message.id,
+        members: handout.members(),
+    };
+    with_bubble_keys(state, move |keys| keys.insert(bubble_id, key, current)).await??;
+    Ok(())
+}
+
+/// Keep the keys handed out among `messages` and decrypt the rest in place
+pub async fn receive_messages(state: &AppData, messages: &mut [Message]) {
+    // Oldest first, so the latest handout ends up as the current key
+    for message in messages.iter().rev() {
+        if KeyHandout::is_handout(&message.message)
+            && let Err(e) = take_handout(state, message).await
+        {
+            warn!(
+                "Failed to take the key handed out in message {}: {e}",
+                message.id
+            );
+        }
+    }
+    decrypt_messages(state, messages).await;
+}
+
+/// Decrypt messages with the keys this device has.
+/// The ones it can't decrypt keep their encrypted text, a key may still come.
+pub(crate) async fn decrypt_messages(state: &AppData, messages: &mut [Message]) {
+    let sealed = messages
+        .iter()
+        .enumerate()
+        .filter(|(_, message)| Envelope::is_encrypted(&message.message))
+        .map(|(i, message)| (i, message.message.clone()))
+        .collect::<Vec<_>>();
+    if sealed.is_empty() {
+        return;
+    }
+    let opened = with_bubble_keys(state, move |keys| {
+        sealed
+            .into_iter()
+            .map(|(i, text)| (i, keys.decrypt(&text)))
+            .collect::<Vec<_>>()
+    })
+    .await;
+    let opened = match opened {
+        Ok(opened) => opened,
+        Err(e) => {
+            warn!("Failed to decrypt messages: {e}");
+            return;
+        }
+    };
+    for (i, text) in opened {
+        match text {
+            Ok(text) => messages[i].message = text,
+            Err(e) => warn!("Failed to decrypt message {}: {e}", messages[i].id),
+        }
+    }
+}
+
+/// The text to send to `bubble_id`, encrypted if the bubble is
+pub(crate) async fn seal(
+    state: &AppData,
+    bubble_id: u64,
+    message: String,
+) -> Result<String, BackendError> {
+    Ok(with_bubble_keys(state, move |keys| {
+        keys.encrypt(bubble_id, &message)
+            .map(|sealed| sealed.unwrap_or(message))
+    })
+    .await??)
+}
+
+/// Fails when `bubble_id` is encrypted, its messages can't carry attachments yet
+pub(crate) async fn refuse_attachments(
+    state: &AppData,
+    bubble_id: u64,
+) -> Result<(), BackendError> {
+    match with_bubble_keys(state, move |keys| keys.current(bubble_id)).await?? {
+        Some(_) => Err(BackendError::EncryptedAttachments),
+        None => Ok(()),
+    }
+}
+
+/// A queued change as it goes to the server, with its text encrypted if the bubble is.
+/// Fails rather than send an edit in the clear when its bubble isn't known.
+pub(crate) async fn seal_op(state: &AppData, op: &OutboxOp) -> Result<OutboxOp, BackendError> {
+    let mut op = op.clone();
+    match &mut op {
+        OutboxOp::Send {
+            bubble_id, message, ..
+        } => *message = seal(state, *bubble_id, std::mem::take(message)).await?,
+        OutboxOp::Edit {
+            message_id,
+            message,
+        } => {
+            let bubble_id = match state.store.message(*message_id)? {
+                Some(edited) => Some(edited.bubble_id),
+                None => state
+                    .message_list
+                    .read()
+                    .map_err(|_| BackendError::RwLockReadError)?
+                    .iter()
+                    .find(|m| m.id == *message_id)
+                    .map(|m| m.bubble_id),
+            };
+            let bubble_id = bubble_id.ok_or(BackendError::UnknownBubble(*message_id))?;
+            *message = seal(state, bubble_id, std::mem::take(message)).await?;
+        }
+        OutboxOp::Delete { .. } | OutboxOp::Reaction { .. } => {}
+    }
+    Ok(op)
+}
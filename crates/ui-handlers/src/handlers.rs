@@ -1,193 +1,360 @@
-use ::settings::Settings;
-use client::{
-    Announcement, Bubble, BubbleStats, Membership, Message, ProntoClient, ReactionType, Task,
-    UserInfo,
-};
+use crate::outbox;
+use ::settings::{Auth, Settings};
+use client::{Announcement, Bubble, BubbleStats, Membership, ProntoClient, Task, UserInfo};
 use dashmap::DashMap;
-use search::SearchResults;
-use search::milli::score_details::ScoringStrategy;
-use search::milli::{GeoSortStrategy, TermsMatchingStrategy, TimeBudget};
-use std::path::PathBuf;
+use encrypt::{
+    BubbleKeys, DEFAULT_MAX_AGE, KeyError, LookupBackend, PublicLookupService, TrustStore,
+};
+use log::warn;
+use search::{Maintenance, ParsedQuery, SearchQuery, SearchResults, SortOrder};
 use std::sync::atomic::AtomicBool;
 use std::sync::{Arc, RwLock};
+use store::{Channel, OutboxOp, Store};
 use tauri::{Emitter, State, command};
+use tokio::sync::Notify;
 use ui_lib::{AppData, AppState, BackendError};
 use updater::Version;
 
-#[command]
-pub async fn load(state: State<'_, AppState>) -> Result<(), BackendError> {
-    if state.is_loaded() {
-        return Ok(());
+/// Everything `load` needs, from the server or from the local store
+struct Snapshot {
+    user_info: UserInfo,
+    users: Vec<UserInfo>,
+    channels: Vec<Channel>,
+    tasks: Vec<Task>,
+    announcements: Vec<Announcement>,
+}
+
+impl Snapshot {
+    /// What the last session left in the store, if anything
+    fn cached(store: &Store) -> Result<Option<Self>, BackendError> {
+        let Some(user_info) = store.current_user()? else {
+            return Ok(None);
+        };
+        let channels = store.channels()?;
+        if channels.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(Self {
+            user_info,
+            users: store.users()?,
+            channels,
+            tasks: store.tasks()?,
+            announcements: store.announcements()?,
+        }))
     }
-    let settings = Settings::load().await?;
-
-    let client = ProntoClient::new(
-        settings
-            .auth
-            .as_ref()
-            .ok_or(BackendError::NotAuthenticated)?
-            .base_url
-            .to_string(),
-        &settings
-            .auth
-            .as_ref()
-            .ok_or(BackendError::NotAuthenticated)?
-            .api_key
-            .clone(),
-    )
-    .unwrap();
-    let user_info_future = client.current_user_info();
-    let channel_list_future = client.bubble_list();
-    let (user_info, channel_list) = futures::join!(user_info_future, channel_list_future);
-    let user_info = user_info?.user;
+
+    /// Everything the server has for `client`, logged in to `organization_id`.
+    /// Logins saved before multiple organizations didn't record it, they only had the one.
+    async fn fetch(
+        client: &ProntoClient,
+        organization_id: Option<u64>,
+    ) -> Result<Self, BackendError> {
+        let user_info_future = client.current_user_info();
+        let channel_list_future = client.bubble_list();
+        let (user_info, channel_list) = futures::join!(user_info_future, channel_list_future);
+        let user_info = user_info?.user;
+        let channel_list = channel_list?;
+        let mut channels = vec![];
+        for bubble in channel_list.bubbles.clone() {
+            let stats = channel_list
+                .stats
+                .iter()
+                .find(|s| s.bubble_id == bubble.id)
+                .cloned();
+            let membership = channel_list
+                .memberships
+                .iter()
+                .find(|m| m.bubble_id == bubble.id)
+                .cloned();
+            channels.push((bubble, stats, membership));
+        }
+        let organization_id = organization_id
+            .or_else(|| user_info.organizations.first().map(|o| o.id))
+            .ok_or(BackendError::NotAuthenticated)?;
+        let tasks_list_incomplete = client.task_list(organization_id, false);
+        let tasks_list_complete = client.task_list(organization_id, true);
+        let announcements_list = client.announcement_list("RECEIVED".to_string());
+        let (tasks_list_incomplete, tasks_list_complete, announcements_list) = futures::join!(
+            tasks_list_incomplete,
+            tasks_list_complete,
+            announcements_list
+        );
+        Ok(Self {
+            users: vec![user_info.clone()],
+            user_info,
+            channels,
+            tasks: tasks_list_incomplete?
+                .tasks
+                .iter()
+                .chain(tasks_list_complete?.tasks.iter())
+                .cloned()
+                .collect(),
+            announcements: announcements_list?.announcements,
+        })
+    }
+
+    fn save(&self, store: &Store) -> store::Result<()> {
+        store.set_current_user(&self.user_info)?;
+        store.replace_channels(&self.channels)?;
+        store.replace_tasks(&self.tasks)?;
+        store.replace_announcements(&self.announcements)
+    }
+}
+
+/// Catch an organization loaded from the store up with the server
+async fn refresh(
+    handle: &tauri::AppHandle,
+    context: &AppState,
+    index: usize,
+) -> Result<(), BackendError> {
+    let state = context.organization(index)?;
+    let snapshot = Snapshot::fetch(&state.client, state.account.organization_id).await?;
+    state.persist(|store| snapshot.save(store));
+    *state
+        .channel_list
+        .write()
+        .map_err(|_| BackendError::RwLockWriteError)? = snapshot.channels;
+    *state
+        .tasks
+        .write()
+        .map_err(|_| BackendError::RwLockWriteError)? = snapshot.tasks;
+    *state
+        .announcements
+        .write()
+        .map_err(|_| BackendError::RwLockWriteError)? = snapshot.announcements;
+    state
+        .users
+        .insert(snapshot.user_info.id, snapshot.user_info);
+    let _ = handle.emit("channelListUpdate", ());
+    let _ = handle.emit("taskListUpdate", ());
+    let _ = handle.emit("announcementListUpdate", ());
+    Ok(())
+}
+
+/// Load an account from its local store when it has data, otherwise wait for the server.
+/// Also returns whether it came from the store and still needs a refresh.
+async fn load_account(
+    account: Auth,
+    settings: Settings,
+    key_lookup: Arc<PublicLookupService>,
+    trust_store: Arc<TrustStore>,
+) -> Result<(AppData, bool), BackendError> {
+    let client = ProntoClient::new(account.base_url.clone(), &account.api_key).unwrap();
+    let dir = ::settings::prontus_dir();
+    let name = format!("store-{}.sqlite", account.key());
+    // Before multiple organizations the only account had a store of its own
+    if account.user_id.is_none() && !dir.join(&name).exists() && dir.join("store.sqlite").exists() {
+        for suffix in ["", "-wal", "-shm"] {
+            let legacy = dir.join(format!("store.sqlite{suffix}"));
+            if legacy.exists()
+                && let Err(e) = std::fs::rename(&legacy, dir.join(format!("{name}{suffix}")))
+            {
+                warn!("Failed to move the local store: {e}");
+            }
+        }
+    }
+    let store = Arc::new(Store::open(dir.join(name))?);
+    let (snapshot, cached) = match Snapshot::cached(&store)? {
+        Some(snapshot) => (snapshot, true),
+        None => {
+            let snapshot = Snapshot::fetch(&client, account.organization_id).await?;
+            if let Err(e) = snapshot.save(&store) {
+                warn!("Failed to update the local store: {e}");
+            }
+            (snapshot, false)
+        }
+    };
     let users = DashMap::new();
-    users.insert(user_info.id, user_info.clone());
-    let channel_list = channel_list?;
-    let mut state_channel_list: Vec<(Bubble, Option<BubbleStats>, Option<Membership>)> = vec![];
-    for bubble in channel_list.bubbles.clone() {
-        let stats = channel_list
-            .stats
-            .iter()
-            .find(|s| s.bubble_id == bubble.id)
-            .cloned();
-        let membership = channel_list
-            .memberships
-            .iter()
-            .find(|m| m.bubble_id == bubble.id)
-            .cloned();
-        state_channel_list.push((bubble, stats, membership));
+    for user in snapshot.users {
+        users.insert(user.id, user);
     }
-    let tasks_list_incomplete = client.task_list(user_info.organizations[0].id, false);
-    let tasks_list_complete = client.task_list(user_info.organizations[0].id, true);
-    let announcements_list = client.announcement_list("RECEIVED".to_string());
-    let (tasks_list_incomplete, tasks_list_complete, announcements_list) = futures::join!(
-        tasks_list_incomplete,
-        tasks_list_complete,
-        announcements_list
-    );
     let data = AppData {
-        user_info,
+        account,
+        bubble_keys: Arc::new(BubbleKeys::new(snapshot.user_info.id)),
+        device_link: RwLock::new(None),
+        user_info: snapshot.user_info,
         users,
         client: Arc::new(client),
-        current_channel: RwLock::new(state_channel_list[0].clone().0),
-        channel_list: RwLock::new(state_channel_list),
+        current_channel: RwLock::new(snapshot.channels[0].clone().0),
+        channel_list: RwLock::new(snapshot.channels),
         message_list: RwLock::new(vec![]),
         parent_messages: RwLock::new(vec![]),
+        translations: DashMap::new(),
         channel_users: DashMap::new(),
-        announcements: RwLock::new(announcements_list?.announcements),
-        tasks: RwLock::new(
-            tasks_list_incomplete?
-                .tasks
-                .iter()
-                .chain(tasks_list_complete?.tasks.iter())
-                .cloned()
-                .collect(),
-        ),
+        announcements: RwLock::new(snapshot.announcements),
+        tasks: RwLock::new(snapshot.tasks),
         is_typing: AtomicBool::new(false),
         typing_users: DashMap::new(),
         settings: RwLock::new(settings),
+        outbox: RwLock::new(store.outbox()?),
+        outbox_queued: Notify::new(),
+        search_indexer: RwLock::new(None),
+        search_maintenance: RwLock::new(None),
+        key_lookup,
+        trust_store,
+        store,
     };
-    state.load(data);
-    Ok(())
+    Ok((data, cached))
 }
 
+/// Held while loading, so accounts aren't loaded twice when `load` is called again meanwhile
+static LOADING: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+/// Load every logged in account, those with a local store refresh in the background.
+/// Accounts that fail to load are skipped unless none of them load.
+/// Called again after logging in, the accounts added since are loaded next to the others.
 #[command]
-pub async fn set_reaction_state(
+pub async fn load(
+    handle: tauri::AppHandle,
     state: State<'_, AppState>,
-    message_id: u64,
-    reaction_id: u64,
-    active: bool,
 ) -> Result<(), BackendError> {
-    async fn send_state_change(
-        state: State<'_, AppState>,
-        message_id: u64,
-        reaction_type: ReactionType,
-        active: bool,
-    ) -> Result<Message, BackendError> {
-        let state = state.try_inner()?;
-
-        let message = if active {
-            state.client.add_reaction(message_id, reaction_type).await?
-        } else {
-            state
-                .client
-                .remove_reaction(message_id, reaction_type)
-                .await?
-        };
-        Ok(message.message)
+    let _loading = LOADING.lock().await;
+    let mut settings = Settings::load().await?;
+    let current_key = settings.auth().ok_or(BackendError::NotAuthenticated)?.key();
+    let loaded: Vec<String> = match state.organizations() {
+        Ok(organizations) => organizations
+            .iter()
+            .map(|data| data.account.key())
+            .collect(),
+        Err(_) => vec![],
+    };
+    let accounts: Vec<Auth> = settings
+        .accounts
+        .iter()
+        .filter(|account| !loaded.contains(&account.key()))
+        .cloned()
+        .collect();
+    if state.is_loaded() && accounts.is_empty() {
+        return Ok(());
     }
-    let message = send_state_change(
-        state.clone(),
-        message_id,
-        ReactionType::from(reaction_id as i32),
-        active,
-    );
-    {
-        let state = state.try_inner()?;
+    // Shared by every account, so the ones added later get those of the first
+    let (key_lookup, trust_store) = match state.organization(0) {
+        Ok(data) => (data.key_lookup.clone(), data.trust_store.clone()),
+        Err(_) => {
+            encrypt::use_file_store(
+                ::settings::prontus_dir().join("keys"),
+                settings
+                    .encryption
+                    .file_key_store
+                    .unwrap_or(cfg!(target_os = "linux")),
+            );
+            let key_lookup = Arc::new(PublicLookupService::new(
+                settings
+                    .encryption
+                    .key_directory
+                    .as_deref()
+                    .map(LookupBackend::parse)
+                    .unwrap_or_default(),
+                DEFAULT_MAX_AGE,
+            ));
+            let trust_store = Arc::new(
+                TrustStore::open(::settings::prontus_dir().join("trust.json"))
+                    .map_err(KeyError::from)?,
+            );
+            (key_lookup, trust_store)
+        }
+    };
 
-        if active {
-            state
-                .client
-                .add_reaction(message_id, ReactionType::from(reaction_id as i32))
-                .await?;
-            let mut message_list = state
-                .message_list
-                .write()
-                .map_err(|_| BackendError::RwLockWriteError)?;
-            let messages = message_list
-                .iter_mut()
-                .find(|message| message.id == message_id)
-                .unwrap();
-            let o = messages
-                .reactions
-                .iter_mut()
-                .find(|reaction| reaction.id == reaction_id);
-            if let Some(o) = o {
-                o.count += 1;
-            } else {
-                messages.reactions.push(client::Reactions {
-                    id: reaction_id,
-                    count: 1,
-                    users: vec![state.user_info.id],
-                });
+    let results = futures::future::join_all(accounts.into_iter().map(|account| {
+        load_account(
+            account,
+            settings.clone(),
+            key_lookup.clone(),
+            trust_store.clone(),
+        )
+    }))
+    .await;
+    let mut organizations = vec![];
+    let mut error = None;
+    for result in results {
+        match result {
+            Ok(loaded) => organizations.push(loaded),
+            Err(e) => {
+                warn!("Failed to load an account: {e}");
+                error.get_or_insert(e);
             }
-        } else {
-            state
-                .client
-                .remove_reaction(message_id, ReactionType::from(reaction_id as i32))
-                .await?;
-            let mut message_list = state
-                .message_list
-                .write()
-                .map_err(|_| BackendError::RwLockWriteError)?;
-            let messages = message_list
-                .iter_mut()
-                .find(|message| message.id == message_id)
-                .unwrap();
-            messages
-                .reactions
-                .iter_mut()
-                .find(|reaction| reaction.id == reaction_id)
-                .unwrap()
-                .count -= 1;
         }
     }
-    let message = message.await?;
-
-    let state = state.try_inner()?;
-    let mut message_list = state
-        .message_list
-        .write()
-        .map_err(|_| BackendError::RwLockWriteError)?;
+    if organizations.is_empty() {
+        return Err(error.unwrap_or(BackendError::NotAuthenticated));
+    }
 
-    *message_list
-        .iter_mut()
-        .find(|m| m.id == message_id)
-        .unwrap() = message;
+    // Logins saved before multiple organizations didn't record their organization,
+    // the user id stays unset so the account keeps its store
+    let mut identified = false;
+    for (data, _) in &mut organizations {
+        if data.account.organization_id.is_none() {
+            let account = &mut data.account;
+            account.organization_id = data.user_info.organizations.first().map(|o| o.id);
+            account.organization_name =
+                data.user_info.organizations.first().map(|o| o.name.clone());
+            settings.add_account(account.clone());
+            identified = true;
+        }
+    }
+    if identified {
+        settings.save().await?;
+    }
 
+    let mut stale = vec![];
+    if state.is_loaded() {
+        // The tasks pick the new organizations up on their own
+        for (data, cached) in organizations {
+            let is_current = data.account.key() == current_key;
+            let index = state.add(data)?;
+            if is_current {
+                state.switch(index)?;
+            }
+            if cached {
+                stale.push(index);
+            }
+        }
+    } else {
+        let mut current = 0;
+        let mut loaded = vec![];
+        for (index, (data, cached)) in organizations.into_iter().enumerate() {
+            if data.account.key() == current_key {
+                current = index;
+            }
+            if cached {
+                stale.push(index);
+            }
+            loaded.push(data);
+        }
+        state.load(loaded, current);
+    }
+    for index in stale {
+        let handle = handle.clone();
+        let context = state.inner().clone();
+        tauri::async_runtime::spawn(async move {
+            if let Err(e) = refresh(&handle, &context, index).await {
+                warn!("Failed to refresh from the server, staying on the local copy: {e}");
+            }
+        });
+    }
     Ok(())
 }
 
+#[command]
+pub async fn set_reaction_state(
+    handle: tauri::AppHandle,
+    state: State<'_, AppState>,
+    message_id: u64,
+    reaction_id: u64,
+    active: bool,
+) -> Result<(), BackendError> {
+    let state = state.try_inner()?;
+    outbox::enqueue(
+        &handle,
+        state,
+        OutboxOp::Reaction {
+            message_id,
+            reaction_id,
+            active,
+        },
+    )
+}
+
 #[command]
 pub async fn create_dm(
     handle: tauri::AppHandle,
@@ -198,7 +365,13 @@ pub async fn create_dm(
         let state = state.try_inner()?;
         state
             .client
-            .create_dm(state.user_info.organizations[0].id, user_id)
+            .create_dm(
+                state
+                    .account
+                    .organization_id
+                    .ok_or(BackendError::NotAuthenticated)?,
+                user_id,
+            )
             .await?;
         let channel_list = state.client.bubble_list().await?;
         let mut state_channel_list: Vec<(Bubble, Option<BubbleStats>, Option<Membership>)> = vec![];
@@ -219,6 +392,7 @@ pub async fn create_dm(
     };
 
     let state = state.try_inner()?;
+    state.persist(|store| store.replace_channels(&channel_list));
     let mut state_channel_list = state
         .channel_list
         .write()
@@ -240,7 +414,13 @@ pub async fn create_bubble(
         let state = state.try_inner()?;
         state
             .client
-            .create_bubble(state.user_info.organizations[0].id, name)
+            .create_bubble(
+                state
+                    .account
+                    .organization_id
+                    .ok_or(BackendError::NotAuthenticated)?,
+                name,
+            )
             .await?;
         let channel_list = state.client.bubble_list().await?;
         let mut state_channel_list: Vec<(Bubble, Option<BubbleStats>, Option<Membership>)> = vec![];
@@ -261,6 +441,7 @@ pub async fn create_bubble(
     };
 
     let state = state.try_inner()?;
+    state.persist(|store| store.replace_channels(&channel_list));
     let mut state_channel_list = state
         .channel_list
         .write()
@@ -310,6 +491,7 @@ pub async fn mark_announcement_read(
 ) -> Result<(), BackendError> {
     let state = state.try_inner()?;
     let new_announcement = state.client.mark_read_announcement(id).await?;
+    state.persist(|store| store.upsert_announcement(&new_announcement.announcement));
     let mut state_announcements = state
         .announcements
         .write()
@@ -345,6 +527,7 @@ pub async fn complete_task(
     };
 
     let state = state.try_inner()?;
+    state.persist(|store| store.upsert_task(&updated_task.task));
     let mut state_tasks = state
         .tasks
         .write()
@@ -368,6 +551,7 @@ pub async fn uncomplete_task(
     };
 
     let state = state.try_inner()?;
+    state.persist(|store| store.upsert_task(&updated_task.task));
     let mut state_tasks = state
         .tasks
         .write()
@@ -389,6 +573,7 @@ pub async fn delete_task(
     let state = state.try_inner()?;
 
     // state.client.task_delete(task_id).await?;
+    state.persist(|store| store.remove_task(task_id));
     let mut tasks = state
         .tasks
         .write()
@@ -420,41 +605,158 @@ pub async fn get_typing_users(
     Ok(state.typing_users.clone())
 }
 
+/// Messages shown around each search hit, on either side
+const SEARCH_CONTEXT: usize = 2;
+/// How much hits are ranked by meaning rather than words, with an embedding model set up
+const SEMANTIC_RATIO: f32 = 0.5;
+
+/// Search the local index with the query language of [`ParsedQuery`],
+/// `from:` and `in:` match the names of users and bubbles
 #[command]
 pub async fn search_local(
     state: State<'_, AppState>,
     query: String,
+    offset: Option<usize>,
 ) -> Result<Option<SearchResults>, BackendError> {
     let state = state.try_inner()?;
-    let settings = &state
+    let Some((index_path, map_size)) = state
         .settings
         .read()
-        .map_err(|_| BackendError::RwLockReadError)?;
-    if let Some(msg) = settings.search.messages.as_ref() {
-        let loc = PathBuf::from(&msg.path);
-        let mut search = search::Search::new(&loc);
-        let results = search
-            .search(
-                (!query.trim().is_empty()).then(|| query.trim()),
-                TermsMatchingStrategy::Last,
-                ScoringStrategy::Skip,
-                false,
-                &None,
-                &None,
-                GeoSortStrategy::default(),
-                0,
-                20,
-                None,
-                TimeBudget::max(),
-                None,
-                None,
+        .map_err(|_| BackendError::RwLockReadError)?
+        .search
+        .messages
+        .as_ref()
+        .map(|msg| {
+            (
+                msg.index_path(&state.account),
+                search::map_size(Some(msg.max_size as usize)),
             )
-            .unwrap();
-        // TODO: no unwrap (see above)
-        Ok(Some(results))
-    } else {
-        Ok(None)
+        })
+    else {
+        return Ok(None);
+    };
+    // Opening the index now would keep it from being closed
+    if state
+        .search_maintenance
+        .read()
+        .map_err(|_| BackendError::RwLockReadError)?
+        .is_some()
+    {
+        return Ok(Some(SearchResults::default()));
+    }
+
+    let parsed = ParsedQuery::parse(&query);
+    let mut search_query = SearchQuery::new()
+        .parsed(&parsed)
+        .offset(offset.unwrap_or(0))
+        .context(SEARCH_CONTEXT)
+        .semantic(SEMANTIC_RATIO);
+    if parsed.text.trim().is_empty() {
+        search_query = search_query.sort(SortOrder::NewestFirst);
+    }
+    for name in &parsed.from {
+        let user_ids = if name.eq_ignore_ascii_case("me") {
+            vec![state.user_info.id]
+        } else {
+            matching_ids(
+                name,
+                state
+                    .users
+                    .iter()
+                    .map(|user| (user.id, user.fullname.clone())),
+            )
+        };
+        // Nobody by that name sent anything
+        if user_ids.is_empty() {
+            return Ok(Some(SearchResults::default()));
+        }
+        for user_id in user_ids {
+            search_query = search_query.from_user(user_id);
+        }
+    }
+    for name in &parsed.channels {
+        let bubble_ids = matching_ids(
+            name,
+            state
+                .channel_list
+                .read()
+                .map_err(|_| BackendError::RwLockReadError)?
+                .iter()
+                .map(|(bubble, _, _)| (bubble.id, bubble.title.clone())),
+        );
+        if bubble_ids.is_empty() {
+            return Ok(Some(SearchResults::default()));
+        }
+        for bubble_id in bubble_ids {
+            search_query = search_query.in_bubble(bubble_id);
+        }
+    }
+
+    // Queries are embedded with the model the indexer embeds messages with
+    let embedder = state
+        .search_indexer
+        .read()
+        .map_err(|_| BackendError::RwLockReadError)?
+        .as_ref()
+        .and_then(|indexer| indexer.embedder());
+    // Embedding the query and searching the index block, so they're kept off the async runtime
+    let results = tokio::task::spawn_blocking(move || {
+        let mut search = search::Search::new(&index_path, map_size)?;
+        if let Some(embedder) = embedder {
+            search = search.with_embedder(embedder);
+        }
+        search.search(&search_query)
+    })
+    .await
+    .map_err(std::io::Error::other)?;
+    match results {
+        Ok(results) => Ok(Some(results)),
+        // Maintenance started after the check above
+        Err(search::SearchError::Closed) => Ok(Some(SearchResults::default())),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Compact or rebuild the search index of the current organization.
+/// The search task stops indexing while it runs, searches find nothing meanwhile.
+#[command]
+pub async fn maintain_search_index(
+    state: State<'_, AppState>,
+    maintenance: Maintenance,
+) -> Result<(), BackendError> {
+    let state = state.try_inner()?;
+    // Nothing to maintain with message search off
+    if state
+        .settings
+        .read()
+        .map_err(|_| BackendError::RwLockReadError)?
+        .search
+        .messages
+        .is_none()
+    {
+        return Ok(());
+    }
+    *state
+        .search_maintenance
+        .write()
+        .map_err(|_| BackendError::RwLockWriteError)? = Some(maintenance);
+    Ok(())
+}
+
+/// Ids whose name is `name`, or contains it when none is an exact match, ignoring case
+fn matching_ids(name: &str, names: impl Iterator<Item = (u64, String)>) -> Vec<u64> {
+    let name = name.to_lowercase();
+    let mut exact = vec![];
+    let mut partial = vec![];
+    for (id, candidate) in names {
+        let candidate = candidate.to_lowercase();
+        if candidate == name {
+            exact.push(id);
+        } else if candidate.contains(&name) {
+            partial.push(id);
+        }
     }
+    if exact.is_empty() { partial } else { exact }
 }
 
 #[command]
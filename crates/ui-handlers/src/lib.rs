@@ -1,13 +1,19 @@
 mod auth;
 mod channel;
+mod encryption;
 mod handlers;
 mod message;
+mod organization;
+mod outbox;
 mod settings;
 mod user;
 
 pub use auth::*;
 pub use channel::*;
+pub use encryption::*;
 pub use handlers::*;
 pub use message::*;
+pub use organization::*;
+pub use outbox::*;
 pub use settings::*;
 pub use user::*;
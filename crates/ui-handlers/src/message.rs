@@ -1,7 +1,42 @@
-use client::Message;
+use crate::{encryption, outbox};
+use client::{Attachment, ErrorKind, Message, MessageTranslation};
+use log::warn;
+use serde::Serialize;
+use store::OutboxOp;
 use tauri::{Emitter, State, command};
-use ui_lib::{AppState, BackendError};
+use ui_lib::{AppData, AppState, BackendError};
 
+/// How many messages a page of `bubble.history` holds
+const HISTORY_PAGE_SIZE: usize = 50;
+
+/// Fetch a page of history and write it through to the local store,
+/// falling back to the stored page when the server can't be reached.
+async fn history(
+    state: &AppData,
+    bubble_id: u64,
+    before: Option<u64>,
+) -> Result<(Vec<Message>, Vec<Message>), BackendError> {
+    match state.client.bubble_history(bubble_id, before).await {
+        Ok(mut history) => {
+            encryption::receive_messages(state, &mut history.messages).await;
+            encryption::decrypt_messages(state, &mut history.parent_messages).await;
+            state.persist(|store| {
+                store.sync_history(bubble_id, before, &history.messages)?;
+                store.upsert_messages(&history.parent_messages)
+            });
+            Ok((history.messages, history.parent_messages))
+        }
+        Err(e) if e.kind() == ErrorKind::Transport => {
+            warn!("Server unreachable, loading messages from the local store: {e}");
+            let messages = state.store.messages(bubble_id, before, HISTORY_PAGE_SIZE)?;
+            let parents = state.store.parents(&messages)?;
+            Ok((messages, parents))
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Queue a message, it shows as sending until the outbox task gets it to the server
 #[command]
 pub async fn send_message(
     handle: tauri::AppHandle,
@@ -9,7 +44,64 @@ pub async fn send_message(
     message: String,
     thread: Option<u64>,
 ) -> Result<(), BackendError> {
-    let response = {
+    let state = state.try_inner()?;
+    let bubble_id = state
+        .current_channel
+        .read()
+        .map_err(|_| BackendError::RwLockReadError)?
+        .id;
+    outbox::enqueue(
+        &handle,
+        state,
+        OutboxOp::Send {
+            bubble_id,
+            user_id: state.user_info.id,
+            message,
+            parent_message_id: thread,
+        },
+    )
+}
+
+#[derive(Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct UploadProgress {
+    path: String,
+    sent: u64,
+    total: u64,
+}
+
+/// Upload files and send them as one message, emitting `uploadProgress` while they upload.
+#[command]
+pub async fn send_files(
+    handle: tauri::AppHandle,
+    state: State<'_, AppState>,
+    paths: Vec<String>,
+    message: String,
+    thread: Option<u64>,
+) -> Result<(), BackendError> {
+    {
+        let state = state.try_inner()?;
+        let id = state
+            .current_channel
+            .read()
+            .map_err(|_| BackendError::RwLockReadError)?
+            .id;
+        encryption::refuse_attachments(state, id).await?;
+    }
+    let mut attachments = Vec::with_capacity(paths.len());
+    for path in paths {
+        let attachment = Attachment::from_path(&path).await?;
+        let handle = handle.clone();
+        attachments.push(attachment.on_progress(move |sent, total| {
+            let progress = UploadProgress {
+                path: path.clone(),
+                sent,
+                total,
+            };
+            let _ = handle.emit("uploadProgress", progress);
+        }));
+    }
+    let mut response = {
         let state = state.try_inner()?;
         let user_id = state.user_info.id;
         let id = state
@@ -17,32 +109,47 @@ pub async fn send_message(
             .read()
             .map_err(|_| BackendError::RwLockReadError)?
             .id;
+        let message = encryption::seal(state, id, message).await?;
         state
             .client
-            .send_message(user_id, id, message, thread)
+            .send_attachments(user_id, id, message, thread, attachments)
             .await?
     };
+    encryption::decrypt_messages(
+        state.try_inner()?,
+        std::slice::from_mut(&mut response.message),
+    )
+    .await;
+
+    insert_sent_message(&handle, &state, response.message)
+}
 
+/// The pusher event for our own message may have beaten the response here
+fn insert_sent_message(
+    handle: &tauri::AppHandle,
+    state: &State<'_, AppState>,
+    message: Message,
+) -> Result<(), BackendError> {
     let state = state.try_inner()?;
+    state.persist(|store| store.upsert_messages([&message]));
     let mut message_list = state
         .message_list
         .write()
         .map_err(|_| BackendError::RwLockWriteError)?;
-    if message_list
-        .iter()
-        .find(|m| m.id == response.message.id)
-        .is_some()
-    {
+    if message_list.iter().any(|m| m.id == message.id) {
         return Ok(());
     }
-    message_list.insert(0, response.message);
+    message_list.insert(0, message);
     let _ = handle.emit("messageListUpdate", ());
     Ok(())
 }
 
 #[command]
-pub async fn load_messages(state: State<'_, AppState>) -> Result<(), BackendError> {
-    let messages = {
+pub async fn load_messages(
+    handle: tauri::AppHandle,
+    state: State<'_, AppState>,
+) -> Result<(), BackendError> {
+    let (mut messages, parents) = {
         let state = state.try_inner()?;
 
         let id = state
@@ -50,11 +157,26 @@ pub async fn load_messages(state: State<'_, AppState>) -> Result<(), BackendErro
             .read()
             .map_err(|_| BackendError::RwLockReadError)?
             .id;
-        state.client.bubble_history(id, None).await?
+        // Show the stored messages while the server is asked
+        let mut cached = state.store.messages(id, None, HISTORY_PAGE_SIZE)?;
+        if !cached.is_empty() {
+            outbox::apply_pending(state, &mut cached)?;
+            *state
+                .parent_messages
+                .write()
+                .map_err(|_| BackendError::RwLockWriteError)? = state.store.parents(&cached)?;
+            *state
+                .message_list
+                .write()
+                .map_err(|_| BackendError::RwLockWriteError)? = cached;
+            let _ = handle.emit("messageListUpdate", ());
+        }
+        history(state, id, None).await?
     };
     let state = state.try_inner()?;
+    outbox::apply_pending(state, &mut messages)?;
 
-    for message in messages.messages.iter() {
+    for message in messages.iter() {
         if !state.users.contains_key(&message.user.id) {
             state.users.insert(message.user.id, message.user.clone());
         }
@@ -64,12 +186,12 @@ pub async fn load_messages(state: State<'_, AppState>) -> Result<(), BackendErro
         .message_list
         .write()
         .map_err(|_| BackendError::RwLockWriteError)?;
-    *message_list = messages.messages;
+    *message_list = messages;
     let mut parent_messages = state
         .parent_messages
         .write()
         .map_err(|_| BackendError::RwLockWriteError)?;
-    *parent_messages = messages.parent_messages;
+    *parent_messages = parents;
     Ok(())
 }
 
@@ -112,12 +234,25 @@ pub async fn get_parent_messages(state: State<'_, AppState>) -> Result<Vec<Messa
         .clone())
 }
 
+#[command]
+pub async fn get_message_translation(
+    state: State<'_, AppState>,
+    message_id: u64,
+) -> Result<Option<MessageTranslation>, BackendError> {
+    let state = state.try_inner()?;
+
+    Ok(state
+        .translations
+        .get(&message_id)
+        .map(|translation| translation.clone()))
+}
+
 #[command]
 pub async fn get_more_messages(
     state: State<'_, AppState>,
     last_message_id: u64,
 ) -> Result<Vec<Message>, BackendError> {
-    let mut messages = {
+    let (mut messages, mut parents) = {
         let state = state.try_inner()?;
 
         let id = state
@@ -125,14 +260,12 @@ pub async fn get_more_messages(
             .read()
             .map_err(|_| BackendError::RwLockReadError)?
             .id;
-        state
-            .client
-            .bubble_history(id, Some(last_message_id))
-            .await?
+        history(state, id, Some(last_message_id)).await?
     };
 
     let state = state.try_inner()?;
-    for message in messages.messages.iter() {
+    outbox::apply_pending(state, &mut messages)?;
+    for message in messages.iter() {
         if !state.users.contains_key(&message.user.id) {
             state.users.insert(message.user.id, message.user.clone());
         }
@@ -141,13 +274,13 @@ pub async fn get_more_messages(
         .message_list
         .write()
         .map_err(|_| BackendError::RwLockWriteError)?;
-    message_list.extend_from_slice(&mut messages.messages.clone());
+    message_list.extend_from_slice(&mut messages.clone());
     let mut parent_messages = state
         .parent_messages
         .write()
         .map_err(|_| BackendError::RwLockWriteError)?;
-    parent_messages.extend_from_slice(&mut messages.parent_messages);
-    Ok(messages.messages)
+    parent_messages.extend_from_slice(&mut parents);
+    Ok(messages)
 }
 
 #[command]
@@ -157,21 +290,15 @@ pub async fn edit_message(
     message_id: u64,
     message: String,
 ) -> Result<(), BackendError> {
-    let message = {
-        let state = state.try_inner()?;
-        state.client.edit_message(message_id, message).await?
-    };
     let state = state.try_inner()?;
-    let mut message_list = state
-        .message_list
-        .write()
-        .map_err(|_| BackendError::RwLockWriteError)?;
-    *message_list
-        .iter_mut()
-        .find(|m| m.id == message_id)
-        .unwrap() = message.message;
-    let _ = handle.emit("messageListUpdate", ());
-    Ok(())
+    outbox::enqueue(
+        &handle,
+        state,
+        OutboxOp::Edit {
+            message_id,
+            message,
+        },
+    )
 }
 
 #[command]
@@ -180,16 +307,6 @@ pub async fn delete_message(
     state: State<'_, AppState>,
     message_id: u64,
 ) -> Result<(), BackendError> {
-    {
-        let state = state.try_inner()?;
-        state.client.delete_message(message_id).await?;
-    }
     let state = state.try_inner()?;
-    let mut message_list = state
-        .message_list
-        .write()
-        .map_err(|_| BackendError::RwLockWriteError)?;
-    message_list.retain(|message| message.id != message_id);
-    let _ = handle.emit("messageListUpdate", ());
-    Ok(())
+    outbox::enqueue(&handle, state, OutboxOp::Delete { message_id })
 }
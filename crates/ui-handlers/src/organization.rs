@@ -0,0 +1,63 @@
+use client::Organization;
+use serde::Serialize;
+use settings::Settings;
+use tauri::{Emitter, State, command};
+use ui_lib::{AppState, BackendError};
+
+#[derive(Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OrganizationSummary {
+    /// What `switch_organization` takes
+    pub index: usize,
+    pub organization: Option<Organization>,
+    pub user_id: u64,
+    pub current: bool,
+}
+
+/// Every organization that is logged in, in the order they were added
+#[command]
+pub async fn get_organizations(
+    state: State<'_, AppState>,
+) -> Result<Vec<OrganizationSummary>, BackendError> {
+    let current = state.current();
+
+    Ok(state
+        .organizations()?
+        .iter()
+        .enumerate()
+        .map(|(index, data)| OrganizationSummary {
+            index,
+            organization: data.user_info.organizations.first().cloned(),
+            user_id: data.user_info.id,
+            current: index == current,
+        })
+        .collect())
+}
+
+/// Show another organization, it stays the one shown on the next start
+#[command]
+pub async fn switch_organization(
+    handle: tauri::AppHandle,
+    state: State<'_, AppState>,
+    index: usize,
+) -> Result<(), BackendError> {
+    state.switch(index)?;
+    let key = state.organization(index)?.account.key();
+
+    let mut settings = Settings::load().await?;
+    if let Some(account) = settings
+        .accounts
+        .iter()
+        .position(|account| account.key() == key)
+    {
+        settings.current_account = account;
+        settings.save().await?;
+    }
+
+    let _ = handle.emit("organizationSwitch", index);
+    let _ = handle.emit("channelListUpdate", ());
+    let _ = handle.emit("taskListUpdate", ());
+    let _ = handle.emit("announcementListUpdate", ());
+    let _ = handle.emit("outboxUpdate", ());
+    Ok(())
+}
@@ -0,0 +1,376 @@
+//! Sends, edits, deletes and reactions are queued in the outbox and replayed in order,
+//! so nothing the user does is lost while the server can't be reached.
+
+use crate::encryption;
+use client::{ErrorKind, Message, ReactionType, Reactions, ResponseError};
+use log::warn;
+use serde::Serialize;
+use store::{OutboxEntry, OutboxOp};
+use tauri::{AppHandle, Emitter, State, command};
+use ui_lib::{AppData, AppState, BackendError};
+use uuid::Uuid;
+
+#[derive(Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct OutboxFailure {
+    uuid: String,
+    op: OutboxOp,
+    message: String,
+}
+
+/// Queue a change and show it on the message list right away
+pub(crate) fn enqueue(
+    handle: &AppHandle,
+    state: &AppData,
+    op: OutboxOp,
+) -> Result<(), BackendError> {
+    let entry = OutboxEntry {
+        uuid: Uuid::new_v4().to_string(),
+        op,
+        attempted: false,
+    };
+    state.persist(|store| store.push_outbox(&entry));
+    {
+        let mut message_list = state
+            .message_list
+            .write()
+            .map_err(|_| BackendError::RwLockWriteError)?;
+        apply(&entry.op, state.user_info.id, &mut message_list);
+    }
+    state
+        .outbox
+        .write()
+        .map_err(|_| BackendError::RwLockWriteError)?
+        .push(entry);
+    state.outbox_queued.notify_one();
+    let _ = handle.emit("outboxUpdate", ());
+    let _ = handle.emit("messageListUpdate", ());
+    Ok(())
+}
+
+/// Show the queued changes on messages that came from the server or the store
+pub(crate) fn apply_pending(
+    state: &AppData,
+    messages: &mut Vec<Message>,
+) -> Result<(), BackendError> {
+    let outbox = state
+        .outbox
+        .read()
+        .map_err(|_| BackendError::RwLockReadError)?;
+    for entry in outbox.iter() {
+        apply(&entry.op, state.user_info.id, messages);
+    }
+    Ok(())
+}
+
+/// Sends are shown from the outbox itself, everything else changes a message in place.
+/// Applying a change twice does nothing.
+fn apply(op: &OutboxOp, user_id: u64, messages: &mut Vec<Message>) {
+    match *op {
+        OutboxOp::Send { .. } => {}
+        OutboxOp::Edit {
+            message_id,
+            ref message,
+        } => {
+            if let Some(edited) = messages.iter_mut().find(|m| m.id == message_id) {
+                edited.message = message.clone();
+            }
+        }
+        OutboxOp::Delete { message_id } => messages.retain(|m| m.id != message_id),
+        OutboxOp::Reaction {
+            message_id,
+            reaction_id,
+            active,
+        } => {
+            let Some(message) = messages.iter_mut().find(|m| m.id == message_id) else {
+                return;
+            };
+            match message.reactions.iter().position(|r| r.id == reaction_id) {
+                Some(index) => {
+                    let reaction = &mut message.reactions[index];
+                    let reacted = reaction.users.contains(&user_id);
+                    if active && !reacted {
+                        reaction.users.push(user_id);
+                        reaction.count += 1;
+                    } else if !active && reacted {
+                        reaction.users.retain(|&id| id != user_id);
+                        reaction.count = reaction.count.saturating_sub(1);
+                    }
+                    if reaction.count == 0 {
+                        message.reactions.remove(index);
+                    }
+                }
+                None if active => message.reactions.push(Reactions {
+                    id: reaction_id,
+                    count: 1,
+                    users: vec![user_id],
+                }),
+                None => {}
+            }
+        }
+    }
+}
+
+/// Queued changes, the frontend shows the sends among the messages as "sending"
+#[command]
+pub async fn get_outbox(state: State<'_, AppState>) -> Result<Vec<OutboxEntry>, BackendError> {
+    let state = state.try_inner()?;
+
+    Ok(state
+        .outbox
+        .read()
+        .map_err(|_| BackendError::RwLockReadError)?
+        .clone())
+}
+
+/// Whether a failed change should stay queued, anything else is never going to go through
+fn retry_later(kind: ErrorKind) -> bool {
+    matches!(
+        kind,
+        ErrorKind::Transport | ErrorKind::RateLimited | ErrorKind::Server | ErrorKind::Unauthorized
+    )
+}
+
+/// Replay the outbox in order until it's empty or the server can't take the next change yet.
+/// Changes the server rejects are dropped and reported with `outboxFailed`.
+pub async fn flush_outbox(handle: &AppHandle, state: &AppData) -> Result<(), BackendError> {
+    loop {
+        let Some(entry) = state
+            .outbox
+            .read()
+            .map_err(|_| BackendError::RwLockReadError)?
+            .first()
+            .cloned()
+        else {
+            return Ok(());
+        };
+        // A change that can't be encrypted is dropped rather than sent in the clear,
+        // and it mustn't hold up the ones queued after it
+        let sealed = match encryption::seal_op(state, &entry.op).await {
+            Ok(op) => OutboxEntry {
+                op,
+                ..entry.clone()
+            },
+            Err(e) => {
+                warn!(
+                    "Dropping queued change {} that can't be sealed: {e}",
+                    entry.uuid
+                );
+                drop_entry(handle, state, entry, e.to_string())?;
+                let _ = handle.emit("messageListUpdate", ());
+                continue;
+            }
+        };
+        match replay(state, &sealed).await {
+            Ok(message) => {
+                remove(handle, state, &entry.uuid)?;
+                finish(state, &entry.op, message).await?;
+            }
+            Err(e) if retry_later(e.kind()) => {
+                if !entry.attempted {
+                    state.persist(|store| store.mark_outbox_attempted(&entry.uuid));
+                    if let Some(queued) = state
+                        .outbox
+                        .write()
+                        .map_err(|_| BackendError::RwLockWriteError)?
+                        .iter_mut()
+                        .find(|queued| queued.uuid == entry.uuid)
+                    {
+                        queued.attempted = true;
+                    }
+                }
+                return Err(e.into());
+            }
+            Err(e) => {
+                warn!("Dropping queued change {}: {e}", entry.uuid);
+                drop_entry(handle, state, entry, e.to_string())?;
+            }
+        }
+        let _ = handle.emit("messageListUpdate", ());
+    }
+}
+
+/// Take a change that's never going to go through out of the outbox, undo it locally
+/// and report it with `outboxFailed`
+fn drop_entry(
+    handle: &AppHandle,
+    state: &AppData,
+    entry: OutboxEntry,
+    message: String,
+) -> Result<(), BackendError> {
+    remove(handle, state, &entry.uuid)?;
+    revert(state, &entry.op)?;
+    let _ = handle.emit(
+        "outboxFailed",
+        OutboxFailure {
+            uuid: entry.uuid,
+            op: entry.op,
+            message,
+        },
+    );
+    Ok(())
+}
+
+async fn replay(state: &AppData, entry: &OutboxEntry) -> Result<Option<Message>, ResponseError> {
+    let client = &state.client;
+    match entry.op {
+        OutboxOp::Send {
+            bubble_id,
+            user_id,
+            ref message,
+            parent_message_id,
+        } => {
+            // The last try may have gone through with only the response lost
+            if entry.attempted {
+                let history = client.bubble_history(bubble_id, None).await?;
+                if let Some(sent) = history
+                    .messages
+                    .into_iter()
+                    .find(|m| m.uuid.as_deref() == Some(entry.uuid.as_str()))
+                {
+                    return Ok(Some(sent));
+                }
+            }
+            let response = client
+                .send_message_with_uuid(
+                    user_id,
+                    bubble_id,
+                    message.clone(),
+                    parent_message_id,
+                    &entry.uuid,
+                )
+                .await?;
+            Ok(Some(response.message))
+        }
+        OutboxOp::Edit {
+            message_id,
+            ref message,
+        } => {
+            let response = client.edit_message(message_id, message.clone()).await?;
+            Ok(Some(response.message))
+        }
+        OutboxOp::Delete { message_id } => match client.delete_message(message_id).await {
+            Ok(_) => Ok(None),
+            // Someone else got to it first
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        },
+        OutboxOp::Reaction {
+            message_id,
+            reaction_id,
+            active,
+        } => {
+            let reaction_type = ReactionType::from(reaction_id as i32);
+            let response = if active {
+                client.add_reaction(message_id, reaction_type).await?
+            } else {
+                client.remove_reaction(message_id, reaction_type).await?
+            };
+            Ok(Some(response.message))
+        }
+    }
+}
+
+/// Put what the server made of a change in place of the optimistic one
+async fn finish(
+    state: &AppData,
+    op: &OutboxOp,
+    message: Option<Message>,
+) -> Result<(), BackendError> {
+    if let OutboxOp::Delete { message_id } = *op {
+        state.persist(|store| store.remove_message(message_id));
+        return Ok(());
+    }
+    let Some(mut message) = message else {
+        return Ok(());
+    };
+    encryption::decrypt_messages(state, std::slice::from_mut(&mut message)).await;
+    state.persist(|store| store.upsert_messages([&message]));
+    let current_channel = state
+        .current_channel
+        .read()
+        .map_err(|_| BackendError::RwLockReadError)?
+        .id;
+    let mut message_list = state
+        .message_list
+        .write()
+        .map_err(|_| BackendError::RwLockWriteError)?;
+    match message_list.iter_mut().find(|m| m.id == message.id) {
+        Some(existing) => *existing = message,
+        // The pusher event for a sent message may have beaten the response here
+        None if matches!(op, OutboxOp::Send { .. }) && message.bubble_id == current_channel => {
+            message_list.insert(0, message)
+        }
+        None => {}
+    }
+    // Later changes to the same message are still queued
+    apply_pending(state, &mut message_list)
+}
+
+/// Undo the optimistic change of a dropped entry with the copy in the store
+fn revert(state: &AppData, op: &OutboxOp) -> Result<(), BackendError> {
+    let message_id = match *op {
+        OutboxOp::Send { .. } => return Ok(()),
+        OutboxOp::Edit { message_id, .. }
+        | OutboxOp::Delete { message_id }
+        | OutboxOp::Reaction { message_id, .. } => message_id,
+    };
+    let Some(message) = state.store.message(message_id)? else {
+        return Ok(());
+    };
+    let mut restored = vec![message];
+    apply_pending(state, &mut restored)?;
+    let Some(message) = restored.pop() else {
+        return Ok(());
+    };
+    let current_channel = state
+        .current_channel
+        .read()
+        .map_err(|_| BackendError::RwLockReadError)?
+        .id;
+    let mut message_list = state
+        .message_list
+        .write()
+        .map_err(|_| BackendError::RwLockWriteError)?;
+    match message_list.iter_mut().find(|m| m.id == message_id) {
+        Some(existing) => *existing = message,
+        // Deleted messages go back where they were, newest first
+        None if message.bubble_id == current_channel => {
+            let index = message_list
+                .iter()
+                .position(|m| m.id < message_id)
+                .unwrap_or(message_list.len());
+            message_list.insert(index, message);
+        }
+        None => {}
+    }
+    Ok(())
+}
+
+/// A message we sent showed up over pusher, so its queued send is done
+pub fn reconcile_sent(
+    handle: &AppHandle,
+    state: &AppData,
+    message: &Message,
+) -> Result<(), BackendError> {
+    match &message.uuid {
+        Some(uuid) if message.user_id == state.user_info.id => remove(handle, state, uuid),
+        _ => Ok(()),
+    }
+}
+
+fn remove(handle: &AppHandle, state: &AppData, uuid: &str) -> Result<(), BackendError> {
+    let mut outbox = state
+        .outbox
+        .write()
+        .map_err(|_| BackendError::RwLockWriteError)?;
+    let queued = outbox.len();
+    outbox.retain(|entry| entry.uuid != uuid);
+    if outbox.len() == queued {
+        return Ok(());
+    }
+    drop(outbox);
+    state.persist(|store| store.remove_outbox(uuid));
+    let _ = handle.emit("outboxUpdate", ());
+    Ok(())
+}
@@ -1,4 +1,6 @@
 use crate::state;
+use client::ErrorKind;
+use serde_json::json;
 use tauri::ipc::InvokeError;
 
 #[derive(Debug, thiserror::Error)]
@@ -17,16 +19,42 @@ pub enum BackendError {
     SettingsError(#[from] settings::SettingsError),
     #[error("Updater error: {0}")]
     UpdaterError(#[from] updater::UpdateError),
-    // #[error("Search error: {0}")]
-    // SearchError(#[from] )
+    #[error("Store error: {0}")]
+    StoreError(#[from] store::StoreError),
+    #[error("Search error: {0}")]
+    SearchError(#[from] search::SearchError),
+    #[error("Key error: {0}")]
+    KeyError(#[from] encrypt::KeyError),
+    #[error("Key lookup error: {0}")]
+    LookupError(#[from] encrypt::LookupError),
+    #[error("Decryption error: {0}")]
+    DecryptionError(#[from] encrypt::DecryptionError),
+    #[error("Key backup error: {0}")]
+    BackupError(#[from] encrypt::BackupError),
+    #[error("Can't tell which bubble message {0} is in to encrypt the edit")]
+    UnknownBubble(u64),
+    #[error("Attachments can't be sent to encrypted bubbles yet")]
+    EncryptedAttachments,
     #[error("RwLockRead Error")]
     RwLockReadError,
     #[error("RwLockWrite Error")]
     RwLockWriteError,
 }
 
+impl BackendError {
+    /// What kind of api failure this is, so the frontend can react to it,
+    /// e.g. by asking the user to log in again on [`ErrorKind::Unauthorized`].
+    pub fn kind(&self) -> Option<ErrorKind> {
+        match self {
+            BackendError::NotAuthenticated => Some(ErrorKind::Unauthorized),
+            BackendError::ResponseError(e) => Some(e.kind()),
+            _ => None,
+        }
+    }
+}
+
 impl Into<InvokeError> for BackendError {
     fn into(self) -> InvokeError {
-        InvokeError::from_error(self)
+        InvokeError(json!({ "kind": self.kind(), "message": self.to_string() }))
     }
 }
@@ -1,17 +1,25 @@
 use client::{
-    Announcement, Bubble, BubbleStats, Membership, Message, ProntoClient, Task, UserInfo,
+    Announcement, Bubble, BubbleStats, Membership, Message, MessageTranslation, ProntoClient, Task,
+    UserInfo,
 };
 use dashmap::DashMap;
-use settings::Settings;
+use encrypt::{BubbleKeys, DeviceLink, PublicLookupService, TrustStore};
+use log::warn;
+use search::{IndexUpdate, Maintenance, MessageIndexer};
+use settings::{Auth, Settings};
 use std::cell::UnsafeCell;
-use std::sync::atomic::AtomicI64;
+use std::sync::atomic::{AtomicI64, AtomicUsize};
 use std::sync::{Arc, RwLock, atomic::AtomicBool};
+use store::{OutboxEntry, Store};
 use thiserror::Error;
+use tokio::sync::{Notify, watch};
 
 #[derive(Copy, Clone, Debug, Error)]
 pub enum UnlockError {
     #[error("Not loaded")]
     NotLoaded,
+    #[error("No organization at index {0}")]
+    NoSuchOrganization(usize),
 }
 
 #[derive(Clone)]
@@ -21,7 +29,10 @@ pub struct ChannelUsers {
     pub users: Vec<u64>,
 }
 
+/// Everything for one account, there is one per logged in organization
 pub struct AppData {
+    /// The saved login this was loaded from
+    pub account: Auth,
     pub user_info: UserInfo,
     pub users: DashMap<u64, UserInfo>,
     pub client: Arc<ProntoClient>,
@@ -30,17 +41,57 @@ pub struct AppData {
     pub current_channel: RwLock<Bubble>,
     pub message_list: RwLock<Vec<Message>>,
     pub parent_messages: RwLock<Vec<Message>>,
+    /// Translations keyed by message id
+    pub translations: DashMap<u64, MessageTranslation>,
     pub announcements: RwLock<Vec<Announcement>>,
     pub tasks: RwLock<Vec<Task>>,
     // TODO: include thread id too
     pub typing_users: DashMap<u64, Vec<u64>>,
     pub is_typing: AtomicBool,
     pub settings: RwLock<Settings>,
+    /// Local copy of the above that survives restarts
+    pub store: Arc<Store>,
+    /// Changes waiting to reach the server, oldest first
+    pub outbox: RwLock<Vec<OutboxEntry>>,
+    /// Wakes the outbox task when something is queued
+    pub outbox_queued: Notify,
+    /// Set by the search task while message search is on
+    pub search_indexer: RwLock<Option<Arc<MessageIndexer>>>,
+    /// Asked for in the settings, set until the search task is done with it
+    pub search_maintenance: RwLock<Option<Maintenance>>,
+    /// Public keys of others for encrypted DMs, from the directory in the settings
+    pub key_lookup: Arc<PublicLookupService>,
+    /// Public keys of others as first seen, shared by every account
+    pub trust_store: Arc<TrustStore>,
+    /// Keys of the encrypted bubbles this account is in
+    pub bubble_keys: Arc<BubbleKeys>,
+    /// The one time key of a link to another device, while waiting for its secret key
+    pub device_link: RwLock<Option<DeviceLink>>,
+}
+
+impl AppData {
+    /// Write through to the local store.
+    /// The store is only a cache, so failures are logged instead of failing whatever changed the state.
+    pub fn persist(&self, f: impl FnOnce(&Store) -> store::Result<()>) {
+        if let Err(e) = f(&self.store) {
+            warn!("Failed to update the local store: {e}");
+        }
+    }
+
+    /// Hand a live change to the search index, if message search is on
+    pub fn index(&self, update: IndexUpdate) {
+        if let Ok(indexer) = self.search_indexer.read()
+            && let Some(indexer) = indexer.as_ref()
+        {
+            indexer.queue(update);
+        }
+    }
 }
 
 pub enum InnerAppState {
     Unloaded,
-    Loaded(AppData),
+    /// Boxed so organizations added later don't move the ones handed out before
+    Loaded(Vec<Box<AppData>>),
 }
 
 /// AppStateV2 is a non-bottlenecked version of AppState
@@ -49,6 +100,10 @@ pub struct AppState {
     pub loaded: Arc<AtomicBool>,
     inner_lock: Arc<AtomicI64>,
     inner: Arc<UnsafeCell<InnerAppState>>,
+    /// Index of the organization the ui shows
+    current: Arc<AtomicUsize>,
+    /// How many organizations are loaded, so tasks can start on the ones added later
+    organization_count: Arc<watch::Sender<usize>>,
 }
 
 unsafe impl Send for AppState {}
@@ -60,52 +115,135 @@ impl AppState {
             loaded: Arc::new(AtomicBool::new(false)),
             inner_lock: Arc::new(AtomicI64::new(0)),
             inner: Arc::new(UnsafeCell::new(InnerAppState::Unloaded)),
+            current: Arc::new(AtomicUsize::new(0)),
+            organization_count: Arc::new(watch::Sender::new(0)),
         }
     }
 
     fn read_lock(&self) {
-        while self.inner_lock.load(std::sync::atomic::Ordering::Relaxed) == -1 {
+        loop {
+            let current = self.inner_lock.load(std::sync::atomic::Ordering::Acquire);
+            if current != -1
+                && self
+                    .inner_lock
+                    .compare_exchange_weak(
+                        current,
+                        current + 1,
+                        std::sync::atomic::Ordering::Acquire,
+                        std::sync::atomic::Ordering::Relaxed,
+                    )
+                    .is_ok()
+            {
+                return;
+            }
             std::hint::spin_loop();
         }
-        let current = self.inner_lock.load(std::sync::atomic::Ordering::Relaxed);
-        self.inner_lock
-            .store(current + 1, std::sync::atomic::Ordering::Relaxed);
     }
 
     fn unlock_read(&self) {
-        let current = self.inner_lock.load(std::sync::atomic::Ordering::Relaxed);
         self.inner_lock
-            .store(current - 1, std::sync::atomic::Ordering::Relaxed);
+            .fetch_sub(1, std::sync::atomic::Ordering::Release);
+    }
+
+    /// Waits for the readers to finish
+    fn write_lock(&self) {
+        while self
+            .inner_lock
+            .compare_exchange_weak(
+                0,
+                -1,
+                std::sync::atomic::Ordering::Acquire,
+                std::sync::atomic::Ordering::Relaxed,
+            )
+            .is_err()
+        {
+            std::hint::spin_loop();
+        }
+    }
+
+    fn unlock_write(&self) {
+        self.inner_lock
+            .store(0, std::sync::atomic::Ordering::Release);
     }
 
     pub fn is_loaded(&self) -> bool {
         self.loaded.load(std::sync::atomic::Ordering::Relaxed)
     }
 
+    /// The organization the ui shows
     pub fn try_inner(&self) -> Result<&AppData, UnlockError> {
+        self.organization(self.current())
+    }
+
+    /// Every loaded organization, in the order they were added
+    pub fn organizations(&self) -> Result<Vec<&AppData>, UnlockError> {
         self.read_lock();
-        // TODO: Return before unlocking
-        if self.loaded.load(std::sync::atomic::Ordering::Relaxed) {
-            self.unlock_read();
-            match unsafe { &*self.inner.get() } {
-                InnerAppState::Loaded(data) => Ok(data),
-                InnerAppState::Unloaded => Err(UnlockError::NotLoaded),
-            }
-        } else {
-            self.unlock_read();
-            Err(UnlockError::NotLoaded)
-        }
+        // Only the list is guarded, the organizations stay put once added
+        let organizations = match unsafe { &*self.inner.get() } {
+            InnerAppState::Loaded(data) => Ok(data.iter().map(|data| &**data).collect()),
+            InnerAppState::Unloaded => Err(UnlockError::NotLoaded),
+        };
+        self.unlock_read();
+        organizations
     }
 
-    pub fn load(&self, data: AppData) {
-        self.inner_lock
-            .store(-1, std::sync::atomic::Ordering::Relaxed);
+    pub fn organization(&self, index: usize) -> Result<&AppData, UnlockError> {
+        self.read_lock();
+        let organization = match unsafe { &*self.inner.get() } {
+            InnerAppState::Loaded(data) => data
+                .get(index)
+                .map(|data| &**data)
+                .ok_or(UnlockError::NoSuchOrganization(index)),
+            InnerAppState::Unloaded => Err(UnlockError::NotLoaded),
+        };
+        self.unlock_read();
+        organization
+    }
+
+    /// Notified with the number of organizations whenever one is added
+    pub fn watch_organizations(&self) -> watch::Receiver<usize> {
+        self.organization_count.subscribe()
+    }
+
+    pub fn current(&self) -> usize {
+        self.current.load(std::sync::atomic::Ordering::Relaxed)
+    }
+
+    /// Show another organization in the ui
+    pub fn switch(&self, index: usize) -> Result<(), UnlockError> {
+        self.organization(index)?;
+        self.current
+            .store(index, std::sync::atomic::Ordering::Relaxed);
+        Ok(())
+    }
+
+    pub fn load(&self, data: Vec<AppData>, current: usize) {
+        let count = data.len();
+        self.write_lock();
         unsafe {
-            *self.inner.get() = InnerAppState::Loaded(data);
+            *self.inner.get() = InnerAppState::Loaded(data.into_iter().map(Box::new).collect());
         }
-        self.inner_lock
-            .store(0, std::sync::atomic::Ordering::Relaxed);
+        self.current
+            .store(current, std::sync::atomic::Ordering::Relaxed);
+        self.unlock_write();
         self.loaded
             .store(true, std::sync::atomic::Ordering::Relaxed);
+        self.organization_count.send_replace(count);
+    }
+
+    /// Add an organization logged in while running, returning its index
+    pub fn add(&self, data: AppData) -> Result<usize, UnlockError> {
+        self.write_lock();
+        let index = match unsafe { &mut *self.inner.get() } {
+            InnerAppState::Loaded(organizations) => {
+                organizations.push(Box::new(data));
+                Ok(organizations.len() - 1)
+            }
+            InnerAppState::Unloaded => Err(UnlockError::NotLoaded),
+        };
+        self.unlock_write();
+        let index = index?;
+        self.organization_count.send_replace(index + 1);
+        Ok(index)
     }
 }
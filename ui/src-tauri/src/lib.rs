@@ -54,11 +54,16 @@ pub fn run() {
             get_messages,
             get_more_messages,
             get_parent_messages,
+            get_message_translation,
             load_messages,
             edit_message,
             send_message,
+            send_files,
             set_reaction_state,
             delete_message,
+            get_outbox,
+            get_organizations,
+            switch_organization,
             get_channel_users,
             load_channel_users,
             get_settings,
@@ -75,6 +80,19 @@ pub fn run() {
             create_dm,
             create_bubble,
             user_search,
+            search_local,
+            maintain_search_index,
+            export_public_key,
+            has_public_key,
+            encrypt_bubble,
+            is_bubble_encrypted,
+            get_key_trust,
+            verify_public_key,
+            export_key_backup,
+            import_key_backup,
+            start_device_link,
+            link_device,
+            finish_device_link,
             get_announcements,
             mark_announcement_read,
             get_tasks,
@@ -11,6 +11,7 @@ mod extension {
         Ok(())
     }
 }
+mod outbox;
 mod proxy;
 mod pusher;
 mod search;
@@ -24,6 +25,7 @@ use ui_lib::AppState;
 pub async fn task_thread(handle: AppHandle, context: AppState) {
     // spawn tasks
     let f1 = tokio::task::spawn({
+        let handle = handle.clone();
         let context = context.clone();
         async move {
             if let Err(e) = pusher::run(handle, context).await {
@@ -31,14 +33,20 @@ pub async fn task_thread(handle: AppHandle, context: AppState) {
             }
         }
     });
-    let f2 = tokio::task::spawn(async move {
-        if let Err(e) = search::run().await {
-            error!("Search Task Error: {:?}", e);
+    let f2 = tokio::task::spawn({
+        let context = context.clone();
+        async move {
+            if let Err(e) = search::run(context).await {
+                error!("Search Task Error: {:?}", e);
+            }
         }
     });
-    let f3 = tokio::task::spawn(async move {
-        if let Err(e) = proxy::run(context).await {
-            error!("Proxy Task Error: {:?}", e);
+    let f3 = tokio::task::spawn({
+        let context = context.clone();
+        async move {
+            if let Err(e) = proxy::run(context).await {
+                error!("Proxy Task Error: {:?}", e);
+            }
         }
     });
     let f4 = tokio::task::spawn(async move {
@@ -46,5 +54,10 @@ pub async fn task_thread(handle: AppHandle, context: AppState) {
             error!("Extension Task Error: {:?}", e);
         }
     });
-    let _ = join!(f1, f2, f3, f4);
+    let f5 = tokio::task::spawn(async move {
+        if let Err(e) = outbox::run(handle, context).await {
+            error!("Outbox Task Error: {:?}", e);
+        }
+    });
+    let _ = join!(f1, f2, f3, f4, f5);
 }
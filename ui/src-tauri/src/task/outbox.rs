@@ -0,0 +1,69 @@
+use log::{error, info};
+use std::time::Duration;
+use tauri::AppHandle;
+use thiserror::Error;
+use ui_lib::{AppState, state::UnlockError};
+
+/// First wait after the server couldn't be reached, doubled up to [`MAX_RETRY_DELAY`]
+const MIN_RETRY_DELAY: Duration = Duration::from_secs(2);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Error)]
+pub enum OutboxThreadError {
+    #[error("Unlock error: {0}")]
+    UnlockError(#[from] UnlockError),
+}
+
+/// Replays the outbox of every organization, including those added while running
+pub async fn run(handle: AppHandle, context: AppState) -> Result<(), OutboxThreadError> {
+    while !context.is_loaded() {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+
+    let mut organizations = context.watch_organizations();
+    let mut started = 0;
+    loop {
+        let count = *organizations.borrow_and_update();
+        for index in started..count {
+            let handle = handle.clone();
+            let context = context.clone();
+            tokio::spawn(async move {
+                if let Err(e) = run_organization(&handle, &context, index).await {
+                    error!("Outbox of organization {index} stopped: {e}");
+                }
+            });
+        }
+        started = count;
+        if organizations.changed().await.is_err() {
+            return Ok(());
+        }
+    }
+}
+
+/// Replays the outbox whenever something is queued, retrying with a backoff while offline.
+async fn run_organization(
+    handle: &AppHandle,
+    context: &AppState,
+    index: usize,
+) -> Result<(), OutboxThreadError> {
+    let state = context.organization(index)?;
+
+    let mut delay = MIN_RETRY_DELAY;
+    loop {
+        match ui_handlers::flush_outbox(handle, state).await {
+            Ok(()) => {
+                delay = MIN_RETRY_DELAY;
+                state.outbox_queued.notified().await;
+            }
+            Err(e) => {
+                info!("Outbox waiting {delay:?} to retry: {e}");
+                // Something newly queued is worth an early try
+                tokio::select! {
+                    _ = tokio::time::sleep(delay) => {}
+                    _ = state.outbox_queued.notified() => {}
+                }
+                delay = (delay * 2).min(MAX_RETRY_DELAY);
+            }
+        }
+    }
+}
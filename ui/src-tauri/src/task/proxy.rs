@@ -1,17 +1,21 @@
 use client::ProntoClient;
 use hyper::body::Incoming;
+use hyper::header::{CACHE_CONTROL, CONTENT_TYPE};
 use hyper::server::conn::http1;
 use hyper::service::Service;
-use hyper::{Request, Response};
+use hyper::{Request, Response, StatusCode};
 use hyper_util::rt::TokioIo;
 use log::error;
+use media_cache::{CacheError, MediaCache};
+use settings::Settings;
 use std::fmt;
 use std::fmt::{Debug, Display, Formatter};
 use std::future::Future;
 use std::net::SocketAddr;
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::net::TcpListener;
-use ui_lib::AppState;
+use ui_lib::{AppState, state::UnlockError};
 
 const PORT: u16 = 10521;
 
@@ -48,12 +52,97 @@ impl std::error::Error for ServiceHandlerError {
 }
 
 pub struct ServiceHandler {
-    client: Arc<ProntoClient>,
+    context: AppState,
+    cache: Arc<MediaCache>,
 }
 
 impl ServiceHandler {
-    pub fn new(client: Arc<ProntoClient>) -> Self {
-        Self { client }
+    pub fn new(context: AppState, cache: Arc<MediaCache>) -> Self {
+        Self { context, cache }
+    }
+}
+
+/// The client of the organization `url` belongs to, or of the current one for urls on no organization
+fn client_for(context: &AppState, url: &str) -> Result<Arc<ProntoClient>, UnlockError> {
+    let origin_of = |url: &str| reqwest::Url::parse(url).ok().map(|url| url.origin());
+    if let Some(origin) = origin_of(url) {
+        for state in context.organizations()? {
+            if origin_of(&state.client.api_base_url).is_some_and(|api| api == origin) {
+                return Ok(state.client.clone());
+            }
+        }
+    }
+    Ok(context.try_inner()?.client.clone())
+}
+
+/// Where a request that isn't for media goes: its path and query on the api's origin.
+/// Only the path and query of the request are used, so the token never leaves the api's host.
+fn passthrough_url(api_base_url: &str, req: &Request<Incoming>) -> Option<reqwest::Url> {
+    let api = reqwest::Url::parse(api_base_url).ok()?;
+    let mut url = api.clone();
+    url.set_path(req.uri().path());
+    url.set_query(req.uri().query());
+    (url.origin() == api.origin()).then_some(url)
+}
+
+/// `/media?url=<url>[&thumbnail=<size>]` is answered from the media cache
+fn media_query(req: &Request<Incoming>) -> Option<(String, Option<u32>)> {
+    if req.uri().path() != "/media" {
+        return None;
+    }
+    // Only used to decode the query
+    let uri = reqwest::Url::parse(&format!("http://localhost{}", req.uri())).ok()?;
+    let mut url = None;
+    let mut thumbnail = None;
+    for (key, value) in uri.query_pairs() {
+        match key.as_ref() {
+            "url" => url = Some(value.into_owned()),
+            "thumbnail" => thumbnail = value.parse().ok(),
+            _ => {}
+        }
+    }
+    Some((url?, thumbnail))
+}
+
+async fn open_media(
+    cache: &MediaCache,
+    client: &ProntoClient,
+    url: &str,
+    thumbnail: Option<u32>,
+) -> Result<(String, tokio::fs::File), CacheError> {
+    let media = match thumbnail {
+        Some(size) => cache.thumbnail(client, url, size).await?,
+        None => cache.get(client, url).await?,
+    };
+    // Streamed from disk rather than read into memory, it may be a large video
+    let file = tokio::fs::File::open(&media.path).await?;
+    Ok((media.mime_type, file))
+}
+
+async fn serve_media(
+    cache: &MediaCache,
+    client: &ProntoClient,
+    url: &str,
+    thumbnail: Option<u32>,
+) -> Response<reqwest::Body> {
+    match open_media(cache, client, url, thumbnail).await {
+        Ok((mime_type, file)) => Response::builder()
+            .header(CONTENT_TYPE, mime_type)
+            .header(CACHE_CONTROL, "private, max-age=86400")
+            .body(file.into())
+            .unwrap(),
+        Err(e) => {
+            error!("Error serving media {url}: {e}");
+            let status = match &e {
+                CacheError::Request(e) => e.status().unwrap_or(StatusCode::BAD_GATEWAY),
+                CacheError::NotAnImage(_) | CacheError::InvalidUrl(_) => StatusCode::BAD_REQUEST,
+                CacheError::TooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+                _ => StatusCode::INTERNAL_SERVER_ERROR,
+            };
+            let mut response = Response::new(reqwest::Body::from(e.to_string()));
+            *response.status_mut() = status;
+            response
+        }
     }
 }
 
@@ -64,17 +153,32 @@ impl Service<Request<Incoming>> for ServiceHandler {
         std::pin::Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
 
     fn call(&self, req: Request<Incoming>) -> Self::Future {
-        let client = self.client.clone();
+        let context = self.context.clone();
+        let cache = self.cache.clone();
         Box::pin(async move {
+            let media = media_query(&req);
+            let url = media.as_ref().map_or("", |(url, _)| url.as_str());
+            let client = match client_for(&context, url) {
+                Ok(client) => client,
+                Err(e) => {
+                    let mut response = Response::new(reqwest::Body::from(e.to_string()));
+                    *response.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+                    return Ok(response);
+                }
+            };
+            if let Some((url, thumbnail)) = media {
+                return Ok(serve_media(&cache, &client, &url, thumbnail).await);
+            }
+            // Anything else goes to the organization shown in the ui
+            let Some(url) = passthrough_url(&client.api_base_url, &req) else {
+                error!("Refusing to proxy {}", req.uri());
+                let mut response = Response::new(reqwest::Body::from("Not a pronto url"));
+                *response.status_mut() = StatusCode::BAD_REQUEST;
+                return Ok(response);
+            };
             let response = client
                 .http_client
-                .request(
-                    req.method().clone(),
-                    format!(
-                        "https://stanfordohs.pronto.io{}",
-                        req.uri().clone().to_string()
-                    ),
-                )
+                .request(req.method().clone(), url)
                 .send()
                 .await
                 .map_err(|e| ServiceHandlerError { inner: e, req })?;
@@ -85,12 +189,13 @@ impl Service<Request<Incoming>> for ServiceHandler {
 
 pub async fn run(context: AppState) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     while !context.is_loaded() {
-        break;
+        tokio::time::sleep(Duration::from_millis(100)).await;
     }
-    let client = {
-        let state = context.try_inner()?;
-        state.client.clone()
-    };
+    let settings = Settings::load().await?;
+    // Shared by every organization, each request is downloaded with the client of its own
+    let cache = Arc::new(
+        MediaCache::open(settings.media_cache.path(), settings.media_cache.max_size).await?,
+    );
 
     let addr = SocketAddr::from(([127, 0, 0, 1], PORT));
 
@@ -107,12 +212,13 @@ pub async fn run(context: AppState) -> Result<(), Box<dyn std::error::Error + Se
 
         // Spawn a tokio task to serve multiple connections concurrently
         tokio::task::spawn({
-            let client = client.clone();
+            let context = context.clone();
+            let cache = cache.clone();
             async move {
                 // Finally, we bind the incoming connection to our service
                 if let Err(err) = http1::Builder::new()
                     // `service_fn` converts our function in a `Service`
-                    .serve_connection(io, ServiceHandler::new(client.clone()))
+                    .serve_connection(io, ServiceHandler::new(context, cache))
                     .await
                 {
                     error!("Error serving connection: {:?}", err);
@@ -1,13 +1,16 @@
-use client::Reactions;
+use client::{Bubble, Message, Reactions};
 use futures::future::join_all;
 use log::{error, info, warn};
 use notify_rust::{Notification, Timeout};
 use pusher::{
     PusherClient, PusherServerEventType, PusherServerMessage, PusherServerMessageWrapper,
 };
+use search::IndexUpdate;
 use settings::{Settings, SettingsError};
+use std::time::Duration;
 use tauri::{AppHandle, Emitter};
 use thiserror::Error;
+use tokio::sync::broadcast::error::RecvError;
 use ui_lib::{AppState, state::UnlockError};
 
 #[derive(Debug, Error)]
@@ -18,28 +21,51 @@ pub enum PusherThreadError {
     UnlockError(#[from] UnlockError),
 }
 
+/// Listens to pusher for every logged in organization, including those added while running
 pub async fn run(handle: AppHandle, context: AppState) -> Result<(), PusherThreadError> {
     while !context.is_loaded() {
-        // TODO: this is a busy loop, we should probably park or use a notifier
-        std::hint::spin_loop();
-        break;
+        tokio::time::sleep(Duration::from_millis(100)).await;
     }
 
+    let mut organizations = context.watch_organizations();
+    let mut started = 0;
+    loop {
+        let count = *organizations.borrow_and_update();
+        for index in started..count {
+            let handle = handle.clone();
+            let context = context.clone();
+            tokio::spawn(async move {
+                if let Err(e) = run_organization(handle, context, index).await {
+                    error!("Pusher of organization {index} stopped: {e}");
+                }
+            });
+        }
+        started = count;
+        if organizations.changed().await.is_err() {
+            return Ok(());
+        }
+    }
+}
+
+async fn run_organization(
+    handle: AppHandle,
+    context: AppState,
+    index: usize,
+) -> Result<(), PusherThreadError> {
     let pusher_client = {
-        let state = context.try_inner()?;
+        let state = context.organization(index)?;
         PusherClient::new(state.client.clone()).await
     };
     pusher_client.init().await;
     info!("Pusher client initialized");
     {
-        let state = context.try_inner()?;
+        let state = context.organization(index)?;
 
-        pusher_client
-            .subscribe(format!(
-                "private-organization.{}",
-                state.user_info.organizations[0].id
-            ))
-            .await;
+        for organization in &state.user_info.organizations {
+            pusher_client
+                .subscribe(format!("private-organization.{}", organization.id))
+                .await;
+        }
         pusher_client
             .subscribe(format!("private-user.{}", state.user_info.id))
             .await;
@@ -57,22 +83,28 @@ pub async fn run(handle: AppHandle, context: AppState) -> Result<(), PusherThrea
     // TODO: this object doesn't update instantly when a user changes a setting
     let settings = Settings::load().await?;
     let direct_mention = {
-        let state = context.try_inner()?;
+        let state = context.organization(index)?;
 
         format!("<@{}>", state.user_info.id)
     };
 
+    let mut server_messages = pusher_client.server_messages().await;
     loop {
-        let message = pusher_client.server_messages().await.recv().await;
+        let message = server_messages.recv().await;
         match message {
             Ok(PusherServerMessageWrapper::PusherServerMessage(message)) => {
                 match message {
                     PusherServerMessage::Event(ev) => {
                         match ev.event {
-                            PusherServerEventType::PusherServerMessageAddedEvent(event) => {
+                            PusherServerEventType::PusherServerMessageAddedEvent(mut event) => {
+                                ui_handlers::receive_messages(
+                                    context.organization(index)?,
+                                    std::slice::from_mut(&mut event.message),
+                                )
+                                .await;
                                 // TODO: Make sure app in not in foreground
                                 if settings.options.notifications {
-                                    let state = context.try_inner()?;
+                                    let state = context.organization(index)?;
 
                                     let channel_list = state.channel_list.read().unwrap();
                                     let channel = channel_list
@@ -117,7 +149,14 @@ pub async fn run(handle: AppHandle, context: AppState) -> Result<(), PusherThrea
                                             .unwrap();
                                     }
                                 }
-                                let state = context.try_inner()?;
+                                let state = context.organization(index)?;
+                                state.persist(|store| store.upsert_messages([&event.message]));
+                                state.index(IndexUpdate::Upsert(event.message.clone()));
+                                if let Err(e) =
+                                    ui_handlers::reconcile_sent(&handle, state, &event.message)
+                                {
+                                    warn!("Failed to reconcile the outbox: {e}");
+                                }
 
                                 if event.message.bubble_id
                                     == state.current_channel.read().unwrap().id
@@ -131,8 +170,20 @@ pub async fn run(handle: AppHandle, context: AppState) -> Result<(), PusherThrea
                                 }
                                 let _ = handle.emit("messageListUpdate", ());
                             }
-                            PusherServerEventType::PusherServerMessageUpdatedEvent(event) => {
-                                let state = context.try_inner()?;
+                            PusherServerEventType::PusherServerMessageUpdatedEvent(mut event) => {
+                                let state = context.organization(index)?;
+                                ui_handlers::receive_messages(
+                                    state,
+                                    std::slice::from_mut(&mut event.message),
+                                )
+                                .await;
+                                state.persist(|store| store.upsert_messages([&event.message]));
+                                state.index(IndexUpdate::Upsert(event.message.clone()));
+                                if let Err(e) =
+                                    ui_handlers::reconcile_sent(&handle, state, &event.message)
+                                {
+                                    warn!("Failed to reconcile the outbox: {e}");
+                                }
 
                                 if event.message.bubble_id
                                     == state.current_channel.read().unwrap().id
@@ -150,14 +201,19 @@ pub async fn run(handle: AppHandle, context: AppState) -> Result<(), PusherThrea
                                 }
                             }
                             PusherServerEventType::PusherServerMessageRemovedEvent(event) => {
-                                let state = context.try_inner()?;
+                                let state = context.organization(index)?;
+                                state.persist(|store| store.remove_message(event.message.id));
+                                state.index(IndexUpdate::Remove(event.message.id));
                                 let mut state_message_list = state.message_list.write().unwrap();
                                 state_message_list.retain(|m| m.id != event.message.id);
 
                                 let _ = handle.emit("messageListUpdate", ());
                             }
                             PusherServerEventType::PusherServerBubbleStatsEvent(event) => {
-                                let state = context.try_inner()?;
+                                let state = context.organization(index)?;
+                                state.persist(|store| {
+                                    event.stats.iter().try_for_each(|s| store.update_stats(s))
+                                });
                                 // double for loop (I can't think of a better way to do this)
                                 // time complexity is O(b*n) in all cases
                                 // Iterating through the event stats first would lead to a better average/best case complexity
@@ -173,7 +229,7 @@ pub async fn run(handle: AppHandle, context: AppState) -> Result<(), PusherThrea
                                 let _ = handle.emit("channelListUpdate", ());
                             }
                             PusherServerEventType::PusherServerUserPresenceEvent(event) => {
-                                let state = context.try_inner()?;
+                                let state = context.organization(index)?;
                                 for mut user in state.users.iter_mut() {
                                     if user.id == event.user_id {
                                         user.online = event.is_online;
@@ -184,7 +240,8 @@ pub async fn run(handle: AppHandle, context: AppState) -> Result<(), PusherThrea
                                 let _ = handle.emit("channelListUpdate", ());
                             }
                             PusherServerEventType::PusherServerUserUpdatedEvent(event) => {
-                                let state = context.try_inner()?;
+                                let state = context.organization(index)?;
+                                state.persist(|store| store.upsert_users([&event.user]));
                                 let user = state.users.get_mut(&event.user.id);
                                 if let Some(mut user) = user {
                                     *user = event.user;
@@ -196,11 +253,8 @@ pub async fn run(handle: AppHandle, context: AppState) -> Result<(), PusherThrea
                                 let _ = handle.emit("channelListUpdate", ());
                             }
                             PusherServerEventType::PusherServerReactionAddedEvent(event) => {
-                                let state = context.try_inner()?;
-                                let mut message_list = state.message_list.write().unwrap();
-                                let message =
-                                    message_list.iter_mut().find(|m| m.id == event.message_id);
-                                if let Some(message) = message {
+                                let state = context.organization(index)?;
+                                let add = |message: &mut Message| {
                                     if message
                                         .reactions
                                         .iter_mut()
@@ -217,15 +271,21 @@ pub async fn run(handle: AppHandle, context: AppState) -> Result<(), PusherThrea
                                             users: vec![event.user_id],
                                         });
                                     }
-                                }
-                                let _ = handle.emit("messageListUpdate", ());
-                            }
-                            PusherServerEventType::PusherServerReactionRemovedEvent(event) => {
-                                let state = context.try_inner()?;
+                                };
+                                state.persist(|store| {
+                                    store.update_message(event.message_id, add).map(|_| ())
+                                });
                                 let mut message_list = state.message_list.write().unwrap();
                                 let message =
                                     message_list.iter_mut().find(|m| m.id == event.message_id);
                                 if let Some(message) = message {
+                                    add(message);
+                                }
+                                let _ = handle.emit("messageListUpdate", ());
+                            }
+                            PusherServerEventType::PusherServerReactionRemovedEvent(event) => {
+                                let state = context.organization(index)?;
+                                let remove = |message: &mut Message| {
                                     if let Some(reaction) = message
                                         .reactions
                                         .iter_mut()
@@ -234,12 +294,36 @@ pub async fn run(handle: AppHandle, context: AppState) -> Result<(), PusherThrea
                                         reaction.users.retain(|u| u != &event.user_id);
                                         reaction.count = event.count;
                                     }
+                                };
+                                state.persist(|store| {
+                                    store.update_message(event.message_id, remove).map(|_| ())
+                                });
+                                let mut message_list = state.message_list.write().unwrap();
+                                let message =
+                                    message_list.iter_mut().find(|m| m.id == event.message_id);
+                                if let Some(message) = message {
+                                    remove(message);
                                 }
 
                                 let _ = handle.emit("messageListUpdate", ());
                             }
                             PusherServerEventType::PusherServerMembershipUpdatedEvent(event) => {
-                                let state = context.try_inner()?;
+                                // Looking up every member's key takes a while, events keep coming meanwhile
+                                let rekey_context = context.clone();
+                                let bubble_id = event.membership.bubble_id;
+                                tokio::spawn(async move {
+                                    let result = match rekey_context.organization(index) {
+                                        Ok(state) => {
+                                            ui_handlers::rekey_bubble(state, bubble_id).await
+                                        }
+                                        Err(e) => Err(e.into()),
+                                    };
+                                    if let Err(e) = result {
+                                        warn!("Failed to rekey the bubble: {e}");
+                                    }
+                                });
+                                let state = context.organization(index)?;
+                                state.persist(|store| store.update_membership(&event.membership));
                                 let mut state_channel_list = state.channel_list.write().unwrap();
 
                                 for (bubble, _, membership) in state_channel_list.iter_mut() {
@@ -254,7 +338,13 @@ pub async fn run(handle: AppHandle, context: AppState) -> Result<(), PusherThrea
                                 let _ = handle.emit("channelListUpdate", ());
                             }
                             PusherServerEventType::PusherServerAnnouncementAddedEvent(event) => {
-                                let state = context.try_inner()?;
+                                let state = context.organization(index)?;
+                                state.persist(|store| {
+                                    store.upsert_announcement(&event.announcement)
+                                });
+                                state.index(IndexUpdate::UpsertAnnouncement(
+                                    event.announcement.clone(),
+                                ));
                                 let mut announcements = state.announcements.write().unwrap();
 
                                 announcements.insert(0, event.announcement.clone());
@@ -262,7 +352,11 @@ pub async fn run(handle: AppHandle, context: AppState) -> Result<(), PusherThrea
                                 let _ = handle.emit("announcementListUpdate", ());
                             }
                             PusherServerEventType::PusherServerAnnouncementRemovedEvent(event) => {
-                                let state = context.try_inner()?;
+                                let state = context.organization(index)?;
+                                state.persist(|store| {
+                                    store.remove_announcement(event.announcement_id)
+                                });
+                                state.index(IndexUpdate::RemoveAnnouncement(event.announcement_id));
 
                                 let mut announcements = state.announcements.write().unwrap();
                                 announcements.retain(|a| a.id != event.announcement_id);
@@ -270,7 +364,13 @@ pub async fn run(handle: AppHandle, context: AppState) -> Result<(), PusherThrea
                                 let _ = handle.emit("announcementListUpdate", ());
                             }
                             PusherServerEventType::PusherServerAnnouncementUpdatedEvent(event) => {
-                                let state = context.try_inner()?;
+                                let state = context.organization(index)?;
+                                state.persist(|store| {
+                                    store.upsert_announcement(&event.announcement)
+                                });
+                                state.index(IndexUpdate::UpsertAnnouncement(
+                                    event.announcement.clone(),
+                                ));
 
                                 let mut announcements = state.announcements.write().unwrap();
                                 let announcement = announcements
@@ -283,7 +383,7 @@ pub async fn run(handle: AppHandle, context: AppState) -> Result<(), PusherThrea
                                 let _ = handle.emit("announcementListUpdate", ());
                             }
                             PusherServerEventType::PusherServerUserTypingEvent(event) => {
-                                let state = context.try_inner()?;
+                                let state = context.organization(index)?;
 
                                 let channel_id =
                                     ev.channel.split(".").nth(1).unwrap().parse().unwrap();
@@ -295,7 +395,7 @@ pub async fn run(handle: AppHandle, context: AppState) -> Result<(), PusherThrea
                                 let _ = handle.emit("typingListUpdate", ());
                             }
                             PusherServerEventType::PusherServerUserStoppedTypingEvent(event) => {
-                                let state = context.try_inner()?;
+                                let state = context.organization(index)?;
 
                                 let channel_id =
                                     ev.channel.split(".").nth(1).unwrap().parse().unwrap();
@@ -305,7 +405,9 @@ pub async fn run(handle: AppHandle, context: AppState) -> Result<(), PusherThrea
                                 let _ = handle.emit("typingListUpdate", ());
                             }
                             PusherServerEventType::PusherServerTaskUpdatedEvent(event) => {
-                                let state = context.try_inner()?;
+                                let state = context.organization(index)?;
+                                state.persist(|store| store.upsert_task(&event.task));
+                                state.index(IndexUpdate::UpsertTask(event.task.clone()));
                                 let mut tasks = state.tasks.write().unwrap();
                                 let task = tasks.iter_mut().find(|t| t.id == event.task.id);
                                 if let Some(task) = task {
@@ -314,6 +416,74 @@ pub async fn run(handle: AppHandle, context: AppState) -> Result<(), PusherThrea
 
                                 let _ = handle.emit("taskListUpdate", ());
                             }
+                            PusherServerEventType::PusherServerCategoryUpdatedEvent(event) => {
+                                let state = context.organization(index)?;
+                                let is_updated = |bubble: &Bubble| {
+                                    bubble
+                                        .category
+                                        .as_ref()
+                                        .is_some_and(|c| c.id == event.category.id)
+                                };
+
+                                let mut state_channel_list = state.channel_list.write().unwrap();
+                                for (bubble, _, _) in state_channel_list.iter_mut() {
+                                    if is_updated(bubble) {
+                                        bubble.category = Some(event.category.clone());
+                                        state.persist(|store| store.upsert_bubble(bubble));
+                                    }
+                                }
+                                let mut current_channel = state.current_channel.write().unwrap();
+                                if is_updated(&current_channel) {
+                                    current_channel.category = Some(event.category.clone());
+                                }
+
+                                let _ = handle.emit("channelListUpdate", ());
+                            }
+                            PusherServerEventType::PusherServerBubbleChangedEvent(event) => {
+                                // The event only carries the id, so the bubble has to be refetched
+                                let client = context.organization(index)?.client.clone();
+                                let bubble = match client.bubble_info(event.bubble.id).await {
+                                    Ok(info) => info.bubble,
+                                    Err(e) => {
+                                        error!("Failed to fetch changed bubble: {:?}", e);
+                                        continue;
+                                    }
+                                };
+                                let state = context.organization(index)?;
+
+                                let mut state_channel_list = state.channel_list.write().unwrap();
+                                if let Some((state_bubble, _, _)) = state_channel_list
+                                    .iter_mut()
+                                    .find(|(b, _, _)| b.id == bubble.id)
+                                {
+                                    state.persist(|store| store.upsert_bubble(&bubble));
+                                    *state_bubble = bubble.clone();
+                                }
+                                let mut current_channel = state.current_channel.write().unwrap();
+                                if current_channel.id == bubble.id {
+                                    *current_channel = bubble;
+                                }
+
+                                let _ = handle.emit("channelListUpdate", ());
+                            }
+                            PusherServerEventType::PusherServerBubbleRemovedEvent(event) => {
+                                let state = context.organization(index)?;
+                                state.persist(|store| store.remove_bubble(event.bubble.id));
+                                let mut state_channel_list = state.channel_list.write().unwrap();
+                                state_channel_list.retain(|(b, _, _)| b.id != event.bubble.id);
+
+                                let _ = handle.emit("channelListUpdate", ());
+                            }
+                            PusherServerEventType::PusherServerMessageTranslationAddedEvent(
+                                event,
+                            ) => {
+                                let state = context.organization(index)?;
+                                state
+                                    .translations
+                                    .insert(event.translation.message_id, event.translation);
+
+                                let _ = handle.emit("messageListUpdate", ());
+                            }
                             // TODO: handle other
                             _ => {}
                         }
@@ -327,6 +497,16 @@ pub async fn run(handle: AppHandle, context: AppState) -> Result<(), PusherThrea
                     _ => {}
                 }
             }
+            Ok(PusherServerMessageWrapper::Disconnected) => {
+                warn!("Lost connection to pusher, reconnecting");
+            }
+            Ok(PusherServerMessageWrapper::Reconnected) => {
+                info!("Reconnected to pusher");
+            }
+            Err(RecvError::Closed) => return Ok(()),
+            Err(RecvError::Lagged(skipped)) => {
+                warn!("Pusher task fell behind, skipped {skipped} messages");
+            }
             _ => {}
         }
     }
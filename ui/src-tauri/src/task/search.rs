@@ -1,54 +1,233 @@
-use log::{error, info};
-use settings::Settings;
+use log::{error, info, warn};
+use search::{Embedder, Maintenance};
+use settings::{MessagesSearchIndex, Settings};
 use std::path::PathBuf;
-use std::sync::Arc;
+use std::sync::{Arc, PoisonError};
+use std::time::{Duration, Instant};
 use thiserror::Error;
+use tokio::task::JoinHandle;
+use ui_lib::{AppData, AppState, state::UnlockError};
+
+/// How often the settings are checked and another page of history is indexed
+const INDEX_INTERVAL: Duration = Duration::from_secs(5);
+/// How often live changes from pusher are written to the index
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
 
 #[derive(Debug, Error)]
 pub enum SearchError {
     #[error("Settings error: {0}")]
     Settings(#[from] settings::SettingsError),
+    #[error("Unlock error: {0}")]
+    Unlock(#[from] UnlockError),
+}
+
+/// The indexer of one organization and its catch up since the last run
+struct Indexer {
+    indexer: Arc<search::MessageIndexer>,
+    fastforward: JoinHandle<()>,
 }
 
-// TODO: handle changes in settings like path changing
-pub async fn run() -> Result<(), SearchError> {
-    let mut future = None;
+impl Drop for Indexer {
+    fn drop(&mut self) {
+        self.fastforward.abort();
+    }
+}
+
+/// Load the embedding model in the settings, searching by meaning is off when it can't be
+async fn load_embedder(index_settings: &MessagesSearchIndex) -> Option<Arc<dyn Embedder>> {
+    let dir = PathBuf::from(index_settings.embedding_model.as_ref()?);
+    match tokio::task::spawn_blocking(move || search::LocalEmbedder::from_dir(&dir)).await {
+        Ok(Ok(embedder)) => Some(Arc::new(embedder)),
+        Ok(Err(e)) => {
+            error!("Failed to load the embedding model: {e}");
+            None
+        }
+        Err(e) => {
+            error!("Failed to load the embedding model: {e}");
+            None
+        }
+    }
+}
+
+/// Open the indexes of `organizations` and start catching them up
+async fn start(
+    organizations: &[&AppData],
+    index_settings: &MessagesSearchIndex,
+    embedder: Option<Arc<dyn Embedder>>,
+) -> Vec<Indexer> {
+    let mut indexers = vec![];
+    for state in organizations {
+        let path = index_settings.index_path(&state.account);
+        let Some(organization_id) = state.account.organization_id else {
+            error!(
+                "No organization recorded for {}, not indexing it",
+                path.display()
+            );
+            continue;
+        };
+        let indexer = match search::MessageIndexer::new(
+            state.client.clone(),
+            organization_id,
+            &path,
+            search::IndexerSettings {
+                max_size: Some(index_settings.max_size as usize),
+                embedder: embedder.clone(),
+            },
+        )
+        .await
+        {
+            Ok(indexer) => Arc::new(indexer),
+            Err(e) => {
+                error!("Failed to open the search index at {}: {e}", path.display());
+                continue;
+            }
+        };
+        let fastforward = tokio::task::spawn({
+            let indexer = indexer.clone();
+            async move {
+                match indexer.fastforward().await {
+                    Ok(()) => info!("Fastforward complete"),
+                    Err(e) => error!("Fastforward error: {e}"),
+                }
+            }
+        });
+        *state
+            .search_indexer
+            .write()
+            .unwrap_or_else(PoisonError::into_inner) = Some(indexer.clone());
+        indexers.push(Indexer {
+            indexer,
+            fastforward,
+        });
+    }
+    indexers
+}
+
+/// Stop handing pusher changes to the indexers
+fn stop(context: &AppState) -> Result<(), SearchError> {
+    for state in context.organizations()? {
+        *state
+            .search_indexer
+            .write()
+            .unwrap_or_else(PoisonError::into_inner) = None;
+    }
+    Ok(())
+}
+
+/// Maintenance asked for in the settings, with the organization and index it's for
+/// and the index's map size
+fn requested_maintenance(
+    context: &AppState,
+    index_settings: Option<&MessagesSearchIndex>,
+) -> Result<Vec<(usize, PathBuf, usize, Maintenance)>, SearchError> {
+    let Some(index_settings) = index_settings else {
+        return Ok(vec![]);
+    };
+    let mut requested = vec![];
+    for (index, state) in context.organizations()?.iter().enumerate() {
+        let maintenance = *state
+            .search_maintenance
+            .read()
+            .unwrap_or_else(PoisonError::into_inner);
+        if let Some(maintenance) = maintenance {
+            requested.push((
+                index,
+                index_settings.index_path(&state.account),
+                search::map_size(Some(index_settings.max_size as usize)),
+                maintenance,
+            ));
+        }
+    }
+    Ok(requested)
+}
+
+/// Run `requested` with the indexers stopped, they're started again by the caller
+async fn maintain(
+    context: &AppState,
+    requested: Vec<(usize, PathBuf, usize, Maintenance)>,
+) -> Result<(), SearchError> {
+    for (index, path, map_size, maintenance) in requested {
+        info!(
+            "Search index maintenance: {maintenance:?} {}",
+            path.display()
+        );
+        match tokio::task::spawn_blocking(move || maintenance.run(&path, map_size)).await {
+            Ok(Ok(())) => info!("Search index maintenance done"),
+            Ok(Err(e)) => error!("Search index maintenance failed: {e}"),
+            Err(e) => error!("Search index maintenance failed: {e}"),
+        }
+        // Unless something else was asked for meanwhile
+        let mut requested = context
+            .organization(index)?
+            .search_maintenance
+            .write()
+            .unwrap_or_else(PoisonError::into_inner);
+        if *requested == Some(maintenance) {
+            *requested = None;
+        }
+    }
+    Ok(())
+}
+
+/// Keeps a message index per organization while message search is enabled in the settings
+pub async fn run(context: AppState) -> Result<(), SearchError> {
+    while !context.is_loaded() {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+
+    let mut current: Option<MessagesSearchIndex> = None;
+    let mut embedder = None;
+    let mut indexers = vec![];
+    // How many organizations the indexers were started for
+    let mut started = 0;
+    let mut last_indexed: Option<Instant> = None;
     loop {
-        let settings = Settings::load().await?;
-        if future.is_none() && settings.search.messages.is_some() {
-            if let Some(ref message_index_settings) = settings.search.messages {
-                let path = PathBuf::from(message_index_settings.path.clone());
-                tokio::fs::create_dir_all(&path).await.unwrap();
-                let indexer = Arc::new(
-                    search::MessageIndexer::new(
-                        &path,
-                        search::IndexerSettings {
-                            max_size: Some(message_index_settings.max_size as usize),
-                        },
-                    )
-                    .await,
+        let requested = requested_maintenance(&context, current.as_ref())?;
+        if !requested.is_empty() {
+            stop(&context)?;
+            indexers.clear();
+            maintain(&context, requested).await?;
+            // Start the indexers again right away
+            current = None;
+            started = 0;
+            last_indexed = None;
+        }
+        if last_indexed.is_none_or(|last| last.elapsed() >= INDEX_INTERVAL) {
+            let settings = Settings::load().await?;
+            if settings.search.messages != current {
+                // Dropping the indexers stops their catch up
+                stop(&context)?;
+                indexers.clear();
+                started = 0;
+                embedder = match settings.search.messages {
+                    Some(ref index_settings) => load_embedder(index_settings).await,
+                    None => None,
+                };
+                current = settings.search.messages;
+            }
+            // Organizations logged in since are indexed too
+            let organizations = context.organizations()?;
+            if let Some(ref index_settings) = current
+                && started < organizations.len()
+            {
+                indexers.extend(
+                    start(&organizations[started..], index_settings, embedder.clone()).await,
                 );
-                future = Some(tokio::task::spawn({
-                    let indexer = indexer.clone();
-                    async move {
-                        let result = indexer.fastforward().await;
-                        info!("Fastforward complete");
-                        if let Err(e) = result {
-                            error!("Error: {:?}", e);
-                        }
-                    }
-                }));
             }
-        }
-        if settings.search.messages.is_none() {
-            let mut set_none = false;
-            if let Some(future) = future.take() {
-                future.abort();
-                set_none = true;
+            started = organizations.len();
+            for indexer in &indexers {
+                if let Err(e) = indexer.indexer.execute().await {
+                    warn!("Failed to index messages: {e}");
+                }
             }
-            if set_none {
-                future = None;
+            last_indexed = Some(Instant::now());
+        } else {
+            for indexer in &indexers {
+                if let Err(e) = indexer.indexer.flush().await {
+                    warn!("Failed to index live changes: {e}");
+                }
             }
         }
+        tokio::time::sleep(FLUSH_INTERVAL).await;
     }
 }
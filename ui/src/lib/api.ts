@@ -1,6 +1,20 @@
 import {invoke} from "@tauri-apps/api/core";
 import {toast} from "svelte-sonner";
 
+const PROXY_URL = "http://localhost:10521";
+
+// Serve pronto media through the local cache, thumbnails fit in a `thumbnail` pixel square
+export function mediaUrl(url: string | null, thumbnail?: number): string | null {
+    if (!url) {
+        return null;
+    }
+    const params = new URLSearchParams({url});
+    if (thumbnail) {
+        params.set("thumbnail", thumbnail.toString());
+    }
+    return `${PROXY_URL}/media?${params}`;
+}
+
 export async function getCode(email: string): Promise<void> {
     try {
         return await invoke("get_code", {email});
@@ -100,6 +114,17 @@ export async function getMoreMessages(lastMessageId: number): Promise<any[]> {
     }
 }
 
+// The translation pronto added for a message, null if there is none.
+// Fetched in the background for every message shown, so failures are only logged.
+export async function getMessageTranslation(messageId: number) {
+    try {
+        return await invoke("get_message_translation", {messageId});
+    } catch (e) {
+        console.warn("Error getting message translation", e);
+        return null;
+    }
+}
+
 export async function getParentMessages(): Promise<any[]> {
     try {
         return await invoke("get_parent_messages");
@@ -136,6 +161,15 @@ export async function sendMessage(message: string, thread?: number) {
     }
 }
 
+export async function sendFiles(paths: string[], message: string, thread?: number) {
+    try {
+        return await invoke("send_files", {paths, message, thread});
+    } catch (e) {
+        toast.error("Error sending files", {description: JSON.stringify(e)});
+        throw e;
+    }
+}
+
 export async function deleteMessage(messageId: number) {
     try {
         return await invoke("delete_message", {messageId});
@@ -154,6 +188,33 @@ export async function setReactionState(messageId: number, reactionId: number, ac
     }
 }
 
+export async function getOutbox(): Promise<any[]> {
+    try {
+        return await invoke("get_outbox");
+    } catch (e) {
+        toast.error("Error getting unsent changes", {description: JSON.stringify(e)});
+        throw e;
+    }
+}
+
+export async function getOrganizations(): Promise<any[]> {
+    try {
+        return await invoke("get_organizations");
+    } catch (e) {
+        toast.error("Error getting organizations", {description: JSON.stringify(e)});
+        throw e;
+    }
+}
+
+export async function switchOrganization(index: number): Promise<void> {
+    try {
+        await invoke("switch_organization", {index});
+    } catch (e) {
+        toast.error("Error switching organization", {description: JSON.stringify(e)});
+        throw e;
+    }
+}
+
 export async function getChannelUsers(id: number): Promise<any> {
     try {
         return await invoke("get_channel_users", {id});
@@ -298,6 +359,129 @@ export async function userSearch(query: string) {
     }
 }
 
+export async function searchLocal(query: string, offset?: number): Promise<any> {
+    try {
+        return await invoke("search_local", {query, offset});
+    } catch (e) {
+        toast.error("Error searching messages", {description: JSON.stringify(e)});
+        throw e;
+    }
+}
+
+export async function maintainSearchIndex(maintenance: "compact" | "rebuild") {
+    try {
+        return await invoke("maintain_search_index", {maintenance});
+    } catch (e) {
+        toast.error("Error maintaining the search index", {description: JSON.stringify(e)});
+        throw e;
+    }
+}
+
+export async function exportPublicKey(): Promise<any> {
+    try {
+        return await invoke("export_public_key");
+    } catch (e) {
+        toast.error("Error exporting public key", {description: JSON.stringify(e)});
+        throw e;
+    }
+}
+
+export async function hasPublicKey(userId: number): Promise<boolean> {
+    try {
+        return await invoke("has_public_key", {userId});
+    } catch (e) {
+        toast.error("Error looking up public key", {description: JSON.stringify(e)});
+        throw e;
+    }
+}
+
+// Returns the members who can't read the bubble because they haven't published a key,
+// or theirs changed and isn't verified yet
+export async function encryptBubble(bubbleId: number): Promise<{unpublished: number[], changed: number[]}> {
+    try {
+        return await invoke("encrypt_bubble", {bubbleId});
+    } catch (e) {
+        toast.error("Error encrypting bubble", {description: JSON.stringify(e)});
+        throw e;
+    }
+}
+
+export async function isBubbleEncrypted(bubbleId: number): Promise<boolean> {
+    try {
+        return await invoke("is_bubble_encrypted", {bubbleId});
+    } catch (e) {
+        toast.error("Error checking bubble encryption", {description: JSON.stringify(e)});
+        throw e;
+    }
+}
+
+export async function getKeyTrust(userId: number) {
+    try {
+        return await invoke("get_key_trust", {userId});
+    } catch (e) {
+        toast.error("Error checking public key", {description: JSON.stringify(e)});
+        throw e;
+    }
+}
+
+export async function verifyPublicKey(userId: number, fingerprint: string, verified: boolean): Promise<boolean> {
+    try {
+        return await invoke("verify_public_key", {userId, fingerprint, verified});
+    } catch (e) {
+        toast.error("Error verifying public key", {description: JSON.stringify(e)});
+        throw e;
+    }
+}
+
+export async function exportKeyBackup(passphrase: string): Promise<string> {
+    try {
+        return await invoke("export_key_backup", {passphrase});
+    } catch (e) {
+        toast.error("Error exporting key backup", {description: JSON.stringify(e)});
+        throw e;
+    }
+}
+
+// Fails with a KeyExists error unless overwrite, if this device has another key
+export async function importKeyBackup(backup: string, passphrase: string, overwrite: boolean) {
+    try {
+        return await invoke("import_key_backup", {backup, passphrase, overwrite});
+    } catch (e) {
+        toast.error("Error importing key backup", {description: JSON.stringify(e)});
+        throw e;
+    }
+}
+
+// On the new device, returns the code to enter on the device with the key
+export async function startDeviceLink(): Promise<string> {
+    try {
+        return await invoke("start_device_link");
+    } catch (e) {
+        toast.error("Error starting device link", {description: JSON.stringify(e)});
+        throw e;
+    }
+}
+
+// On the device with the key, returns the payload to enter on the new device
+export async function linkDevice(code: string): Promise<string> {
+    try {
+        return await invoke("link_device", {code});
+    } catch (e) {
+        toast.error("Error linking device", {description: JSON.stringify(e)});
+        throw e;
+    }
+}
+
+// Fails with a KeyExists error unless overwrite, if this device has another key
+export async function finishDeviceLink(payload: string, overwrite: boolean) {
+    try {
+        return await invoke("finish_device_link", {payload, overwrite});
+    } catch (e) {
+        toast.error("Error finishing device link", {description: JSON.stringify(e)});
+        throw e;
+    }
+}
+
 export async function getAnnouncements() {
     try {
         return await invoke("get_announcements");